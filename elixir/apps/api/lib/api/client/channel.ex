@@ -143,7 +143,8 @@ defmodule API.Client.Channel do
 
   # This the list of ICE candidates gathered by the gateway and relayed to the client
   def handle_info(
-        {:ice_candidates, gateway_id, candidates, {opentelemetry_ctx, opentelemetry_span_ctx}},
+        {:ice_candidates, gateway_id, candidates, end_of_candidates,
+         {opentelemetry_ctx, opentelemetry_span_ctx}},
         socket
       ) do
     OpenTelemetry.Ctx.attach(opentelemetry_ctx)
@@ -156,7 +157,8 @@ defmodule API.Client.Channel do
       } do
       push(socket, "ice_candidates", %{
         gateway_id: gateway_id,
-        candidates: candidates
+        candidates: candidates,
+        end_of_candidates: end_of_candidates
       })
 
       {:noreply, socket}
@@ -185,6 +187,26 @@ defmodule API.Client.Channel do
     end
   end
 
+  def handle_info(
+        {:ice_credentials, gateway_id, credentials, {opentelemetry_ctx, opentelemetry_span_ctx}},
+        socket
+      ) do
+    OpenTelemetry.Ctx.attach(opentelemetry_ctx)
+    OpenTelemetry.Tracer.set_current_span(opentelemetry_span_ctx)
+
+    OpenTelemetry.Tracer.with_span "client.ice_credentials",
+      attributes: %{
+        gateway_id: gateway_id
+      } do
+      push(socket, "ice_credentials", %{
+        gateway_id: gateway_id,
+        credentials: credentials
+      })
+
+      {:noreply, socket}
+    end
+  end
+
   # This message is sent by the gateway when it is ready to accept the connection from the client
   def handle_info(
         {:connect, socket_ref, resource_id, gateway_public_key, payload,
@@ -564,9 +586,11 @@ defmodule API.Client.Channel do
   # The client pushes it's ICE candidates list and the list of gateways that need to receive it
   def handle_in(
         "broadcast_ice_candidates",
-        %{"candidates" => candidates, "gateway_ids" => gateway_ids},
+        %{"candidates" => candidates, "gateway_ids" => gateway_ids} = attrs,
         socket
       ) do
+    end_of_candidates = Map.get(attrs, "end_of_candidates", false)
+
     OpenTelemetry.Ctx.attach(socket.assigns.opentelemetry_ctx)
     OpenTelemetry.Tracer.set_current_span(socket.assigns.opentelemetry_span_ctx)
 
@@ -578,7 +602,7 @@ defmodule API.Client.Channel do
         Enum.each(gateway_ids, fn gateway_id ->
           Gateways.broadcast_to_gateway(
             gateway_id,
-            {:ice_candidates, socket.assigns.client.id, candidates,
+            {:ice_candidates, socket.assigns.client.id, candidates, end_of_candidates,
              {opentelemetry_ctx, opentelemetry_span_ctx}}
           )
         end)
@@ -612,6 +636,59 @@ defmodule API.Client.Channel do
     end
   end
 
+  # The client restarted ICE and pushes its new credentials to the gateways it is connected to
+  def handle_in(
+        "broadcast_ice_credentials",
+        %{"credentials" => credentials, "gateway_ids" => gateway_ids},
+        socket
+      ) do
+    OpenTelemetry.Ctx.attach(socket.assigns.opentelemetry_ctx)
+    OpenTelemetry.Tracer.set_current_span(socket.assigns.opentelemetry_span_ctx)
+
+    OpenTelemetry.Tracer.with_span "client.broadcast_ice_credentials" do
+      opentelemetry_ctx = OpenTelemetry.Ctx.get_current()
+      opentelemetry_span_ctx = OpenTelemetry.Tracer.current_span_ctx()
+
+      :ok =
+        Enum.each(gateway_ids, fn gateway_id ->
+          Gateways.broadcast_to_gateway(
+            gateway_id,
+            {:ice_credentials, socket.assigns.client.id, credentials,
+             {opentelemetry_ctx, opentelemetry_span_ctx}}
+          )
+        end)
+
+      {:noreply, socket}
+    end
+  end
+
+  # The client rotated the preshared key of its connection to a gateway
+  def handle_in(
+        "rotate_preshared_key",
+        %{"gateway_id" => gateway_id, "preshared_key" => preshared_key},
+        socket
+      ) do
+    OpenTelemetry.Ctx.attach(socket.assigns.opentelemetry_ctx)
+    OpenTelemetry.Tracer.set_current_span(socket.assigns.opentelemetry_span_ctx)
+
+    OpenTelemetry.Tracer.with_span "client.rotate_preshared_key",
+      attributes: %{
+        gateway_id: gateway_id
+      } do
+      opentelemetry_ctx = OpenTelemetry.Ctx.get_current()
+      opentelemetry_span_ctx = OpenTelemetry.Tracer.current_span_ctx()
+
+      :ok =
+        Gateways.broadcast_to_gateway(
+          gateway_id,
+          {:preshared_key, socket.assigns.client.id, preshared_key,
+           {opentelemetry_ctx, opentelemetry_span_ctx}}
+        )
+
+      {:noreply, socket}
+    end
+  end
+
   defp select_relays(socket) do
     {:ok, relays} = Relays.all_connected_relays_for_account(socket.assigns.subject.account)
 
@@ -64,7 +64,8 @@ defmodule API.Gateway.Channel do
   end
 
   def handle_info(
-        {:ice_candidates, client_id, candidates, {opentelemetry_ctx, opentelemetry_span_ctx}},
+        {:ice_candidates, client_id, candidates, end_of_candidates,
+         {opentelemetry_ctx, opentelemetry_span_ctx}},
         socket
       ) do
     OpenTelemetry.Ctx.attach(opentelemetry_ctx)
@@ -77,7 +78,8 @@ defmodule API.Gateway.Channel do
       } do
       push(socket, "ice_candidates", %{
         client_id: client_id,
-        candidates: candidates
+        candidates: candidates,
+        end_of_candidates: end_of_candidates
       })
 
       {:noreply, socket}
@@ -106,6 +108,46 @@ defmodule API.Gateway.Channel do
     end
   end
 
+  def handle_info(
+        {:ice_credentials, client_id, credentials, {opentelemetry_ctx, opentelemetry_span_ctx}},
+        socket
+      ) do
+    OpenTelemetry.Ctx.attach(opentelemetry_ctx)
+    OpenTelemetry.Tracer.set_current_span(opentelemetry_span_ctx)
+
+    OpenTelemetry.Tracer.with_span "gateway.ice_credentials",
+      attributes: %{
+        client_id: client_id
+      } do
+      push(socket, "ice_credentials", %{
+        client_id: client_id,
+        credentials: credentials
+      })
+
+      {:noreply, socket}
+    end
+  end
+
+  def handle_info(
+        {:preshared_key, client_id, preshared_key, {opentelemetry_ctx, opentelemetry_span_ctx}},
+        socket
+      ) do
+    OpenTelemetry.Ctx.attach(opentelemetry_ctx)
+    OpenTelemetry.Tracer.set_current_span(opentelemetry_span_ctx)
+
+    OpenTelemetry.Tracer.with_span "gateway.preshared_key",
+      attributes: %{
+        client_id: client_id
+      } do
+      push(socket, "preshared_key", %{
+        client_id: client_id,
+        preshared_key: preshared_key
+      })
+
+      {:noreply, socket}
+    end
+  end
+
   def handle_info(
         {:allow_access, {channel_pid, socket_ref}, attrs,
          {opentelemetry_ctx, opentelemetry_span_ctx}},
@@ -343,9 +385,11 @@ defmodule API.Gateway.Channel do
 
   def handle_in(
         "broadcast_ice_candidates",
-        %{"candidates" => candidates, "client_ids" => client_ids},
+        %{"candidates" => candidates, "client_ids" => client_ids} = attrs,
         socket
       ) do
+    end_of_candidates = Map.get(attrs, "end_of_candidates", false)
+
     OpenTelemetry.Ctx.attach(socket.assigns.opentelemetry_ctx)
     OpenTelemetry.Tracer.set_current_span(socket.assigns.opentelemetry_span_ctx)
 
@@ -357,7 +401,7 @@ defmodule API.Gateway.Channel do
         Enum.each(client_ids, fn client_id ->
           Clients.broadcast_to_client(
             client_id,
-            {:ice_candidates, socket.assigns.gateway.id, candidates,
+            {:ice_candidates, socket.assigns.gateway.id, candidates, end_of_candidates,
              {opentelemetry_ctx, opentelemetry_span_ctx}}
           )
         end)
@@ -391,6 +435,32 @@ defmodule API.Gateway.Channel do
     end
   end
 
+  # The gateway restarted ICE and pushes its new credentials to the clients it is connected to
+  def handle_in(
+        "broadcast_ice_credentials",
+        %{"credentials" => credentials, "client_ids" => client_ids},
+        socket
+      ) do
+    OpenTelemetry.Ctx.attach(socket.assigns.opentelemetry_ctx)
+    OpenTelemetry.Tracer.set_current_span(socket.assigns.opentelemetry_span_ctx)
+
+    OpenTelemetry.Tracer.with_span "gateway.broadcast_ice_credentials" do
+      opentelemetry_ctx = OpenTelemetry.Ctx.get_current()
+      opentelemetry_span_ctx = OpenTelemetry.Tracer.current_span_ctx()
+
+      :ok =
+        Enum.each(client_ids, fn client_id ->
+          Clients.broadcast_to_client(
+            client_id,
+            {:ice_credentials, socket.assigns.gateway.id, credentials,
+             {opentelemetry_ctx, opentelemetry_span_ctx}}
+          )
+        end)
+
+      {:noreply, socket}
+    end
+  end
+
   def handle_in(
         "metrics",
         %{
@@ -426,14 +426,15 @@ defmodule API.Client.ChannelTest do
 
       send(
         socket.channel_pid,
-        {:ice_candidates, gateway.id, candidates, otel_ctx}
+        {:ice_candidates, gateway.id, candidates, true, otel_ctx}
       )
 
       assert_push "ice_candidates", payload
 
       assert payload == %{
                candidates: candidates,
-               gateway_id: gateway.id
+               gateway_id: gateway.id,
+               end_of_candidates: true
              }
     end
   end
@@ -461,6 +462,29 @@ defmodule API.Client.ChannelTest do
     end
   end
 
+  describe "handle_info/2 :ice_credentials" do
+    test "pushes ice_credentials message", %{
+      gateway: gateway,
+      socket: socket
+    } do
+      otel_ctx = {OpenTelemetry.Ctx.new(), OpenTelemetry.Tracer.start_span("connect")}
+
+      credentials = %{"username" => "foo", "password" => "bar"}
+
+      send(
+        socket.channel_pid,
+        {:ice_credentials, gateway.id, credentials, otel_ctx}
+      )
+
+      assert_push "ice_credentials", payload
+
+      assert payload == %{
+               credentials: credentials,
+               gateway_id: gateway.id
+             }
+    end
+  end
+
   describe "handle_info/2 :update_resource" do
     test "pushes message to the socket for authorized clients", %{
       gateway_group: gateway_group,
@@ -1426,7 +1450,7 @@ defmodule API.Client.ChannelTest do
       }
 
       push(socket, "broadcast_ice_candidates", attrs)
-      refute_receive {:ice_candidates, _client_id, _candidates, _opentelemetry_ctx}
+      refute_receive {:ice_candidates, _client_id, _candidates, _end_of_candidates, _otel_ctx}
     end
 
     test "broadcasts :ice_candidates message to all gateways", %{
@@ -1447,7 +1471,30 @@ defmodule API.Client.ChannelTest do
 
       push(socket, "broadcast_ice_candidates", attrs)
 
-      assert_receive {:ice_candidates, client_id, ^candidates, _opentelemetry_ctx}, 200
+      assert_receive {:ice_candidates, client_id, ^candidates, false, _opentelemetry_ctx}, 200
+      assert client.id == client_id
+    end
+
+    test "forwards end of candidates to gateways", %{
+      client: client,
+      gateway_group_token: gateway_group_token,
+      gateway: gateway,
+      socket: socket
+    } do
+      candidates = ["foo", "bar"]
+
+      attrs = %{
+        "candidates" => candidates,
+        "gateway_ids" => [gateway.id],
+        "end_of_candidates" => true
+      }
+
+      :ok = Domain.Gateways.connect_gateway(gateway)
+      Domain.PubSub.subscribe(Domain.Tokens.socket_id(gateway_group_token))
+
+      push(socket, "broadcast_ice_candidates", attrs)
+
+      assert_receive {:ice_candidates, client_id, ^candidates, true, _opentelemetry_ctx}, 200
       assert client.id == client_id
     end
   end
@@ -1489,4 +1536,64 @@ defmodule API.Client.ChannelTest do
       assert client.id == client_id
     end
   end
+
+  describe "handle_in/3 broadcast_ice_credentials" do
+    test "does nothing when gateways list is empty", %{
+      socket: socket
+    } do
+      attrs = %{
+        "credentials" => %{"username" => "foo", "password" => "bar"},
+        "gateway_ids" => []
+      }
+
+      push(socket, "broadcast_ice_credentials", attrs)
+      refute_receive {:ice_credentials, _client_id, _credentials, _opentelemetry_ctx}
+    end
+
+    test "broadcasts :ice_credentials message to all gateways", %{
+      client: client,
+      gateway_group_token: gateway_group_token,
+      gateway: gateway,
+      socket: socket
+    } do
+      credentials = %{"username" => "foo", "password" => "bar"}
+
+      attrs = %{
+        "credentials" => credentials,
+        "gateway_ids" => [gateway.id]
+      }
+
+      :ok = Domain.Gateways.connect_gateway(gateway)
+      Domain.PubSub.subscribe(Domain.Tokens.socket_id(gateway_group_token))
+
+      push(socket, "broadcast_ice_credentials", attrs)
+
+      assert_receive {:ice_credentials, client_id, ^credentials, _opentelemetry_ctx}, 200
+      assert client.id == client_id
+    end
+  end
+
+  describe "handle_in/3 rotate_preshared_key" do
+    test "sends :preshared_key message to the gateway", %{
+      client: client,
+      gateway_group_token: gateway_group_token,
+      gateway: gateway,
+      socket: socket
+    } do
+      preshared_key = "NZEyHD409lCdOPqmnLffloVEqgeGOe43u+pz10awMFc="
+
+      attrs = %{
+        "preshared_key" => preshared_key,
+        "gateway_id" => gateway.id
+      }
+
+      :ok = Domain.Gateways.connect_gateway(gateway)
+      Domain.PubSub.subscribe(Domain.Tokens.socket_id(gateway_group_token))
+
+      push(socket, "rotate_preshared_key", attrs)
+
+      assert_receive {:preshared_key, client_id, ^preshared_key, _opentelemetry_ctx}, 200
+      assert client.id == client_id
+    end
+  end
 end
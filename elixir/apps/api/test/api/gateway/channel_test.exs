@@ -330,14 +330,15 @@ defmodule API.Gateway.ChannelTest do
 
       send(
         socket.channel_pid,
-        {:ice_candidates, client.id, candidates, otel_ctx}
+        {:ice_candidates, client.id, candidates, true, otel_ctx}
       )
 
       assert_push "ice_candidates", payload
 
       assert payload == %{
                candidates: candidates,
-               client_id: client.id
+               client_id: client.id,
+               end_of_candidates: true
              }
     end
   end
@@ -365,6 +366,52 @@ defmodule API.Gateway.ChannelTest do
     end
   end
 
+  describe "handle_info/2 :ice_credentials" do
+    test "pushes ice_credentials message", %{
+      client: client,
+      socket: socket
+    } do
+      otel_ctx = {OpenTelemetry.Ctx.new(), OpenTelemetry.Tracer.start_span("connect")}
+
+      credentials = %{"username" => "foo", "password" => "bar"}
+
+      send(
+        socket.channel_pid,
+        {:ice_credentials, client.id, credentials, otel_ctx}
+      )
+
+      assert_push "ice_credentials", payload
+
+      assert payload == %{
+               credentials: credentials,
+               client_id: client.id
+             }
+    end
+  end
+
+  describe "handle_info/2 :preshared_key" do
+    test "pushes preshared_key message", %{
+      client: client,
+      socket: socket
+    } do
+      otel_ctx = {OpenTelemetry.Ctx.new(), OpenTelemetry.Tracer.start_span("connect")}
+
+      preshared_key = "NZEyHD409lCdOPqmnLffloVEqgeGOe43u+pz10awMFc="
+
+      send(
+        socket.channel_pid,
+        {:preshared_key, client.id, preshared_key, otel_ctx}
+      )
+
+      assert_push "preshared_key", payload
+
+      assert payload == %{
+               preshared_key: preshared_key,
+               client_id: client.id
+             }
+    end
+  end
+
   describe "handle_info/2 :request_connection" do
     test "pushes request_connection message with managed relays", %{
       client: client,
@@ -828,7 +875,7 @@ defmodule API.Gateway.ChannelTest do
       }
 
       push(socket, "broadcast_ice_candidates", attrs)
-      refute_receive {:ice_candidates, _client_id, _candidates, _opentelemetry_ctx}
+      refute_receive {:ice_candidates, _client_id, _candidates, _end_of_candidates, _otel_ctx}
     end
 
     test "broadcasts :ice_candidates message to all gateways", %{
@@ -849,7 +896,30 @@ defmodule API.Gateway.ChannelTest do
 
       push(socket, "broadcast_ice_candidates", attrs)
 
-      assert_receive {:ice_candidates, gateway_id, ^candidates, _opentelemetry_ctx}, 200
+      assert_receive {:ice_candidates, gateway_id, ^candidates, false, _opentelemetry_ctx}, 200
+      assert gateway.id == gateway_id
+    end
+
+    test "forwards end of candidates to clients", %{
+      client: client,
+      gateway: gateway,
+      subject: subject,
+      socket: socket
+    } do
+      candidates = ["foo", "bar"]
+
+      attrs = %{
+        "candidates" => candidates,
+        "client_ids" => [client.id],
+        "end_of_candidates" => true
+      }
+
+      :ok = Domain.Clients.connect_client(client)
+      Domain.PubSub.subscribe(Domain.Tokens.socket_id(subject.token_id))
+
+      push(socket, "broadcast_ice_candidates", attrs)
+
+      assert_receive {:ice_candidates, gateway_id, ^candidates, true, _opentelemetry_ctx}, 200
       assert gateway.id == gateway_id
     end
   end
@@ -894,6 +964,42 @@ defmodule API.Gateway.ChannelTest do
     end
   end
 
+  describe "handle_in/3 broadcast_ice_credentials" do
+    test "does nothing when clients list is empty", %{
+      socket: socket
+    } do
+      attrs = %{
+        "credentials" => %{"username" => "foo", "password" => "bar"},
+        "client_ids" => []
+      }
+
+      push(socket, "broadcast_ice_credentials", attrs)
+      refute_receive {:ice_credentials, _gateway_id, _credentials, _opentelemetry_ctx}
+    end
+
+    test "broadcasts :ice_credentials message to all clients", %{
+      client: client,
+      gateway: gateway,
+      subject: subject,
+      socket: socket
+    } do
+      credentials = %{"username" => "foo", "password" => "bar"}
+
+      attrs = %{
+        "credentials" => credentials,
+        "client_ids" => [client.id]
+      }
+
+      :ok = Domain.Clients.connect_client(client)
+      Domain.PubSub.subscribe(Domain.Tokens.socket_id(subject.token_id))
+
+      push(socket, "broadcast_ice_credentials", attrs)
+
+      assert_receive {:ice_credentials, gateway_id, ^credentials, _opentelemetry_ctx}, 200
+      assert gateway.id == gateway_id
+    end
+  end
+
   describe "handle_in/3 metrics" do
     test "inserts activities", %{
       account: account,
@@ -6,6 +6,7 @@ interface ConnlibCallback {
         addressIPv4: String,
         addressIPv6: String,
         dnsAddresses: String,
+        searchDomains: String,
     )
 
     fun onUpdateRoutes(
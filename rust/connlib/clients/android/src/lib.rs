@@ -6,6 +6,7 @@
 use connlib_client_shared::{
     callbacks::ResourceDescription, file_logger, keypair, Callbacks, ConnectArgs, Error, LoginUrl,
     LoginUrlError, Session, Sockets, Tun, V4RouteList, V6RouteList,
+    BATTERY_SAVING_CONNECTION_CONFIG, DEFAULT_MAX_RELAYS,
 };
 use ip_network::{Ipv4Network, Ipv6Network};
 use jni::{
@@ -152,6 +153,7 @@ impl Callbacks for CallbackHandler {
         tunnel_address_v4: Ipv4Addr,
         tunnel_address_v6: Ipv6Addr,
         dns_addresses: Vec<IpAddr>,
+        search_domains: Vec<String>,
     ) {
         self.env(|mut env| {
             let tunnel_address_v4 =
@@ -172,15 +174,22 @@ impl Callbacks for CallbackHandler {
                     name: "dns_addresses",
                     source,
                 })?;
+            let search_domains = env
+                .new_string(serde_json::to_string(&search_domains)?)
+                .map_err(|source| CallbackError::NewStringFailed {
+                    name: "search_domains",
+                    source,
+                })?;
             let name = "onSetInterfaceConfig";
             env.call_method(
                 &self.callback_handler,
                 name,
-                "(Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;)V",
+                "(Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;)V",
                 &[
                     JValue::from(&tunnel_address_v4),
                     JValue::from(&tunnel_address_v6),
                     JValue::from(&dns_addresses),
+                    JValue::from(&search_domains),
                 ],
             )
             .map_err(|source| CallbackError::CallMethodFailed { name, source })?;
@@ -374,6 +383,10 @@ fn connect(
         app_version: env!("CARGO_PKG_VERSION").to_string(),
         callbacks,
         max_partition_time: Some(MAX_PARTITION_TIME),
+        max_relays: DEFAULT_MAX_RELAYS,
+        connection_config: BATTERY_SAVING_CONNECTION_CONFIG,
+        // Persisting proxy IPs is only supported on Linux and Windows, the Android app doesn't give us a directory for state.
+        proxy_ip_store: None,
     };
     let session = Session::connect(args, runtime.handle().clone());
 
@@ -5,7 +5,8 @@ mod make_writer;
 
 use connlib_client_shared::{
     callbacks::ResourceDescription, file_logger, keypair, Callbacks, ConnectArgs, Error, LoginUrl,
-    Session, Sockets, Tun, V4RouteList, V6RouteList,
+    Session, Sockets, Tun, V4RouteList, V6RouteList, BATTERY_SAVING_CONNECTION_CONFIG,
+    DEFAULT_MAX_RELAYS,
 };
 use ip_network::{Ipv4Network, Ipv6Network};
 use secrecy::SecretString;
@@ -66,6 +67,7 @@ mod ffi {
             tunnelAddressIPv4: String,
             tunnelAddressIPv6: String,
             dnsAddresses: String,
+            searchDomains: String,
         );
 
         #[swift_bridge(swift_name = "onUpdateRoutes")]
@@ -109,12 +111,15 @@ impl Callbacks for CallbackHandler {
         tunnel_address_v4: Ipv4Addr,
         tunnel_address_v6: Ipv6Addr,
         dns_addresses: Vec<IpAddr>,
+        search_domains: Vec<String>,
     ) {
         self.inner.on_set_interface_config(
             tunnel_address_v4.to_string(),
             tunnel_address_v6.to_string(),
             serde_json::to_string(&dns_addresses)
                 .expect("developer error: a list of ips should always be serializable"),
+            serde_json::to_string(&search_domains)
+                .expect("developer error: a list of strings should always be serializable"),
         );
     }
 
@@ -202,6 +207,10 @@ impl WrappedSession {
                 inner: Arc::new(callback_handler),
             },
             max_partition_time: Some(MAX_PARTITION_TIME),
+            max_relays: DEFAULT_MAX_RELAYS,
+            connection_config: BATTERY_SAVING_CONNECTION_CONFIG,
+            // Persisting proxy IPs is only supported on Linux and Windows, the Apple app doesn't give us a directory for state.
+            proxy_ip_store: None,
         };
         let session = Session::connect(args, runtime.handle().clone());
         let _enter = runtime.enter();
@@ -1,22 +1,31 @@
 use crate::{
     messages::{
-        Connect, ConnectionDetails, EgressMessages, GatewayIceCandidates, GatewaysIceCandidates,
-        IngressMessages, InitClient, ReplyMessages,
+        Connect, ConnectionDetails, EgressMessages, GatewayIceCandidates, GatewayIceCredentials,
+        GatewayPresharedKey, GatewaysIceCandidates, GatewaysIceCredentials, IngressMessages,
+        InitClient, ReplyMessages,
     },
-    PHOENIX_TOPIC,
+    ProxyIpStore, PHOENIX_TOPIC, PROXY_IPS_SAVE_DELAY,
 };
 use anyhow::Result;
 use connlib_shared::{
-    messages::{ConnectionAccepted, GatewayResponse, RelaysPresence, ResourceAccepted, ResourceId},
+    messages::{
+        ConnectionAccepted, GatewayResponse, Key, RelaysPresence, ResourceAccepted, ResourceId,
+    },
     Callbacks,
 };
 use firezone_tunnel::{ClientTunnel, Tun};
 use phoenix_channel::{ErrorReply, OutboundRequestId, PhoenixChannel};
+use secrecy::Secret;
 use std::{
     collections::{HashMap, HashSet},
+    future::Future as _,
+    io,
     net::IpAddr,
-    task::{Context, Poll},
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
 };
+use tokio::{task::JoinHandle, time::Sleep};
 
 pub struct Eventloop<C: Callbacks> {
     tunnel: ClientTunnel<C>,
@@ -25,6 +34,16 @@ pub struct Eventloop<C: Callbacks> {
     rx: tokio::sync::mpsc::UnboundedReceiver<Command>,
 
     connection_intents: SentConnectionIntents,
+
+    proxy_ip_store: Option<Arc<dyn ProxyIpStore>>,
+    /// The latest proxy IPs, if we haven't saved them yet.
+    unsaved_proxy_ips: Option<HashMap<String, Vec<IpAddr>>>,
+    /// Fires [`PROXY_IPS_SAVE_DELAY`] after the first change to the proxy IPs since we last saved them.
+    save_proxy_ips_timer: Option<Pin<Box<Sleep>>>,
+    /// The save that is currently running on a blocking thread.
+    ///
+    /// We only ever run one at a time, such that an older save cannot overwrite a newer one.
+    saving_proxy_ips: Option<JoinHandle<io::Result<()>>>,
 }
 
 /// Commands that can be sent to the [`Eventloop`].
@@ -33,6 +52,7 @@ pub enum Command {
     Reconnect,
     SetDns(Vec<IpAddr>),
     SetTun(Tun),
+    SetDnsQueryAudit(bool),
 }
 
 impl<C: Callbacks> Eventloop<C> {
@@ -40,12 +60,17 @@ impl<C: Callbacks> Eventloop<C> {
         tunnel: ClientTunnel<C>,
         portal: PhoenixChannel<(), IngressMessages, ReplyMessages>,
         rx: tokio::sync::mpsc::UnboundedReceiver<Command>,
+        proxy_ip_store: Option<Box<dyn ProxyIpStore>>,
     ) -> Self {
         Self {
             tunnel,
             portal,
             connection_intents: SentConnectionIntents::default(),
             rx,
+            proxy_ip_store: proxy_ip_store.map(Arc::from),
+            unsaved_proxy_ips: None,
+            save_proxy_ips_timer: None,
+            saving_proxy_ips: None,
         }
     }
 }
@@ -67,6 +92,10 @@ where
                     self.tunnel.set_tun(tun);
                     continue;
                 }
+                Poll::Ready(Some(Command::SetDnsQueryAudit(enabled))) => {
+                    self.tunnel.set_dns_query_audit(enabled);
+                    continue;
+                }
                 Poll::Ready(Some(Command::Reconnect)) => {
                     self.portal.reconnect();
                     if let Err(e) = self.tunnel.reset() {
@@ -98,23 +127,60 @@ where
                 Poll::Pending => {}
             }
 
+            if self.poll_save_proxy_ips(cx).is_ready() {
+                continue;
+            }
+
             return Poll::Pending;
         }
     }
 
+    /// Saves the latest proxy IPs on a blocking thread once [`PROXY_IPS_SAVE_DELAY`] passed.
+    fn poll_save_proxy_ips(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(saving) = self.saving_proxy_ips.as_mut() {
+            let result = ready!(Pin::new(saving).poll(cx));
+            self.saving_proxy_ips = None;
+
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => tracing::warn!("Failed to save proxy IPs: {e}"),
+                Err(e) => tracing::warn!("Failed to save proxy IPs: {e}"),
+            }
+
+            return Poll::Ready(());
+        }
+
+        let Some(timer) = self.save_proxy_ips_timer.as_mut() else {
+            return Poll::Pending;
+        };
+        ready!(timer.as_mut().poll(cx));
+        self.save_proxy_ips_timer = None;
+
+        let (Some(store), Some(proxy_ips)) =
+            (self.proxy_ip_store.clone(), self.unsaved_proxy_ips.take())
+        else {
+            return Poll::Ready(());
+        };
+        self.saving_proxy_ips = Some(tokio::task::spawn_blocking(move || store.save(&proxy_ips)));
+
+        Poll::Ready(())
+    }
+
     fn handle_tunnel_event(&mut self, event: firezone_tunnel::ClientEvent) {
         match event {
             firezone_tunnel::ClientEvent::AddedIceCandidates {
                 conn_id: gateway,
                 candidates,
+                end_of_candidates,
             } => {
-                tracing::debug!(%gateway, ?candidates, "Sending new ICE candidates to gateway");
+                tracing::debug!(%gateway, ?candidates, %end_of_candidates, "Sending new ICE candidates to gateway");
 
                 self.portal.send(
                     PHOENIX_TOPIC,
                     EgressMessages::BroadcastIceCandidates(GatewaysIceCandidates {
                         gateway_ids: vec![gateway],
                         candidates,
+                        end_of_candidates,
                     }),
                 );
             }
@@ -129,6 +195,35 @@ where
                     EgressMessages::BroadcastInvalidatedIceCandidates(GatewaysIceCandidates {
                         gateway_ids: vec![gateway],
                         candidates,
+                        end_of_candidates: false,
+                    }),
+                );
+            }
+            firezone_tunnel::ClientEvent::NewIceCredentials {
+                conn_id: gateway,
+                credentials,
+            } => {
+                tracing::debug!(%gateway, "Sending new ICE credentials to gateway");
+
+                self.portal.send(
+                    PHOENIX_TOPIC,
+                    EgressMessages::BroadcastIceCredentials(GatewaysIceCredentials {
+                        gateway_ids: vec![gateway],
+                        credentials,
+                    }),
+                );
+            }
+            firezone_tunnel::ClientEvent::NewPresharedKey {
+                conn_id: gateway,
+                key,
+            } => {
+                tracing::debug!(%gateway, "Sending new preshared key to gateway");
+
+                self.portal.send(
+                    PHOENIX_TOPIC,
+                    EgressMessages::RotatePresharedKey(GatewayPresharedKey {
+                        gateway_id: gateway,
+                        preshared_key: Secret::new(Key(*key.expose_secret())),
                     }),
                 );
             }
@@ -168,6 +263,20 @@ where
                 // As we decouple the core of connlib from the callbacks, this is where we will hook into the DNS server change and notify our clients to set new DNS servers on their platform.
                 // See https://github.com/firezone/firezone/issues/5106 for details.
             }
+            firezone_tunnel::ClientEvent::DnsQueryAnswered { query } => {
+                self.tunnel.callbacks.on_dns_query(query)
+            }
+            firezone_tunnel::ClientEvent::ProxyIpsChanged { proxy_ips } => {
+                if self.proxy_ip_store.is_none() {
+                    return;
+                }
+
+                self.unsaved_proxy_ips = Some(proxy_ips);
+                if self.save_proxy_ips_timer.is_none() {
+                    self.save_proxy_ips_timer =
+                        Some(Box::pin(tokio::time::sleep(PROXY_IPS_SAVE_DELAY)));
+                }
+            }
         }
     }
 
@@ -206,10 +315,15 @@ where
             IngressMessages::IceCandidates(GatewayIceCandidates {
                 gateway_id,
                 candidates,
+                end_of_candidates,
             }) => {
                 for candidate in candidates {
                     self.tunnel.add_ice_candidate(gateway_id, candidate)
                 }
+
+                if end_of_candidates {
+                    self.tunnel.end_of_ice_candidates(gateway_id)
+                }
             }
             IngressMessages::Init(InitClient {
                 interface,
@@ -240,11 +354,19 @@ where
             IngressMessages::InvalidateIceCandidates(GatewayIceCandidates {
                 gateway_id,
                 candidates,
+                ..
             }) => {
                 for candidate in candidates {
                     self.tunnel.remove_ice_candidate(gateway_id, candidate)
                 }
             }
+            IngressMessages::IceCredentials(GatewayIceCredentials {
+                gateway_id,
+                credentials,
+            }) => {
+                self.tunnel
+                    .set_remote_ice_credentials(gateway_id, credentials);
+            }
         }
     }
 
@@ -252,7 +374,12 @@ where
         match res {
             ReplyMessages::Connect(Connect {
                 gateway_payload:
-                    GatewayResponse::ConnectionAccepted(ConnectionAccepted { ice_parameters, .. }),
+                    GatewayResponse::ConnectionAccepted(ConnectionAccepted {
+                        ice_parameters,
+                        supports_psk_rotation,
+                        supports_dns_resolution,
+                        ..
+                    }),
                 gateway_public_key,
                 resource_id,
                 ..
@@ -261,6 +388,8 @@ where
                     resource_id,
                     ice_parameters,
                     gateway_public_key.0.into(),
+                    supports_psk_rotation,
+                    supports_dns_resolution,
                 ) {
                     tracing::warn!("Failed to accept connection: {e}");
                 }
@@ -5,7 +5,7 @@ pub use connlib_shared::{
     callbacks, keypair, Callbacks, Error, LoginUrl, LoginUrlError, StaticSecret,
 };
 pub use eventloop::Eventloop;
-pub use firezone_tunnel::{Sockets, Tun};
+pub use firezone_tunnel::{ConnectionConfig, Sockets, Tun, DEFAULT_MAX_RELAYS};
 pub use tracing_appender::non_blocking::WorkerGuard;
 
 use backoff::ExponentialBackoffBuilder;
@@ -24,6 +24,20 @@ mod serde_routelist;
 
 const PHOENIX_TOPIC: &str = "client";
 
+/// How long we wait after proxy IPs changed before we save them, such that we save bursts of changes only once.
+pub const PROXY_IPS_SAVE_DELAY: Duration = Duration::from_secs(1);
+
+/// Timings of connections to gateways for clients on battery-powered devices.
+///
+/// Keep-alives are only sent often enough to keep NAT bindings open and consent checks are throttled.
+/// Broken paths are detected later in exchange.
+pub const BATTERY_SAVING_CONNECTION_CONFIG: ConnectionConfig = ConnectionConfig {
+    idle_timeout: Duration::from_secs(5 * 60),
+    keepalive: Some(Duration::from_secs(25)),
+    psk_rotation: None,
+    consent_check_interval: Some(Duration::from_secs(10)),
+};
+
 use eventloop::Command;
 use secrecy::Secret;
 use tokio::task::JoinHandle;
@@ -45,6 +59,30 @@ pub struct ConnectArgs<CB> {
     pub app_version: String,
     pub callbacks: CB,
     pub max_partition_time: Option<Duration>,
+    /// How many of the relays with the lowest latency we use for relayed connections, usually [`DEFAULT_MAX_RELAYS`].
+    pub max_relays: usize,
+    /// Timings of connections to gateways, e.g. [`BATTERY_SAVING_CONNECTION_CONFIG`] on mobile devices.
+    pub connection_config: ConnectionConfig,
+    /// Where to persist the proxy IPs of DNS resources across sessions, if anywhere.
+    ///
+    /// Only the Linux and Windows clients persist them, Android and Apple pass `None`.
+    pub proxy_ip_store: Option<Box<dyn ProxyIpStore>>,
+}
+
+/// Persists the proxy IPs connlib assigned to the names of DNS resources.
+///
+/// Applications, long-lived connections and OS DNS caches hold on to resolved addresses.
+/// Restoring the proxy IPs in the next session keeps those addresses working, as long as the resource still exists.
+pub trait ProxyIpStore: Send + Sync {
+    /// Loads the proxy IPs of the previous session, indexed by name.
+    ///
+    /// Should return an empty map if nothing was saved yet.
+    fn load(&self) -> std::io::Result<HashMap<String, Vec<IpAddr>>>;
+
+    /// Replaces the saved proxy IPs.
+    ///
+    /// Called on a blocking thread, at most once per [`PROXY_IPS_SAVE_DELAY`] after we assigned proxy IPs to new names.
+    fn save(&self, proxy_ips: &HashMap<String, Vec<IpAddr>>) -> std::io::Result<()>;
 }
 
 impl Session {
@@ -103,6 +141,13 @@ impl Session {
         let _ = self.channel.send(Command::SetTun(new_tun));
     }
 
+    /// Enables or disables audit events for the DNS queries answered by connlib.
+    ///
+    /// Audit events are disabled by default and delivered via [`Callbacks::on_dns_query`].
+    pub fn set_dns_query_audit(&self, enabled: bool) {
+        let _ = self.channel.send(Command::SetDnsQueryAudit(enabled));
+    }
+
     /// Disconnect a [`Session`].
     ///
     /// This consumes [`Session`] which cleans up all state associated with it.
@@ -126,6 +171,9 @@ where
         app_version,
         callbacks,
         max_partition_time,
+        max_relays,
+        connection_config,
+        proxy_ip_store,
     } = args;
 
     // Note on the first connect these addresses won't be used yet, though coincidentally phoenix_channel might resolve to the same ones, however thereafter they will.
@@ -137,12 +185,21 @@ where
         .map(|addr| addr.ip())
         .collect();
 
-    let tunnel = ClientTunnel::new(
+    let mut tunnel = ClientTunnel::new(
         private_key,
         sockets,
         callbacks,
         HashMap::from([(url.host().to_string(), addrs)]),
     )?;
+    tunnel.set_max_relays(max_relays);
+    tunnel.set_connection_config(connection_config);
+
+    if let Some(store) = proxy_ip_store.as_deref() {
+        match store.load() {
+            Ok(proxy_ips) => tunnel.restore_proxy_ips(proxy_ips),
+            Err(e) => tracing::warn!("Failed to load proxy IPs of previous session: {e}"),
+        }
+    }
 
     let portal = PhoenixChannel::connect(
         Secret::new(url),
@@ -154,7 +211,7 @@ where
             .build(),
     );
 
-    let mut eventloop = Eventloop::new(tunnel, portal, rx);
+    let mut eventloop = Eventloop::new(tunnel, portal, rx, proxy_ip_store);
 
     std::future::poll_fn(|cx| eventloop.poll(cx))
         .await
@@ -1,8 +1,9 @@
 use connlib_shared::messages::{
     client::{ResourceDescription, SiteId},
-    GatewayId, GatewayResponse, Interface, Key, Relay, RelaysPresence, RequestConnection,
-    ResourceId, ReuseConnection,
+    GatewayId, GatewayResponse, IceCredentials, Interface, Key, Relay, RelaysPresence,
+    RequestConnection, ResourceId, ReuseConnection, SecretKey,
 };
+use secrecy::ExposeSecret as _;
 use serde::{Deserialize, Serialize};
 use std::{collections::HashSet, net::IpAddr};
 
@@ -50,6 +51,7 @@ pub enum IngressMessages {
 
     IceCandidates(GatewayIceCandidates),
     InvalidateIceCandidates(GatewayIceCandidates),
+    IceCredentials(GatewayIceCredentials),
 
     ConfigChanged(ConfigUpdate),
 
@@ -62,6 +64,9 @@ pub struct GatewaysIceCandidates {
     pub gateway_ids: Vec<GatewayId>,
     /// Actual RTC ice candidates
     pub candidates: HashSet<String>,
+    /// Whether we finished gathering candidates, i.e. no more will follow until ICE restarts.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub end_of_candidates: bool,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
@@ -70,6 +75,40 @@ pub struct GatewayIceCandidates {
     pub gateway_id: GatewayId,
     /// Actual RTC ice candidates
     pub candidates: Vec<String>,
+    /// Whether the gateway finished gathering candidates, i.e. no more will follow until ICE restarts.
+    #[serde(default)]
+    pub end_of_candidates: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct GatewaysIceCredentials {
+    /// The list of gateway IDs these credentials will be broadcast to.
+    pub gateway_ids: Vec<GatewayId>,
+    /// The new ICE credentials after restarting ICE.
+    pub credentials: IceCredentials,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct GatewayIceCredentials {
+    /// Gateway's id the ice credentials are from
+    pub gateway_id: GatewayId,
+    /// The new ICE credentials after restarting ICE.
+    pub credentials: IceCredentials,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct GatewayPresharedKey {
+    /// The gateway this preshared key is meant for.
+    pub gateway_id: GatewayId,
+    /// The new preshared key for the connection to the gateway.
+    pub preshared_key: SecretKey,
+}
+
+impl PartialEq for GatewayPresharedKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.gateway_id == other.gateway_id
+            && self.preshared_key.expose_secret() == other.preshared_key.expose_secret()
+    }
 }
 
 /// The replies that can arrive from the channel by a client
@@ -96,6 +135,10 @@ pub enum EgressMessages {
     BroadcastIceCandidates(GatewaysIceCandidates),
     /// Candidates that should no longer be used by the addressed gateways.
     BroadcastInvalidatedIceCandidates(GatewaysIceCandidates),
+    /// New ICE credentials for the addressed gateways after we restarted ICE.
+    BroadcastIceCredentials(GatewaysIceCredentials),
+    /// A new preshared key for the addressed gateway after we rotated it.
+    RotatePresharedKey(GatewayPresharedKey),
 }
 
 #[cfg(test)]
@@ -104,9 +147,10 @@ mod test {
     use chrono::DateTime;
     use connlib_shared::messages::{
         client::{ResourceDescriptionCidr, ResourceDescriptionDns, Site},
-        DnsServer, IpDnsServer, Turn,
+        DnsServer, HttpsDnsServer, IpDnsServer, TlsDnsServer, Turn,
     };
     use phoenix_channel::{OutboundRequestId, PhoenixMessage};
+    use secrecy::Secret;
 
     // TODO: request_connection tests
 
@@ -121,6 +165,7 @@ mod test {
                     "candidate:7031633958891736544 1 udp 50331391 35.244.108.190 53909 typ relay"
                         .to_owned(),
                 ]),
+                end_of_candidates: false,
             }),
             Some(OutboundRequestId::for_test(6)),
         );
@@ -130,6 +175,63 @@ mod test {
         assert_eq!(ingress_message, expected);
     }
 
+    #[test]
+    fn broadcast_ice_credentials() {
+        let message = r#"{"topic":"client","event":"broadcast_ice_credentials","payload":{"gateway_ids":["b3d34a15-55ab-40df-994b-a838e75d65d7"],"credentials":{"username":"fvJ4","password":"ZpG2MxbBQ8JBzErm9RzRsRlS"}},"ref":7}"#;
+        let expected = PhoenixMessage::new_message(
+            "client",
+            EgressMessages::BroadcastIceCredentials(GatewaysIceCredentials {
+                gateway_ids: vec!["b3d34a15-55ab-40df-994b-a838e75d65d7".parse().unwrap()],
+                credentials: IceCredentials {
+                    username: "fvJ4".to_owned(),
+                    password: "ZpG2MxbBQ8JBzErm9RzRsRlS".to_owned(),
+                },
+            }),
+            Some(OutboundRequestId::for_test(7)),
+        );
+
+        let ingress_message = serde_json::from_str::<PhoenixMessage<_, ()>>(message).unwrap();
+
+        assert_eq!(ingress_message, expected);
+    }
+
+    #[test]
+    fn rotate_preshared_key() {
+        let message = r#"{"topic":"client","event":"rotate_preshared_key","payload":{"gateway_id":"b3d34a15-55ab-40df-994b-a838e75d65d7","preshared_key":"NZEyHD409lCdOPqmnLffloVEqgeGOe43u+pz10awMFc="},"ref":8}"#;
+        let expected = PhoenixMessage::new_message(
+            "client",
+            EgressMessages::RotatePresharedKey(GatewayPresharedKey {
+                gateway_id: "b3d34a15-55ab-40df-994b-a838e75d65d7".parse().unwrap(),
+                preshared_key: Secret::new(
+                    "NZEyHD409lCdOPqmnLffloVEqgeGOe43u+pz10awMFc="
+                        .parse()
+                        .unwrap(),
+                ),
+            }),
+            Some(OutboundRequestId::for_test(8)),
+        );
+
+        let ingress_message = serde_json::from_str::<PhoenixMessage<_, ()>>(message).unwrap();
+
+        assert_eq!(ingress_message, expected);
+    }
+
+    #[test]
+    fn ice_credentials_message() {
+        let msg = r#"{"event":"ice_credentials","ref":null,"topic":"client","payload":{"credentials":{"username":"fvJ4","password":"ZpG2MxbBQ8JBzErm9RzRsRlS"},"gateway_id":"2b1524e6-239e-4570-bc73-70a188e12101"}}"#;
+        let expected = IngressMessages::IceCredentials(GatewayIceCredentials {
+            gateway_id: "2b1524e6-239e-4570-bc73-70a188e12101".parse().unwrap(),
+            credentials: IceCredentials {
+                username: "fvJ4".to_owned(),
+                password: "ZpG2MxbBQ8JBzErm9RzRsRlS".to_owned(),
+            },
+        });
+
+        let actual = serde_json::from_str::<IngressMessages>(msg).unwrap();
+
+        assert_eq!(actual, expected);
+    }
+
     #[test]
     fn invalidate_ice_candidates_message() {
         let msg = r#"{"event":"invalidate_ice_candidates","ref":null,"topic":"client","payload":{"candidates":["candidate:7854631899965427361 1 udp 1694498559 172.28.0.100 47717 typ srflx"],"gateway_id":"2b1524e6-239e-4570-bc73-70a188e12101"}}"#;
@@ -139,6 +241,21 @@ mod test {
                 "candidate:7854631899965427361 1 udp 1694498559 172.28.0.100 47717 typ srflx"
                     .to_owned(),
             ],
+            end_of_candidates: false,
+        });
+
+        let actual = serde_json::from_str::<IngressMessages>(msg).unwrap();
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn ice_candidates_message_with_end_of_candidates() {
+        let msg = r#"{"event":"ice_candidates","ref":null,"topic":"client","payload":{"candidates":[],"gateway_id":"2b1524e6-239e-4570-bc73-70a188e12101","end_of_candidates":true}}"#;
+        let expected = IngressMessages::IceCandidates(GatewayIceCandidates {
+            gateway_id: "2b1524e6-239e-4570-bc73-70a188e12101".parse().unwrap(),
+            candidates: vec![],
+            end_of_candidates: true,
         });
 
         let actual = serde_json::from_str::<IngressMessages>(msg).unwrap();
@@ -191,6 +308,8 @@ mod test {
                     upstream_dns: vec![DnsServer::IpPort(IpDnsServer {
                         address: "1.1.1.1:53".parse().unwrap(),
                     })],
+                    dns_resource_ttl: None,
+                    search_domains: vec![],
                 },
             }),
             None,
@@ -219,6 +338,101 @@ mod test {
         assert_eq!(m, ingress_message);
     }
 
+    #[test]
+    fn config_updated_with_dns_resource_ttl() {
+        let m = PhoenixMessage::new_message(
+            "client",
+            IngressMessages::ConfigChanged(ConfigUpdate {
+                interface: Interface {
+                    ipv4: "100.67.138.25".parse().unwrap(),
+                    ipv6: "fd00:2021:1111::e:65ea".parse().unwrap(),
+                    upstream_dns: vec![],
+                    dns_resource_ttl: Some(300),
+                    search_domains: vec![],
+                },
+            }),
+            None,
+        );
+        let message = r#"
+        {
+            "event": "config_changed",
+            "ref": null,
+            "topic": "client",
+            "payload": {
+              "interface": {
+                "ipv6": "fd00:2021:1111::e:65ea",
+                "ipv4": "100.67.138.25",
+                "dns_resource_ttl": 300
+              }
+            }
+          }
+        "#;
+        let ingress_message: PhoenixMessage<IngressMessages, ReplyMessages> =
+            serde_json::from_str(message).unwrap();
+        assert_eq!(m, ingress_message);
+    }
+
+    #[test]
+    fn config_updated_with_encrypted_upstream_dns() {
+        let m = PhoenixMessage::new_message(
+            "client",
+            IngressMessages::ConfigChanged(ConfigUpdate {
+                interface: Interface {
+                    ipv4: "100.67.138.25".parse().unwrap(),
+                    ipv6: "fd00:2021:1111::e:65ea".parse().unwrap(),
+                    upstream_dns: vec![
+                        DnsServer::Https(HttpsDnsServer {
+                            address: "1.1.1.1:443".parse().unwrap(),
+                            hostname: "cloudflare-dns.com".to_owned(),
+                            url: "https://cloudflare-dns.com/dns-query".to_owned(),
+                            spki_pin: None,
+                        }),
+                        DnsServer::Tls(TlsDnsServer {
+                            address: "9.9.9.9:853".parse().unwrap(),
+                            hostname: "dns.quad9.net".to_owned(),
+                            spki_pin: Some(
+                                "/SlsviBkb05Y/8XiKF9+CZsgCtrqPQk5bh47o0R3/Cg=".to_owned(),
+                            ),
+                        }),
+                    ],
+                    dns_resource_ttl: None,
+                    search_domains: vec![],
+                },
+            }),
+            None,
+        );
+        let message = r#"
+        {
+            "event": "config_changed",
+            "ref": null,
+            "topic": "client",
+            "payload": {
+              "interface": {
+                "ipv6": "fd00:2021:1111::e:65ea",
+                "upstream_dns": [
+                  {
+                    "protocol": "https",
+                    "address": "1.1.1.1:443",
+                    "hostname": "cloudflare-dns.com",
+                    "url": "https://cloudflare-dns.com/dns-query"
+                  },
+                  {
+                    "protocol": "tls",
+                    "address": "9.9.9.9:853",
+                    "hostname": "dns.quad9.net",
+                    "spki_pin": "/SlsviBkb05Y/8XiKF9+CZsgCtrqPQk5bh47o0R3/Cg="
+                  }
+                ],
+                "ipv4": "100.67.138.25"
+              }
+            }
+          }
+        "#;
+        let ingress_message: PhoenixMessage<IngressMessages, ReplyMessages> =
+            serde_json::from_str(message).unwrap();
+        assert_eq!(m, ingress_message);
+    }
+
     #[test]
     fn init_phoenix_message() {
         let m = PhoenixMessage::new_message(
@@ -228,6 +442,8 @@ mod test {
                     ipv4: "100.72.112.111".parse().unwrap(),
                     ipv6: "fd00:2021:1111::13:efb9".parse().unwrap(),
                     upstream_dns: vec![],
+                    dns_resource_ttl: None,
+                    search_domains: vec![],
                 },
                 resources: vec![
                     ResourceDescription::Cidr(ResourceDescriptionCidr {
@@ -255,6 +471,7 @@ mod test {
                         address: "gitlab.mycorp.com".to_string(),
                         name: "gitlab.mycorp.com".to_string(),
                         address_description: Some("dns resource".to_string()),
+                        exclusions: vec![],
                         sites: vec![Site {
                             name: "test".to_string(),
                             id: "bf56f32d-7b2c-4f5d-a784-788977d014a4".parse().unwrap(),
@@ -265,6 +482,7 @@ mod test {
                         address: "github.mycorp.com".to_string(),
                         name: "github.mycorp.com".to_string(),
                         address_description: None,
+                        exclusions: vec![],
                         sites: vec![Site {
                             name: "test".to_string(),
                             id: "bf56f32d-7b2c-4f5d-a784-788977d014a4".parse().unwrap(),
@@ -336,6 +554,8 @@ mod test {
                     ipv4: "100.72.112.111".parse().unwrap(),
                     ipv6: "fd00:2021:1111::13:efb9".parse().unwrap(),
                     upstream_dns: vec![],
+                    dns_resource_ttl: None,
+                    search_domains: vec![],
                 },
                 resources: vec![
                     ResourceDescription::Cidr(ResourceDescriptionCidr {
@@ -353,6 +573,7 @@ mod test {
                         address: "gitlab.mycorp.com".to_string(),
                         name: "gitlab.mycorp.com".to_string(),
                         address_description: Some("dns resource".to_string()),
+                        exclusions: vec![],
                         sites: vec![Site {
                             name: "test".to_string(),
                             id: "bf56f32d-7b2c-4f5d-a784-788977d014a4".parse().unwrap(),
@@ -412,6 +633,8 @@ mod test {
                     ipv4: "100.72.112.111".parse().unwrap(),
                     ipv6: "fd00:2021:1111::13:efb9".parse().unwrap(),
                     upstream_dns: vec![],
+                    dns_resource_ttl: None,
+                    search_domains: vec![],
                 },
                 resources: vec![],
                 relays: vec![],
@@ -446,6 +669,8 @@ mod test {
                     ipv4: "100.72.112.111".parse().unwrap(),
                     ipv6: "fd00:2021:1111::13:efb9".parse().unwrap(),
                     upstream_dns: vec![],
+                    dns_resource_ttl: None,
+                    search_domains: vec![],
                 },
                 resources: vec![],
                 relays: vec![],
@@ -480,6 +705,8 @@ mod test {
                     ipv4: "100.72.112.111".parse().unwrap(),
                     ipv6: "fd00:2021:1111::13:efb9".parse().unwrap(),
                     upstream_dns: vec![],
+                    dns_resource_ttl: None,
+                    search_domains: vec![],
                 },
                 resources: vec![],
                 relays: vec![],
@@ -514,6 +741,8 @@ mod test {
                     ipv4: "100.72.112.111".parse().unwrap(),
                     ipv6: "fd00:2021:1111::13:efb9".parse().unwrap(),
                     upstream_dns: vec![],
+                    dns_resource_ttl: None,
+                    search_domains: vec![],
                 },
                 resources: vec![],
                 relays: vec![],
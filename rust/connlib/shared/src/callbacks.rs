@@ -101,10 +101,40 @@ pub struct ResourceDescriptionCidr {
     pub status: Status,
 }
 
+/// A DNS query that connlib answered, see [`Callbacks::on_dns_query`].
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct DnsQueryEvent {
+    /// The queried name, e.g. `app.example.com`.
+    pub name: String,
+    /// The queried record type, e.g. `AAAA`.
+    pub record_type: String,
+    /// The DNS resource the name belongs to, if any.
+    pub resource: Option<ResourceId>,
+    pub resolution: DnsResolution,
+    /// The response code of our answer, e.g. `NXDOMAIN`.
+    pub response_code: String,
+}
+
+/// Where the answer to a DNS query came from.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsResolution {
+    /// Connlib answered the query itself, e.g. with the proxy IPs of a DNS resource.
+    Local,
+    /// The answer of an upstream resolver that connlib cached earlier.
+    Cached,
+    /// Connlib forwarded the query to an upstream resolver.
+    Forwarded,
+    /// The gateway of the DNS resource resolved the query.
+    Gateway,
+}
+
 /// Traits that will be used by connlib to callback the client upper layers.
 pub trait Callbacks: Clone + Send + Sync {
     /// Called when the tunnel address is set.
-    fn on_set_interface_config(&self, _: Ipv4Addr, _: Ipv6Addr, _: Vec<IpAddr>) {}
+    ///
+    /// Passes the sentinel DNS servers and the search domains to configure on the system.
+    fn on_set_interface_config(&self, _: Ipv4Addr, _: Ipv6Addr, _: Vec<IpAddr>, _: Vec<String>) {}
 
     /// Called when the route list changes.
     fn on_update_routes(&self, _: Vec<Ipv4Network>, _: Vec<Ipv6Network>) {}
@@ -112,6 +142,9 @@ pub trait Callbacks: Clone + Send + Sync {
     /// Called when the resource list changes.
     fn on_update_resources(&self, _: Vec<ResourceDescription>) {}
 
+    /// Called for every DNS query connlib answered, if enabled with `Session::set_dns_query_audit`.
+    fn on_dns_query(&self, _: DnsQueryEvent) {}
+
     /// Called when the tunnel is disconnected.
     ///
     /// If the tunnel disconnected due to a fatal error, `error` is the error
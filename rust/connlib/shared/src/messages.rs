@@ -225,6 +225,13 @@ pub struct Offer {
     pub password: String,
 }
 
+/// New ICE credentials of a peer after it restarted ICE, e.g. due to a network change.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IceCredentials {
+    pub username: String,
+    pub password: String,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone, Hash, PartialEq, Eq)]
 pub struct DomainResponse {
     pub domain: DomainName,
@@ -235,6 +242,12 @@ pub struct DomainResponse {
 pub struct ConnectionAccepted {
     pub ice_parameters: Answer,
     pub domain_response: Option<DomainResponse>,
+    /// Whether the gateway applies preshared keys rotated by the client.
+    #[serde(default)]
+    pub supports_psk_rotation: bool,
+    /// Whether the gateway resolves queries for records of DNS resources that clients send through the tunnel.
+    #[serde(default)]
+    pub supports_dns_resolution: bool,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
@@ -248,22 +261,34 @@ pub enum GatewayResponse {
     ResourceAccepted(ResourceAccepted),
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
 #[serde(tag = "protocol", rename_all = "snake_case")]
 pub enum DnsServer {
     IpPort(IpDnsServer),
+    /// DNS over HTTPS, see [RFC8484](https://www.rfc-editor.org/rfc/rfc8484).
+    Https(HttpsDnsServer),
+    /// DNS over TLS, see [RFC7858](https://www.rfc-editor.org/rfc/rfc7858).
+    Tls(TlsDnsServer),
 }
 
 impl DnsServer {
     pub fn ip(&self) -> IpAddr {
-        match self {
-            DnsServer::IpPort(s) => s.address.ip(),
-        }
+        self.address().ip()
     }
 
     pub fn address(&self) -> SocketAddr {
         match self {
             DnsServer::IpPort(s) => s.address,
+            DnsServer::Https(s) => s.address,
+            DnsServer::Tls(s) => s.address,
+        }
+    }
+
+    /// Whether queries to this server are encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        match self {
+            DnsServer::IpPort(_) => false,
+            DnsServer::Https(_) | DnsServer::Tls(_) => true,
         }
     }
 }
@@ -284,6 +309,32 @@ pub struct IpDnsServer {
     pub address: SocketAddr,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub struct HttpsDnsServer {
+    /// The socket we connect to, typically on port 443.
+    pub address: SocketAddr,
+    /// The name the server's certificate must be valid for.
+    pub hostname: String,
+    /// The URL of the DoH endpoint, e.g. `https://dns.example.com/dns-query`.
+    ///
+    /// Only the `/dns-query` path is supported, servers with any other path are not used.
+    pub url: String,
+    /// The base64-encoded SHA-256 hash of the server's `SubjectPublicKeyInfo`, see [RFC7469](https://www.rfc-editor.org/rfc/rfc7469#section-2.4).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spki_pin: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub struct TlsDnsServer {
+    /// The socket we connect to, typically on port 853.
+    pub address: SocketAddr,
+    /// The name the server's certificate must be valid for.
+    pub hostname: String,
+    /// The base64-encoded SHA-256 hash of the server's `SubjectPublicKeyInfo`, see [RFC7469](https://www.rfc-editor.org/rfc/rfc7469#section-2.4).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spki_pin: Option<String>,
+}
+
 /// Represents a wireguard interface configuration.
 ///
 /// Note that the ips are /32 for ipv4 and /128 for ipv6.
@@ -298,6 +349,16 @@ pub struct Interface {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default)]
     pub upstream_dns: Vec<DnsServer>,
+    /// TTL in seconds of the DNS records connlib synthesises for DNS resources.
+    ///
+    /// Connlib uses its own default if this is not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub dns_resource_ttl: Option<u32>,
+    /// Domains to resolve single-label names relative to, e.g. `wiki` to `wiki.corp.example.com`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub search_domains: Vec<String>,
 }
 
 /// A single relay
@@ -362,6 +423,7 @@ mod tests {
             name: name.to_string(),
             address: "unused.example.com".to_string(),
             address_description: Some("test description".to_string()),
+            exclusions: vec![],
             sites: vec![Site {
                 name: "test".to_string(),
                 id: "99ba0c1e-5189-4cfc-a4db-fd6cb1c937fd".parse().unwrap(),
@@ -25,6 +25,11 @@ pub struct ResourceDescriptionDns {
     pub address_description: Option<String>,
     #[serde(rename = "gateway_groups")]
     pub sites: Vec<Site>,
+    /// Names that match `address` but are not part of this resource.
+    ///
+    /// Supports the same `*.` and `?.` wildcards as `address`.
+    #[serde(default)]
+    pub exclusions: Vec<String>,
 }
 
 impl ResourceDescriptionDns {
@@ -21,6 +21,9 @@ pub struct ResourceDescriptionDns {
     ///
     /// Used only for display.
     pub name: String,
+    /// Names that match `address` but are not part of this resource.
+    #[serde(default)]
+    pub exclusions: Vec<String>,
 
     pub filters: Filters,
 }
@@ -52,6 +55,8 @@ pub struct ResolvedResourceDescriptionDns {
     pub name: String,
 
     pub addresses: Vec<IpAddr>,
+    /// Names that match `domain` but are not part of this resource.
+    pub exclusions: Vec<String>,
 
     pub filters: Filters,
 }
@@ -101,12 +106,14 @@ impl ResourceDescription<ResourceDescriptionDns> {
                 id,
                 address,
                 name,
+                exclusions,
                 filters,
             }) => ResourceDescription::Dns(ResolvedResourceDescriptionDns {
                 id,
                 domain: address,
                 name,
                 addresses,
+                exclusions,
 
                 filters,
             }),
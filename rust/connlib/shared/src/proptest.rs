@@ -64,6 +64,7 @@ pub fn dns_resource_with_sites(sites: Vec<Site>) -> impl Strategy<Value = Resour
                 name,
                 sites: sites.clone(),
                 address_description,
+                exclusions: vec![],
             },
         )
 }
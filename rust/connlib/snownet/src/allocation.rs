@@ -2,6 +2,8 @@ use crate::{
     backoff::{self, ExponentialBackoff},
     node::{CandidateEvent, Transmit},
     ringbuffer::RingBuffer,
+    snapshot::{AllocationSnapshot, ChannelSnapshot},
+    stats::smooth_rtt,
     utils::earliest,
 };
 use ::backoff::backoff::Backoff;
@@ -54,6 +56,10 @@ pub struct Allocation {
     ip4_srflx_candidate: Option<Candidate>,
     /// If present, the IPv6 address the relay observed for us.
     ip6_srflx_candidate: Option<Candidate>,
+    /// The local IPv4 socket from which we sent the BINDING request that yielded `ip4_srflx_candidate`.
+    ip4_srflx_base: Option<SocketAddr>,
+    /// The local IPv6 socket from which we sent the BINDING request that yielded `ip6_srflx_candidate`.
+    ip6_srflx_base: Option<SocketAddr>,
     /// If present, the IPv4 socket the relay allocated for us.
     ip4_allocation: Option<Candidate>,
     /// If present, the IPv6 socket the relay allocated for us.
@@ -62,6 +68,11 @@ pub struct Allocation {
     /// When we received the allocation and how long it is valid.
     allocation_lifetime: Option<(Instant, Duration)>,
 
+    /// The smoothed round-trip time to the relay, measured from our BINDING requests.
+    rtt: Option<Duration>,
+    /// Whether `rtt` changed since the last call to [`Allocation::take_rtt_changed`].
+    rtt_changed: bool,
+
     buffered_transmits: VecDeque<Transmit<'static>>,
     events: VecDeque<CandidateEvent>,
 
@@ -196,6 +207,8 @@ impl Allocation {
             active_socket: None,
             ip4_srflx_candidate: Default::default(),
             ip6_srflx_candidate: Default::default(),
+            ip4_srflx_base: Default::default(),
+            ip6_srflx_base: Default::default(),
             ip4_allocation: Default::default(),
             ip6_allocation: Default::default(),
             buffered_transmits: Default::default(),
@@ -208,6 +221,8 @@ impl Allocation {
                 nonce: Default::default(),
             }),
             allocation_lifetime: Default::default(),
+            rtt: None,
+            rtt_changed: false,
             channel_bindings: Default::default(),
             last_now: now,
             buffered_channel_bindings: RingBuffer::new(100),
@@ -253,6 +268,15 @@ impl Allocation {
 
             return;
         }
+
+        self.update_server(socket);
+    }
+
+    /// Switches to a different socket of the relay, e.g. because we discovered the NAT64 on our network.
+    pub fn update_server(&mut self, socket: RelaySocket) {
+        if self.server == socket {
+            return;
+        }
         self.server = socket;
 
         // Server isn't the same, let's pick a new socket.
@@ -422,13 +446,19 @@ impl Allocation {
 
         match message.method() {
             BINDING => {
+                self.rtt = Some(smooth_rtt(self.rtt, rtt));
+                self.rtt_changed = true;
+
                 // First, process the binding request itself.
-                let current_srflx_candidate = match original_dst {
-                    SocketAddr::V4(_) => &mut self.ip4_srflx_candidate,
-                    SocketAddr::V6(_) => &mut self.ip6_srflx_candidate,
+                let (current_srflx_candidate, current_srflx_base) = match original_dst {
+                    SocketAddr::V4(_) => (&mut self.ip4_srflx_candidate, &mut self.ip4_srflx_base),
+                    SocketAddr::V6(_) => (&mut self.ip6_srflx_candidate, &mut self.ip6_srflx_base),
                 };
 
                 let maybe_candidate = message.attributes().find_map(|a| srflx_candidate(local, a));
+                if maybe_candidate.is_some() {
+                    *current_srflx_base = Some(local);
+                }
                 update_candidate(maybe_candidate, current_srflx_candidate, &mut self.events);
 
                 self.log_update(now);
@@ -710,6 +740,8 @@ impl Allocation {
             src: None,
             dst: self.active_socket?,
             payload: Cow::Borrowed(&buffer[..total_length]),
+            segment_size: None,
+            tos: 0,
         })
     }
 
@@ -726,6 +758,8 @@ impl Allocation {
             src: None,
             dst: self.active_socket?,
             payload: Cow::Owned(channel_data),
+            segment_size: None,
+            tos: 0,
         })
     }
 
@@ -751,6 +785,38 @@ impl Allocation {
         None
     }
 
+    /// Whether this [`Allocation`] may still discover new candidates.
+    ///
+    /// That is the case whilst a BINDING or ALLOCATE request is in flight or we have candidate events that haven't been polled yet.
+    pub fn is_gathering(&self) -> bool {
+        let binding_in_flight = self
+            .sent_requests
+            .values()
+            .any(|(_, r, _, _, _)| r.method() == BINDING);
+
+        binding_in_flight || self.allocate_in_flight() || !self.events.is_empty()
+    }
+
+    /// The addresses this relay observed for us, together with the local socket they map to.
+    pub fn srflx_mappings(&self) -> impl Iterator<Item = (SocketAddr, SocketAddr)> + '_ {
+        [
+            (self.ip4_srflx_base, &self.ip4_srflx_candidate),
+            (self.ip6_srflx_base, &self.ip6_srflx_candidate),
+        ]
+        .into_iter()
+        .filter_map(|(base, candidate)| Some((base?, candidate.as_ref()?.addr())))
+    }
+
+    /// The smoothed round-trip time to the relay, if we have received a response to a BINDING request.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Returns whether [`Allocation::rtt`] changed since the last call and resets the flag.
+    pub fn take_rtt_changed(&mut self) -> bool {
+        std::mem::take(&mut self.rtt_changed)
+    }
+
     pub fn received_any_response(&self) -> bool {
         self.active_socket.is_some()
     }
@@ -759,6 +825,32 @@ impl Allocation {
         self.credentials.is_some()
     }
 
+    pub fn snapshot<RId>(
+        &self,
+        relay: RId,
+        preferred: bool,
+        now: Instant,
+    ) -> AllocationSnapshot<RId> {
+        AllocationSnapshot {
+            relay,
+            server_ip4: self.server.as_v4().copied(),
+            server_ip6: self.server.as_v6().copied(),
+            active_socket: self.active_socket,
+            preferred,
+            has_credentials: self.has_credentials(),
+            candidates: self
+                .current_candidates()
+                .map(|c| c.to_sdp_string())
+                .collect(),
+            time_until_expiry: self
+                .allocation_expires_at()
+                .map(|expires_at| expires_at.saturating_duration_since(now)),
+            rtt: self.rtt,
+            requests_in_flight: self.sent_requests.len(),
+            channels: self.channel_bindings.snapshot(now),
+        }
+    }
+
     fn log_update(&self, now: Instant) {
         tracing::info!(
             srflx_ip4 = ?self.ip4_srflx_candidate.as_ref().map(|c| c.addr()),
@@ -806,6 +898,11 @@ impl Allocation {
         is_ip4 || is_ip6
     }
 
+    /// The socket of the relay we are talking to, once we received a response on it.
+    pub fn active_socket(&self) -> Option<SocketAddr> {
+        self.active_socket
+    }
+
     pub fn server(&self) -> RelaySocket {
         self.server
     }
@@ -943,6 +1040,8 @@ impl Allocation {
             src: None,
             dst,
             payload: encode(message).into(),
+            segment_size: None,
+            tos: 0,
         });
 
         true
@@ -1247,6 +1346,23 @@ impl ChannelBindings {
     fn clear(&mut self) {
         self.inner.clear();
     }
+
+    fn snapshot(&self, now: Instant) -> Vec<ChannelSnapshot> {
+        let mut channels = self
+            .inner
+            .iter()
+            .map(|(number, channel)| ChannelSnapshot {
+                number: *number,
+                peer: channel.peer,
+                bound: channel.bound,
+                time_since_bound: now.saturating_duration_since(channel.bound_at),
+                time_since_last_received: now.saturating_duration_since(channel.last_received),
+            })
+            .collect::<Vec<_>>();
+        channels.sort_by_key(|c| c.number);
+
+        channels
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -1827,6 +1943,24 @@ mod tests {
         assert_eq!(next_event, None);
     }
 
+    #[test]
+    fn binding_responses_are_used_to_measure_rtt() {
+        let now = Instant::now();
+        let mut allocation = Allocation::for_test_ip4(now);
+
+        assert_eq!(allocation.rtt(), None);
+
+        let binding = allocation.next_message().unwrap();
+        allocation.handle_test_input_ip4(
+            &binding_response(&binding, PEER1),
+            now + Duration::from_millis(40),
+        );
+
+        assert_eq!(allocation.rtt(), Some(Duration::from_millis(40)));
+        assert!(allocation.take_rtt_changed());
+        assert!(!allocation.take_rtt_changed());
+    }
+
     #[test]
     fn calling_refresh_with_same_credentials_will_trigger_refresh() {
         let mut allocation = Allocation::for_test_ip4(Instant::now()).with_binding_response(PEER1);
@@ -4,14 +4,28 @@ mod allocation;
 mod backoff;
 mod channel_data;
 mod index;
+mod nat;
+mod nat64;
 mod node;
+mod pmtu;
+mod rate_limit;
 mod ringbuffer;
+mod snapshot;
 mod stats;
 mod utils;
+mod workers;
 
 pub use allocation::RelaySocket;
+pub use nat::{NatMapping, NatStatus, NatType};
+pub use nat64::{Nat64Prefix, IPV4ONLY_ARPA};
 pub use node::{
-    Answer, Client, ClientNode, Credentials, Error, Event, Node, Offer, Server, ServerNode,
-    Transmit, HANDSHAKE_TIMEOUT,
+    Answer, Client, ClientNode, ConnectionConfig, Credentials, Error, Event, Node, Offer,
+    PresharedKey, Server, ServerNode, TrafficClassPropagation, Transmit, HANDSHAKE_TIMEOUT,
+    MAX_GSO_SEGMENTS,
 };
-pub use stats::{ConnectionStats, NodeStats};
+pub use pmtu::{MAX_MTU, MIN_MTU};
+pub use snapshot::{
+    AllocationSnapshot, CandidatePairSnapshot, CandidatePairState, ChannelSnapshot,
+    ConnectionSnapshot, ConnectionState, IceSnapshot, IceState, NodeSnapshot, WireGuardSnapshot,
+};
+pub use stats::{CandidatePair, CandidateType, ConnectionStats, NodeStats};
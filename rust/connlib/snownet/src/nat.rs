@@ -0,0 +1,155 @@
+//! Classification of the NAT we are behind, based on the server-reflexive addresses observed by our relays.
+
+use serde::Serialize;
+use std::{collections::BTreeMap, net::SocketAddr};
+
+/// The NAT we are behind, per IP version.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct NatStatus {
+    pub ipv4: NatType,
+    pub ipv6: NatType,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct NatType {
+    pub mapping: NatMapping,
+    /// Whether the NAT preserves the port of our local socket.
+    ///
+    /// `None` if we are not behind a NAT or haven't learned any mapped address yet.
+    pub port_preservation: Option<bool>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum NatMapping {
+    /// We haven't learned enough mapped addresses to classify the NAT.
+    #[default]
+    Unknown,
+    /// The address of our local socket is publicly reachable.
+    NoNat,
+    /// The NAT maps our local socket to the same public address, regardless of the destination.
+    ///
+    /// Hole-punching is very likely to succeed behind such a NAT.
+    EndpointIndependent,
+    /// The NAT maps our local socket to a different public address per destination, also known as "symmetric NAT".
+    ///
+    /// Hole-punching will typically fail and traffic needs to be relayed.
+    EndpointDependent,
+}
+
+impl NatType {
+    /// Classifies the NAT from the `(local, mapped)` address pairs observed by different relays.
+    ///
+    /// Only mappings of the same local socket tell us whether the mapping depends on the destination.
+    /// Different local sockets (e.g. on different interfaces) are expected to map to different addresses.
+    pub(crate) fn classify(mappings: impl IntoIterator<Item = (SocketAddr, SocketAddr)>) -> Self {
+        let mut mapped_by_local = BTreeMap::<SocketAddr, Vec<SocketAddr>>::new();
+        for (local, mapped) in mappings {
+            mapped_by_local.entry(local).or_default().push(mapped);
+        }
+
+        if mapped_by_local.is_empty() {
+            return Self::default();
+        }
+
+        if mapped_by_local
+            .iter()
+            .all(|(local, mapped)| mapped.iter().all(|m| m.ip() == local.ip()))
+        {
+            return Self {
+                mapping: NatMapping::NoNat,
+                port_preservation: None,
+            };
+        }
+
+        let port_preservation = mapped_by_local
+            .iter()
+            .all(|(local, mapped)| mapped.iter().all(|m| m.port() == local.port()));
+
+        // We need the view of at least two relays on the same local socket to tell whether the mapping depends on the destination.
+        let is_endpoint_dependent = mapped_by_local
+            .values()
+            .any(|mapped| mapped.iter().any(|m| *m != mapped[0]));
+        let has_multiple_views = mapped_by_local.values().any(|mapped| mapped.len() >= 2);
+
+        let mapping = if is_endpoint_dependent {
+            NatMapping::EndpointDependent
+        } else if has_multiple_views {
+            NatMapping::EndpointIndependent
+        } else {
+            NatMapping::Unknown
+        };
+
+        Self {
+            mapping,
+            port_preservation: Some(port_preservation),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL: SocketAddr = SocketAddr::new(
+        std::net::IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 0, 10)),
+        52625,
+    );
+
+    #[test]
+    fn no_mappings_is_unknown() {
+        let nat = NatType::classify([]);
+
+        assert_eq!(nat.mapping, NatMapping::Unknown);
+        assert_eq!(nat.port_preservation, None);
+    }
+
+    #[test]
+    fn same_ip_as_local_socket_is_no_nat() {
+        let nat = NatType::classify([(LOCAL, LOCAL), (LOCAL, LOCAL)]);
+
+        assert_eq!(nat.mapping, NatMapping::NoNat);
+    }
+
+    #[test]
+    fn single_relay_cannot_determine_mapping() {
+        let nat = NatType::classify([(LOCAL, "1.1.1.1:52625".parse().unwrap())]);
+
+        assert_eq!(nat.mapping, NatMapping::Unknown);
+        assert_eq!(nat.port_preservation, Some(true));
+    }
+
+    #[test]
+    fn same_mapped_address_across_relays_is_endpoint_independent() {
+        let nat = NatType::classify([
+            (LOCAL, "1.1.1.1:52625".parse().unwrap()),
+            (LOCAL, "1.1.1.1:52625".parse().unwrap()),
+        ]);
+
+        assert_eq!(nat.mapping, NatMapping::EndpointIndependent);
+        assert_eq!(nat.port_preservation, Some(true));
+    }
+
+    #[test]
+    fn mappings_of_different_local_sockets_are_not_compared() {
+        let other_local = "192.168.0.11:52625".parse().unwrap();
+
+        let nat = NatType::classify([
+            (LOCAL, "1.1.1.1:52625".parse().unwrap()),
+            (other_local, "2.2.2.2:52625".parse().unwrap()),
+        ]);
+
+        assert_eq!(nat.mapping, NatMapping::Unknown);
+        assert_eq!(nat.port_preservation, Some(true));
+    }
+
+    #[test]
+    fn different_mapped_address_across_relays_is_endpoint_dependent() {
+        let nat = NatType::classify([
+            (LOCAL, "1.1.1.1:40000".parse().unwrap()),
+            (LOCAL, "1.1.1.1:40001".parse().unwrap()),
+        ]);
+
+        assert_eq!(nat.mapping, NatMapping::EndpointDependent);
+        assert_eq!(nat.port_preservation, Some(false));
+    }
+}
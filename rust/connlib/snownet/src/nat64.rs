@@ -0,0 +1,172 @@
+//! Synthesis of IPv6 addresses for IPv4-only peers and relays on networks with NAT64, see [RFC6052](https://www.rfc-editor.org/rfc/rfc6052) and [RFC7050](https://www.rfc-editor.org/rfc/rfc7050).
+
+use std::{
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6},
+};
+
+/// The domain that DNS64 resolvers synthesize AAAA records for, revealing the NAT64 prefix.
+pub const IPV4ONLY_ARPA: &str = "ipv4only.arpa";
+
+/// The well-known IPv4 addresses of [`IPV4ONLY_ARPA`].
+const IPV4ONLY_ARPA_ADDRS: [Ipv4Addr; 2] =
+    [Ipv4Addr::new(192, 0, 0, 170), Ipv4Addr::new(192, 0, 0, 171)];
+
+/// The prefix lengths allowed by RFC6052, longest first.
+const PREFIX_LENGTHS: [u8; 6] = [96, 64, 56, 48, 40, 32];
+
+/// The prefix a NAT64 uses to represent IPv4 addresses within IPv6.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Nat64Prefix {
+    prefix: Ipv6Addr,
+    len: u8,
+}
+
+impl Nat64Prefix {
+    /// The well-known prefix `64:ff9b::/96`.
+    pub const WELL_KNOWN: Self = Self {
+        prefix: Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0),
+        len: 96,
+    };
+
+    /// Creates a new prefix, returning `None` if `len` is not one of the lengths allowed by RFC6052.
+    pub fn new(prefix: Ipv6Addr, len: u8) -> Option<Self> {
+        if !PREFIX_LENGTHS.contains(&len) {
+            return None;
+        }
+
+        Some(Self {
+            prefix: mask(prefix, len),
+            len,
+        })
+    }
+
+    /// Discovers the prefix from the AAAA records of [`IPV4ONLY_ARPA`].
+    ///
+    /// Returns `None` if none of the addresses embeds one of the well-known IPv4 addresses, i.e. the network doesn't use DNS64.
+    pub fn from_ipv4only_arpa(addrs: impl IntoIterator<Item = Ipv6Addr>) -> Option<Self> {
+        addrs.into_iter().find_map(|addr| {
+            PREFIX_LENGTHS.into_iter().find_map(|len| {
+                let prefix = Self::new(addr, len)?;
+
+                IPV4ONLY_ARPA_ADDRS
+                    .contains(&prefix.extract(addr)?)
+                    .then_some(prefix)
+            })
+        })
+    }
+
+    /// Embeds the IPv4 address into this prefix.
+    pub fn synthesize(&self, ip: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = self.prefix.octets();
+
+        for (position, byte) in embedding_positions(self.len).zip(ip.octets()) {
+            octets[position] = byte;
+        }
+
+        Ipv6Addr::from(octets)
+    }
+
+    /// Embeds the IP of the IPv4 socket into this prefix, keeping the port.
+    pub fn synthesize_socket(&self, socket: SocketAddrV4) -> SocketAddrV6 {
+        SocketAddrV6::new(self.synthesize(*socket.ip()), socket.port(), 0, 0)
+    }
+
+    /// Extracts the embedded IPv4 address, returning `None` if `ip` is not within this prefix.
+    pub fn extract(&self, ip: Ipv6Addr) -> Option<Ipv4Addr> {
+        if mask(ip, self.len) != self.prefix {
+            return None;
+        }
+
+        let octets = ip.octets();
+        let mut ip4 = [0u8; 4];
+
+        for (byte, position) in ip4.iter_mut().zip(embedding_positions(self.len)) {
+            *byte = octets[position];
+        }
+
+        Some(Ipv4Addr::from(ip4))
+    }
+}
+
+impl fmt::Display for Nat64Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.prefix, self.len)
+    }
+}
+
+/// The octets of the IPv6 address that hold the IPv4 address; octet 8 is reserved and always skipped.
+fn embedding_positions(len: u8) -> impl Iterator<Item = usize> {
+    (usize::from(len / 8)..16).filter(|p| *p != 8).take(4)
+}
+
+fn mask(ip: Ipv6Addr, len: u8) -> Ipv6Addr {
+    let mask = u128::MAX.checked_shl(128 - u32::from(len)).unwrap_or(0);
+
+    Ipv6Addr::from(u128::from(ip) & mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn synthesizes_with_well_known_prefix() {
+        let ip = Nat64Prefix::WELL_KNOWN.synthesize(Ipv4Addr::new(192, 0, 2, 33));
+
+        assert_eq!(ip, "64:ff9b::192.0.2.33".parse::<Ipv6Addr>().unwrap());
+    }
+
+    #[test]
+    fn displays_as_cidr() {
+        assert_eq!(Nat64Prefix::WELL_KNOWN.to_string(), "64:ff9b::/96");
+    }
+
+    #[test]
+    fn synthesizes_examples_of_rfc6052() {
+        let cases = [
+            ("2001:db8::", 32, "2001:db8:c000:221::"),
+            ("2001:db8:100::", 40, "2001:db8:1c0:2:21::"),
+            ("2001:db8:122::", 48, "2001:db8:122:c000:2:2100::"),
+            ("2001:db8:122:300::", 56, "2001:db8:122:3c0:0:221::"),
+            ("2001:db8:122:344::", 64, "2001:db8:122:344:c0:2:2100:0"),
+            ("2001:db8:122:344::", 96, "2001:db8:122:344::192.0.2.33"),
+        ];
+
+        for (prefix, len, expected) in cases {
+            let prefix = Nat64Prefix::new(prefix.parse().unwrap(), len).unwrap();
+            let expected = expected.parse().unwrap();
+
+            assert_eq!(prefix.synthesize(Ipv4Addr::new(192, 0, 2, 33)), expected);
+            assert_eq!(prefix.extract(expected), Some(Ipv4Addr::new(192, 0, 2, 33)));
+        }
+    }
+
+    #[test]
+    fn does_not_extract_from_other_prefix() {
+        assert_eq!(
+            Nat64Prefix::WELL_KNOWN.extract("2001:db8::192.0.2.33".parse().unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn discovers_prefix_from_ipv4only_arpa() {
+        let prefix = Nat64Prefix::from_ipv4only_arpa([
+            "2001:db8:122:344::192.0.0.170".parse().unwrap(),
+            "2001:db8:122:344::192.0.0.171".parse().unwrap(),
+        ]);
+
+        assert_eq!(
+            prefix,
+            Nat64Prefix::new("2001:db8:122:344::".parse().unwrap(), 96)
+        );
+    }
+
+    #[test]
+    fn no_prefix_without_dns64() {
+        let prefix = Nat64Prefix::from_ipv4only_arpa(["2001:db8::1".parse().unwrap()]);
+
+        assert_eq!(prefix, None);
+    }
+}
@@ -1,10 +1,19 @@
 use crate::allocation::{Allocation, RelaySocket, Socket};
 use crate::index::IndexLfsr;
+use crate::nat::{NatStatus, NatType};
+use crate::nat64::{Nat64Prefix, IPV4ONLY_ARPA};
+use crate::pmtu::{self, PathMtuDiscovery};
+use crate::rate_limit::{SourceRateLimiters, HANDSHAKE_RATE_LIMIT_PER_PEER};
 use crate::ringbuffer::RingBuffer;
-use crate::stats::{ConnectionStats, NodeStats};
+use crate::snapshot::{
+    self, CandidatePairSnapshot, CandidatePairState, ConnectionSnapshot, IceSnapshot, NodeSnapshot,
+    WireGuardSnapshot,
+};
+use crate::stats::{CandidatePair, CandidateType, ConnectionStats, ConsentChecks, NodeStats};
 use crate::utils::earliest;
+use crate::workers::Workers;
 use boringtun::noise::errors::WireGuardError;
-use boringtun::noise::{Tunn, TunnResult};
+use boringtun::noise::{Packet, Tunn, TunnResult};
 use boringtun::x25519::PublicKey;
 use boringtun::{noise::rate_limiter::RateLimiter, x25519::StaticSecret};
 use core::fmt;
@@ -17,30 +26,51 @@ use std::borrow::Cow;
 use std::hash::Hash;
 use std::marker::PhantomData;
 use std::mem;
-use std::ops::ControlFlow;
+use std::ops::{ControlFlow, Range};
 use std::time::{Duration, Instant};
 use std::{
     collections::{HashMap, HashSet, VecDeque},
-    net::SocketAddr,
+    io,
+    net::{Ipv6Addr, SocketAddr},
     sync::Arc,
 };
 use str0m::ice::{IceAgent, IceAgentEvent, IceCreds, StunMessage, StunPacket};
 use str0m::net::Protocol;
 use str0m::{Candidate, CandidateKind, IceConnectionState};
 use stun_codec::rfc5389::attributes::{Realm, Username};
+use subtle::ConstantTimeEq;
 use tracing::info_span;
 
-// Note: Taken from boringtun
-const HANDSHAKE_RATE_LIMIT: u64 = 100;
-
 /// How long we will at most wait for a candidate from the remote.
 const CANDIDATE_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// How long ICE may at most run its connectivity checks once both sides finished gathering candidates.
+const END_OF_CANDIDATES_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How much faster another relay has to be than one we use for relay candidates before we switch over to it.
+const RELAY_RTT_HYSTERESIS: Duration = Duration::from_millis(10);
+
 /// How long we will at most wait for an [`Answer`] from the remote.
 pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(20);
 
 const MAX_UDP_SIZE: usize = (1 << 16) - 1;
 
+/// The maximum number of segments Linux accepts in a single GSO send (`UDP_MAX_SEGMENTS`).
+///
+/// Used until we learn what the socket actually supports, see [`Node::set_max_gso_segments`].
+pub const MAX_GSO_SEGMENTS: usize = 64;
+
+/// The size of a WireGuard handshake initiation, which boringtun may emit instead of encrypting a packet.
+const HANDSHAKE_INIT_SIZE: usize = 148;
+
+/// The maximum payload of a single GSO send: a UDP packet minus the IPv6 and UDP headers.
+const MAX_GSO_PAYLOAD: usize = MAX_UDP_SIZE - 40 - 8;
+
+/// For how long we keep the tunnel of the previous preshared key around after completing a handshake with the new one.
+///
+/// This allows us to still decrypt packets that were in-flight whilst we switched over.
+const PREVIOUS_TUNNEL_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
 /// Manages a set of wireguard connections for a server.
 pub type ServerNode<TId, RId> = Node<Server, TId, RId>;
 /// Manages a set of wireguard connections for a client.
@@ -81,16 +111,38 @@ pub enum Client {}
 pub struct Node<T, TId, RId> {
     private_key: StaticSecret,
     index: IndexLfsr,
-    rate_limiter: Arc<RateLimiter>,
     host_candidates: HashSet<Candidate>,
     buffered_transmits: VecDeque<Transmit<'static>>,
 
     next_rate_limiter_reset: Option<Instant>,
+    /// Limits the handshakes per source address, on top of the rate limiter of each connection.
+    source_rate_limiters: SourceRateLimiters,
 
     allocations: HashMap<RId, Allocation>,
+    /// The maximum number of relays we use for relay candidates.
+    max_relays: usize,
+    /// The relays with the lowest RTT, up to `max_relays`.
+    ///
+    /// Only relay candidates from these relays are used for connections.
+    preferred_relays: HashSet<RId>,
+    /// Whether relays were added or removed since we last ranked them.
+    relays_changed: bool,
+    /// The NAT we are behind, as observed by our relays.
+    nat_status: NatStatus,
+    /// The config new connections start out with.
+    default_connection_config: ConnectionConfig,
+    /// The threads we use to encrypt and decrypt packets of different connections in parallel.
+    workers: Workers,
+    /// Buffers for the output of crypto jobs, reused across calls to avoid allocating for every batch.
+    crypto_buffers: Vec<Vec<u8>>,
+    /// How many datagrams we coalesce into a single [`Transmit`] at most.
+    max_gso_segments: usize,
+    traffic_class_propagation: TrafficClassPropagation,
+    /// The prefix of the NAT64 on our network, if any.
+    nat64_prefix: Option<Nat64Prefix>,
 
     connections: Connections<TId, RId>,
-    pending_events: VecDeque<Event<TId>>,
+    pending_events: VecDeque<Event<TId, RId>>,
 
     buffer: Box<[u8; MAX_UDP_SIZE]>,
 
@@ -123,18 +175,29 @@ where
     RId: Copy + Eq + Hash + PartialEq + fmt::Debug + fmt::Display,
 {
     pub fn new(private_key: StaticSecret) -> Self {
-        let public_key = &(&private_key).into();
+        let public_key = (&private_key).into();
+
         Self {
             private_key,
             marker: Default::default(),
             index: IndexLfsr::default(),
-            rate_limiter: Arc::new(RateLimiter::new(public_key, HANDSHAKE_RATE_LIMIT)),
             host_candidates: HashSet::default(),
             buffered_transmits: VecDeque::default(),
             next_rate_limiter_reset: None,
+            source_rate_limiters: SourceRateLimiters::new(public_key),
             pending_events: VecDeque::default(),
             buffer: Box::new([0u8; MAX_UDP_SIZE]),
             allocations: HashMap::default(),
+            max_relays: usize::MAX,
+            preferred_relays: HashSet::default(),
+            relays_changed: false,
+            nat_status: NatStatus::default(),
+            default_connection_config: ConnectionConfig::default(),
+            workers: Workers::new(1),
+            crypto_buffers: Vec::new(),
+            max_gso_segments: MAX_GSO_SEGMENTS,
+            traffic_class_propagation: TrafficClassPropagation::default(),
+            nat64_prefix: None,
             connections: Default::default(),
             stats: Default::default(),
         }
@@ -153,6 +216,7 @@ where
     /// `snownet` cannot control which IP / port we are binding to, thus upper layers MUST ensure that a new IP / port is allocated after calling [`Node::reset`].
     pub fn reset(&mut self) {
         self.allocations.clear();
+        self.preferred_relays.clear();
 
         self.buffered_transmits.clear();
 
@@ -174,21 +238,341 @@ where
         tracing::debug!(%num_connections, "Closed all connections as part of reconnecting");
     }
 
+    /// Restarts ICE for all connections, e.g. after a change in network connectivity.
+    ///
+    /// In contrast to [`Node::reset`], this keeps the wireguard sessions of all established connections alive.
+    /// All host candidates are discarded and all [`Allocation`]s are refreshed which will re-discover server-reflexive and relay candidates.
+    ///
+    /// For each established connection, new ICE credentials are generated and emitted via [`Event::NewIceCredentials`].
+    /// They need to be signalled to the remote which must pass them to [`Node::set_remote_credentials`].
+    /// Until a new candidate pair is nominated, we continue to send data on the previously nominated socket.
+    ///
+    /// Connections that are still waiting for an answer cannot be restarted because the remote will use the credentials of our offer.
+    /// They are failed instead and emitted as [`Event::ConnectionFailed`], allowing the application to set them up again.
+    pub fn ice_restart(&mut self, now: Instant) {
+        for candidate in self.host_candidates.drain() {
+            for (cid, agent) in self.connections.agents_mut() {
+                let _span = info_span!("connection", %cid).entered();
+
+                remove_local_candidate(cid, agent, &candidate, &mut self.pending_events);
+            }
+        }
+
+        for allocation in self.allocations.values_mut() {
+            allocation.refresh(now);
+        }
+
+        let ids = self
+            .connections
+            .iter_established()
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        let num_connections = ids.len();
+
+        for cid in ids {
+            self.restart_connection(cid, now);
+        }
+
+        for (cid, connection) in self.connections.iter_initial_mut() {
+            tracing::info!(%cid, "Failing connection without answer as part of ICE restart");
+
+            connection.is_failed = true;
+        }
+        self.connections.gc(&mut self.pending_events);
+
+        tracing::debug!(%num_connections, "Restarted ICE for all connections");
+    }
+
+    /// Sets the ICE credentials of the remote for the given connection.
+    ///
+    /// If the remote restarted ICE on its own (i.e. we are not expecting new credentials), we restart ICE locally as well and emit our own credentials via [`Event::NewIceCredentials`].
+    #[tracing::instrument(level = "info", skip_all, fields(%cid))]
+    pub fn set_remote_credentials(&mut self, cid: TId, credentials: Credentials, now: Instant) {
+        let credentials = IceCreds {
+            ufrag: credentials.username,
+            pass: credentials.password,
+        };
+
+        let Some(connection) = self.connections.get_established_mut(&cid) else {
+            tracing::debug!("Unknown connection, ignoring remote ICE credentials");
+            return;
+        };
+
+        let remote_restarted = match &connection.remote_credentials {
+            Some(current) if current == &credentials => return,
+            Some(_) => true,
+            None => false, // We restarted ICE ourselves and were waiting for these.
+        };
+
+        if remote_restarted {
+            tracing::info!("Remote restarted ICE");
+
+            self.restart_connection(cid, now);
+        }
+
+        let Some(connection) = self.connections.get_established_mut(&cid) else {
+            return;
+        };
+
+        connection.agent.set_remote_credentials(credentials.clone());
+        connection.remote_credentials = Some(credentials);
+    }
+
+    /// Rotate the preshared key of the given connection.
+    ///
+    /// The new key is emitted as [`Event::NewPresharedKey`] and must be passed to the remote via a signalling channel.
+    /// Until the remote completes a handshake using the new key, we continue to use the previous one.
+    ///
+    /// For connections created via [`Node::new_connection`], this happens automatically if [`ConnectionConfig::psk_rotation`] is set.
+    pub fn rotate_preshared_key(&mut self, cid: TId, now: Instant) {
+        let Some((remote, rate_limiter)) = self
+            .connections
+            .get_established_mut(&cid)
+            .map(|c| (c.remote_pub_key, c.rate_limiter.clone()))
+        else {
+            tracing::debug!(%cid, "Unknown connection");
+            return;
+        };
+        let _span = info_span!("connection", %cid).entered();
+
+        let key = random::<[u8; 32]>();
+        let (index, tunnel) = self.new_tunnel(remote, key, rate_limiter);
+
+        let connection = self
+            .connections
+            .get_established_mut(&cid)
+            .expect("connection to exist");
+        connection.rotate_tunnel(index, tunnel);
+        connection.next_psk_rotation = connection
+            .next_psk_rotation
+            .and(connection.config.psk_rotation)
+            .map(|interval| now + interval);
+
+        self.pending_events.push_back(Event::NewPresharedKey {
+            connection: cid,
+            key: PresharedKey::new(key),
+        });
+
+        tracing::info!("Rotated preshared key");
+    }
+
+    /// Switch the given connection over to the preshared key the remote signalled to us.
+    ///
+    /// We immediately initiate a new handshake using this key.
+    /// Once it completes, both sides switch over to the new key.
+    pub fn set_preshared_key(&mut self, cid: TId, key: PresharedKey, now: Instant) {
+        let Some((remote, rate_limiter)) = self
+            .connections
+            .get_established_mut(&cid)
+            .map(|c| (c.remote_pub_key, c.rate_limiter.clone()))
+        else {
+            tracing::debug!(%cid, "Unknown connection");
+            return;
+        };
+        let _span = info_span!("connection", %cid).entered();
+
+        let (index, tunnel) = self.new_tunnel(remote, *key.expose_secret(), rate_limiter);
+
+        let connection = self
+            .connections
+            .get_established_mut(&cid)
+            .expect("connection to exist");
+        connection.rotate_tunnel(index, tunnel);
+
+        if connection.socket().is_some() {
+            connection.force_handshake(&mut self.allocations, &mut self.buffered_transmits, now);
+        }
+
+        tracing::info!("Remote rotated preshared key");
+    }
+
+    fn restart_connection(&mut self, cid: TId, now: Instant) {
+        let Some(mut connection) = self.connections.established.remove(&cid) else {
+            return;
+        };
+        let _span = info_span!("connection", %cid).entered();
+
+        let local_credentials = IceCreds::new();
+
+        connection
+            .agent
+            .ice_restart(local_credentials.clone(), false);
+        connection.remote_credentials = None;
+        connection.signalling_completed_at = now;
+        connection.local_end_of_candidates = None;
+        connection.remote_end_of_candidates = None;
+        connection.consent_checks.clear_in_flight();
+        connection.consent_checks.clear_pairs();
+
+        self.pending_events.push_back(Event::NewIceCredentials {
+            connection: cid,
+            credentials: Credentials {
+                username: local_credentials.ufrag,
+                password: local_credentials.pass,
+            },
+        });
+        self.seed_agent_with_local_candidates(cid, &mut connection.agent);
+
+        self.connections.established.insert(cid, connection);
+
+        tracing::info!("Restarted ICE");
+    }
+
+    /// Limits the number of relays we use for relay candidates.
+    ///
+    /// We measure the RTT to all relays and only use relay candidates from the `max_relays` nearest ones.
+    /// Other relays are still used to discover server-reflexive candidates.
+    pub fn set_max_relays(&mut self, max_relays: usize) {
+        self.max_relays = max_relays;
+        self.relays_changed = true;
+    }
+
+    /// Sets how many threads [`Node::encapsulate_many`] and [`Node::decapsulate_many`] use for the crypto of different connections.
+    ///
+    /// Defaults to 1, i.e. all packets are processed on the calling thread.
+    /// The threads are scoped to a single call, they are spawned per batch and joined before it returns.
+    pub fn set_crypto_workers(&mut self, workers: usize) {
+        self.workers = Workers::new(workers);
+    }
+
+    /// Limits how many datagrams [`Node::encapsulate_batch`] and [`Node::encapsulate_many`] coalesce into a single [`Transmit`].
+    ///
+    /// This should be what the socket supports for UDP GSO, `1` disables coalescing.
+    pub fn set_max_gso_segments(&mut self, segments: usize) {
+        self.max_gso_segments = segments.clamp(1, MAX_GSO_SEGMENTS);
+    }
+
+    /// Sets which parts of the traffic class of tunneled packets we copy onto the outer datagram.
+    ///
+    /// A new [`Node`] copies neither DSCP nor ECN, see [`TrafficClassPropagation::default`].
+    /// This only affects outgoing packets: applying the ECN of a received datagram to the packet it carried is up to the caller.
+    pub fn set_traffic_class_propagation(&mut self, propagation: TrafficClassPropagation) {
+        self.traffic_class_propagation = propagation;
+    }
+
+    pub fn traffic_class_propagation(&self) -> TrafficClassPropagation {
+        self.traffic_class_propagation
+    }
+
+    /// Sets the prefix of the NAT64 on our network.
+    ///
+    /// With a prefix, we synthesize IPv6 forms of IPv4-only relays and of the IPv4 candidates of remotes so we can reach them from an IPv6-only network.
+    /// Relays we already know switch to their synthesized form, remote candidates are only synthesized for the ones added from now on.
+    pub fn set_nat64_prefix(&mut self, prefix: Option<Nat64Prefix>) {
+        let previous = std::mem::replace(&mut self.nat64_prefix, prefix);
+
+        if previous == prefix {
+            return;
+        }
+
+        // The prefix may only be discovered after we added our relays.
+        for allocation in self.allocations.values_mut() {
+            let configured = configured_relay_socket(allocation.server(), previous);
+
+            allocation.update_server(reachable_relay_socket(configured, prefix));
+        }
+    }
+
+    pub fn nat64_prefix(&self) -> Option<Nat64Prefix> {
+        self.nat64_prefix
+    }
+
+    /// Discovers the prefix of the NAT64 on our network as per RFC7050 and sets it via [`Node::set_nat64_prefix`].
+    ///
+    /// `lookup` must resolve the AAAA records of the given domain using the DNS servers of the network, i.e. the ones that would perform DNS64.
+    pub fn discover_nat64_prefix(
+        &mut self,
+        lookup: impl FnOnce(&str) -> io::Result<Vec<Ipv6Addr>>,
+    ) -> Option<Nat64Prefix> {
+        let prefix = match lookup(IPV4ONLY_ARPA) {
+            Ok(addrs) => Nat64Prefix::from_ipv4only_arpa(addrs),
+            Err(e) => {
+                tracing::debug!("Failed to resolve {IPV4ONLY_ARPA}: {e}");
+
+                None
+            }
+        };
+
+        tracing::info!(?prefix, "Discovered NAT64 prefix");
+
+        self.set_nat64_prefix(prefix);
+
+        prefix
+    }
+
+    /// Sets the config for all connections created from now on.
+    ///
+    /// Existing connections keep their config, use [`Node::set_connection_config`] to change those.
+    pub fn set_default_connection_config(&mut self, config: ConnectionConfig) {
+        self.default_connection_config = config;
+    }
+
+    /// Overrides the config of a particular connection.
+    pub fn set_connection_config(&mut self, cid: TId, config: ConnectionConfig) {
+        if let Some(initial) = self.connections.initial.get_mut(&cid) {
+            initial.config = config;
+            return;
+        }
+
+        let Some(connection) = self.connections.get_established_mut(&cid) else {
+            tracing::debug!(%cid, "Unknown connection");
+            return;
+        };
+
+        connection.config = config;
+    }
+
+    /// The NAT we are behind, as classified from the addresses our relays observe for us.
+    ///
+    /// Changes to this are also emitted as [`Event::NatStatusChanged`].
+    /// The portal doesn't accept NAT reports yet so connlib only logs them, see also [`Node::snapshot`].
+    pub fn nat_status(&self) -> NatStatus {
+        self.nat_status
+    }
+
     pub fn public_key(&self) -> PublicKey {
         (&self.private_key).into()
     }
 
     pub fn connection_id(&self, key: PublicKey) -> Option<TId> {
         self.connections.iter_established().find_map(|(id, c)| {
-            (c.remote_pub_key == key && c.tunnel.time_since_last_handshake().is_some())
-                .then_some(id)
+            (c.remote_pub_key == key && c.wg_handshake_complete()).then_some(id)
         })
     }
 
-    pub fn stats(&self) -> (NodeStats, impl Iterator<Item = (TId, ConnectionStats)> + '_) {
+    pub fn stats(
+        &self,
+    ) -> (
+        NodeStats,
+        impl Iterator<Item = (TId, ConnectionStats<RId>)> + '_,
+    ) {
         (self.stats, self.connections.stats())
     }
 
+    /// A snapshot of the state of all connections and allocations, to be included in support bundles.
+    pub fn snapshot(&self, now: Instant) -> NodeSnapshot<TId, RId> {
+        NodeSnapshot {
+            nat_status: self.nat_status,
+            nat64_prefix: self.nat64_prefix.map(|p| p.to_string()),
+            host_candidates: self
+                .host_candidates
+                .iter()
+                .map(|c| c.to_sdp_string())
+                .collect(),
+            allocations: self
+                .allocations
+                .iter()
+                .map(|(rid, a)| a.snapshot(*rid, self.preferred_relays.contains(rid), now))
+                .collect(),
+            pending_connections: self.connections.initial.keys().copied().collect(),
+            connections: self
+                .connections
+                .iter_established()
+                .map(|(cid, c)| c.snapshot(cid, now))
+                .collect(),
+        }
+    }
+
     /// Add an address as a `host` candidate.
     ///
     /// For most network topologies, [`snownet`](crate) will automatically discover host candidates via the traffic to the configured STUN and TURN servers.
@@ -230,6 +614,21 @@ where
 
         if let Some(agent) = self.connections.agent_mut(cid) {
             agent.add_remote_candidate(candidate.clone());
+
+            // The relays are not behind our NAT64, thus we only need the synthesized candidate in our agent.
+            if let Some(synthesized) = self
+                .nat64_prefix
+                .and_then(|prefix| synthesize_candidate(prefix, &candidate))
+            {
+                agent.add_remote_candidate(synthesized);
+            }
+        }
+
+        // The remote is gathering candidates again, it will signal the end of them once it is done.
+        if let Some(connection) = self.connections.get_established_mut(&cid) {
+            if connection.remote_end_of_candidates.take().is_some() {
+                tracing::debug!("Remote sent a candidate after end-of-candidates");
+            }
         }
 
         match candidate.kind() {
@@ -255,6 +654,23 @@ where
         }
     }
 
+    /// Signals that the remote finished gathering candidates for this connection.
+    ///
+    /// Once both sides are done gathering, ICE has [`END_OF_CANDIDATES_TIMEOUT`] to find a working candidate pair before we consider the connection failed.
+    /// If the remote didn't send a single candidate, the connection fails right away.
+    /// A candidate added after this starts a new generation of candidates for which the remote needs to signal the end again.
+    #[tracing::instrument(level = "info", skip_all, fields(%cid))]
+    pub fn end_of_remote_candidates(&mut self, cid: TId, now: Instant) {
+        let Some(connection) = self.connections.get_established_mut(&cid) else {
+            tracing::debug!("Unknown connection, ignoring end-of-candidates");
+            return;
+        };
+
+        tracing::debug!("Remote finished gathering candidates");
+
+        connection.remote_end_of_candidates = Some(now);
+    }
+
     #[tracing::instrument(level = "info", skip_all, fields(%cid))]
     pub fn remove_remote_candidate(&mut self, cid: TId, candidate: String) {
         let candidate = match Candidate::from_sdp_string(&candidate) {
@@ -267,6 +683,13 @@ where
 
         if let Some(agent) = self.connections.agent_mut(cid) {
             agent.invalidate_candidate(&candidate);
+
+            if let Some(synthesized) = self
+                .nat64_prefix
+                .and_then(|prefix| synthesize_candidate(prefix, &candidate))
+            {
+                agent.invalidate_candidate(&synthesized);
+            }
         }
     }
 
@@ -276,11 +699,12 @@ where
     /// The same relay might be reachable over IPv4 and IPv6.
     #[must_use]
     fn same_relay_as_peer(&mut self, candidate: &Candidate) -> Option<&mut Allocation> {
-        self.allocations.iter_mut().find_map(|(_, allocation)| {
-            allocation
-                .current_candidates()
-                .any(|c| c.addr().ip() == candidate.addr().ip())
-                .then_some(allocation)
+        self.allocations.iter_mut().find_map(|(rid, allocation)| {
+            (self.preferred_relays.contains(rid)
+                && allocation
+                    .current_candidates()
+                    .any(|c| c.addr().ip() == candidate.addr().ip()))
+            .then_some(allocation)
         })
     }
 
@@ -342,6 +766,7 @@ where
 
         // Must bail early if we don't have a socket yet to avoid running into WG timeouts.
         let socket = conn.socket().ok_or(Error::NotConnected)?;
+        let tos = self.traffic_class_propagation.outer_tos(&packet);
 
         // Encode the packet with an offset of 4 bytes, in case we need to wrap it in a channel-data message.
         let Some(packet_len) = conn
@@ -367,6 +792,8 @@ where
                     src: Some(source),
                     dst: remote,
                     payload: Cow::Borrowed(packet),
+                    segment_size: None,
+                    tos,
                 }))
             }
             PeerSocket::Relay { relay, dest: peer } => {
@@ -376,20 +803,375 @@ where
                 };
                 let packet = &mut self.buffer.as_mut()[..packet_end];
 
-                let Some(transmit) = allocation.encode_to_borrowed_transmit(peer, packet, now)
-                else {
-                    tracing::warn!(%peer, "No channel");
-                    return Ok(None);
-                };
+                let Some(transmit) = allocation.encode_to_borrowed_transmit(peer, packet, now)
+                else {
+                    tracing::warn!(%peer, "No channel");
+                    return Ok(None);
+                };
+
+                Ok(Some(Transmit { tos, ..transmit }))
+            }
+        }
+    }
+
+    /// Decapsulate a batch of datagrams received from the same remote, i.e. what a single UDP GRO read returns.
+    ///
+    /// All datagrams within `datagrams` are `stride` bytes long, except for the last one which may be shorter.
+    /// Each datagram is decrypted into its own `stride`-sized region of `buffer` which must therefore be at least as large as `datagrams`.
+    /// Returns the decrypted IP packets together with the connection they arrived on, in the order of the datagrams.
+    pub fn decapsulate_batch<'b>(
+        &mut self,
+        local: SocketAddr,
+        from: SocketAddr,
+        datagrams: &[u8],
+        stride: usize,
+        now: Instant,
+        buffer: &'b mut [u8],
+    ) -> Vec<(TId, MutableIpPacket<'b>)> {
+        let stride = stride.max(1);
+        debug_assert!(buffer.len() >= datagrams.len());
+
+        datagrams
+            .chunks(stride)
+            .zip(buffer.chunks_mut(stride))
+            .filter_map(|(datagram, buffer)| {
+                self.decapsulate(local, from, datagram, now, buffer)
+                    .inspect_err(|e| {
+                        tracing::debug!(%local, %from, num_bytes = %datagram.len(), "Failed to decapsulate datagram: {e}")
+                    })
+                    .ok()?
+            })
+            .collect()
+    }
+
+    /// Encapsulate a batch of outgoing IP packets for the same connection.
+    ///
+    /// Consecutive packets that encrypt to the same size are coalesced into a single [`Transmit`] with [`Transmit::segment_size`] set, ready to be sent with UDP GSO.
+    /// For bulk transfers, this typically results in a handful of [`Transmit`]s for many packets.
+    pub fn encapsulate_batch<'p>(
+        &mut self,
+        connection: TId,
+        packets: impl IntoIterator<Item = IpPacket<'p>>,
+        now: Instant,
+    ) -> Result<Vec<Transmit<'static>>, Error> {
+        let conn = self
+            .connections
+            .get_established_mut(&connection)
+            .ok_or(Error::NotConnected)?;
+
+        // Must bail early if we don't have a socket yet to avoid running into WG timeouts.
+        let socket = conn.socket().ok_or(Error::NotConnected)?;
+
+        let mut transmits = Vec::new();
+
+        for packet in packets {
+            let tos = self.traffic_class_propagation.outer_tos(&packet);
+
+            // Encode the packet with an offset of 4 bytes, in case we need to wrap it in a channel-data message.
+            let packet_len = match conn.encapsulate(packet.packet(), &mut self.buffer[4..], now) {
+                Ok(Some(packet)) => packet.len(),
+                Ok(None) => continue,
+                Err(e) => {
+                    tracing::debug!(%connection, "Failed to encapsulate: {e}");
+                    continue;
+                }
+            };
+            let packet_end = 4 + packet_len;
+
+            match socket {
+                PeerSocket::Direct {
+                    dest: remote,
+                    source,
+                } => push_segment(
+                    &mut transmits,
+                    self.max_gso_segments,
+                    Some(source),
+                    remote,
+                    tos,
+                    &self.buffer[4..packet_end],
+                ),
+                PeerSocket::Relay { relay, dest: peer } => {
+                    let Some(allocation) = self.allocations.get(&relay) else {
+                        tracing::warn!(%relay, "No allocation");
+                        continue;
+                    };
+
+                    let Some(transmit) = allocation.encode_to_borrowed_transmit(
+                        peer,
+                        &mut self.buffer[..packet_end],
+                        now,
+                    ) else {
+                        tracing::warn!(%peer, "No channel");
+                        continue;
+                    };
+
+                    push_segment(
+                        &mut transmits,
+                        self.max_gso_segments,
+                        transmit.src,
+                        transmit.dst,
+                        tos,
+                        &transmit.payload,
+                    );
+                }
+            }
+        }
+
+        Ok(transmits)
+    }
+
+    /// Encapsulate outgoing IP packets for many connections at once.
+    ///
+    /// The packets of different connections are encrypted in parallel, see [`Node::set_crypto_workers`].
+    /// Packets of the same connection are sent in the order they were passed in and coalesced for GSO like in [`Node::encapsulate_batch`].
+    pub fn encapsulate_many<'p>(
+        &mut self,
+        packets: impl IntoIterator<Item = (TId, IpPacket<'p>)>,
+        now: Instant,
+    ) -> Vec<Transmit<'static>>
+    where
+        TId: Send,
+        RId: Send,
+    {
+        let mut packets_by_connection = HashMap::<TId, Vec<IpPacket<'p>>>::new();
+        for (cid, packet) in packets {
+            packets_by_connection.entry(cid).or_default().push(packet);
+        }
+
+        let mut jobs = Vec::with_capacity(packets_by_connection.len());
+
+        for (cid, conn) in self.connections.iter_established_mut() {
+            let Some(packets) = packets_by_connection.remove(&cid) else {
+                continue;
+            };
+
+            // Must bail early if we don't have a socket yet to avoid running into WG timeouts.
+            if conn.socket().is_none() {
+                tracing::debug!(%cid, "Failed to encapsulate: {}", Error::NotConnected);
+                continue;
+            }
+
+            jobs.push(EncryptJob {
+                cid,
+                conn,
+                packets,
+                buffer: self.crypto_buffers.pop().unwrap_or_default(),
+                ciphertexts: Vec::new(),
+            });
+        }
+
+        for cid in packets_by_connection.keys() {
+            tracing::debug!(%cid, "Failed to encapsulate: {}", Error::NotConnected);
+        }
+
+        let traffic_class_propagation = self.traffic_class_propagation;
+
+        self.workers.run(&mut jobs, |job| {
+            for packet in &job.packets {
+                let tos = traffic_class_propagation.outer_tos(packet);
+
+                // Encrypt straight into the job's buffer, with enough room for a handshake initiation in case boringtun needs to send one instead.
+                let start = job.buffer.len();
+                let capacity = (packet.packet().len() + usize::from(pmtu::WIREGUARD_OVERHEAD))
+                    .max(HANDSHAKE_INIT_SIZE);
+                job.buffer.resize(start + capacity, 0);
+
+                match job
+                    .conn
+                    .encapsulate(packet.packet(), &mut job.buffer[start..], now)
+                {
+                    Ok(Some(ciphertext)) => {
+                        let end = start + ciphertext.len();
+
+                        job.buffer.truncate(end);
+                        job.ciphertexts.push((start..end, tos));
+                    }
+                    Ok(None) => job.buffer.truncate(start),
+                    Err(e) => {
+                        job.buffer.truncate(start);
+                        tracing::debug!(cid = %job.cid, "Failed to encapsulate: {e}");
+                    }
+                }
+            }
+        });
+
+        let mut transmits = Vec::new();
+        let max_gso_segments = self.max_gso_segments;
+
+        for job in jobs {
+            if let Some(socket) = job.conn.socket() {
+                for (range, tos) in job.ciphertexts {
+                    let ciphertext = &job.buffer[range];
+
+                    match socket {
+                        PeerSocket::Direct {
+                            dest: remote,
+                            source,
+                        } => push_segment(
+                            &mut transmits,
+                            max_gso_segments,
+                            Some(source),
+                            remote,
+                            tos,
+                            ciphertext,
+                        ),
+                        PeerSocket::Relay { relay, dest: peer } => {
+                            let transmit = match encode_as_channel_data(
+                                relay,
+                                peer,
+                                ciphertext,
+                                &mut self.allocations,
+                                now,
+                            ) {
+                                Ok(transmit) => transmit,
+                                Err(e) => {
+                                    tracing::warn!(%relay, %peer, "Failed to encode channel data: {e:?}");
+                                    continue;
+                                }
+                            };
+
+                            push_segment(
+                                &mut transmits,
+                                max_gso_segments,
+                                transmit.src,
+                                transmit.dst,
+                                tos,
+                                &transmit.payload,
+                            );
+                        }
+                    }
+                }
+            }
+
+            let mut buffer = job.buffer;
+            buffer.clear();
+            self.crypto_buffers.push(buffer);
+        }
+
+        transmits
+    }
+
+    /// Decapsulate datagrams received from many remotes at once.
+    ///
+    /// STUN, TURN and WireGuard handshakes are handled on the calling thread.
+    /// WireGuard data packets of different connections are decrypted in parallel, see [`Node::set_crypto_workers`].
+    /// Each decrypted IP packet is passed to `on_packet`, in order for any particular connection.
+    pub fn decapsulate_many<'d>(
+        &mut self,
+        datagrams: impl IntoIterator<Item = (SocketAddr, SocketAddr, &'d [u8])>,
+        now: Instant,
+        buffer: &mut [u8],
+        mut on_packet: impl FnMut(TId, MutableIpPacket<'_>),
+    ) where
+        TId: Send,
+        RId: Send,
+    {
+        let mut data_by_connection = HashMap::<TId, Vec<(SocketAddr, &'d [u8])>>::new();
+
+        for (local, from, datagram) in datagrams {
+            if let Err(e) = self.add_local_as_host_candidate(local) {
+                tracing::debug!(%local, %from, num_bytes = %datagram.len(), "Failed to decapsulate datagram: {e}");
+                continue;
+            }
+
+            let (from, packet, relayed) =
+                match self.allocations_try_handle(from, local, datagram, now) {
+                    ControlFlow::Continue(c) => c,
+                    ControlFlow::Break(()) => continue,
+                };
+
+            // For our agents, it is important what the initial "destination" of the packet was.
+            let destination = relayed.map(|s| s.address()).unwrap_or(local);
+
+            match self.agents_try_handle(from, destination, packet, now) {
+                ControlFlow::Continue(()) => {}
+                ControlFlow::Break(Ok(())) => continue,
+                ControlFlow::Break(Err(e)) => {
+                    tracing::debug!(%local, %from, num_bytes = %datagram.len(), "Failed to decapsulate datagram: {e}");
+                    continue;
+                }
+            };
+
+            // Data packets of established sessions are decrypted on the workers, everything else right here.
+            if let Some(cid) = self.connection_for_data_packet(from, packet) {
+                data_by_connection
+                    .entry(cid)
+                    .or_default()
+                    .push((from, packet));
+                continue;
+            }
+
+            match self.connections_try_handle(from, packet, buffer, now) {
+                ControlFlow::Continue((cid, packet)) => on_packet(cid, packet),
+                ControlFlow::Break(Ok(())) => {}
+                ControlFlow::Break(Err(e)) => {
+                    tracing::debug!(%local, %from, num_bytes = %datagram.len(), "Failed to decapsulate datagram: {e}")
+                }
+            };
+        }
+
+        let mut jobs = Vec::with_capacity(data_by_connection.len());
+
+        for (cid, conn) in self.connections.iter_established_mut() {
+            let Some(packets) = data_by_connection.remove(&cid) else {
+                continue;
+            };
+
+            jobs.push(DecryptJob {
+                cid,
+                conn,
+                packets,
+                buffer: self.crypto_buffers.pop().unwrap_or_default(),
+                plaintexts: Vec::new(),
+            });
+        }
+
+        self.workers.run(&mut jobs, |job| {
+            for (from, packet) in &job.packets {
+                // The decrypted packet is always smaller than the encrypted one, plus room for the IPv4 header in case we need to convert it.
+                let start = job.buffer.len();
+                job.buffer.resize(start + packet.len() + 20, 0);
+
+                match job.conn.decrypt(*from, packet, &mut job.buffer[start..]) {
+                    Ok(Some(len)) => {
+                        job.buffer.truncate(start + 20 + len);
+                        job.plaintexts.push((start, len));
+                    }
+                    Ok(None) => job.buffer.truncate(start),
+                    Err(e) => {
+                        job.buffer.truncate(start);
+                        tracing::debug!(cid = %job.cid, "Failed to decrypt: {e}");
+                    }
+                }
+            }
+        });
 
-                Ok(Some(transmit))
+        for mut job in jobs {
+            for (start, len) in job.plaintexts {
+                match job.conn.on_decrypted(
+                    &mut job.buffer[start..(start + 20 + len)],
+                    len,
+                    &mut self.allocations,
+                    &mut self.buffered_transmits,
+                    now,
+                ) {
+                    ControlFlow::Continue(packet) => on_packet(job.cid, packet),
+                    ControlFlow::Break(Ok(())) => {}
+                    ControlFlow::Break(Err(e)) => {
+                        tracing::debug!(cid = %job.cid, "Failed to decapsulate datagram: {e}")
+                    }
+                }
             }
+
+            let mut buffer = job.buffer;
+            buffer.clear();
+            self.crypto_buffers.push(buffer);
         }
     }
 
     /// Returns a pending [`Event`] from the pool.
     #[must_use]
-    pub fn poll_event(&mut self) -> Option<Event<TId>> {
+    pub fn poll_event(&mut self) -> Option<Event<TId, RId>> {
         self.pending_events.pop_front()
     }
 
@@ -432,9 +1214,27 @@ where
     /// As such, it ends up being cleaner to "drain" all lower-level components of their events, transmits etc within this function.
     pub fn handle_timeout(&mut self, now: Instant) {
         self.bindings_and_allocations_drain_events();
+        self.signal_end_of_candidates(now);
+
+        let due_psk_rotations = self
+            .connections
+            .iter_established()
+            .filter(|(_, c)| c.next_psk_rotation.is_some_and(|at| now >= at))
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+
+        for id in due_psk_rotations {
+            self.rotate_preshared_key(id, now);
+        }
 
         for (id, connection) in self.connections.iter_established_mut() {
-            connection.handle_timeout(id, now, &mut self.allocations, &mut self.buffered_transmits);
+            connection.handle_timeout(
+                id,
+                now,
+                &mut self.allocations,
+                &mut self.buffered_transmits,
+                &mut self.pending_events,
+            );
         }
 
         for (id, connection) in self.connections.initial.iter_mut() {
@@ -448,7 +1248,10 @@ where
         let next_reset = *self.next_rate_limiter_reset.get_or_insert(now);
 
         if now >= next_reset {
-            self.rate_limiter.reset_count();
+            for (_, connection) in self.connections.iter_established_mut() {
+                connection.rate_limiter.reset_count();
+            }
+            self.source_rate_limiters.reset_count(now);
             self.next_rate_limiter_reset = Some(now + Duration::from_secs(1));
         }
 
@@ -511,11 +1314,15 @@ where
                 }
             }
 
+            self.relays_changed = true;
+
             tracing::info!(%rid, address = ?allocation.server(), "Removed TURN server");
         }
 
         // Second, upsert all new relays.
         for (rid, server, username, password, realm) in to_add {
+            let server = reachable_relay_socket(*server, self.nat64_prefix);
+
             let Ok(username) = Username::new(username.to_owned()) else {
                 tracing::debug!(%username, "Invalid TURN username");
                 continue;
@@ -526,14 +1333,15 @@ where
             };
 
             if let Some(existing) = self.allocations.get_mut(rid) {
-                existing.update_credentials(*server, username, password, realm, now);
+                existing.update_credentials(server, username, password, realm, now);
                 continue;
             }
 
             self.allocations.insert(
                 *rid,
-                Allocation::new(*server, username, password.clone(), realm, now),
+                Allocation::new(server, username, password.clone(), realm, now),
             );
+            self.relays_changed = true;
 
             tracing::info!(%rid, address = ?server, "Added new TURN server");
         }
@@ -544,33 +1352,42 @@ where
     fn init_connection(
         &mut self,
         mut agent: IceAgent,
+        remote_credentials: IceCreds,
         remote: PublicKey,
         key: [u8; 32],
+        config: ConnectionConfig,
         intent_sent_at: Instant,
         now: Instant,
     ) -> Connection<RId> {
         agent.handle_timeout(now);
 
-        /// We set a Wireguard keep-alive to ensure the WG session doesn't timeout on an idle connection.
-        ///
-        /// Without such a timeout, using a tunnel after the REKEY_TIMEOUT requires handshaking a new session which delays the new application packet by 1 RTT.
-        const WG_KEEP_ALIVE: Option<u16> = Some(10);
+        // Each peer gets its own budget of handshakes so a misbehaving one cannot exhaust it for everybody else.
+        let rate_limiter = Arc::new(RateLimiter::new(
+            &self.public_key(),
+            HANDSHAKE_RATE_LIMIT_PER_PEER,
+        ));
+        let (tunnel_index, tunnel) = self.new_tunnel(remote, key, rate_limiter.clone());
 
         Connection {
             agent,
-            tunnel: Tunn::new(
-                self.private_key.clone(),
-                remote,
-                Some(key),
-                WG_KEEP_ALIVE,
-                self.index.next(),
-                Some(self.rate_limiter.clone()),
-            ),
+            remote_credentials: Some(remote_credentials),
+            tunnel,
+            tunnel_index,
+            rate_limiter,
+            remote_tunnel_index: None,
+            previous_tunnel: None,
+            previous_remote_tunnel_index: None,
+            next_psk_rotation: None,
             next_timer_update: now,
             stats: Default::default(),
+            consent_checks: Default::default(),
+            path_mtu: Default::default(),
+            config,
             buffer: Box::new([0u8; MAX_UDP_SIZE]),
             intent_sent_at,
             signalling_completed_at: now,
+            local_end_of_candidates: None,
+            remote_end_of_candidates: None,
             remote_pub_key: remote,
             state: ConnectionState::Connecting {
                 possible_sockets: HashSet::default(),
@@ -578,9 +1395,31 @@ where
             },
             last_outgoing: now,
             last_incoming: now,
+            last_keepalive: now,
+            last_agent_timeout: now,
         }
     }
 
+    /// Creates a new WireGuard tunnel to `remote`, returning it together with its index.
+    fn new_tunnel(
+        &mut self,
+        remote: PublicKey,
+        key: [u8; 32],
+        rate_limiter: Arc<RateLimiter>,
+    ) -> (u32, Tunn) {
+        let index = self.index.next();
+        let tunnel = Tunn::new(
+            self.private_key.clone(),
+            remote,
+            Some(key),
+            None, // We send keep-alives ourselves, see `ConnectionConfig::keepalive`.
+            index,
+            Some(rate_limiter),
+        );
+
+        (index, tunnel)
+    }
+
     /// Attempt to add the `local` address as a host candidate.
     ///
     /// Receiving traffic on a certain interface means we at least have a connection to a relay via this interface.
@@ -594,11 +1433,11 @@ where
             return Ok(());
         }
 
-        for (cid, agent) in self.connections.agents_mut() {
-            let _span = info_span!("connection", %cid).entered();
-
-            add_local_candidate(cid, agent, host_candidate.clone(), &mut self.pending_events);
-        }
+        add_local_candidate_to_all(
+            host_candidate,
+            &mut self.connections,
+            &mut self.pending_events,
+        );
 
         Ok(())
     }
@@ -682,6 +1521,9 @@ where
             return ControlFlow::Continue(());
         };
 
+        let trans_id = message.trans_id();
+        let is_binding_response = message.is_successful_binding_response();
+
         for (cid, agent) in self.connections.agents_mut() {
             let _span = info_span!("connection", %cid).entered();
 
@@ -696,6 +1538,14 @@ where
                     },
                 );
 
+                if is_binding_response {
+                    if let Some(connection) = self.connections.get_established_mut(&cid) {
+                        connection
+                            .consent_checks
+                            .on_response_received(trans_id, now);
+                    }
+                }
+
                 return ControlFlow::Break(Ok(()));
             }
         }
@@ -705,6 +1555,28 @@ where
         }))
     }
 
+    /// Returns the connection that can decrypt this WireGuard data packet without any further control-plane work.
+    fn connection_for_data_packet(&mut self, from: SocketAddr, packet: &[u8]) -> Option<TId> {
+        if !matches!(
+            Tunn::parse_incoming_packet(packet),
+            Ok(Packet::PacketData(_))
+        ) {
+            return None;
+        }
+
+        let (cid, conn) = self
+            .connections
+            .iter_established_mut()
+            .find(|(_, conn)| conn.accepts(&from))?;
+
+        // A data packet may complete the handshake on the responder's side, we need to emit an event for that.
+        if !conn.wg_handshake_complete() {
+            return None;
+        }
+
+        Some(cid)
+    }
+
     #[must_use]
     fn connections_try_handle<'b>(
         &mut self,
@@ -723,8 +1595,10 @@ where
             let handshake_complete_before_decapsulate = conn.wg_handshake_complete();
 
             let control_flow = conn.decapsulate(
+                from,
                 packet,
                 buffer,
+                &mut self.source_rate_limiters,
                 &mut self.allocations,
                 &mut self.buffered_transmits,
                 now,
@@ -751,14 +1625,92 @@ where
         }))
     }
 
+    /// Ranks all relays by their RTT and updates the relay candidates of all connections accordingly.
+    ///
+    /// This only does work if the RTT of a relay changed or relays were added or removed.
+    fn update_preferred_relays(&mut self) {
+        let rtt_changed = self
+            .allocations
+            .values_mut()
+            .fold(false, |changed, a| a.take_rtt_changed() || changed);
+        let relays_changed = std::mem::take(&mut self.relays_changed);
+
+        if !rtt_changed && !relays_changed {
+            return;
+        }
+
+        let preferred = rank_relays(
+            self.allocations
+                .iter()
+                .filter_map(|(rid, allocation)| Some((*rid, allocation.rtt()?))),
+            &self.preferred_relays,
+            self.max_relays,
+        );
+
+        if preferred == self.preferred_relays {
+            return;
+        }
+
+        for rid in self.preferred_relays.difference(&preferred) {
+            let Some(allocation) = self.allocations.get(rid) else {
+                continue;
+            };
+
+            for candidate in allocation
+                .current_candidates()
+                .filter(|c| c.kind() == CandidateKind::Relayed)
+            {
+                // Connections that are relayed through this relay keep its candidates, we don't want to disrupt them.
+                for (cid, agent) in self.connections.agents_not_relayed_by_mut(*rid) {
+                    let _span = info_span!("connection", %cid).entered();
+
+                    remove_local_candidate(cid, agent, &candidate, &mut self.pending_events);
+                }
+            }
+
+            tracing::info!(%rid, rtt = ?allocation.rtt(), "No longer using relay for relay candidates");
+        }
+
+        let newly_preferred = prioritise_by_rtt(
+            preferred
+                .difference(&self.preferred_relays)
+                .filter_map(|rid| Some((rid, self.allocations.get(rid)?))),
+        );
+
+        for (rid, allocation) in newly_preferred {
+            for candidate in allocation
+                .current_candidates()
+                .filter(|c| c.kind() == CandidateKind::Relayed)
+            {
+                add_local_candidate_to_all(
+                    candidate,
+                    &mut self.connections,
+                    &mut self.pending_events,
+                );
+            }
+
+            tracing::info!(%rid, rtt = ?allocation.rtt(), "Using relay for relay candidates");
+        }
+
+        self.preferred_relays = preferred;
+    }
+
     fn bindings_and_allocations_drain_events(&mut self) {
+        self.update_preferred_relays();
+
         let allocation_events = self
             .allocations
-            .values_mut()
-            .flat_map(|allocation| allocation.poll_event());
+            .iter_mut()
+            .flat_map(|(rid, allocation)| allocation.poll_event().map(|e| (*rid, e)));
 
-        for event in allocation_events {
+        for (rid, event) in allocation_events {
             match event {
+                CandidateEvent::New(candidate)
+                    if candidate.kind() == CandidateKind::Relayed
+                        && !self.preferred_relays.contains(&rid) =>
+                {
+                    tracing::debug!(%rid, "Ignoring relay candidate from non-preferred relay");
+                }
                 CandidateEvent::New(candidate) => {
                     add_local_candidate_to_all(
                         candidate,
@@ -775,6 +1727,44 @@ where
                 }
             }
         }
+
+        self.update_nat_status();
+    }
+
+    /// Emits [`Event::EndOfIceCandidates`] for all connections once none of our [`Allocation`]s are still discovering candidates.
+    ///
+    /// Without any relays, we cannot tell whether more candidates are going to show up and thus never signal the end of them.
+    fn signal_end_of_candidates(&mut self, now: Instant) {
+        if self.allocations.is_empty() || self.allocations.values().any(Allocation::is_gathering) {
+            return;
+        }
+
+        for (cid, connection) in self.connections.iter_established_mut() {
+            if connection.local_end_of_candidates.is_some() {
+                continue;
+            }
+
+            connection.local_end_of_candidates = Some(now);
+            self.pending_events
+                .push_back(Event::EndOfIceCandidates { connection: cid });
+        }
+    }
+
+    fn update_nat_status(&mut self) {
+        let mappings = || self.allocations.values().flat_map(|a| a.srflx_mappings());
+
+        let nat_status = NatStatus {
+            ipv4: NatType::classify(mappings().filter(|(_, mapped)| mapped.is_ipv4())),
+            ipv6: NatType::classify(mappings().filter(|(_, mapped)| mapped.is_ipv6())),
+        };
+
+        if nat_status == self.nat_status {
+            return;
+        }
+
+        self.nat_status = nat_status;
+        self.pending_events
+            .push_back(Event::NatStatusChanged(nat_status));
     }
 }
 
@@ -817,6 +1807,7 @@ where
         let initial_connection = InitialConnection {
             agent,
             session_key,
+            config: self.default_connection_config,
             created_at: now,
             intent_sent_at,
             is_failed: false,
@@ -845,20 +1836,27 @@ where
         };
 
         let mut agent = initial.agent;
-        agent.set_remote_credentials(IceCreds {
+        let remote_credentials = IceCreds {
             ufrag: answer.credentials.username,
             pass: answer.credentials.password,
-        });
+        };
+        agent.set_remote_credentials(remote_credentials.clone());
 
         self.seed_agent_with_local_candidates(cid, &mut agent);
 
-        let connection = self.init_connection(
+        let mut connection = self.init_connection(
             agent,
+            remote_credentials,
             remote,
             *initial.session_key.expose_secret(),
+            initial.config,
             initial.intent_sent_at,
             now,
         );
+        connection.next_psk_rotation = connection
+            .config
+            .psk_rotation
+            .map(|interval| now + interval);
         let duration_since_intent = connection.duration_since_intent(now);
 
         let existing = self.connections.established.insert(cid, connection);
@@ -896,12 +1894,14 @@ where
             tracing::info!("Replacing existing established connection");
         };
 
-        let mut agent = IceAgent::new();
-        agent.set_controlling(false);
-        agent.set_remote_credentials(IceCreds {
+        let remote_credentials = IceCreds {
             ufrag: offer.credentials.username,
             pass: offer.credentials.password,
-        });
+        };
+
+        let mut agent = IceAgent::new();
+        agent.set_controlling(false);
+        agent.set_remote_credentials(remote_credentials.clone());
         agent.set_timing_advance(Duration::ZERO);
 
         let answer = Answer {
@@ -915,8 +1915,10 @@ where
 
         let connection = self.init_connection(
             agent,
+            remote_credentials,
             remote,
             *offer.session_key.expose_secret(),
+            self.default_connection_config,
             now, // Technically, this isn't fully correct because gateways don't send intents so we just use the current time.
             now,
         );
@@ -940,11 +1942,13 @@ where
             add_local_candidate(connection, agent, candidate, &mut self.pending_events);
         }
 
-        for candidate in self
-            .allocations
-            .values()
-            .flat_map(|allocation| allocation.current_candidates())
-        {
+        for candidate in prioritise_by_rtt(self.allocations.iter()).flat_map(|(rid, allocation)| {
+            let is_preferred = self.preferred_relays.contains(&rid);
+
+            allocation
+                .current_candidates()
+                .filter(move |c| is_preferred || c.kind() != CandidateKind::Relayed)
+        }) {
             add_local_candidate(
                 connection,
                 agent,
@@ -974,7 +1978,7 @@ where
     TId: Eq + Hash + Copy + fmt::Display,
     RId: Copy + Eq + Hash + PartialEq + fmt::Debug + fmt::Display,
 {
-    fn gc(&mut self, events: &mut VecDeque<Event<TId>>) {
+    fn gc(&mut self, events: &mut VecDeque<Event<TId, RId>>) {
         self.initial.retain(|id, conn| {
             if conn.is_failed {
                 events.push_back(Event::ConnectionFailed(*id));
@@ -999,8 +2003,8 @@ where
         });
     }
 
-    fn stats(&self) -> impl Iterator<Item = (TId, ConnectionStats)> + '_ {
-        self.established.iter().map(move |(id, c)| (*id, c.stats))
+    fn stats(&self) -> impl Iterator<Item = (TId, ConnectionStats<RId>)> + '_ {
+        self.established.iter().map(move |(id, c)| (*id, c.stats()))
     }
 
     fn agent_mut(&mut self, id: TId) -> Option<&mut IceAgent> {
@@ -1020,6 +2024,23 @@ where
         initial_agents.chain(negotiated_agents)
     }
 
+    /// Like [`Connections::agents_mut`] but skips connections that currently send through the given relay.
+    fn agents_not_relayed_by_mut(
+        &mut self,
+        rid: RId,
+    ) -> impl Iterator<Item = (TId, &mut IceAgent)> {
+        let initial_agents = self.initial.iter_mut().map(|(id, c)| (*id, &mut c.agent));
+        let negotiated_agents = self
+            .established
+            .iter_mut()
+            .filter(move |(_, c)| {
+                !matches!(c.socket(), Some(PeerSocket::Relay { relay, .. }) if relay == rid)
+            })
+            .map(|(id, c)| (*id, &mut c.agent));
+
+        initial_agents.chain(negotiated_agents)
+    }
+
     fn get_established_mut(&mut self, id: &TId) -> Option<&mut Connection<RId>> {
         self.established.get_mut(id)
     }
@@ -1075,41 +2096,243 @@ where
     Ok(transmit)
 }
 
+/// The packets of a single connection that should be encrypted on a worker.
+struct EncryptJob<'c, 'p, TId, RId> {
+    cid: TId,
+    conn: &'c mut Connection<RId>,
+    packets: Vec<IpPacket<'p>>,
+    /// Holds all encrypted packets of this job back to back.
+    buffer: Vec<u8>,
+    /// Where in `buffer` the encrypted packets are, together with the TOS of their outer datagram.
+    ciphertexts: Vec<(Range<usize>, u8)>,
+}
+
+/// The data packets of a single connection that should be decrypted on a worker.
+struct DecryptJob<'c, 'd, TId, RId> {
+    cid: TId,
+    conn: &'c mut Connection<RId>,
+    /// The data packets, together with the address they came from.
+    packets: Vec<(SocketAddr, &'d [u8])>,
+    /// Holds all decrypted packets of this job back to back, each with 20 bytes of headroom in front.
+    buffer: Vec<u8>,
+    /// Where in `buffer` the decrypted packets start (including the headroom), together with their length.
+    plaintexts: Vec<(usize, usize)>,
+}
+
+/// Appends a datagram to the last [`Transmit`] if they can be sent together using GSO, otherwise starts a new [`Transmit`].
+///
+/// GSO requires all segments to be of equal size, only the last one may be shorter.
+fn push_segment(
+    transmits: &mut Vec<Transmit<'static>>,
+    max_segments: usize,
+    src: Option<SocketAddr>,
+    dst: SocketAddr,
+    tos: u8,
+    datagram: &[u8],
+) {
+    if let Some(last) = transmits.last_mut() {
+        let len = last.payload.len();
+        let segment_size = last.segment_size.unwrap_or(len);
+
+        let same_path = last.src == src && last.dst == dst && last.tos == tos;
+        let fits_segment = datagram.len() <= segment_size && len % segment_size == 0;
+        let has_capacity =
+            len / segment_size < max_segments && len + datagram.len() <= MAX_GSO_PAYLOAD;
+
+        if same_path && fits_segment && has_capacity {
+            last.segment_size = Some(segment_size);
+            last.payload.to_mut().extend_from_slice(datagram);
+
+            return;
+        }
+    }
+
+    transmits.push(Transmit {
+        src,
+        dst,
+        payload: Cow::Owned(datagram.to_vec()),
+        segment_size: None,
+        tos,
+    });
+}
+
 #[derive(Debug)]
 enum EncodeError {
     NoAllocation,
     NoChannel,
 }
 
+/// Selects the tunnel we should send with.
+///
+/// Until the tunnel of a new preshared key has a session, we keep sending via the previous one.
+fn sending_tunnel<'a>(
+    tunnel: &'a mut Tunn,
+    previous_tunnel: &'a mut Option<(u32, Tunn)>,
+) -> &'a mut Tunn {
+    match previous_tunnel {
+        Some((_, previous)) if tunnel.time_since_last_handshake().is_none() => previous,
+        _ => tunnel,
+    }
+}
+
+/// Whether the given WireGuard packet is addressed to the tunnel with the given index.
+///
+/// Handshake initiations don't carry the index of the receiving tunnel.
+/// Instead, we match them by the remote's tunnel that the tunnel with `index` handshaked with, as both use the same preshared key.
+fn is_addressed_to(packet: &[u8], index: u32, remote_index: Option<u32>) -> bool {
+    // `boringtun` uses the lower 8 bits to distinguish sessions within a tunnel.
+    let receiver_idx = match Tunn::parse_incoming_packet(packet) {
+        Ok(Packet::HandshakeResponse(p)) => p.receiver_idx,
+        Ok(Packet::PacketCookieReply(p)) => p.receiver_idx,
+        Ok(Packet::PacketData(p)) => p.receiver_idx,
+        Ok(Packet::HandshakeInit(p)) => return Some(p.sender_idx >> 8) == remote_index,
+        Err(_) => return false,
+    };
+
+    receiver_idx >> 8 == index
+}
+
+/// The index of the remote's tunnel that sent the given handshake packet.
+fn remote_tunnel_index(packet: &[u8]) -> Option<u32> {
+    match Tunn::parse_incoming_packet(packet) {
+        Ok(Packet::HandshakeInit(p)) => Some(p.sender_idx >> 8),
+        Ok(Packet::HandshakeResponse(p)) => Some(p.sender_idx >> 8),
+        Ok(Packet::PacketCookieReply(_)) | Ok(Packet::PacketData(_)) | Err(_) => None,
+    }
+}
+
+/// Picks the `max_relays` relays with the lowest RTT.
+///
+/// A relay has to be at least [`RELAY_RTT_HYSTERESIS`] faster than one we already prefer to replace it.
+/// Otherwise, jitter between similarly distant relays would make us swap their candidates all the time.
+fn rank_relays<RId>(
+    rtts: impl Iterator<Item = (RId, Duration)>,
+    preferred: &HashSet<RId>,
+    max_relays: usize,
+) -> HashSet<RId>
+where
+    RId: Copy + Eq + Hash,
+{
+    let mut ranked = rtts
+        .map(|(rid, rtt)| {
+            let is_preferred = preferred.contains(&rid);
+            let rtt = if is_preferred {
+                rtt.saturating_sub(RELAY_RTT_HYSTERESIS)
+            } else {
+                rtt
+            };
+
+            (rid, rtt, is_preferred)
+        })
+        .collect::<Vec<_>>();
+    // On ties, stick with the relays we already use to avoid needlessly swapping candidates.
+    ranked.sort_by_key(|(_, rtt, is_preferred)| (*rtt, !is_preferred));
+
+    ranked
+        .into_iter()
+        .take(max_relays)
+        .map(|(rid, _, _)| rid)
+        .collect()
+}
+
+/// Orders allocations by their RTT, nearest relay first.
+///
+/// str0m assigns decreasing local preferences to candidates of the same kind in the order they are added to an agent.
+/// Adding the relay candidates of nearer relays first thus gives them a higher priority and makes ICE prefer candidate pairs through them.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8445#section-5.1.2.1> for details.
+fn prioritise_by_rtt<'a, RId>(
+    allocations: impl Iterator<Item = (&'a RId, &'a Allocation)>,
+) -> impl Iterator<Item = (RId, &'a Allocation)>
+where
+    RId: Copy + 'a,
+{
+    let mut allocations = allocations
+        .map(|(rid, allocation)| (*rid, allocation))
+        .collect::<Vec<_>>();
+    allocations.sort_by_key(|(_, allocation)| allocation.rtt().unwrap_or(Duration::MAX));
+
+    allocations.into_iter()
+}
+
 fn add_local_candidate_to_all<TId, RId>(
     candidate: Candidate,
     connections: &mut Connections<TId, RId>,
-    pending_events: &mut VecDeque<Event<TId>>,
+    pending_events: &mut VecDeque<Event<TId, RId>>,
 ) where
     TId: Copy + fmt::Display,
 {
-    let initial_connections = connections
-        .initial
-        .iter_mut()
-        .map(|(id, c)| (*id, &mut c.agent));
-    let established_connections = connections
-        .established
-        .iter_mut()
-        .map(|(id, c)| (*id, &mut c.agent));
-
-    for (cid, agent) in initial_connections.chain(established_connections) {
+    for (cid, connection) in connections.initial.iter_mut() {
+        let _span = info_span!("connection", %cid).entered();
+
+        add_local_candidate(
+            *cid,
+            &mut connection.agent,
+            candidate.clone(),
+            pending_events,
+        );
+    }
+
+    for (cid, connection) in connections.established.iter_mut() {
         let _span = info_span!("connection", %cid).entered();
 
-        add_local_candidate(cid, agent, candidate.clone(), pending_events);
+        if add_local_candidate(
+            *cid,
+            &mut connection.agent,
+            candidate.clone(),
+            pending_events,
+        ) {
+            connection.on_new_local_candidate();
+        }
+    }
+}
+
+/// Adds the synthesized IPv6 socket to IPv4-only relays so we can reach them through our NAT64.
+fn reachable_relay_socket(server: RelaySocket, nat64_prefix: Option<Nat64Prefix>) -> RelaySocket {
+    match (server, nat64_prefix) {
+        (RelaySocket::V4(v4), Some(prefix)) => RelaySocket::Dual {
+            v4,
+            v6: prefix.synthesize_socket(v4),
+        },
+        (server, _) => server,
+    }
+}
+
+/// Reverts [`reachable_relay_socket`], i.e. returns the socket of the relay as it was configured.
+fn configured_relay_socket(server: RelaySocket, nat64_prefix: Option<Nat64Prefix>) -> RelaySocket {
+    match (server, nat64_prefix) {
+        (RelaySocket::Dual { v4, v6 }, Some(prefix)) if prefix.synthesize_socket(v4) == v6 => {
+            RelaySocket::V4(v4)
+        }
+        (server, _) => server,
     }
 }
 
-fn add_local_candidate<TId>(
+/// Synthesizes the IPv6 form of a remote's IPv4 candidate, reachable through our NAT64.
+fn synthesize_candidate(prefix: Nat64Prefix, candidate: &Candidate) -> Option<Candidate> {
+    let SocketAddr::V4(addr) = candidate.addr() else {
+        return None;
+    };
+    let addr = SocketAddr::V6(prefix.synthesize_socket(addr));
+
+    let synthesized = match candidate.kind() {
+        CandidateKind::Host => Candidate::host(addr, Protocol::Udp),
+        CandidateKind::ServerReflexive => Candidate::server_reflexive(addr, addr, Protocol::Udp),
+        CandidateKind::Relayed => Candidate::relayed(addr, Protocol::Udp),
+        CandidateKind::PeerReflexive => return None, // Peer-reflexive candidates are never signalled.
+    };
+
+    synthesized.ok()
+}
+
+/// Returns whether we signalled the candidate to the remote.
+fn add_local_candidate<TId, RId>(
     id: TId,
     agent: &mut IceAgent,
     candidate: Candidate,
-    pending_events: &mut VecDeque<Event<TId>>,
-) where
+    pending_events: &mut VecDeque<Event<TId, RId>>,
+) -> bool
+where
     TId: fmt::Display,
 {
     // srflx candidates don't need to be added to the local agent because we always send from the `base` anyway.
@@ -1118,7 +2341,7 @@ fn add_local_candidate<TId>(
             connection: id,
             candidate: candidate.to_sdp_string(),
         });
-        return;
+        return true;
     }
 
     let is_new = agent.add_local_candidate(candidate.clone());
@@ -1129,13 +2352,15 @@ fn add_local_candidate<TId>(
             candidate: candidate.to_sdp_string(),
         })
     }
+
+    is_new
 }
 
-fn remove_local_candidate<TId>(
+fn remove_local_candidate<TId, RId>(
     id: TId,
     agent: &mut IceAgent,
     candidate: &Candidate,
-    pending_events: &mut VecDeque<Event<TId>>,
+    pending_events: &mut VecDeque<Event<TId, RId>>,
 ) where
     TId: fmt::Display,
 {
@@ -1167,6 +2392,93 @@ pub struct Answer {
     pub credentials: Credentials,
 }
 
+/// Timings that govern the lifetime of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionConfig {
+    /// For how long a connection may neither send nor receive any traffic before we close it.
+    pub idle_timeout: Duration,
+    /// After how long without sending any traffic we send a WireGuard keep-alive.
+    ///
+    /// Keep-alives ensure the WG session doesn't timeout on an idle connection.
+    /// Without them, using a tunnel after the REKEY_TIMEOUT requires handshaking a new session which delays the new application packet by 1 RTT.
+    /// Disabling them saves battery on mobile devices.
+    pub keepalive: Option<Duration>,
+    /// How often we rotate the preshared key of connections we initiated, `None` disables rotation.
+    ///
+    /// Only enable this if the remote passes the keys of [`Event::NewPresharedKey`] to [`Node::set_preshared_key`].
+    /// It takes effect once the answer is accepted.
+    pub psk_rotation: Option<Duration>,
+    /// At most how often the ICE agent sends consent checks on a connected path, `None` lets it check at its own pace.
+    ///
+    /// We achieve this by driving the agent's timers at this interval once it has completed its connectivity checks, so it also retransmits unanswered checks at this pace.
+    /// Larger intervals save battery but detect broken paths later.
+    pub consent_check_interval: Option<Duration>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(5 * 60),
+            keepalive: Some(Duration::from_secs(10)),
+            psk_rotation: None,
+            consent_check_interval: None,
+        }
+    }
+}
+
+/// Which parts of a tunneled packet's traffic class we copy onto the UDP datagram that carries it.
+///
+/// Copying the DSCP allows networks along the path to apply QoS to the tunneled traffic.
+/// Copying the ECN bits follows the "normal mode" of RFC 6040 and allows congestion to be signalled without dropping packets.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TrafficClassPropagation {
+    pub dscp: bool,
+    pub ecn: bool,
+}
+
+impl TrafficClassPropagation {
+    /// Computes the TOS of the datagram carrying the given packet.
+    pub(crate) fn outer_tos(&self, packet: &IpPacket<'_>) -> u8 {
+        let dscp = if self.dscp { packet.dscp() << 2 } else { 0 };
+        let ecn = if self.ecn { packet.ecn() } else { 0 };
+
+        dscp | ecn
+    }
+}
+
+/// A WireGuard preshared key.
+#[derive(Clone)]
+pub struct PresharedKey(Secret<[u8; 32]>);
+
+impl PresharedKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(Secret::new(key))
+    }
+
+    pub fn expose_secret(&self) -> &[u8; 32] {
+        self.0.expose_secret()
+    }
+}
+
+impl PartialEq for PresharedKey {
+    fn eq(&self, other: &Self) -> bool {
+        // Compare in constant-time to not leak how much of the key matched.
+        self.expose_secret()
+            .as_slice()
+            .ct_eq(other.expose_secret().as_slice())
+            .into()
+    }
+}
+
+impl Eq for PresharedKey {}
+
+impl fmt::Debug for PresharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PresharedKey([REDACTED])")
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct Credentials {
     /// The ICE username (ufrag).
     pub username: String,
@@ -1175,25 +2487,65 @@ pub struct Credentials {
 }
 
 #[derive(Debug, PartialEq, Clone)]
-pub enum Event<TId> {
+pub enum Event<TId, RId> {
     /// We created a new candidate for this connection and ask to signal it to the remote party.
     ///
     /// Candidates are in SDP format although this may change and should be considered an implementation detail of the application.
     NewIceCandidate {
         connection: TId,
-        candidate: String,
+        candidate: String,
+    },
+
+    /// We invalidated a candidate for this connection and ask to signal that to the remote party.
+    ///
+    /// Candidates are in SDP format although this may change and should be considered an implementation detail of the application.
+    InvalidateIceCandidate {
+        connection: TId,
+        candidate: String,
+    },
+
+    /// We finished gathering candidates for this connection and ask to signal that to the remote party.
+    ///
+    /// The remote should pass this on to [`Node::end_of_remote_candidates`].
+    /// If we discover more candidates later (e.g. because a relay was added), they are followed by another [`Event::EndOfIceCandidates`].
+    EndOfIceCandidates {
+        connection: TId,
     },
 
-    /// We invalidated a candidate for this connection and ask to signal that to the remote party.
+    /// We restarted ICE for this connection and ask to signal our new credentials to the remote party.
     ///
-    /// Candidates are in SDP format although this may change and should be considered an implementation detail of the application.
-    InvalidateIceCandidate {
+    /// The credentials must be signalled before any of the candidates emitted afterwards.
+    NewIceCredentials {
         connection: TId,
-        candidate: String,
+        credentials: Credentials,
+    },
+
+    /// We rotated the preshared key for this connection and ask to signal it to the remote party.
+    NewPresharedKey {
+        connection: TId,
+        key: PresharedKey,
     },
 
     ConnectionEstablished(TId),
 
+    /// ICE nominated a different candidate pair for this connection, e.g. because we switched from a relayed to a direct path.
+    ConnectionPathChanged {
+        connection: TId,
+        from: CandidatePair<RId>,
+        to: CandidatePair<RId>,
+    },
+
+    /// We discovered a different path MTU for this connection.
+    ///
+    /// This is the size of the largest IP packet that can be sent through the tunnel to the remote.
+    PathMtuChanged {
+        connection: TId,
+        mtu: u16,
+    },
+
+    /// The NAT we are behind changed, for example because we roamed to a different network.
+    NatStatusChanged(NatStatus),
+
     /// We failed to establish a connection.
     ///
     /// All state associated with the connection has been cleared.
@@ -1216,6 +2568,14 @@ pub struct Transmit<'a> {
     pub dst: SocketAddr,
     /// The data that should be sent.
     pub payload: Cow<'a, [u8]>,
+    /// If set, `payload` contains several datagrams of this size that should be sent using UDP GSO.
+    ///
+    /// Only the last datagram may be shorter.
+    pub segment_size: Option<usize>,
+    /// The Type of Service (IPv4) or Traffic Class (IPv6) of the datagram, i.e. its DSCP and ECN bits.
+    ///
+    /// Only set for packets sent through a tunnel, see [`TrafficClassPropagation`].
+    pub tos: u8,
 }
 
 impl<'a> fmt::Debug for Transmit<'a> {
@@ -1224,16 +2584,26 @@ impl<'a> fmt::Debug for Transmit<'a> {
             .field("src", &self.src)
             .field("dst", &self.dst)
             .field("len", &self.payload.len())
+            .field("segment_size", &self.segment_size)
+            .field("tos", &self.tos)
             .finish()
     }
 }
 
 impl<'a> Transmit<'a> {
+    /// Returns the individual datagrams of this [`Transmit`].
+    pub fn datagrams(&self) -> impl Iterator<Item = &[u8]> {
+        self.payload
+            .chunks(self.segment_size.unwrap_or(self.payload.len()).max(1))
+    }
+
     pub fn into_owned(self) -> Transmit<'static> {
         Transmit {
             src: self.src,
             dst: self.dst,
             payload: Cow::Owned(self.payload.into_owned()),
+            segment_size: self.segment_size,
+            tos: self.tos,
         }
     }
 }
@@ -1247,6 +2617,7 @@ pub(crate) enum CandidateEvent {
 struct InitialConnection {
     agent: IceAgent,
     session_key: Secret<[u8; 32]>,
+    config: ConnectionConfig,
 
     created_at: Instant,
     intent_sent_at: Instant,
@@ -1286,21 +2657,53 @@ impl InitialConnection {
 
 struct Connection<RId> {
     agent: IceAgent,
+    /// The ICE credentials of the remote.
+    ///
+    /// `None` if we restarted ICE and are waiting for the remote's new credentials.
+    remote_credentials: Option<IceCreds>,
 
     tunnel: Tunn,
+    /// The index `tunnel` was created with.
+    tunnel_index: u32,
+    /// The index of the remote's tunnel that `tunnel` handshaked with.
+    remote_tunnel_index: Option<u32>,
+    /// The tunnel using the previous preshared key, together with its index.
+    ///
+    /// We keep using it until `tunnel` completes its first handshake.
+    previous_tunnel: Option<(u32, Tunn)>,
+    /// The index of the remote's tunnel that `previous_tunnel` handshaked with.
+    ///
+    /// Handshake initiations from that tunnel still use the previous preshared key.
+    previous_remote_tunnel_index: Option<u32>,
+    /// Limits the handshakes we accept from this peer, shared by `tunnel` and `previous_tunnel`.
+    rate_limiter: Arc<RateLimiter>,
+    /// When we will next rotate the preshared key.
+    ///
+    /// Only the side that initiated the connection rotates keys and only if [`ConnectionConfig::psk_rotation`] is set, otherwise this is `None`.
+    next_psk_rotation: Option<Instant>,
     remote_pub_key: PublicKey,
     next_timer_update: Instant,
 
     state: ConnectionState<RId>,
 
-    stats: ConnectionStats,
+    stats: ConnectionStats<RId>,
+    consent_checks: ConsentChecks,
+    path_mtu: PathMtuDiscovery,
+    config: ConnectionConfig,
     intent_sent_at: Instant,
     signalling_completed_at: Instant,
+    /// When we signalled to the remote that we finished gathering candidates.
+    local_end_of_candidates: Option<Instant>,
+    /// When the remote signalled that it finished gathering candidates.
+    remote_end_of_candidates: Option<Instant>,
 
     buffer: Box<[u8; MAX_UDP_SIZE]>,
 
     last_outgoing: Instant,
     last_incoming: Instant,
+    last_keepalive: Instant,
+    /// When we last drove the timers of the ICE agent, see [`ConnectionConfig::consent_check_interval`].
+    last_agent_timeout: Instant,
 }
 
 enum ConnectionState<RId> {
@@ -1386,7 +2789,30 @@ where
     }
 
     fn wg_handshake_complete(&self) -> bool {
-        self.tunnel.time_since_last_handshake().is_some()
+        self.wg_handshake_age().is_some()
+    }
+
+    fn wg_handshake_age(&self) -> Option<Duration> {
+        self.tunnel.time_since_last_handshake().or_else(|| {
+            self.previous_tunnel
+                .as_ref()
+                .and_then(|(_, t)| t.time_since_last_handshake())
+        })
+    }
+
+    /// Replaces our tunnel with one that uses a new preshared key.
+    fn rotate_tunnel(&mut self, index: u32, tunnel: Tunn) {
+        let previous = mem::replace(&mut self.tunnel, tunnel);
+        let previous_index = mem::replace(&mut self.tunnel_index, index);
+        let previous_remote_index = self.remote_tunnel_index.take();
+
+        // If the last rotation didn't complete yet, `previous` never had a session so we keep the one before it.
+        if self.previous_tunnel.is_some() && previous.time_since_last_handshake().is_none() {
+            return;
+        }
+
+        self.previous_tunnel = Some((previous_index, previous));
+        self.previous_remote_tunnel_index = previous_remote_index;
     }
 
     fn duration_since_intent(&self, now: Instant) -> Duration {
@@ -1395,13 +2821,20 @@ where
 
     #[must_use]
     fn poll_timeout(&mut self) -> Option<Instant> {
-        let agent_timeout = self.agent.poll_timeout();
+        let agent_timeout = self.agent_timeout();
         let next_wg_timer = Some(self.next_timer_update);
-        let candidate_timeout = self.candidate_timeout();
+        let candidate_timeout =
+            earliest(self.candidate_timeout(), self.end_of_candidates_timeout());
         let idle_timeout = self.idle_timeout();
 
         earliest(
-            Some(idle_timeout),
+            earliest(
+                Some(idle_timeout),
+                earliest(
+                    self.next_psk_rotation,
+                    earliest(self.path_mtu.poll_timeout(), self.keepalive_timeout()),
+                ),
+            ),
             earliest(agent_timeout, earliest(next_wg_timer, candidate_timeout)),
         )
     }
@@ -1414,10 +2847,66 @@ where
         Some(self.signalling_completed_at + CANDIDATE_TIMEOUT)
     }
 
+    /// A candidate after our end-of-candidates (e.g. from a newly added relay) starts a new generation of candidates.
+    /// We signal the end of it again once our allocations are done gathering.
+    fn on_new_local_candidate(&mut self) {
+        if self.local_end_of_candidates.take().is_some() {
+            tracing::debug!("Gathering new candidates after end-of-candidates");
+        }
+    }
+
+    /// When we give up on ICE because both sides finished gathering candidates and we still don't have a working pair.
+    fn end_of_candidates_timeout(&self) -> Option<Instant> {
+        if self.socket().is_some() {
+            return None;
+        }
+
+        let remote = self.remote_end_of_candidates?;
+
+        if self.agent.remote_candidates().is_empty() {
+            return Some(remote);
+        }
+
+        let local = self.local_end_of_candidates?;
+
+        Some(remote.max(local) + END_OF_CANDIDATES_TIMEOUT)
+    }
+
     fn idle_timeout(&self) -> Instant {
-        const MAX_IDLE: Duration = Duration::from_secs(5 * 60);
+        self.last_incoming.max(self.last_outgoing) + self.config.idle_timeout
+    }
 
-        self.last_incoming.max(self.last_outgoing) + MAX_IDLE
+    fn agent_timeout(&self) -> Option<Instant> {
+        let timeout = self.agent.poll_timeout()?;
+
+        match self.next_agent_timeout() {
+            Some(next) => Some(timeout.max(next)),
+            None => Some(timeout),
+        }
+    }
+
+    /// The earliest time we may drive the timers of the ICE agent again, if we throttle them.
+    ///
+    /// Once the agent completed, i.e. has nominated a pair and no checks left to perform, we throttle them to [`ConnectionConfig::consent_check_interval`].
+    /// We never do that whilst it is still checking, e.g. after an ICE restart or new candidates, to not delay the connectivity checks.
+    fn next_agent_timeout(&self) -> Option<Instant> {
+        let interval = self.config.consent_check_interval?;
+
+        if self.agent.state() != IceConnectionState::Completed {
+            return None;
+        }
+
+        Some(self.last_agent_timeout + interval)
+    }
+
+    fn keepalive_timeout(&self) -> Option<Instant> {
+        let keepalive = self.config.keepalive?;
+
+        if self.socket().is_none() || !self.wg_handshake_complete() {
+            return None;
+        }
+
+        Some(self.last_outgoing.max(self.last_keepalive) + keepalive)
     }
 
     #[tracing::instrument(level = "info", skip_all, fields(%cid))]
@@ -1427,11 +2916,16 @@ where
         now: Instant,
         allocations: &mut HashMap<RId, Allocation>,
         transmits: &mut VecDeque<Transmit<'static>>,
+        events: &mut VecDeque<Event<TId, RId>>,
     ) where
         TId: fmt::Display + Copy,
         RId: Copy + fmt::Display,
     {
-        self.agent.handle_timeout(now);
+        if self.next_agent_timeout().map_or(true, |next| now >= next) {
+            self.agent.handle_timeout(now);
+            self.last_agent_timeout = now;
+        }
+        self.consent_checks.handle_timeout(now);
 
         if self
             .candidate_timeout()
@@ -1442,6 +2936,15 @@ where
             return;
         }
 
+        if self
+            .end_of_candidates_timeout()
+            .is_some_and(|timeout| now >= timeout)
+        {
+            tracing::info!("Connection failed (no working candidate pair after end-of-candidates)");
+            self.state = ConnectionState::Failed;
+            return;
+        }
+
         if now >= self.idle_timeout() {
             tracing::info!("Connection is idle");
             self.state = ConnectionState::Idle;
@@ -1452,6 +2955,17 @@ where
         if now >= self.next_timer_update {
             self.next_timer_update = now + Duration::from_secs(1);
 
+            if self
+                .tunnel
+                .time_since_last_handshake()
+                .is_some_and(|age| age >= PREVIOUS_TUNNEL_GRACE_PERIOD)
+                && self.previous_tunnel.take().is_some()
+            {
+                self.previous_remote_tunnel_index = None;
+
+                tracing::debug!("Discarding tunnel of previous preshared key");
+            }
+
             // Don't update wireguard timers until we are connected.
             let Some(peer_socket) = self.socket() else {
                 return;
@@ -1464,6 +2978,28 @@ where
 
             let mut buf = [0u8; MAX_SCRATCH_SPACE];
 
+            // The previous tunnel may still need to re-key its session whilst the remote hasn't switched over yet.
+            if let Some((_, previous)) = self.previous_tunnel.as_mut() {
+                match previous.update_timers(&mut buf) {
+                    TunnResult::Done => {}
+                    TunnResult::Err(WireGuardError::ConnectionExpired) => {
+                        tracing::debug!("Tunnel of previous preshared key expired");
+
+                        self.previous_tunnel = None;
+                        self.previous_remote_tunnel_index = None;
+                    }
+                    TunnResult::Err(e) => {
+                        tracing::warn!(?e);
+                    }
+                    TunnResult::WriteToNetwork(b) => {
+                        transmits.extend(make_owned_transmit(peer_socket, b, allocations, now));
+                    }
+                    TunnResult::WriteToTunnelV4(..) | TunnResult::WriteToTunnelV6(..) => {
+                        panic!("Unexpected result from update_timers")
+                    }
+                };
+            }
+
             match self.tunnel.update_timers(&mut buf) {
                 TunnResult::Done => {}
                 TunnResult::Err(WireGuardError::ConnectionExpired) => {
@@ -1482,6 +3018,34 @@ where
             };
         }
 
+        if self.socket().is_some() && self.wg_handshake_complete() {
+            if let Some(size) = self.path_mtu.poll_probe(now) {
+                tracing::debug!(%size, "Probing path MTU");
+
+                self.send_through_tunnel(
+                    &pmtu::Message::Probe(size).to_bytes(),
+                    allocations,
+                    transmits,
+                    now,
+                );
+            }
+        }
+
+        if self
+            .keepalive_timeout()
+            .is_some_and(|timeout| now >= timeout)
+        {
+            self.last_keepalive = now;
+            self.send_through_tunnel(&[], allocations, transmits, now); // An empty packet is a keep-alive.
+        }
+
+        if let Some(mtu) = self.path_mtu.poll_change() {
+            events.push_back(Event::PathMtuChanged {
+                connection: cid,
+                mtu,
+            });
+        }
+
         while let Some(event) = self.agent.poll_event() {
             match event {
                 IceAgentEvent::DiscoveredRecv { source, .. } => {
@@ -1552,6 +3116,21 @@ where
 
                     tracing::info!(?old, new = ?remote_socket, duration_since_intent = ?self.duration_since_intent(now), "Updating remote socket");
 
+                    let new_pair = self.candidate_pair(source, destination, remote_socket);
+
+                    self.path_mtu
+                        .reset(max_mtu(remote_socket, &new_pair, allocations)); // A different path may have a different MTU.
+
+                    if let Some(from) = self.stats.nominated_pair.replace(new_pair) {
+                        if old.is_some() && from != new_pair {
+                            events.push_back(Event::ConnectionPathChanged {
+                                connection: cid,
+                                from,
+                                to: new_pair,
+                            });
+                        }
+                    }
+
                     self.force_handshake(allocations, transmits, now);
                 }
                 IceAgentEvent::IceRestart(_) | IceAgentEvent::IceConnectionStateChange(_) => {}
@@ -1563,6 +3142,14 @@ where
             let dst = transmit.destination;
             let packet = transmit.contents;
 
+            if let Some(request) = StunMessage::parse(&packet)
+                .ok()
+                .filter(|m| m.is_binding_request())
+            {
+                self.consent_checks
+                    .on_request_sent(request.trans_id(), source, dst, now);
+            }
+
             // Check if `str0m` wants us to send from a "remote" socket, i.e. one that we allocated with a relay.
             let allocation = allocations
                 .iter_mut()
@@ -1576,6 +3163,8 @@ where
                     src: Some(source),
                     dst,
                     payload: Cow::Owned(packet.into()),
+                    segment_size: None,
+                    tos: 0,
                 });
                 continue;
             };
@@ -1599,7 +3188,9 @@ where
         buffer: &'b mut [u8],
         now: Instant,
     ) -> Result<Option<&'b [u8]>, Error> {
-        let len = match self.tunnel.encapsulate(packet, buffer) {
+        let tunnel = sending_tunnel(&mut self.tunnel, &mut self.previous_tunnel);
+
+        let len = match tunnel.encapsulate(packet, buffer) {
             TunnResult::Done => return Ok(None),
             TunnResult::Err(e) => return Err(Error::Encapsulate(e)),
             TunnResult::WriteToNetwork(packet) => packet.len(),
@@ -1609,6 +3200,8 @@ where
         };
 
         self.last_outgoing = now;
+        self.stats.bytes_sent += packet.len();
+        self.stats.packets_sent += 1;
 
         Ok(Some(&buffer[..len]))
     }
@@ -1616,38 +3209,67 @@ where
     #[allow(clippy::too_many_arguments)]
     fn decapsulate<'b>(
         &mut self,
+        from: SocketAddr,
         packet: &[u8],
         buffer: &'b mut [u8],
+        source_rate_limiters: &mut SourceRateLimiters,
         allocations: &mut HashMap<RId, Allocation>,
         transmits: &mut VecDeque<Transmit<'static>>,
         now: Instant,
     ) -> ControlFlow<Result<(), Error>, MutableIpPacket<'b>> {
-        let control_flow = match self.tunnel.decapsulate(None, packet, &mut buffer[20..]) {
-            TunnResult::Done => ControlFlow::Break(Ok(())),
-            TunnResult::Err(e) => ControlFlow::Break(Err(Error::Decapsulate(e))),
+        match source_rate_limiters.verify(from.ip(), packet, self.buffer.as_mut(), now) {
+            Ok(()) => {}
+            Err(TunnResult::WriteToNetwork(cookie_reply)) => {
+                tracing::debug!(%from, "Source address exceeded handshake rate limit, replying with cookie");
+
+                self.stats.handshakes_throttled += 1;
+
+                let cookie_reply = cookie_reply.to_vec();
+                self.send_unencrypted(&cookie_reply, allocations, transmits, now);
+
+                return ControlFlow::Break(Ok(()));
+            }
+            Err(TunnResult::Err(e)) => return ControlFlow::Break(Err(Error::Decapsulate(e))),
+            Err(TunnResult::Done)
+            | Err(TunnResult::WriteToTunnelV4(..))
+            | Err(TunnResult::WriteToTunnelV6(..)) => return ControlFlow::Break(Ok(())),
+        }
+
+        let previous_remote_index = self.previous_remote_tunnel_index;
+        let (tunnel, remote_index) = match self.previous_tunnel.as_mut() {
+            Some((index, previous)) if is_addressed_to(packet, *index, previous_remote_index) => {
+                (previous, &mut self.previous_remote_tunnel_index)
+            }
+            Some(_) | None => (&mut self.tunnel, &mut self.remote_tunnel_index),
+        };
+
+        // Passing the source address binds the cookies of the rate limiter to it.
+        let result = tunnel.decapsulate(Some(from.ip()), packet, &mut buffer[20..]);
+
+        let handshake_accepted = match &result {
+            TunnResult::WriteToNetwork(bytes) => !matches!(
+                Tunn::parse_incoming_packet(bytes),
+                Ok(Packet::PacketCookieReply(_))
+            ),
+            TunnResult::Done => true,
+            TunnResult::Err(_)
+            | TunnResult::WriteToTunnelV4(..)
+            | TunnResult::WriteToTunnelV6(..) => false,
+        };
+        if let Some(index) = remote_tunnel_index(packet).filter(|_| handshake_accepted) {
+            *remote_index = Some(index);
+        }
+
+        let packet_len = match result {
+            TunnResult::Done => return ControlFlow::Break(Ok(())),
+            TunnResult::Err(e) => return ControlFlow::Break(Err(Error::Decapsulate(e))),
 
             // For WriteToTunnel{V4,V6}, boringtun returns the source IP of the packet that was tunneled to us.
             // I am guessing this was done for convenience reasons.
             // In our API, we parse the packets directly as an IpPacket.
             // Thus, the caller can query whatever data they'd like, not just the source IP so we don't return it in addition.
-            TunnResult::WriteToTunnelV4(packet, ip) => {
-                let packet_len = packet.len();
-                let ipv4_packet = ConvertibleIpv4Packet::new(&mut buffer[..(packet_len + 20)])
-                    .expect("boringtun verifies validity");
-                debug_assert_eq!(ipv4_packet.get_source(), ip);
-
-                ControlFlow::Continue(ipv4_packet.into())
-            }
-            TunnResult::WriteToTunnelV6(packet, ip) => {
-                // For ipv4 we need to use buffer to create the ip packet because we need the extra 20 bytes at the beginning
-                // for ipv6 we just need this to convince the borrow-checker that `packet`'s lifetime isn't `'b`, otherwise it's taken
-                // as `'b` for all branches.
-                let packet_len = packet.len();
-                let ipv6_packet = ConvertibleIpv6Packet::new(&mut buffer[20..(packet_len + 20)])
-                    .expect("boringtun verifies validity");
-                debug_assert_eq!(ipv6_packet.get_source(), ip);
-
-                ControlFlow::Continue(ipv6_packet.into())
+            TunnResult::WriteToTunnelV4(packet, _) | TunnResult::WriteToTunnelV6(packet, _) => {
+                packet.len()
             }
 
             // During normal operation, i.e. when the tunnel is active, decapsulating a packet straight yields the decrypted packet.
@@ -1655,6 +3277,15 @@ where
             // This should be fairly rare which is why we just allocate these and return them from `poll_transmit` instead.
             // Overall, this results in a much nicer API for our caller and should not affect performance.
             TunnResult::WriteToNetwork(bytes) => {
+                if matches!(
+                    Tunn::parse_incoming_packet(bytes),
+                    Ok(Packet::PacketCookieReply(_))
+                ) {
+                    tracing::debug!(%from, "Peer exceeded handshake rate limit, replying with cookie");
+
+                    self.stats.handshakes_throttled += 1;
+                }
+
                 match &mut self.state {
                     ConnectionState::Connecting { buffered, .. } => {
                         tracing::debug!("No socket has been nominated yet, buffering WG packet");
@@ -1662,7 +3293,7 @@ where
                         buffered.push(bytes.to_owned());
 
                         while let TunnResult::WriteToNetwork(packet) =
-                            self.tunnel.decapsulate(None, &[], self.buffer.as_mut())
+                            tunnel.decapsulate(None, &[], self.buffer.as_mut())
                         {
                             buffered.push(packet.to_owned());
                         }
@@ -1676,7 +3307,7 @@ where
                         ));
 
                         while let TunnResult::WriteToNetwork(packet) =
-                            self.tunnel.decapsulate(None, &[], self.buffer.as_mut())
+                            tunnel.decapsulate(None, &[], self.buffer.as_mut())
                         {
                             transmits.extend(make_owned_transmit(
                                 *peer_socket,
@@ -1689,15 +3320,96 @@ where
                     ConnectionState::Idle | ConnectionState::Failed => {}
                 }
 
-                ControlFlow::Break(Ok(()))
+                return ControlFlow::Break(Ok(()));
+            }
+        };
+
+        self.on_decrypted(buffer, packet_len, allocations, transmits, now)
+    }
+
+    /// Decrypts a WireGuard data packet into `buffer`, after 20 bytes of headroom.
+    ///
+    /// Unlike [`Connection::decapsulate`], this only touches the tunnels of this connection and can thus run on a crypto worker.
+    /// The result needs to be passed to [`Connection::on_decrypted`].
+    fn decrypt(
+        &mut self,
+        from: SocketAddr,
+        packet: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<Option<usize>, Error> {
+        let tunnel = match self.previous_tunnel.as_mut() {
+            Some((index, previous))
+                if is_addressed_to(packet, *index, self.previous_remote_tunnel_index) =>
+            {
+                previous
             }
+            Some(_) | None => &mut self.tunnel,
         };
 
-        if control_flow.is_continue() {
-            self.last_incoming = now;
+        // Data packets are never rate limited but like in `decapsulate`, we pass the source address so boringtun could.
+        match tunnel.decapsulate(Some(from.ip()), packet, &mut buffer[20..]) {
+            TunnResult::Done => Ok(None),
+            TunnResult::Err(e) => Err(Error::Decapsulate(e)),
+            TunnResult::WriteToTunnelV4(packet, _) | TunnResult::WriteToTunnelV6(packet, _) => {
+                Ok(Some(packet.len()))
+            }
+            TunnResult::WriteToNetwork(_) => {
+                tracing::warn!("Decrypting a data packet should never yield a network packet");
+
+                Ok(None)
+            }
         }
+    }
+
+    /// Handles a packet that was decrypted into `buffer`, after 20 bytes of headroom.
+    fn on_decrypted<'b>(
+        &mut self,
+        buffer: &'b mut [u8],
+        packet_len: usize,
+        allocations: &mut HashMap<RId, Allocation>,
+        transmits: &mut VecDeque<Transmit<'static>>,
+        now: Instant,
+    ) -> ControlFlow<Result<(), Error>, MutableIpPacket<'b>>
+    where
+        RId: Copy,
+    {
+        let packet: MutableIpPacket<'b> = match buffer[20] >> 4 {
+            // For ipv4 we need to use buffer to create the ip packet because we need the extra 20 bytes at the beginning.
+            4 => ConvertibleIpv4Packet::new(&mut buffer[..(packet_len + 20)])
+                .expect("boringtun verifies validity")
+                .into(),
+            6 => {
+                match pmtu::Message::parse(&buffer[20..(packet_len + 20)]) {
+                    Some(pmtu::Message::Probe(size)) => {
+                        self.send_through_tunnel(
+                            &pmtu::Message::Ack(size).to_bytes(),
+                            allocations,
+                            transmits,
+                            now,
+                        );
+
+                        return ControlFlow::Break(Ok(()));
+                    }
+                    Some(pmtu::Message::Ack(size)) => {
+                        self.path_mtu.on_ack(size);
+
+                        return ControlFlow::Break(Ok(()));
+                    }
+                    None => {}
+                }
+
+                ConvertibleIpv6Packet::new(&mut buffer[20..(packet_len + 20)])
+                    .expect("boringtun verifies validity")
+                    .into()
+            }
+            version => unreachable!("boringtun only decrypts IP packets, got version {version}"),
+        };
+
+        self.last_incoming = now;
+        self.stats.bytes_received += packet.packet().len();
+        self.stats.packets_received += 1;
 
-        control_flow
+        ControlFlow::Continue(packet)
     }
 
     fn force_handshake(
@@ -1728,6 +3440,173 @@ where
         transmits.extend(make_owned_transmit(socket, bytes, allocations, now));
     }
 
+    /// Sends a WireGuard message that doesn't need to be encrypted to the remote, e.g. a cookie reply.
+    ///
+    /// Like the responses of our tunnels, we buffer it until a socket is nominated.
+    fn send_unencrypted(
+        &mut self,
+        message: &[u8],
+        allocations: &mut HashMap<RId, Allocation>,
+        transmits: &mut VecDeque<Transmit<'static>>,
+        now: Instant,
+    ) where
+        RId: Copy,
+    {
+        match &mut self.state {
+            ConnectionState::Connecting { buffered, .. } => buffered.push(message.to_owned()),
+            ConnectionState::Connected { peer_socket, .. } => {
+                transmits.extend(make_owned_transmit(*peer_socket, message, allocations, now));
+            }
+            ConnectionState::Idle | ConnectionState::Failed => {}
+        }
+    }
+
+    /// Sends a packet generated by us through the tunnel, e.g. a path MTU probe or a keep-alive.
+    ///
+    /// These don't count towards our stats as they are not traffic of the application.
+    fn send_through_tunnel(
+        &mut self,
+        packet: &[u8],
+        allocations: &mut HashMap<RId, Allocation>,
+        transmits: &mut VecDeque<Transmit<'static>>,
+        now: Instant,
+    ) where
+        RId: Copy,
+    {
+        let Some(socket) = self.socket() else {
+            return;
+        };
+
+        let tunnel = sending_tunnel(&mut self.tunnel, &mut self.previous_tunnel);
+
+        let TunnResult::WriteToNetwork(bytes) = tunnel.encapsulate(packet, self.buffer.as_mut())
+        else {
+            return;
+        };
+
+        transmits.extend(make_owned_transmit(socket, bytes, allocations, now));
+    }
+
+    fn stats(&self) -> ConnectionStats<RId> {
+        ConnectionStats {
+            ice_rtt: self.consent_checks.rtt(),
+            wg_handshake_age: self.wg_handshake_age(),
+            path_mtu: Some(self.path_mtu.mtu()),
+            packet_loss: self.consent_checks.packet_loss(),
+            ..self.stats
+        }
+    }
+
+    fn snapshot<TId>(&self, id: TId, now: Instant) -> ConnectionSnapshot<TId, RId> {
+        let state = match self.state {
+            ConnectionState::Connecting { .. } => snapshot::ConnectionState::Connecting,
+            ConnectionState::Connected { .. } => snapshot::ConnectionState::Connected,
+            ConnectionState::Failed => snapshot::ConnectionState::Failed,
+            ConnectionState::Idle => snapshot::ConnectionState::Idle,
+        };
+        let nominated = self.stats.nominated_pair.map(|p| (p.local, p.remote));
+
+        let candidate_pairs = self
+            .consent_checks
+            .pairs()
+            .map(|((local, remote), checks)| {
+                let state = if checks.num_answered > 0 {
+                    CandidatePairState::Succeeded
+                } else if self.consent_checks.num_in_flight((local, remote)) > 0 {
+                    CandidatePairState::InProgress
+                } else {
+                    CandidatePairState::Failed
+                };
+
+                CandidatePairSnapshot {
+                    local,
+                    remote,
+                    state,
+                    checks_sent: checks.num_sent,
+                    checks_answered: checks.num_answered,
+                    time_since_last_answer: checks
+                        .last_answered
+                        .map(|at| now.saturating_duration_since(at)),
+                    nominated: nominated == Some((local, remote)),
+                }
+            })
+            .collect();
+
+        ConnectionSnapshot {
+            id,
+            state,
+            ice: IceSnapshot {
+                state: self.agent.state().into(),
+                local_candidates: self
+                    .agent
+                    .local_candidates()
+                    .iter()
+                    .map(|c| c.to_sdp_string())
+                    .collect(),
+                remote_candidates: self
+                    .agent
+                    .remote_candidates()
+                    .iter()
+                    .map(|c| c.to_sdp_string())
+                    .collect(),
+                candidate_pairs,
+                nominated_pair: self.stats.nominated_pair,
+                local_end_of_candidates: self.local_end_of_candidates.is_some(),
+                remote_end_of_candidates: self.remote_end_of_candidates.is_some(),
+            },
+            wireguard: WireGuardSnapshot {
+                session_index: self.tunnel_index,
+                time_since_last_handshake: self.wg_handshake_age(),
+                has_previous_session: self.previous_tunnel.is_some(),
+                time_until_psk_rotation: self
+                    .next_psk_rotation
+                    .map(|at| at.saturating_duration_since(now)),
+                time_until_timer_update: self.next_timer_update.saturating_duration_since(now),
+                time_since_last_incoming: now.saturating_duration_since(self.last_incoming),
+                time_since_last_outgoing: now.saturating_duration_since(self.last_outgoing),
+                time_since_last_keepalive: now.saturating_duration_since(self.last_keepalive),
+            },
+        }
+    }
+
+    fn candidate_pair(
+        &self,
+        source: SocketAddr,
+        destination: SocketAddr,
+        peer_socket: PeerSocket<RId>,
+    ) -> CandidatePair<RId> {
+        let remote_type = self
+            .agent
+            .remote_candidates()
+            .iter()
+            .find(|c| c.addr() == destination)
+            .map(|c| CandidateType::from(c.kind()))
+            .unwrap_or(CandidateType::PeerReflexive); // Remotes we only learn about through STUN traffic are peer-reflexive.
+
+        let (local_type, relay) = match peer_socket {
+            PeerSocket::Direct { .. } => {
+                let local_type = self
+                    .agent
+                    .local_candidates()
+                    .iter()
+                    .find(|c| c.addr() == source)
+                    .map(|c| CandidateType::from(c.kind()))
+                    .unwrap_or(CandidateType::Host);
+
+                (local_type, None)
+            }
+            PeerSocket::Relay { relay, .. } => (CandidateType::Relayed, Some(relay)),
+        };
+
+        CandidatePair {
+            local: source,
+            local_type,
+            remote: destination,
+            remote_type,
+            relay,
+        }
+    }
+
     fn socket(&self) -> Option<PeerSocket<RId>> {
         match self.state {
             ConnectionState::Connected { peer_socket, .. } => Some(peer_socket),
@@ -1746,6 +3625,34 @@ where
     }
 }
 
+/// The largest MTU the given path supports, depending on the address family and whether packets are relayed on each hop.
+fn max_mtu<RId>(
+    peer_socket: PeerSocket<RId>,
+    pair: &CandidatePair<RId>,
+    allocations: &HashMap<RId, Allocation>,
+) -> u16
+where
+    RId: Eq + Hash,
+{
+    // If the remote is on a relay candidate, its relay forwards our packets in channel-data messages.
+    let last_hop = pmtu::max_mtu(
+        pair.remote.is_ipv6(),
+        pair.remote_type == CandidateType::Relayed,
+    );
+
+    match peer_socket {
+        PeerSocket::Direct { .. } => last_hop,
+        PeerSocket::Relay { relay, .. } => {
+            let relay_is_ipv6 = allocations
+                .get(&relay)
+                .and_then(|a| a.active_socket())
+                .map_or(pair.local.is_ipv6(), |s| s.is_ipv6());
+
+            last_hop.min(pmtu::max_mtu(relay_is_ipv6, true))
+        }
+    }
+}
+
 #[must_use]
 fn make_owned_transmit<RId>(
     socket: PeerSocket<RId>,
@@ -1764,6 +3671,8 @@ where
             src: Some(source),
             dst: remote,
             payload: Cow::Owned(message.into()),
+            segment_size: None,
+            tos: 0,
         },
         PeerSocket::Relay { relay, dest: peer } => {
             encode_as_channel_data(relay, peer, message, allocations, now).ok()?
@@ -1772,3 +3681,141 @@ where
 
     Some(transmit)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    #[test]
+    fn relays_switch_to_synthesized_socket_when_nat64_prefix_is_discovered_late() {
+        let mut node =
+            ClientNode::<u64, u64>::new(StaticSecret::random_from_rng(rand::thread_rng()));
+        let relay = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 3478);
+
+        node.update_relays(
+            HashSet::default(),
+            &HashSet::from([(
+                1,
+                RelaySocket::V4(relay),
+                "user".to_owned(),
+                "pass".to_owned(),
+                "firezone".to_owned(),
+            )]),
+            Instant::now(),
+        );
+        node.set_nat64_prefix(Some(Nat64Prefix::WELL_KNOWN));
+
+        assert_eq!(
+            node.allocations[&1].server(),
+            RelaySocket::Dual {
+                v4: relay,
+                v6: Nat64Prefix::WELL_KNOWN.synthesize_socket(relay),
+            }
+        );
+
+        node.set_nat64_prefix(None);
+
+        assert_eq!(node.allocations[&1].server(), RelaySocket::V4(relay));
+    }
+
+    #[test]
+    fn nearer_relay_only_replaces_preferred_relay_if_sufficiently_faster() {
+        let preferred = HashSet::from([1]);
+
+        let ranked = rank_relays(
+            [
+                (1, Duration::from_millis(50)),
+                (2, Duration::from_millis(45)),
+            ]
+            .into_iter(),
+            &preferred,
+            1,
+        );
+        assert_eq!(ranked, HashSet::from([1]));
+
+        let ranked = rank_relays(
+            [
+                (1, Duration::from_millis(50)),
+                (2, Duration::from_millis(30)),
+            ]
+            .into_iter(),
+            &preferred,
+            1,
+        );
+        assert_eq!(ranked, HashSet::from([2]));
+    }
+
+    #[test]
+    fn both_sides_can_rekey_previous_session_mid_rotation() {
+        let alice_key = StaticSecret::random_from_rng(rand::thread_rng());
+        let bob_key = StaticSecret::random_from_rng(rand::thread_rng());
+        let old_psk = random::<[u8; 32]>();
+        let new_psk = random::<[u8; 32]>();
+
+        let mut alice_old = test_tunnel(&alice_key, &bob_key, old_psk, 1);
+        let mut bob_old = test_tunnel(&bob_key, &alice_key, old_psk, 2);
+
+        let init = handshake_initiation(&mut alice_old);
+        complete_handshake(&init, &mut alice_old, &mut bob_old);
+
+        // Alice rotated her key but Bob didn't receive it yet.
+        // Bob re-keys under the old key so Alice must answer with her previous tunnel.
+        let mut alice_new = test_tunnel(&alice_key, &bob_key, new_psk, 3);
+        let init = handshake_initiation(&mut bob_old);
+
+        assert_eq!(remote_tunnel_index(&init), Some(2));
+        assert!(is_addressed_to(&init, 1, Some(2)));
+        complete_handshake(&init, &mut bob_old, &mut alice_old);
+
+        // Bob received the new key and handshakes with it so Alice must answer with her new tunnel.
+        let mut bob_new = test_tunnel(&bob_key, &alice_key, new_psk, 4);
+        let init = handshake_initiation(&mut bob_new);
+
+        assert!(!is_addressed_to(&init, 1, Some(2)));
+        complete_handshake(&init, &mut bob_new, &mut alice_new);
+
+        // Alice re-keys her previous session before discarding it so Bob must answer with his previous tunnel.
+        let init = handshake_initiation(&mut alice_old);
+
+        assert!(is_addressed_to(&init, 2, Some(1)));
+        complete_handshake(&init, &mut alice_old, &mut bob_old);
+    }
+
+    fn test_tunnel(local: &StaticSecret, remote: &StaticSecret, psk: [u8; 32], index: u32) -> Tunn {
+        Tunn::new(local.clone(), remote.into(), Some(psk), None, index, None)
+    }
+
+    fn handshake_initiation(initiator: &mut Tunn) -> Vec<u8> {
+        let mut buf = [0u8; 148];
+
+        match initiator.format_handshake_initiation(&mut buf, true) {
+            TunnResult::WriteToNetwork(init) => init.to_vec(),
+            TunnResult::Done
+            | TunnResult::Err(_)
+            | TunnResult::WriteToTunnelV4(..)
+            | TunnResult::WriteToTunnelV6(..) => panic!("Expected handshake initiation"),
+        }
+    }
+
+    fn complete_handshake(init: &[u8], initiator: &mut Tunn, responder: &mut Tunn) {
+        let mut buf = vec![0u8; MAX_UDP_SIZE];
+
+        let response = match responder.decapsulate(None, init, &mut buf) {
+            TunnResult::WriteToNetwork(response) => response.to_vec(),
+            TunnResult::Done
+            | TunnResult::Err(_)
+            | TunnResult::WriteToTunnelV4(..)
+            | TunnResult::WriteToTunnelV6(..) => panic!("Expected handshake response"),
+        };
+
+        assert!(
+            !matches!(
+                initiator.decapsulate(None, &response, &mut buf),
+                TunnResult::Err(_)
+            ),
+            "Responder should use the same preshared key"
+        );
+        assert!(initiator.time_since_last_handshake().is_some());
+    }
+}
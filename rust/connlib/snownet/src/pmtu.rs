@@ -0,0 +1,339 @@
+//! Path MTU discovery for connections, loosely following DPLPMTUD (RFC 8899).
+//!
+//! We probe a path by sending padded packets through the WireGuard tunnel and wait for the remote to acknowledge them.
+//! Probes are IPv6 packets without any payload protocol (next header 59) from and to the unspecified address.
+//! They are consumed by `snownet` on the receiving side and never surface as regular traffic.
+//!
+//! The MTU we discover is the size of the largest IP packet that can be sent through the tunnel, i.e. what the TUN device should use.
+//! How large it can get depends on the address family and relaying of each hop of the path, see [`max_mtu`].
+//! This relies on the operating system not fragmenting our UDP packets, otherwise oversized probes may still succeed.
+
+use std::time::{Duration, Instant};
+
+/// The smallest MTU we will ever use: the minimum MTU of IPv6.
+pub const MIN_MTU: u16 = 1280;
+/// The largest MTU of any path: an Ethernet frame minus the IPv4, UDP and WireGuard headers.
+pub const MAX_MTU: u16 = max_mtu(false, false);
+
+/// We assume that the links along the path are Ethernet.
+const LINK_MTU: u16 = 1500;
+const IPV4_OVERHEAD: u16 = 20;
+const IPV6_OVERHEAD: u16 = 40;
+const UDP_OVERHEAD: u16 = 8;
+/// The header and the authentication tag of a WireGuard data message.
+pub(crate) const WIREGUARD_OVERHEAD: u16 = 16 + 16;
+/// The header of a TURN channel-data message, see <https://www.rfc-editor.org/rfc/rfc8656#section-12.4>.
+const CHANNEL_DATA_OVERHEAD: u16 = 4;
+
+/// How long we wait for the remote to acknowledge a probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// How many times we send a probe of a particular size before we consider it too big.
+const MAX_PROBES: u8 = 3;
+/// After converging, how long we wait before we search for a larger MTU again.
+const RAISE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// We stop searching once the MTU is known with this precision.
+const SEARCH_GRANULARITY: u16 = 8;
+
+const IPV6_HEADER_LEN: usize = IPV6_OVERHEAD as usize;
+const NO_NEXT_HEADER: u8 = 59;
+const MAGIC: &[u8; 8] = b"snowpmtu";
+const KIND_PROBE: u8 = 1;
+const KIND_ACK: u8 = 2;
+const MESSAGE_LEN: usize = IPV6_HEADER_LEN + MAGIC.len() + 1 + 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Message {
+    /// A probe of the given size that the remote should acknowledge.
+    Probe(u16),
+    /// The remote received our probe of the given size.
+    Ack(u16),
+}
+
+impl Message {
+    pub(crate) fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() < MESSAGE_LEN
+            || packet[0] >> 4 != 6
+            || packet[6] != NO_NEXT_HEADER
+            || packet[8..IPV6_HEADER_LEN].iter().any(|b| *b != 0)
+        {
+            return None;
+        }
+
+        let payload = &packet[IPV6_HEADER_LEN..];
+
+        if &payload[..MAGIC.len()] != MAGIC {
+            return None;
+        }
+
+        let kind = payload[MAGIC.len()];
+        let size = u16::from_be_bytes([payload[MAGIC.len() + 1], payload[MAGIC.len() + 2]]);
+
+        match kind {
+            KIND_PROBE => Some(Self::Probe(size)),
+            KIND_ACK => Some(Self::Ack(size)),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn to_bytes(self) -> Vec<u8> {
+        let (kind, size, len) = match self {
+            Message::Probe(size) => (KIND_PROBE, size, usize::from(size).max(MESSAGE_LEN)),
+            Message::Ack(size) => (KIND_ACK, size, MESSAGE_LEN),
+        };
+
+        let mut packet = vec![0u8; len];
+
+        let payload_len =
+            u16::try_from(len - IPV6_HEADER_LEN).expect("probes are smaller than 64k");
+
+        packet[0] = 6 << 4;
+        packet[4..6].copy_from_slice(&payload_len.to_be_bytes());
+        packet[6] = NO_NEXT_HEADER;
+        // Source and destination address as well as the hop limit stay 0 so this packet can never be routed anywhere.
+
+        let payload = &mut packet[IPV6_HEADER_LEN..];
+        payload[..MAGIC.len()].copy_from_slice(MAGIC);
+        payload[MAGIC.len()] = kind;
+        payload[MAGIC.len() + 1..MAGIC.len() + 3].copy_from_slice(&size.to_be_bytes());
+
+        packet
+    }
+}
+
+/// The largest MTU we can probe for on a single hop of a path.
+///
+/// `ipv6` is the address family of the UDP datagrams on that hop, `channel_data` whether they are wrapped in a TURN channel-data message.
+pub(crate) const fn max_mtu(ipv6: bool, channel_data: bool) -> u16 {
+    let ip_overhead = if ipv6 { IPV6_OVERHEAD } else { IPV4_OVERHEAD };
+    let channel_data_overhead = if channel_data {
+        CHANNEL_DATA_OVERHEAD
+    } else {
+        0
+    };
+
+    LINK_MTU - ip_overhead - UDP_OVERHEAD - channel_data_overhead - WIREGUARD_OVERHEAD
+}
+
+/// Searches for the largest packet size the path to a remote supports.
+pub(crate) struct PathMtuDiscovery {
+    /// The largest size we probe for on the current path.
+    max: u16,
+    /// The largest size the remote acknowledged.
+    confirmed: u16,
+    /// The smallest size we know to be too big.
+    too_big: u16,
+    in_flight: Option<Probe>,
+    /// When to search for a larger MTU again, once we converged.
+    raise_at: Option<Instant>,
+    /// The MTU we last reported via [`PathMtuDiscovery::poll_change`].
+    reported: u16,
+}
+
+struct Probe {
+    size: u16,
+    sent_at: Instant,
+    attempts: u8,
+}
+
+impl Default for PathMtuDiscovery {
+    fn default() -> Self {
+        Self {
+            max: MAX_MTU,
+            confirmed: MIN_MTU,
+            too_big: MAX_MTU + 1,
+            in_flight: None,
+            raise_at: None,
+            reported: MIN_MTU,
+        }
+    }
+}
+
+impl PathMtuDiscovery {
+    pub(crate) fn mtu(&self) -> u16 {
+        self.confirmed
+    }
+
+    /// Returns the size of the next probe we should send, if any.
+    pub(crate) fn poll_probe(&mut self, now: Instant) -> Option<u16> {
+        if let Some(probe) = self.in_flight.as_mut() {
+            if now < probe.sent_at + PROBE_TIMEOUT {
+                return None;
+            }
+
+            if probe.attempts < MAX_PROBES {
+                probe.attempts += 1;
+                probe.sent_at = now;
+
+                return Some(probe.size);
+            }
+
+            tracing::debug!(size = %probe.size, "Path MTU probe was not acknowledged");
+
+            self.too_big = probe.size;
+            self.in_flight = None;
+        }
+
+        if self.too_big - self.confirmed <= SEARCH_GRANULARITY {
+            let raise_at = *self.raise_at.get_or_insert(now + RAISE_INTERVAL);
+
+            if now < raise_at {
+                return None;
+            }
+
+            self.raise_at = None;
+            self.too_big = self.max + 1;
+        }
+
+        // Most paths support the maximum so we try that first before we start a binary search.
+        let size = if self.too_big > self.max {
+            self.max
+        } else {
+            self.confirmed + (self.too_big - self.confirmed) / 2
+        };
+
+        if size <= self.confirmed {
+            return None;
+        }
+
+        self.in_flight = Some(Probe {
+            size,
+            sent_at: now,
+            attempts: 1,
+        });
+
+        Some(size)
+    }
+
+    pub(crate) fn on_ack(&mut self, size: u16) {
+        if self.in_flight.as_ref().is_some_and(|p| p.size == size) {
+            self.in_flight = None;
+        }
+
+        if size > self.confirmed && size < self.too_big {
+            self.confirmed = size;
+        }
+    }
+
+    /// Restarts the search because the connection switched to a path that supports at most `max`, see [`max_mtu`].
+    pub(crate) fn reset(&mut self, max: u16) {
+        *self = Self {
+            max,
+            too_big: max + 1,
+            reported: self.reported,
+            ..Self::default()
+        };
+    }
+
+    /// Returns the new MTU if it changed since the last call.
+    pub(crate) fn poll_change(&mut self) -> Option<u16> {
+        if self.confirmed == self.reported {
+            return None;
+        }
+
+        self.reported = self.confirmed;
+
+        Some(self.confirmed)
+    }
+
+    pub(crate) fn poll_timeout(&self) -> Option<Instant> {
+        self.in_flight
+            .as_ref()
+            .map(|p| p.sent_at + PROBE_TIMEOUT)
+            .or(self.raise_at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_roundtrip() {
+        let probe = Message::Probe(1400).to_bytes();
+        let ack = Message::Ack(1400).to_bytes();
+
+        assert_eq!(probe.len(), 1400);
+        assert_eq!(Message::parse(&probe), Some(Message::Probe(1400)));
+        assert_eq!(Message::parse(&ack), Some(Message::Ack(1400)));
+    }
+
+    #[test]
+    fn regular_ipv6_packets_are_not_messages() {
+        let mut packet = Message::Probe(1400).to_bytes();
+        packet[24] = 1; // Set a destination address.
+
+        assert_eq!(Message::parse(&packet), None);
+    }
+
+    #[test]
+    fn probes_maximum_first() {
+        let mut pmtu = PathMtuDiscovery::default();
+        let now = Instant::now();
+
+        let size = pmtu.poll_probe(now).unwrap();
+        pmtu.on_ack(size);
+
+        assert_eq!(size, MAX_MTU);
+        assert_eq!(pmtu.mtu(), MAX_MTU);
+        assert_eq!(pmtu.poll_change(), Some(MAX_MTU));
+        assert_eq!(pmtu.poll_probe(now), None);
+    }
+
+    #[test]
+    fn converges_on_path_limit() {
+        const PATH_LIMIT: u16 = 1380;
+
+        let mut pmtu = PathMtuDiscovery::default();
+        let mut now = Instant::now();
+
+        for _ in 0..100 {
+            if let Some(size) = pmtu.poll_probe(now) {
+                if size <= PATH_LIMIT {
+                    pmtu.on_ack(size);
+                }
+            }
+
+            now += Duration::from_secs(1);
+        }
+
+        assert!(pmtu.mtu() <= PATH_LIMIT);
+        assert!(pmtu.mtu() > PATH_LIMIT - SEARCH_GRANULARITY);
+    }
+
+    #[test]
+    fn reset_falls_back_to_minimum() {
+        let mut pmtu = PathMtuDiscovery::default();
+        let now = Instant::now();
+
+        let size = pmtu.poll_probe(now).unwrap();
+        pmtu.on_ack(size);
+        pmtu.poll_change();
+
+        pmtu.reset(MAX_MTU);
+
+        assert_eq!(pmtu.mtu(), MIN_MTU);
+        assert_eq!(pmtu.poll_change(), Some(MIN_MTU));
+    }
+
+    #[test]
+    fn overhead_depends_on_address_family_and_relaying() {
+        assert_eq!(max_mtu(false, false), 1440);
+        assert_eq!(max_mtu(true, false), 1420);
+        assert_eq!(max_mtu(false, true), 1436);
+        assert_eq!(max_mtu(true, true), 1416);
+    }
+
+    #[test]
+    fn probes_maximum_of_path_after_reset() {
+        let mut pmtu = PathMtuDiscovery::default();
+        let now = Instant::now();
+
+        pmtu.reset(max_mtu(true, true));
+
+        let size = pmtu.poll_probe(now).unwrap();
+        pmtu.on_ack(size);
+
+        assert_eq!(size, 1416);
+        assert_eq!(pmtu.mtu(), 1416);
+    }
+}
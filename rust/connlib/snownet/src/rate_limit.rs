@@ -0,0 +1,224 @@
+//! Rate limiting of WireGuard handshakes.
+//!
+//! Every connection limits the handshakes of its peer, see [`HANDSHAKE_RATE_LIMIT_PER_PEER`].
+//! On top of that, [`SourceRateLimiters`] limits the handshakes arriving from a single IP address, regardless of which peer they claim to be from.
+
+use boringtun::noise::{rate_limiter::RateLimiter, Packet, Tunn, TunnResult};
+use boringtun::x25519::PublicKey;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// How many handshakes per second we accept from a single peer before we demand a cookie.
+///
+/// A legitimate peer handshakes every two minutes, plus once per ICE restart or preshared key rotation.
+/// More than a few handshakes per second mean the peer misbehaves or someone replays its handshakes.
+pub(crate) const HANDSHAKE_RATE_LIMIT_PER_PEER: u64 = 10;
+
+/// How many handshakes per second we accept from a single IP address before we demand a cookie.
+///
+/// Many peers may share an address when they are behind the same NAT, hence this is higher than [`HANDSHAKE_RATE_LIMIT_PER_PEER`].
+/// This is boringtun's default for its global rate limiter.
+pub(crate) const HANDSHAKE_RATE_LIMIT_PER_SOURCE: u64 = 100;
+
+/// How long we keep the rate limiter of an address without any handshakes from it.
+///
+/// Cookies are only valid for two minutes, there is no point in keeping a rate limiter around for longer.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// A rate limiter per source address of WireGuard handshakes.
+pub(crate) struct SourceRateLimiters {
+    public_key: PublicKey,
+    limiters: HashMap<IpAddr, SourceRateLimiter>,
+}
+
+struct SourceRateLimiter {
+    inner: RateLimiter,
+    last_handshake: Instant,
+}
+
+impl SourceRateLimiters {
+    pub(crate) fn new(public_key: PublicKey) -> Self {
+        Self {
+            public_key,
+            limiters: HashMap::default(),
+        }
+    }
+
+    /// Counts a handshake from `from` towards its rate limit.
+    ///
+    /// Packets other than handshakes always pass.
+    /// If `from` exceeded its rate limit and the handshake doesn't carry a valid cookie, this returns a cookie reply in `buffer`.
+    pub(crate) fn verify<'b>(
+        &mut self,
+        from: IpAddr,
+        packet: &[u8],
+        buffer: &'b mut [u8],
+        now: Instant,
+    ) -> Result<(), TunnResult<'b>> {
+        if !matches!(
+            Tunn::parse_incoming_packet(packet),
+            Ok(Packet::HandshakeInit(_) | Packet::HandshakeResponse(_))
+        ) {
+            return Ok(());
+        }
+
+        let limiter = self
+            .limiters
+            .entry(from)
+            .or_insert_with(|| SourceRateLimiter {
+                inner: RateLimiter::new(&self.public_key, HANDSHAKE_RATE_LIMIT_PER_SOURCE),
+                last_handshake: now,
+            });
+        limiter.last_handshake = now;
+
+        limiter.inner.verify_packet(Some(from), packet, buffer)?;
+
+        Ok(())
+    }
+
+    /// Resets the handshake count of all addresses, must be called every second.
+    pub(crate) fn reset_count(&mut self, now: Instant) {
+        self.limiters
+            .retain(|_, l| now.duration_since(l.last_handshake) < IDLE_TIMEOUT);
+
+        for limiter in self.limiters.values() {
+            limiter.inner.reset_count();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use boringtun::x25519::StaticSecret;
+
+    #[test]
+    fn handshakes_beyond_limit_of_source_get_cookie_reply() {
+        let (mut limiters, responder) = limiters();
+        let now = Instant::now();
+        let mut buffer = [0u8; 148];
+
+        for _ in 0..HANDSHAKE_RATE_LIMIT_PER_SOURCE {
+            let init = handshake_initiation(&responder);
+
+            assert!(limiters
+                .verify(ip("1.1.1.1"), &init, &mut buffer, now)
+                .is_ok());
+        }
+
+        let init = handshake_initiation(&responder);
+        let result = limiters.verify(ip("1.1.1.1"), &init, &mut buffer, now);
+
+        assert!(is_cookie_reply(result));
+    }
+
+    #[test]
+    fn other_sources_are_not_affected() {
+        let (mut limiters, responder) = limiters();
+        let now = Instant::now();
+        let mut buffer = [0u8; 148];
+
+        for _ in 0..=HANDSHAKE_RATE_LIMIT_PER_SOURCE {
+            let init = handshake_initiation(&responder);
+            let _ = limiters.verify(ip("1.1.1.1"), &init, &mut buffer, now);
+        }
+
+        let init = handshake_initiation(&responder);
+
+        assert!(limiters
+            .verify(ip("2.2.2.2"), &init, &mut buffer, now)
+            .is_ok());
+    }
+
+    #[test]
+    fn limit_applies_again_after_reset() {
+        let (mut limiters, responder) = limiters();
+        let mut now = Instant::now();
+        let mut buffer = [0u8; 148];
+
+        for _ in 0..=HANDSHAKE_RATE_LIMIT_PER_SOURCE {
+            let init = handshake_initiation(&responder);
+            let _ = limiters.verify(ip("1.1.1.1"), &init, &mut buffer, now);
+        }
+
+        now += Duration::from_secs(1);
+        limiters.reset_count(now);
+
+        let init = handshake_initiation(&responder);
+
+        assert!(limiters
+            .verify(ip("1.1.1.1"), &init, &mut buffer, now)
+            .is_ok());
+    }
+
+    #[test]
+    fn idle_sources_are_forgotten() {
+        let (mut limiters, responder) = limiters();
+        let mut now = Instant::now();
+        let mut buffer = [0u8; 148];
+
+        let init = handshake_initiation(&responder);
+        let _ = limiters.verify(ip("1.1.1.1"), &init, &mut buffer, now);
+
+        now += IDLE_TIMEOUT;
+        limiters.reset_count(now);
+
+        assert!(limiters.limiters.is_empty());
+    }
+
+    #[test]
+    fn data_packets_are_not_counted() {
+        let (mut limiters, _) = limiters();
+        let mut buffer = [0u8; 148];
+
+        let mut data = [0u8; 32];
+        data[0] = 4; // Message type of a WireGuard data packet.
+
+        assert!(limiters
+            .verify(ip("1.1.1.1"), &data, &mut buffer, Instant::now())
+            .is_ok());
+        assert!(limiters.limiters.is_empty());
+    }
+
+    fn limiters() -> (SourceRateLimiters, StaticSecret) {
+        let responder = StaticSecret::random_from_rng(rand::thread_rng());
+
+        (SourceRateLimiters::new((&responder).into()), responder)
+    }
+
+    /// Creates a handshake initiation towards `responder` from a new peer.
+    fn handshake_initiation(responder: &StaticSecret) -> Vec<u8> {
+        let initiator = StaticSecret::random_from_rng(rand::thread_rng());
+        let mut tunnel = Tunn::new(initiator, responder.into(), None, None, 0, None);
+        let mut buf = [0u8; 148];
+
+        match tunnel.format_handshake_initiation(&mut buf, true) {
+            TunnResult::WriteToNetwork(init) => init.to_vec(),
+            TunnResult::Done
+            | TunnResult::Err(_)
+            | TunnResult::WriteToTunnelV4(..)
+            | TunnResult::WriteToTunnelV6(..) => panic!("Expected handshake initiation"),
+        }
+    }
+
+    fn is_cookie_reply(result: Result<(), TunnResult<'_>>) -> bool {
+        match result {
+            Err(TunnResult::WriteToNetwork(reply)) => matches!(
+                Tunn::parse_incoming_packet(reply),
+                Ok(Packet::PacketCookieReply(_))
+            ),
+            Ok(())
+            | Err(TunnResult::Done)
+            | Err(TunnResult::Err(_))
+            | Err(TunnResult::WriteToTunnelV4(..))
+            | Err(TunnResult::WriteToTunnelV6(..)) => false,
+        }
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+}
@@ -0,0 +1,132 @@
+//! A point-in-time view of a [`Node`](crate::Node)'s state for debugging connections that won't establish.
+//!
+//! All points in time are expressed relative to the `now` the snapshot was taken at, candidates are in their SDP form.
+
+use crate::{CandidatePair, NatStatus};
+use serde::Serialize;
+use std::{
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    time::Duration,
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeSnapshot<TId, RId> {
+    pub nat_status: NatStatus,
+    /// The prefix of the NAT64 on our network, formatted as `prefix/len`.
+    pub nat64_prefix: Option<String>,
+    pub host_candidates: Vec<String>,
+    pub allocations: Vec<AllocationSnapshot<RId>>,
+    /// Connections for which we sent an offer but haven't received an answer yet.
+    pub pending_connections: Vec<TId>,
+    pub connections: Vec<ConnectionSnapshot<TId, RId>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionSnapshot<TId, RId> {
+    pub id: TId,
+    pub state: ConnectionState,
+    pub ice: IceSnapshot<RId>,
+    pub wireguard: WireGuardSnapshot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Failed,
+    Idle,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IceSnapshot<RId> {
+    pub state: IceState,
+    pub local_candidates: Vec<String>,
+    pub remote_candidates: Vec<String>,
+    /// The candidate pairs we sent connectivity checks on.
+    pub candidate_pairs: Vec<CandidatePairSnapshot>,
+    pub nominated_pair: Option<CandidatePair<RId>>,
+    pub local_end_of_candidates: bool,
+    pub remote_end_of_candidates: bool,
+}
+
+/// Mirrors the connection state of the ICE agent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum IceState {
+    New,
+    Checking,
+    Connected,
+    Completed,
+    Disconnected,
+}
+
+impl From<str0m::IceConnectionState> for IceState {
+    fn from(state: str0m::IceConnectionState) -> Self {
+        match state {
+            str0m::IceConnectionState::New => IceState::New,
+            str0m::IceConnectionState::Checking => IceState::Checking,
+            str0m::IceConnectionState::Connected => IceState::Connected,
+            str0m::IceConnectionState::Completed => IceState::Completed,
+            str0m::IceConnectionState::Disconnected => IceState::Disconnected,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CandidatePairSnapshot {
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+    pub state: CandidatePairState,
+    pub checks_sent: u64,
+    pub checks_answered: u64,
+    pub time_since_last_answer: Option<Duration>,
+    pub nominated: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum CandidatePairState {
+    /// We are waiting for the response to at least one check and haven't received any response yet.
+    InProgress,
+    /// At least one check was answered.
+    Succeeded,
+    /// None of the checks were answered.
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WireGuardSnapshot {
+    pub session_index: u32,
+    pub time_since_last_handshake: Option<Duration>,
+    /// Whether we still hold the session with the previous preshared key.
+    pub has_previous_session: bool,
+    pub time_until_psk_rotation: Option<Duration>,
+    pub time_until_timer_update: Duration,
+    pub time_since_last_incoming: Duration,
+    pub time_since_last_outgoing: Duration,
+    pub time_since_last_keepalive: Duration,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AllocationSnapshot<RId> {
+    pub relay: RId,
+    pub server_ip4: Option<SocketAddrV4>,
+    pub server_ip6: Option<SocketAddrV6>,
+    /// The socket we chose to talk to the relay, `None` until the relay answered.
+    pub active_socket: Option<SocketAddr>,
+    /// Whether we use the candidates of this relay for connections.
+    pub preferred: bool,
+    pub has_credentials: bool,
+    pub candidates: Vec<String>,
+    pub time_until_expiry: Option<Duration>,
+    pub rtt: Option<Duration>,
+    pub requests_in_flight: usize,
+    pub channels: Vec<ChannelSnapshot>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelSnapshot {
+    pub number: u16,
+    pub peer: SocketAddr,
+    pub bound: bool,
+    pub time_since_bound: Duration,
+    pub time_since_last_received: Duration,
+}
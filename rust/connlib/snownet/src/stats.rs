@@ -1,4 +1,15 @@
-use std::ops::AddAssign;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    ops::AddAssign,
+    time::{Duration, Instant},
+};
+use str0m::ice::TransId;
+use str0m::CandidateKind;
+
+/// How long we wait for a response to a consent check before we consider it lost.
+const CONSENT_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Default, Debug, Clone, Copy)]
 pub struct NodeStats {
@@ -6,12 +17,192 @@ pub struct NodeStats {
     pub stun_bytes_to_relays: HumanBytes,
 }
 
-#[derive(Default, Debug, Clone, Copy)]
-pub struct ConnectionStats {
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionStats<RId> {
     /// How many bytes we sent as part of exchanging STUN messages to other peers directly.
     pub stun_bytes_to_peer_direct: HumanBytes,
     /// How many bytes we sent as part of exchanging STUN messages to other peers via relays.
     pub stun_bytes_to_peer_relayed: HumanBytes,
+
+    /// How many bytes of IP packets we sent through the tunnel.
+    pub bytes_sent: HumanBytes,
+    /// How many bytes of IP packets we received through the tunnel.
+    pub bytes_received: HumanBytes,
+    /// How many IP packets we sent through the tunnel.
+    pub packets_sent: u64,
+    /// How many IP packets we received through the tunnel.
+    pub packets_received: u64,
+
+    /// The candidate pair that is currently nominated for sending data, if any.
+    pub nominated_pair: Option<CandidatePair<RId>>,
+    /// The smoothed round-trip time of the consent checks on this connection.
+    pub ice_rtt: Option<Duration>,
+    /// How long ago we completed the last wireguard handshake.
+    pub wg_handshake_age: Option<Duration>,
+    /// The fraction of consent checks (between `0.0` and `1.0`) that did not receive a response.
+    pub packet_loss: Option<f32>,
+    /// The size of the largest IP packet we can send through the tunnel, as determined by path MTU discovery.
+    pub path_mtu: Option<u16>,
+    /// How many handshakes of this peer we answered with a cookie because it exceeded its rate limit.
+    pub handshakes_throttled: u64,
+}
+
+impl<RId> Default for ConnectionStats<RId> {
+    fn default() -> Self {
+        Self {
+            stun_bytes_to_peer_direct: Default::default(),
+            stun_bytes_to_peer_relayed: Default::default(),
+            bytes_sent: Default::default(),
+            bytes_received: Default::default(),
+            packets_sent: 0,
+            packets_received: 0,
+            nominated_pair: None,
+            ice_rtt: None,
+            wg_handshake_age: None,
+            packet_loss: None,
+            path_mtu: None,
+            handshakes_throttled: 0,
+        }
+    }
+}
+
+/// The candidate pair a connection uses to send data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CandidatePair<RId> {
+    pub local: SocketAddr,
+    pub local_type: CandidateType,
+    pub remote: SocketAddr,
+    pub remote_type: CandidateType,
+    /// The relay through which we are sending data, `None` for direct connections.
+    pub relay: Option<RId>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum CandidateType {
+    Host,
+    ServerReflexive,
+    PeerReflexive,
+    Relayed,
+}
+
+impl From<CandidateKind> for CandidateType {
+    fn from(kind: CandidateKind) -> Self {
+        match kind {
+            CandidateKind::Host => CandidateType::Host,
+            CandidateKind::ServerReflexive => CandidateType::ServerReflexive,
+            CandidateKind::PeerReflexive => CandidateType::PeerReflexive,
+            CandidateKind::Relayed => CandidateType::Relayed,
+        }
+    }
+}
+
+/// Tracks the consent checks (STUN binding requests) we send to a peer to estimate RTT and packet loss.
+#[derive(Debug, Default)]
+pub(crate) struct ConsentChecks {
+    in_flight: HashMap<TransId, (Instant, (SocketAddr, SocketAddr))>,
+    /// The checks per `(source, destination)` of the candidate pair they were sent on.
+    pairs: BTreeMap<(SocketAddr, SocketAddr), PairChecks>,
+
+    num_answered: u64,
+    num_lost: u64,
+
+    smoothed_rtt: Option<Duration>,
+}
+
+impl ConsentChecks {
+    pub(crate) fn on_request_sent(
+        &mut self,
+        id: TransId,
+        source: SocketAddr,
+        destination: SocketAddr,
+        now: Instant,
+    ) {
+        self.in_flight.insert(id, (now, (source, destination)));
+        self.pairs
+            .entry((source, destination))
+            .or_default()
+            .num_sent += 1;
+    }
+
+    pub(crate) fn on_response_received(&mut self, id: TransId, now: Instant) {
+        let Some((sent_at, pair)) = self.in_flight.remove(&id) else {
+            return;
+        };
+
+        self.num_answered += 1;
+
+        if let Some(checks) = self.pairs.get_mut(&pair) {
+            checks.num_answered += 1;
+            checks.last_answered = Some(now);
+        }
+
+        let rtt = now.duration_since(sent_at);
+
+        self.smoothed_rtt = Some(smooth_rtt(self.smoothed_rtt, rtt));
+    }
+
+    pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        let num_in_flight = self.in_flight.len();
+
+        self.in_flight
+            .retain(|_, (sent_at, _)| now.duration_since(*sent_at) < CONSENT_CHECK_TIMEOUT);
+
+        self.num_lost += (num_in_flight - self.in_flight.len()) as u64;
+    }
+
+    /// Forgets all in-flight checks, e.g. because we restarted ICE and will never receive a response for them.
+    pub(crate) fn clear_in_flight(&mut self) {
+        self.in_flight.clear();
+    }
+
+    /// Forgets the checks of all candidate pairs, e.g. because we restarted ICE and the agent will form new pairs.
+    pub(crate) fn clear_pairs(&mut self) {
+        self.pairs.clear();
+    }
+
+    /// The checks of each candidate pair, keyed by `(source, destination)`.
+    pub(crate) fn pairs(
+        &self,
+    ) -> impl Iterator<Item = ((SocketAddr, SocketAddr), PairChecks)> + '_ {
+        self.pairs.iter().map(|(pair, checks)| (*pair, *checks))
+    }
+
+    /// How many checks on the given candidate pair are awaiting a response.
+    pub(crate) fn num_in_flight(&self, pair: (SocketAddr, SocketAddr)) -> usize {
+        self.in_flight.values().filter(|(_, p)| *p == pair).count()
+    }
+
+    pub(crate) fn rtt(&self) -> Option<Duration> {
+        self.smoothed_rtt
+    }
+
+    pub(crate) fn packet_loss(&self) -> Option<f32> {
+        let total = self.num_answered + self.num_lost;
+
+        if total == 0 {
+            return None;
+        }
+
+        Some(self.num_lost as f32 / total as f32)
+    }
+}
+
+/// The STUN binding requests we sent on a single candidate pair.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct PairChecks {
+    pub(crate) num_sent: u64,
+    pub(crate) num_answered: u64,
+    pub(crate) last_answered: Option<Instant>,
+}
+
+/// Folds a new RTT sample into the smoothed RTT.
+///
+/// Same smoothing as TCP's SRTT, see <https://www.rfc-editor.org/rfc/rfc6298#section-2>.
+pub(crate) fn smooth_rtt(srtt: Option<Duration>, rtt: Duration) -> Duration {
+    match srtt {
+        Some(srtt) => (srtt * 7 + rtt) / 8,
+        None => rtt,
+    }
 }
 
 #[derive(Default, Clone, Copy)]
@@ -46,6 +237,10 @@ fn fmt_human_bytes(mut throughput: f64) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    const SRC: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 1000));
+    const DST: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 2000));
 
     #[test]
     fn fmt_human_bytes() {
@@ -53,4 +248,55 @@ mod tests {
         assert_eq!(format!("{:?}", HumanBytes(1_000)), "1.00 kB");
         assert_eq!(format!("{:?}", HumanBytes(12_500_000)), "12.50 MB");
     }
+
+    #[test]
+    fn consent_checks_estimate_rtt_and_loss() {
+        let start = Instant::now();
+        let mut checks = ConsentChecks::default();
+
+        checks.on_request_sent(TransId::new(), SRC, DST, start);
+        checks.on_request_sent(TransId::new(), SRC, DST, start);
+
+        let answered = TransId::new();
+        checks.on_request_sent(answered, SRC, DST, start);
+        checks.on_response_received(answered, start + Duration::from_millis(40));
+
+        checks.handle_timeout(start + CONSENT_CHECK_TIMEOUT);
+
+        assert_eq!(checks.rtt(), Some(Duration::from_millis(40)));
+        assert_eq!(checks.packet_loss(), Some(2.0 / 3.0));
+    }
+
+    #[test]
+    fn consent_checks_without_responses_have_no_rtt() {
+        let start = Instant::now();
+        let mut checks = ConsentChecks::default();
+
+        checks.on_request_sent(TransId::new(), SRC, DST, start);
+        checks.handle_timeout(start + Duration::from_secs(1));
+
+        assert_eq!(checks.rtt(), None);
+        assert_eq!(checks.packet_loss(), None);
+    }
+
+    #[test]
+    fn consent_checks_are_tracked_per_candidate_pair() {
+        let start = Instant::now();
+        let mut checks = ConsentChecks::default();
+
+        let answered = TransId::new();
+        checks.on_request_sent(answered, SRC, DST, start);
+        checks.on_request_sent(TransId::new(), DST, SRC, start);
+        checks.on_response_received(answered, start + Duration::from_millis(40));
+
+        let pairs = checks.pairs().collect::<Vec<_>>();
+
+        assert_eq!(pairs.len(), 2);
+        assert_eq!(pairs[0].0, (SRC, DST));
+        assert_eq!(pairs[0].1.num_answered, 1);
+        assert_eq!(pairs[1].0, (DST, SRC));
+        assert_eq!(pairs[1].1.num_answered, 0);
+        assert_eq!(checks.num_in_flight((DST, SRC)), 1);
+        assert_eq!(checks.num_in_flight((SRC, DST)), 0);
+    }
 }
@@ -0,0 +1,93 @@
+//! Spreads the WireGuard crypto of many connections across several threads.
+//!
+//! Only encryption and decryption of data packets is offloaded, everything else (ICE, handshakes, timers) stays on the thread driving the [`Node`](crate::Node).
+//! Each job covers all packets of a single connection and is processed by exactly one thread which preserves the order of packets within a connection.
+
+use std::{panic, thread};
+
+/// Runs the crypto of a batch on several threads on behalf of the calling thread.
+///
+/// Threads are scoped to a single [`Workers::run`] which lets jobs borrow from the caller.
+pub(crate) struct Workers {
+    num_threads: usize,
+}
+
+impl Workers {
+    /// Creates a pool that uses up to `workers` threads.
+    ///
+    /// With a single worker, no threads are spawned and all jobs run on the calling thread.
+    pub(crate) fn new(workers: usize) -> Self {
+        Self {
+            num_threads: workers.max(1),
+        }
+    }
+
+    /// Runs `work` for every job and returns once all jobs are done.
+    ///
+    /// With a single worker or a single job, everything runs on the calling thread.
+    /// Otherwise, the calling thread processes the first chunk of jobs itself.
+    /// A panic in any job is resumed on the calling thread.
+    pub(crate) fn run<J>(&self, jobs: &mut [J], work: impl Fn(&mut J) + Sync)
+    where
+        J: Send,
+    {
+        if self.num_threads <= 1 || jobs.len() <= 1 {
+            jobs.iter_mut().for_each(work);
+            return;
+        }
+
+        let jobs_per_worker = jobs.len().div_ceil(self.num_threads);
+        let work = &work;
+
+        thread::scope(|scope| {
+            let mut chunks = jobs.chunks_mut(jobs_per_worker);
+            let own = chunks.next();
+
+            let handles = chunks
+                .map(|chunk| scope.spawn(move || chunk.iter_mut().for_each(work)))
+                .collect::<Vec<_>>();
+
+            own.into_iter().flatten().for_each(work);
+
+            for handle in handles {
+                if let Err(payload) = handle.join() {
+                    panic::resume_unwind(payload);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn processes_every_job_exactly_once() {
+        let workers = Workers::new(4);
+        let mut jobs = (0..10).map(|i| (i, 0)).collect::<Vec<_>>();
+
+        workers.run(&mut jobs, |(_, count)| *count += 1);
+
+        assert!(jobs.iter().all(|(_, count)| *count == 1));
+    }
+
+    #[test]
+    fn preserves_order_within_a_job() {
+        let workers = Workers::new(3);
+        let mut jobs = (0..3).map(|_| Vec::new()).collect::<Vec<_>>();
+
+        workers.run(&mut jobs, |job| job.extend(0..100));
+
+        assert!(jobs.iter().all(|job| job.iter().copied().eq(0..100)));
+    }
+
+    #[test]
+    #[should_panic(expected = "job failed")]
+    fn resumes_panics_of_jobs_on_calling_thread() {
+        let workers = Workers::new(2);
+        let mut jobs = vec![false, true];
+
+        workers.run(&mut jobs, |fail| assert!(!*fail, "job failed"));
+    }
+}
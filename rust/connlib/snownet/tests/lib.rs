@@ -2,7 +2,11 @@ use boringtun::x25519::StaticSecret;
 use firezone_relay::{AddressFamily, AllocationPort, ClientSocket, IpStack, PeerSocket};
 use ip_packet::*;
 use rand::rngs::OsRng;
-use snownet::{Answer, Client, ClientNode, Event, Node, RelaySocket, Server, ServerNode, Transmit};
+use snownet::{
+    Answer, CandidatePairState, CandidateType, Client, ClientNode, ConnectionConfig,
+    ConnectionState, Event, IceState, Node, RelaySocket, Server, ServerNode,
+    TrafficClassPropagation, Transmit, MAX_MTU,
+};
 use std::{
     collections::{HashSet, VecDeque},
     iter,
@@ -10,7 +14,7 @@ use std::{
     time::{Duration, Instant, SystemTime},
     vec,
 };
-use str0m::{net::Protocol, Candidate};
+use str0m::{net::Protocol, Candidate, CandidateKind};
 use tracing::{debug_span, Span};
 use tracing_subscriber::util::SubscriberInitExt;
 
@@ -77,6 +81,454 @@ fn migrate_connection_to_new_relay() {
     bob.ping(ip("8.8.8.8"), ip("9.9.9.9"), &alice, clock.now);
     progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
     assert_eq!(alice.packets_from(ip("8.8.8.8")).count(), 1);
+
+    assert!(alice
+        .events
+        .iter()
+        .chain(bob.events.iter())
+        .any(|(e, _)| matches!(e, Event::ConnectionPathChanged { .. })));
+}
+
+#[test]
+fn candidates_of_late_relay_are_followed_by_new_end_of_candidates() {
+    let _guard = setup_tracing();
+    let (alice, bob) = alice_and_bob();
+    let (mut alice, mut bob, [roger], firewall, mut clock) =
+        connected_alice_and_bob(alice, bob, true);
+    let mut relays = [roger];
+
+    for _ in 0..50 {
+        progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    }
+    assert_eq!(end_of_candidates(&alice), 1);
+
+    // Alice learns about "Robert" after she already signalled the end of her candidates.
+    let [roger] = relays;
+    let mut relays = [
+        roger,
+        (
+            2,
+            TestRelay::new(
+                SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 3478),
+                debug_span!("Robert"),
+            ),
+        ),
+    ];
+    alice = alice.with_relays("alice", HashSet::default(), &mut relays, clock.now);
+
+    for _ in 0..50 {
+        progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    }
+
+    let robert_candidate = alice
+        .events
+        .iter()
+        .position(|(e, _)| matches!(e, Event::NewIceCandidate { candidate, .. } if candidate.contains("10.0.0.1")))
+        .expect("Robert's candidates to be signalled");
+    let last_end_of_candidates = alice
+        .events
+        .iter()
+        .rposition(|(e, _)| matches!(e, Event::EndOfIceCandidates { .. }))
+        .unwrap();
+
+    assert_eq!(end_of_candidates(&alice), 2);
+    assert!(robert_candidate < last_end_of_candidates);
+    assert!(alice.is_connected_to(&bob) && bob.is_connected_to(&alice));
+}
+
+fn end_of_candidates<R>(node: &TestNode<R>) -> usize {
+    node.events
+        .iter()
+        .filter(|(e, _)| matches!(e, Event::EndOfIceCandidates { .. }))
+        .count()
+}
+
+#[test]
+fn only_nearest_relays_are_used_for_relay_candidates() {
+    let _guard = setup_tracing();
+    let mut clock = Clock::new();
+
+    let (mut alice, bob) = alice_and_bob();
+    alice.set_max_relays(1);
+
+    let mut relays = [
+        (
+            1,
+            TestRelay::new(
+                SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3478),
+                debug_span!("Roger"),
+            ),
+        ),
+        (
+            2,
+            TestRelay::new(
+                SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 3478),
+                debug_span!("Robert"),
+            ),
+        ),
+    ];
+    let mut alice = TestNode::new(debug_span!("Alice"), alice, "1.1.1.1:80").with_relays(
+        "alice",
+        HashSet::default(),
+        &mut relays,
+        clock.now,
+    );
+    let mut bob = TestNode::new(debug_span!("Bob"), bob, "2.2.2.2:80").with_relays(
+        "bob",
+        HashSet::default(),
+        &mut relays,
+        clock.now,
+    );
+    let firewall = Firewall::default()
+        .with_block_rule(&alice, &bob)
+        .with_block_rule(&bob, &alice);
+
+    handshake(&mut alice, &mut bob, &clock);
+
+    loop {
+        if alice.is_connected_to(&bob) && bob.is_connected_to(&alice) {
+            break;
+        }
+
+        progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    }
+
+    let snapshot = alice.node.snapshot(clock.now);
+    let [preferred] = snapshot
+        .allocations
+        .iter()
+        .filter(|a| a.preferred)
+        .collect::<Vec<_>>()[..]
+    else {
+        panic!("Expected exactly one preferred relay")
+    };
+    let preferred_ip = IpAddr::V4(*preferred.server_ip4.unwrap().ip());
+
+    let mut relay_candidates = HashSet::new();
+    for (event, _) in &alice.events {
+        if let Event::NewIceCandidate { candidate, .. } = event {
+            relay_candidates.insert(candidate.clone());
+        }
+        if let Event::InvalidateIceCandidate { candidate, .. } = event {
+            relay_candidates.remove(candidate);
+        }
+    }
+    let relay_candidates = relay_candidates
+        .iter()
+        .map(|c| Candidate::from_sdp_string(c).unwrap())
+        .filter(|c| c.kind() == CandidateKind::Relayed)
+        .collect::<Vec<_>>();
+
+    assert!(!relay_candidates.is_empty());
+    assert!(relay_candidates
+        .iter()
+        .all(|c| c.addr().ip() == preferred_ip));
+}
+
+#[test]
+fn ice_restart_keeps_connection_alive() {
+    let _guard = setup_tracing();
+    let (alice, bob) = alice_and_bob();
+    let (mut alice, mut bob, mut relays, firewall, mut clock) =
+        connected_alice_and_bob(alice, bob, false);
+
+    alice.span.in_scope(|| alice.node.ice_restart(clock.now));
+
+    for _ in 0..22 {
+        progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    }
+
+    alice.ping(ip("9.9.9.9"), ip("8.8.8.8"), &bob, clock.now);
+    progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    assert_eq!(bob.packets_from(ip("9.9.9.9")).count(), 1);
+
+    bob.ping(ip("8.8.8.8"), ip("9.9.9.9"), &alice, clock.now);
+    progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    assert_eq!(alice.packets_from(ip("8.8.8.8")).count(), 1);
+
+    assert!(alice
+        .events
+        .iter()
+        .any(|(e, _)| matches!(e, Event::NewIceCredentials { .. })));
+    assert!(bob
+        .events
+        .iter()
+        .any(|(e, _)| matches!(e, Event::NewIceCredentials { .. })));
+    assert!(!alice
+        .events
+        .iter()
+        .chain(bob.events.iter())
+        .any(|(e, _)| matches!(e, Event::ConnectionClosed(_) | Event::ConnectionFailed(_))));
+}
+
+#[test]
+fn ice_restart_fails_connection_without_answer() {
+    let (mut alice, _) = alice_and_bob();
+
+    let now = Instant::now();
+
+    let _ = alice.new_connection(1, now, now);
+    alice.ice_restart(now);
+
+    assert_eq!(alice.poll_event().unwrap(), Event::ConnectionFailed(1));
+}
+
+#[test]
+fn rotating_preshared_key_keeps_connection_alive() {
+    let _guard = setup_tracing();
+    let (alice, bob) = alice_and_bob();
+    let (mut alice, mut bob, mut relays, firewall, mut clock) =
+        connected_alice_and_bob(alice, bob, false);
+
+    alice
+        .span
+        .in_scope(|| alice.node.rotate_preshared_key(1, clock.now));
+
+    for _ in 0..5 {
+        progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    }
+
+    alice.ping(ip("9.9.9.9"), ip("8.8.8.8"), &bob, clock.now);
+    progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    assert_eq!(bob.packets_from(ip("9.9.9.9")).count(), 1);
+
+    bob.ping(ip("8.8.8.8"), ip("9.9.9.9"), &alice, clock.now);
+    progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    assert_eq!(alice.packets_from(ip("8.8.8.8")).count(), 1);
+
+    alice.ping(ip("9.9.9.9"), ip("8.8.8.8"), &bob, clock.now);
+    progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    assert_eq!(bob.packets_from(ip("9.9.9.9")).count(), 2);
+
+    assert!(alice
+        .events
+        .iter()
+        .any(|(e, _)| matches!(e, Event::NewPresharedKey { connection: 1, .. })));
+    assert!(!alice
+        .events
+        .iter()
+        .chain(bob.events.iter())
+        .any(|(e, _)| matches!(e, Event::ConnectionClosed(_) | Event::ConnectionFailed(_))));
+}
+
+#[test]
+fn path_mtu_discovery_finds_maximum_mtu() {
+    let _guard = setup_tracing();
+    let (alice, bob) = alice_and_bob();
+    let (mut alice, mut bob, mut relays, firewall, mut clock) =
+        connected_alice_and_bob(alice, bob, false);
+
+    for _ in 0..30 {
+        progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    }
+
+    assert!(alice.events.iter().any(|(e, _)| matches!(
+        e,
+        Event::PathMtuChanged {
+            connection: 1,
+            mtu: MAX_MTU
+        }
+    )));
+    assert_eq!(
+        alice.node.stats().1.next().unwrap().1.path_mtu,
+        Some(MAX_MTU)
+    );
+}
+
+#[test]
+fn batch_of_equally_sized_packets_is_sent_as_single_gso_transmit() {
+    let _guard = setup_tracing();
+    let (alice, bob) = alice_and_bob();
+    let (mut alice, mut bob, mut relays, firewall, mut clock) =
+        connected_alice_and_bob(alice, bob, false);
+
+    let packets = (0..10)
+        .map(|seq| ip_packet::make::icmp_request_packet(ip("9.9.9.9"), ip("8.8.8.8"), seq, 0))
+        .collect::<Vec<_>>();
+
+    let transmits = alice
+        .span
+        .in_scope(|| {
+            alice
+                .node
+                .encapsulate_batch(1, packets.iter().map(|p| p.to_immutable()), clock.now)
+        })
+        .unwrap();
+
+    assert_eq!(transmits.len(), 1);
+    assert_eq!(transmits[0].datagrams().count(), 10);
+    assert!(transmits[0].segment_size.is_some());
+
+    alice.transmits.extend(transmits);
+    progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+
+    assert_eq!(bob.packets_from(ip("9.9.9.9")).count(), 10);
+}
+
+#[test]
+fn gso_transmits_are_limited_to_max_segments() {
+    let _guard = setup_tracing();
+    let (mut alice, bob) = alice_and_bob();
+    alice.set_max_gso_segments(4);
+    let (mut alice, mut bob, mut relays, firewall, mut clock) =
+        connected_alice_and_bob(alice, bob, false);
+
+    let packets = (0..10)
+        .map(|seq| ip_packet::make::icmp_request_packet(ip("9.9.9.9"), ip("8.8.8.8"), seq, 0))
+        .collect::<Vec<_>>();
+
+    let transmits = alice
+        .span
+        .in_scope(|| {
+            alice
+                .node
+                .encapsulate_batch(1, packets.iter().map(|p| p.to_immutable()), clock.now)
+        })
+        .unwrap();
+
+    assert_eq!(
+        transmits
+            .iter()
+            .map(|t| t.datagrams().count())
+            .collect::<Vec<_>>(),
+        vec![4, 4, 2]
+    );
+
+    alice.transmits.extend(transmits);
+    progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+
+    assert_eq!(bob.packets_from(ip("9.9.9.9")).count(), 10);
+}
+
+#[test]
+fn crypto_workers_preserve_packet_order() {
+    let _guard = setup_tracing();
+    let (mut alice, mut bob) = alice_and_bob();
+    alice.set_crypto_workers(4);
+    bob.set_crypto_workers(4);
+    let (mut alice, mut bob, mut relays, firewall, mut clock) =
+        connected_alice_and_bob(alice, bob, false);
+
+    let packets = (0..10)
+        .map(|seq| ip_packet::make::icmp_request_packet(ip("9.9.9.9"), ip("8.8.8.8"), seq, 0))
+        .collect::<Vec<_>>();
+
+    let transmits = alice.span.in_scope(|| {
+        alice
+            .node
+            .encapsulate_many(packets.iter().map(|p| (1, p.to_immutable())), clock.now)
+    });
+
+    let mut received = Vec::new();
+    bob.span.in_scope(|| {
+        bob.node.decapsulate_many(
+            transmits.iter().flat_map(|t| {
+                t.datagrams()
+                    .map(move |datagram| (t.dst, t.src.unwrap(), datagram))
+            }),
+            clock.now,
+            bob.buffer.as_mut(),
+            |_, packet| received.push(packet.packet().to_vec()),
+        )
+    });
+
+    assert_eq!(
+        received,
+        packets
+            .iter()
+            .map(|p| p.packet().to_vec())
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn ecn_of_tunneled_packet_is_copied_to_outer_datagram() {
+    let _guard = setup_tracing();
+    let (mut alice, bob) = alice_and_bob();
+    alice.set_traffic_class_propagation(TrafficClassPropagation {
+        dscp: false,
+        ecn: true,
+    });
+    let (mut alice, mut bob, mut relays, firewall, mut clock) =
+        connected_alice_and_bob(alice, bob, false);
+
+    let mut packet = ip_packet::make::icmp_request_packet(ip("9.9.9.9"), ip("8.8.8.8"), 1, 0);
+    packet.set_ecn(ECN_CE);
+
+    let transmit = alice
+        .span
+        .in_scope(|| alice.node.encapsulate(1, packet.to_immutable(), clock.now))
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(transmit.tos, ECN_CE);
+}
+
+#[test]
+fn connection_stats_report_nominated_pair_and_traffic() {
+    let _guard = setup_tracing();
+    let (alice, bob) = alice_and_bob();
+    let (mut alice, mut bob, mut relays, firewall, mut clock) =
+        connected_alice_and_bob(alice, bob, true);
+
+    alice.ping(ip("9.9.9.9"), ip("8.8.8.8"), &bob, clock.now);
+    progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+
+    let (_, alice_stats) = alice.node.stats().1.find(|(id, _)| *id == 1).unwrap();
+    let (_, bob_stats) = bob.node.stats().1.find(|(id, _)| *id == 1).unwrap();
+
+    let nominated_pair = alice_stats.nominated_pair.unwrap();
+    assert!(
+        nominated_pair.local_type == CandidateType::Relayed
+            || nominated_pair.remote_type == CandidateType::Relayed,
+        "direct traffic is blocked by the firewall"
+    );
+    assert!(alice_stats.wg_handshake_age.is_some());
+    assert!(alice_stats.ice_rtt.is_some());
+    assert_eq!(alice_stats.packets_sent, 1);
+    assert_eq!(bob_stats.packets_received, 1);
+}
+
+#[test]
+fn snapshot_reports_connection_and_allocation_state() {
+    let _guard = setup_tracing();
+    let (alice, bob) = alice_and_bob();
+    let (mut alice, mut bob, mut relays, firewall, mut clock) =
+        connected_alice_and_bob(alice, bob, true);
+
+    let snapshot = alice.node.snapshot(clock.now);
+
+    let [connection] = snapshot.connections.as_slice() else {
+        panic!("Expected exactly one connection")
+    };
+    assert_eq!(connection.id, 1);
+    assert_eq!(connection.state, ConnectionState::Connected);
+    assert!(matches!(
+        connection.ice.state,
+        IceState::Connected | IceState::Completed
+    ));
+    assert!(!connection.ice.local_candidates.is_empty());
+    assert!(!connection.ice.remote_candidates.is_empty());
+    assert!(connection.wireguard.time_since_last_handshake.is_some());
+
+    let nominated_pair = connection.ice.nominated_pair.unwrap();
+    let nominated = connection
+        .ice
+        .candidate_pairs
+        .iter()
+        .find(|p| p.nominated)
+        .unwrap();
+    assert_eq!(nominated.local, nominated_pair.local);
+    assert_eq!(nominated.remote, nominated_pair.remote);
+    assert_eq!(nominated.state, CandidatePairState::Succeeded);
+
+    let [allocation] = snapshot.allocations.as_slice() else {
+        panic!("Expected exactly one allocation")
+    };
+    assert_eq!(allocation.relay, 1);
+    assert!(allocation.active_socket.is_some());
+    assert!(allocation.time_until_expiry.is_some());
+    assert!(snapshot.pending_connections.is_empty());
 }
 
 #[test]
@@ -136,6 +588,150 @@ fn idle_connection_is_closed_after_5_minutes() {
         .contains(&(Event::ConnectionClosed(1), clock.now)));
 }
 
+#[test]
+fn idle_timeout_is_configurable() {
+    let _guard = setup_tracing();
+    let (mut alice, bob) = alice_and_bob();
+    alice.set_default_connection_config(ConnectionConfig {
+        idle_timeout: Duration::from_secs(60),
+        ..ConnectionConfig::default()
+    });
+    let (mut alice, mut bob, mut relays, firewall, mut clock) =
+        connected_alice_and_bob(alice, bob, false);
+
+    alice.ping(ip("9.9.9.9"), ip("8.8.8.8"), &bob, clock.now);
+
+    let start = clock.now;
+
+    while !alice
+        .events
+        .iter()
+        .any(|(e, _)| e == &Event::ConnectionClosed(1))
+    {
+        progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    }
+
+    assert!(clock.elapsed(start) >= Duration::from_secs(60));
+    assert!(clock.elapsed(start) < Duration::from_secs(61));
+}
+
+#[test]
+fn keepalives_follow_connection_config() {
+    let _guard = setup_tracing();
+    let (mut alice, mut bob) = alice_and_bob();
+    alice.set_default_connection_config(ConnectionConfig {
+        keepalive: Some(Duration::from_secs(5)),
+        ..ConnectionConfig::default()
+    });
+    bob.set_default_connection_config(ConnectionConfig {
+        keepalive: None,
+        ..ConnectionConfig::default()
+    });
+    let (mut alice, mut bob, mut relays, firewall, mut clock) =
+        connected_alice_and_bob(alice, bob, false);
+
+    let start = clock.now;
+
+    while clock.elapsed(start) <= Duration::from_secs(30) {
+        progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    }
+
+    let alice_snapshot = alice.node.snapshot(clock.now);
+    let bob_snapshot = bob.node.snapshot(clock.now);
+
+    assert!(
+        alice_snapshot.connections[0]
+            .wireguard
+            .time_since_last_keepalive
+            <= Duration::from_secs(5)
+    );
+    assert!(
+        bob_snapshot.connections[0]
+            .wireguard
+            .time_since_last_keepalive
+            >= Duration::from_secs(30)
+    );
+}
+
+#[test]
+fn consent_checks_follow_connection_config() {
+    let _guard = setup_tracing();
+
+    let stun_bytes_within_a_minute = |config: ConnectionConfig| {
+        let (mut alice, mut bob) = alice_and_bob();
+        alice.set_default_connection_config(config);
+        bob.set_default_connection_config(config);
+        let (mut alice, mut bob, mut relays, firewall, mut clock) =
+            connected_alice_and_bob(alice, bob, false);
+
+        let before = alice
+            .node
+            .stats()
+            .1
+            .next()
+            .unwrap()
+            .1
+            .stun_bytes_to_peer_direct
+            .0;
+        let start = clock.now;
+
+        while clock.elapsed(start) <= Duration::from_secs(60) {
+            progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+        }
+
+        assert!(alice.is_connected_to(&bob));
+        assert!(!alice
+            .events
+            .iter()
+            .any(|(e, _)| matches!(e, Event::ConnectionFailed(_) | Event::ConnectionClosed(_))));
+
+        alice
+            .node
+            .stats()
+            .1
+            .next()
+            .unwrap()
+            .1
+            .stun_bytes_to_peer_direct
+            .0
+            - before
+    };
+
+    let default = stun_bytes_within_a_minute(ConnectionConfig::default());
+    let throttled = stun_bytes_within_a_minute(ConnectionConfig {
+        consent_check_interval: Some(Duration::from_secs(10)),
+        ..ConnectionConfig::default()
+    });
+
+    assert!(throttled < default);
+}
+
+#[test]
+fn throttled_consent_checks_do_not_delay_ice_restart() {
+    let _guard = setup_tracing();
+    let config = ConnectionConfig {
+        consent_check_interval: Some(Duration::from_secs(10)),
+        ..ConnectionConfig::default()
+    };
+    let (mut alice, mut bob) = alice_and_bob();
+    alice.set_default_connection_config(config);
+    bob.set_default_connection_config(config);
+    let (mut alice, mut bob, mut relays, firewall, mut clock) =
+        connected_alice_and_bob(alice, bob, false);
+
+    alice.span.in_scope(|| alice.node.ice_restart(clock.now));
+
+    for _ in 0..22 {
+        progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    }
+
+    let snapshot = alice.node.snapshot(clock.now);
+    assert!(matches!(
+        snapshot.connections[0].ice.state,
+        IceState::Connected | IceState::Completed
+    ));
+}
+
 #[test]
 fn connection_times_out_after_20_seconds() {
     let (mut alice, _) = alice_and_bob();
@@ -187,6 +783,24 @@ fn connection_with_candidates_does_not_time_out_after_10_seconds() {
     assert!(!any_failed);
 }
 
+#[test]
+fn connection_without_candidates_fails_on_end_of_candidates() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+
+    let start = Instant::now();
+
+    let (mut alice, mut bob) = alice_and_bob();
+    let answer = send_offer(&mut alice, &mut bob, start);
+
+    let accepted_at = start + Duration::from_secs(1);
+    alice.accept_answer(1, bob.public_key(), answer, accepted_at);
+    alice.end_of_remote_candidates(1, accepted_at);
+
+    alice.handle_timeout(accepted_at);
+
+    assert_eq!(alice.poll_event().unwrap(), Event::ConnectionFailed(1));
+}
+
 #[test]
 fn answer_after_stale_connection_does_not_panic() {
     let start = Instant::now();
@@ -245,6 +859,62 @@ fn alice_and_bob() -> (ClientNode<u64, u64>, ServerNode<u64, u64>) {
     (alice, bob)
 }
 
+/// Connects Alice and Bob through a single relay "Roger".
+///
+/// With `relayed_only`, the firewall blocks all direct traffic between them.
+fn connected_alice_and_bob(
+    alice: ClientNode<u64, u64>,
+    bob: ServerNode<u64, u64>,
+    relayed_only: bool,
+) -> (
+    TestNode<Client>,
+    TestNode<Server>,
+    [(u64, TestRelay); 1],
+    Firewall,
+    Clock,
+) {
+    let mut clock = Clock::new();
+
+    let mut relays = [(
+        1,
+        TestRelay::new(
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3478),
+            debug_span!("Roger"),
+        ),
+    )];
+    let mut alice = TestNode::new(debug_span!("Alice"), alice, "1.1.1.1:80").with_relays(
+        "alice",
+        HashSet::default(),
+        &mut relays,
+        clock.now,
+    );
+    let mut bob = TestNode::new(debug_span!("Bob"), bob, "2.2.2.2:80").with_relays(
+        "bob",
+        HashSet::default(),
+        &mut relays,
+        clock.now,
+    );
+    let mut firewall = Firewall::default();
+
+    if relayed_only {
+        firewall = firewall
+            .with_block_rule(&alice, &bob)
+            .with_block_rule(&bob, &alice);
+    }
+
+    handshake(&mut alice, &mut bob, &clock);
+
+    loop {
+        if alice.is_connected_to(&bob) && bob.is_connected_to(&alice) {
+            break;
+        }
+
+        progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    }
+
+    (alice, bob, relays, firewall, clock)
+}
+
 fn send_offer(
     alice: &mut ClientNode<u64, u64>,
     bob: &mut ServerNode<u64, u64>,
@@ -280,9 +950,9 @@ struct TestNode<R> {
     primary: SocketAddr,
     /// All local interfaces.
     local: Vec<SocketAddr>,
-    events: Vec<(Event<u64>, Instant)>,
+    events: Vec<(Event<u64, u64>, Instant)>,
 
-    buffer: Box<[u8; 10_000]>,
+    buffer: Box<[u8; (1 << 16) - 1]>,
 }
 
 struct TestRelay {
@@ -569,7 +1239,7 @@ impl<R> TestNode<R> {
             node,
             span,
             received_packets: vec![],
-            buffer: Box::new([0u8; 10_000]),
+            buffer: Box::new([0u8; (1 << 16) - 1]),
             primary,
             local: vec![primary],
             events: Default::default(),
@@ -652,6 +1322,28 @@ impl<R> TestNode<R> {
         }
     }
 
+    fn receive_batch(
+        &mut self,
+        local: SocketAddr,
+        from: SocketAddr,
+        datagrams: &[u8],
+        stride: usize,
+        now: Instant,
+    ) {
+        debug_assert!(self.local.contains(&local));
+
+        let packets = self.span.in_scope(|| {
+            self.node
+                .decapsulate_batch(local, from, datagrams, stride, now, self.buffer.as_mut())
+        });
+
+        self.received_packets.extend(
+            packets
+                .into_iter()
+                .map(|(_, packet)| packet.to_immutable().to_owned()),
+        );
+    }
+
     fn drain_events<RO>(&mut self, other: &mut TestNode<RO>, now: Instant) {
         while let Some(v) = self.span.in_scope(|| self.node.poll_event()) {
             self.events.push((v.clone(), now));
@@ -669,7 +1361,24 @@ impl<R> TestNode<R> {
                 } => other
                     .span
                     .in_scope(|| other.node.remove_remote_candidate(connection, candidate)),
+                Event::EndOfIceCandidates { connection } => other
+                    .span
+                    .in_scope(|| other.node.end_of_remote_candidates(connection, now)),
+                Event::NewIceCredentials {
+                    connection,
+                    credentials,
+                } => other.span.in_scope(|| {
+                    other
+                        .node
+                        .set_remote_credentials(connection, credentials, now)
+                }),
+                Event::NewPresharedKey { connection, key } => other
+                    .span
+                    .in_scope(|| other.node.set_preshared_key(connection, key, now)),
                 Event::ConnectionEstablished(_)
+                | Event::ConnectionPathChanged { .. }
+                | Event::PathMtuChanged { .. }
+                | Event::NatStatusChanged(_)
                 | Event::ConnectionFailed(_)
                 | Event::ConnectionClosed(_) => {}
             };
@@ -688,7 +1397,9 @@ impl<R> TestNode<R> {
             let dst = trans.dst;
 
             if let Some((_, relay)) = relays.iter_mut().find(|(_, r)| r.wants(trans.dst)) {
-                relay.handle_packet(payload, self.primary, dst, other, now);
+                for datagram in trans.datagrams() {
+                    relay.handle_packet(datagram, self.primary, dst, other, now);
+                }
                 continue;
             }
 
@@ -709,7 +1420,10 @@ impl<R> TestNode<R> {
             }
 
             // Firewall allowed traffic, let's dispatch it.
-            other.receive(dst, src, payload, now);
+            match trans.segment_size {
+                Some(stride) => other.receive_batch(dst, src, payload, stride, now),
+                None => other.receive(dst, src, payload, now),
+            }
         }
     }
 }
@@ -1,31 +1,31 @@
-use crate::dns::StubResolver;
+use crate::dns::{ResponseCache, StubResolver, TcpDnsServer};
 use crate::io::DnsQueryError;
+use crate::mtu::TunMtu;
 use crate::peer_store::PeerStore;
+use crate::sockets::Received;
 use crate::{dns, dns::DnsQuery};
 use anyhow::Context;
 use bimap::BiMap;
-use connlib_shared::callbacks::Status;
+use connlib_shared::callbacks::{DnsResolution, Status};
 use connlib_shared::error::ConnlibError as Error;
 use connlib_shared::messages::client::{Site, SiteId};
 use connlib_shared::messages::ResolveRequest;
 use connlib_shared::messages::{
     client::ResourceDescription, client::ResourceDescriptionCidr, Answer, ClientPayload, DnsServer,
-    GatewayId, Interface as InterfaceConfig, IpDnsServer, Key, Offer, Relay, RelayId,
-    RequestConnection, ResourceId, ReuseConnection,
+    GatewayId, IceCredentials, Interface as InterfaceConfig, IpDnsServer, Key, Offer, Relay,
+    RelayId, RequestConnection, ResourceId, ReuseConnection,
 };
 use connlib_shared::{callbacks, Callbacks, DomainName, PublicKey, StaticSecret};
 use ip_network::{IpNetwork, Ipv4Network, Ipv6Network};
 use ip_network_table::IpNetworkTable;
 use ip_packet::{IpPacket, MutableIpPacket, Packet as _};
 use itertools::Itertools;
-use tracing::Level;
 
 use crate::peer::GatewayOnClient;
 use crate::utils::{self, earliest, turn};
-use crate::{ClientEvent, ClientTunnel, Tun};
-use core::fmt;
+use crate::{ClientEvent, ClientTunnel, Tun, DEFAULT_MAX_RELAYS};
 use secrecy::{ExposeSecret as _, Secret};
-use snownet::{ClientNode, RelaySocket};
+use snownet::{ClientNode, ConnectionConfig, RelaySocket, TrafficClassPropagation};
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::iter;
@@ -38,13 +38,16 @@ const IPV4_RESOURCES: &str = "100.96.0.0/11";
 const IPV6_RESOURCES: &str = "fd00:2021:1111:8000::/107";
 
 const DNS_PORT: u16 = 53;
-const DNS_SENTINELS_V4: &str = "100.100.111.0/24";
-const DNS_SENTINELS_V6: &str = "fd00:2021:1111:8000:100:100:111:0/120";
+pub(crate) const DNS_SENTINELS_V4: &str = "100.100.111.0/24";
+pub(crate) const DNS_SENTINELS_V6: &str = "fd00:2021:1111:8000:100:100:111:0/120";
 
 // The max time a dns request can be configured to live in resolvconf
 // is 30 seconds. See resolvconf(5) timeout.
 const IDS_EXPIRE: std::time::Duration = std::time::Duration::from_secs(60);
 
+/// How often we rotate the preshared key of connections to gateways that support it.
+const PSK_ROTATION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
 impl<CB> ClientTunnel<CB>
 where
     CB: Callbacks + 'static,
@@ -63,6 +66,37 @@ where
         self.io.device_mut().set_tun(tun);
     }
 
+    /// Enables or disables [`ClientEvent::DnsQueryAnswered`] events.
+    pub fn set_dns_query_audit(&mut self, enabled: bool) {
+        self.role_state.set_dns_query_audit(enabled);
+    }
+
+    /// Restores the proxy IPs of a previous session, as emitted via [`ClientEvent::ProxyIpsChanged`].
+    ///
+    /// Must be called before any DNS queries are handled.
+    pub fn restore_proxy_ips(&mut self, proxy_ips: HashMap<String, Vec<IpAddr>>) {
+        self.role_state.restore_proxy_ips(proxy_ips, Instant::now());
+    }
+
+    /// Sets which parts of the traffic class of tunneled packets we copy onto the outer datagram and back.
+    ///
+    /// Both DSCP and ECN are copied unless configured otherwise.
+    pub fn set_traffic_class_propagation(&mut self, propagation: TrafficClassPropagation) {
+        self.role_state
+            .node
+            .set_traffic_class_propagation(propagation);
+    }
+
+    /// Limits relay candidates to the `max_relays` relays with the lowest RTT, see [`DEFAULT_MAX_RELAYS`].
+    pub fn set_max_relays(&mut self, max_relays: usize) {
+        self.role_state.node.set_max_relays(max_relays);
+    }
+
+    /// Sets the idle timeout, keep-alive and consent check interval of connections to gateways created from now on.
+    pub fn set_connection_config(&mut self, config: ConnectionConfig) {
+        self.role_state.set_connection_config(config);
+    }
+
     pub fn update_relays(&mut self, to_remove: HashSet<RelayId>, to_add: Vec<Relay>) {
         self.role_state
             .update_relays(to_remove, turn(&to_add), Instant::now())
@@ -134,9 +168,12 @@ where
         // We can just sort in here because sentinel ips are created in order
         let dns_config = dns_mapping.left_values().copied().sorted().collect();
 
-        self.callbacks
-            .clone()
-            .on_set_interface_config(config.ipv4, config.ipv6, dns_config);
+        self.callbacks.clone().on_set_interface_config(
+            config.ipv4,
+            config.ipv6,
+            dns_config,
+            config.search_domains,
+        );
         self.callbacks.on_update_routes(
             self.role_state.routes().filter_map(utils::ipv4).collect(),
             self.role_state.routes().filter_map(utils::ipv6).collect(),
@@ -165,6 +202,21 @@ where
         self.role_state.remove_ice_candidate(conn_id, ice_candidate);
     }
 
+    pub fn end_of_ice_candidates(&mut self, conn_id: GatewayId) {
+        self.role_state
+            .end_of_ice_candidates(conn_id, Instant::now());
+    }
+
+    /// A snapshot of the state of our connections and relays, to be included in support bundles.
+    pub fn snapshot(&self) -> snownet::NodeSnapshot<GatewayId, RelayId> {
+        self.role_state.snapshot(Instant::now())
+    }
+
+    pub fn set_remote_ice_credentials(&mut self, conn_id: GatewayId, credentials: IceCredentials) {
+        self.role_state
+            .set_remote_ice_credentials(conn_id, credentials, Instant::now());
+    }
+
     pub fn create_or_reuse_connection(
         &mut self,
         resource_id: ResourceId,
@@ -175,11 +227,17 @@ where
             .create_or_reuse_connection(resource_id, gateway_id, site_id)
     }
 
+    /// Accepts the answer of a gateway to our connection request.
+    ///
+    /// `supports_psk_rotation` is whether the gateway applies the preshared keys we emit via [`ClientEvent::NewPresharedKey`].
+    /// `supports_dns_resolution` is whether the gateway resolves queries for records of DNS resources that we send through the tunnel.
     pub fn received_offer_response(
         &mut self,
         resource_id: ResourceId,
         answer: Answer,
         gateway_public_key: PublicKey,
+        supports_psk_rotation: bool,
+        supports_dns_resolution: bool,
     ) -> connlib_shared::Result<()> {
         self.role_state.accept_answer(
             snownet::Answer {
@@ -190,6 +248,8 @@ where
             },
             resource_id,
             gateway_public_key,
+            supports_psk_rotation,
+            supports_dns_resolution,
             Instant::now(),
         )?;
 
@@ -228,6 +288,8 @@ impl Request {
 pub struct ClientState {
     /// Manages wireguard tunnels to gateways.
     node: ClientNode<GatewayId, RelayId>,
+    /// The config of new connections to gateways.
+    connection_config: ConnectionConfig,
     /// All gateways we are connected to and the associated, connection-specific state.
     peers: PeerStore<GatewayId, GatewayOnClient>,
     /// Which Resources we are trying to connect to.
@@ -254,17 +316,41 @@ pub struct ClientState {
     /// Maps from connlib-assigned IP of a DNS server back to the originally configured system DNS resolver.
     dns_mapping: BiMap<IpAddr, DnsServer>,
     /// DNS queries that had their destination IP mangled because the servers is a CIDR resource.
-    ///
-    /// The [`Instant`] tracks when the DNS query expires.
-    mangled_dns_queries: HashMap<u16, Instant>,
+    mangled_dns_queries: HashMap<u16, MangledDnsQuery>,
     /// Manages internal dns records and emits forwarding event when not internally handled
     stub_resolver: StubResolver,
+    /// Terminates DNS queries sent over TCP to our sentinel DNS servers.
+    tcp_dns_server: TcpDnsServer,
+    /// DNS queries for records of DNS resources that we sent to a gateway, indexed by the socket they were sent from and their ID.
+    dns_queries_via_gateway: HashMap<(SocketAddr, u16), DnsQueryViaGateway>,
+    /// Responses of the upstream resolvers to the queries we forwarded to them.
+    dns_cache: ResponseCache,
+    /// Whether we emit a [`ClientEvent::DnsQueryAnswered`] for every DNS query we answer.
+    audit_dns_queries: bool,
 
     /// Configuration of the TUN device, when it is up.
     interface_config: Option<InterfaceConfig>,
+    /// The MTU of the TUN device, following the path MTUs of our connections to gateways.
+    tun_mtu: TunMtu<GatewayId>,
 
     buffered_events: VecDeque<ClientEvent>,
     buffered_packets: VecDeque<IpPacket<'static>>,
+    buffered_transmits: VecDeque<snownet::Transmit<'static>>,
+}
+
+#[derive(Debug, Clone)]
+struct MangledDnsQuery {
+    expires_at: Instant,
+    /// The query as the [`TcpDnsServer`] handed it to us, if we received it over TCP.
+    tcp_query: Option<IpPacket<'static>>,
+}
+
+#[derive(Debug, Clone)]
+struct DnsQueryViaGateway {
+    gateway: GatewayId,
+    /// Kept around so we can still forward it upstream if the gateway turns out not to resolve DNS.
+    query: DnsQuery<'static>,
+    expires_at: Instant,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -279,6 +365,13 @@ impl ClientState {
         private_key: impl Into<StaticSecret>,
         known_hosts: HashMap<String, Vec<IpAddr>>,
     ) -> Self {
+        let mut node = ClientNode::new(private_key.into());
+        node.set_traffic_class_propagation(TrafficClassPropagation {
+            dscp: true,
+            ecn: true,
+        });
+        node.set_max_relays(DEFAULT_MAX_RELAYS);
+
         Self {
             awaiting_connection_details: Default::default(),
             resources_gateways: Default::default(),
@@ -288,14 +381,21 @@ impl ClientState {
             dns_mapping: Default::default(),
             buffered_events: Default::default(),
             interface_config: Default::default(),
+            tun_mtu: Default::default(),
             buffered_packets: Default::default(),
             buffered_dns_queries: Default::default(),
-            node: ClientNode::new(private_key.into()),
+            node,
+            connection_config: ConnectionConfig::default(),
             system_resolvers: Default::default(),
             sites_status: Default::default(),
             gateways_site: Default::default(),
             mangled_dns_queries: Default::default(),
             stub_resolver: StubResolver::new(known_hosts),
+            tcp_dns_server: TcpDnsServer::new(Instant::now()),
+            dns_queries_via_gateway: Default::default(),
+            dns_cache: ResponseCache::new(),
+            audit_dns_queries: false,
+            buffered_transmits: Default::default(),
         }
     }
 
@@ -390,12 +490,56 @@ impl ClientState {
         })
     }
 
+    #[cfg(test)]
     pub(crate) fn encapsulate<'s>(
         &'s mut self,
         packet: MutableIpPacket<'_>,
         now: Instant,
     ) -> Option<snownet::Transmit<'s>> {
-        let (packet, dst) = match self.handle_dns(packet) {
+        let (gid, packet) = self.route(packet, now)?;
+
+        let transmit = self
+            .node
+            .encapsulate(gid, packet.as_immutable(), now)
+            .inspect_err(|e| tracing::debug!(%gid, "Failed to encapsulate: {e}"))
+            .ok()??;
+
+        Some(transmit)
+    }
+
+    /// Encapsulates a batch of packets read from the TUN device.
+    ///
+    /// The packets of different gateways are encrypted in parallel and those for the same gateway are coalesced for UDP GSO, see [`snownet::Node::encapsulate_many`].
+    pub(crate) fn encapsulate_batch<'p>(
+        &mut self,
+        packets: impl IntoIterator<Item = MutableIpPacket<'p>>,
+        now: Instant,
+    ) -> Vec<snownet::Transmit<'static>> {
+        let packets = packets
+            .into_iter()
+            .filter_map(|packet| self.route(packet, now))
+            .collect::<Vec<_>>();
+
+        self.node.encapsulate_many(
+            packets
+                .iter()
+                .map(|(gid, packet)| (*gid, packet.as_immutable())),
+            now,
+        )
+    }
+
+    /// Figures out which gateway a packet read from the TUN device needs to be sent to.
+    ///
+    /// Packets that we handle locally, like DNS queries, or that we can't send yet yield `None`.
+    fn route<'p>(
+        &mut self,
+        packet: MutableIpPacket<'p>,
+        now: Instant,
+    ) -> Option<(GatewayId, MutableIpPacket<'p>)> {
+        let dns_result = self.handle_dns(packet, now);
+        self.forget_reclaimed_proxy_ips();
+
+        let (packet, dst) = match dns_result {
             Ok(response) => {
                 self.buffered_packets.push_back(response?.to_owned());
                 return None;
@@ -411,6 +555,7 @@ impl ClientState {
             tracing::trace!(%dst, "Unknown resource");
             return None;
         };
+        self.stub_resolver.on_proxy_ip_traffic(&dst, now);
 
         let Some(peer) = peer_by_resource_mut(&self.resources_gateways, &mut self.peers, resource)
         else {
@@ -422,6 +567,7 @@ impl ClientState {
             packet,
             &self.dns_mapping,
             &mut self.mangled_dns_queries,
+            None,
             now,
         );
 
@@ -431,34 +577,42 @@ impl ClientState {
             return None;
         }
 
-        let gid = peer.id();
+        Some((peer.id(), packet))
+    }
 
-        let transmit = self
-            .node
-            .encapsulate(gid, packet.as_immutable(), now)
-            .inspect_err(|e| tracing::debug!(%gid, "Failed to encapsulate: {e}"))
-            .ok()??;
+    pub(crate) fn decapsulate_batch<'b>(
+        &mut self,
+        received: Received<'_>,
+        now: Instant,
+        buffer: &'b mut [u8],
+    ) -> Vec<IpPacket<'b>> {
+        let Received {
+            local,
+            from,
+            datagrams,
+            stride,
+            ecn,
+        } = received;
 
-        Some(transmit)
+        self.node
+            .decapsulate_batch(local, from, datagrams, stride, now, buffer)
+            .into_iter()
+            .filter_map(|(gid, packet)| self.on_decapsulated(local, from, gid, packet, ecn, now))
+            .collect()
     }
 
-    pub(crate) fn decapsulate<'b>(
+    fn on_decapsulated<'b>(
         &mut self,
         local: SocketAddr,
         from: SocketAddr,
-        packet: &[u8],
+        gid: GatewayId,
+        packet: MutableIpPacket<'b>,
+        ecn: u8,
         now: Instant,
-        buffer: &'b mut [u8],
     ) -> Option<IpPacket<'b>> {
-        let (gid, packet) = self.node.decapsulate(
-            local,
-            from,
-            packet.as_ref(),
-            now,
-            buffer,
-        )
-        .inspect_err(|e| tracing::debug!(%local, num_bytes = %packet.len(), "Failed to decapsulate incoming packet: {e}"))
-        .ok()??;
+        if self.handle_dns_response_via_gateway(gid, packet.as_immutable(), now) {
+            return None;
+        }
 
         let Some(peer) = self.peers.get_mut(&gid) else {
             tracing::error!(%gid, "Couldn't find connection by ID");
@@ -470,13 +624,30 @@ impl ClientState {
             .inspect_err(|e| tracing::debug!(%gid, %local, %from, "{e}"))
             .ok()?;
 
-        let packet = maybe_mangle_dns_response_from_cidr_resource(
+        let (mut packet, mangled_query) = maybe_mangle_dns_response_from_cidr_resource(
             packet,
             &self.dns_mapping,
             &mut self.mangled_dns_queries,
             now,
         );
 
+        if self.node.traffic_class_propagation().ecn && !utils::propagate_ecn(&mut packet, ecn) {
+            tracing::debug!(%from, "Dropping packet without ECN support that experienced congestion");
+            return None;
+        }
+
+        if let Some(query) = mangled_query {
+            let response = packet.into_immutable();
+            self.audit_dns_response(&response, DnsResolution::Forwarded);
+
+            if query.tcp_query.is_some() {
+                self.tcp_dns_server.send_response(response);
+                return None;
+            }
+
+            return Some(response);
+        }
+
         Some(packet.into_immutable())
     }
 
@@ -488,12 +659,39 @@ impl ClientState {
         self.node.remove_remote_candidate(conn_id, ice_candidate);
     }
 
+    pub fn end_of_ice_candidates(&mut self, conn_id: GatewayId, now: Instant) {
+        self.node.end_of_remote_candidates(conn_id, now);
+    }
+
+    pub fn snapshot(&self, now: Instant) -> snownet::NodeSnapshot<GatewayId, RelayId> {
+        self.node.snapshot(now)
+    }
+
+    pub fn set_remote_ice_credentials(
+        &mut self,
+        conn_id: GatewayId,
+        credentials: IceCredentials,
+        now: Instant,
+    ) {
+        self.node.set_remote_credentials(
+            conn_id,
+            snownet::Credentials {
+                username: credentials.username,
+                password: credentials.password,
+            },
+            now,
+        );
+        self.drain_node_events();
+    }
+
     #[tracing::instrument(level = "trace", skip_all, fields(%resource_id))]
     pub fn accept_answer(
         &mut self,
         answer: snownet::Answer,
         resource_id: ResourceId,
         gateway: PublicKey,
+        supports_psk_rotation: bool,
+        supports_dns_resolution: bool,
         now: Instant,
     ) -> connlib_shared::Result<()> {
         debug_assert!(!self.awaiting_connection_details.contains_key(&resource_id));
@@ -502,6 +700,24 @@ impl ClientState {
             .gateway_by_resource(&resource_id)
             .ok_or(Error::UnknownResource)?;
 
+        if let Some(peer) = self.peers.get_mut(&gateway_id) {
+            peer.resolves_dns = supports_dns_resolution;
+        }
+        if !supports_dns_resolution {
+            self.forward_dns_queries_via_gateway_upstream(gateway_id);
+        }
+
+        // Older gateways ignore new preshared keys and would break the connection once we switch over.
+        if supports_psk_rotation {
+            self.node.set_connection_config(
+                gateway_id,
+                ConnectionConfig {
+                    psk_rotation: Some(PSK_ROTATION_INTERVAL),
+                    ..self.connection_config
+                },
+            );
+        }
+
         self.node.accept_answer(gateway_id, gateway, answer, now);
 
         Ok(())
@@ -584,30 +800,45 @@ impl ClientState {
     fn handle_dns<'a>(
         &mut self,
         packet: MutableIpPacket<'a>,
+        now: Instant,
     ) -> Result<Option<IpPacket<'a>>, (MutableIpPacket<'a>, IpAddr)> {
-        match self
-            .stub_resolver
-            .handle(&self.dns_mapping, packet.as_immutable())
+        if self
+            .tcp_dns_server
+            .handle(&self.dns_mapping, &packet.as_immutable(), now)
         {
-            Some(dns::ResolveStrategy::LocalResponse(query)) => Ok(Some(query)),
+            self.handle_tcp_dns_queries(now);
+
+            return Ok(None);
+        }
+
+        match self.dns_strategy(packet.as_immutable(), now) {
+            Some(dns::ResolveStrategy::LocalResponse(response)) => {
+                self.audit_dns_response(&response, DnsResolution::Local);
+
+                Ok(Some(response))
+            }
             Some(dns::ResolveStrategy::ForwardQuery(query)) => {
                 // There's an edge case here, where the resolver's ip has been resolved before as
                 // a dns resource... we will ignore that weird case for now.
                 // Assuming a single upstream dns until #3123 lands
-                if let Some(upstream_dns) = self.dns_mapping.get_by_left(&query.query.destination())
-                {
-                    let ip = upstream_dns.ip();
-
+                if let Some(ip) = self.upstream_dns_resource(&query) {
                     // In case the DNS server is a CIDR resource, it needs to go through the tunnel.
-                    if self.cidr_resources.longest_match(ip).is_some() {
-                        return Err((packet, ip));
-                    }
+                    return Err((packet, ip));
+                }
+
+                if let Some(response) = self.cached_dns_response(&query, now) {
+                    self.audit_dns_response(&response, DnsResolution::Cached);
+
+                    return Ok(Some(response));
                 }
 
                 self.buffered_dns_queries.push_back(query.into_owned());
 
                 Ok(None)
             }
+            Some(dns::ResolveStrategy::RecurseViaGateway { resource, query }) => {
+                Ok(self.recurse_via_gateway(resource, query, now))
+            }
             None => {
                 let dest = packet.destination();
                 Err((packet, dest))
@@ -615,6 +846,375 @@ impl ClientState {
         }
     }
 
+    /// Decides how to answer a DNS query, see [`StubResolver::handle`].
+    ///
+    /// Gateways that don't resolve DNS queries would drop the ones we send them, hence we forward those to our upstream resolvers instead.
+    fn dns_strategy<'p>(
+        &mut self,
+        packet: IpPacket<'p>,
+        now: Instant,
+    ) -> Option<dns::ResolveStrategy<'p>> {
+        let strategy = match self.stub_resolver.handle(&self.dns_mapping, packet, now)? {
+            dns::ResolveStrategy::RecurseViaGateway { resource, query }
+                if !self.gateway_resolves_dns(resource) =>
+            {
+                dns::ResolveStrategy::ForwardQuery(query)
+            }
+            strategy @ (dns::ResolveStrategy::LocalResponse(_)
+            | dns::ResolveStrategy::ForwardQuery(_)
+            | dns::ResolveStrategy::RecurseViaGateway { .. }) => strategy,
+        };
+
+        Some(strategy)
+    }
+
+    /// Whether the gateway of `resource` resolves DNS queries for it.
+    ///
+    /// If we don't have a gateway for the resource yet, we assume it does: [`ClientState::recurse_via_gateway`] then connects to it.
+    fn gateway_resolves_dns(&self, resource: ResourceId) -> bool {
+        self.resources_gateways
+            .get(&resource)
+            .and_then(|gid| self.peers.get(gid))
+            .map_or(true, |peer| peer.resolves_dns)
+    }
+
+    /// Resolves the queries the [`TcpDnsServer`] received, the same way as queries sent over UDP.
+    fn handle_tcp_dns_queries(&mut self, now: Instant) {
+        while let Some(packet) = self.tcp_dns_server.poll_query() {
+            match self.dns_strategy(packet.as_immutable(), now) {
+                Some(dns::ResolveStrategy::LocalResponse(response)) => {
+                    self.audit_dns_response(&response, DnsResolution::Local);
+                    self.tcp_dns_server.send_response(response);
+                }
+                Some(dns::ResolveStrategy::ForwardQuery(query)) => {
+                    if let Some(upstream) = self.upstream_dns_resource(&query) {
+                        if let Some(response) =
+                            self.forward_tcp_dns_query_to_resource(packet, upstream, now)
+                        {
+                            self.audit_dns_response(&response, DnsResolution::Local);
+                            self.tcp_dns_server.send_response(response);
+                        }
+                        continue;
+                    }
+
+                    if let Some(response) = self.cached_dns_response(&query, now) {
+                        self.audit_dns_response(&response, DnsResolution::Cached);
+                        self.tcp_dns_server.send_response(response);
+                        continue;
+                    }
+
+                    self.buffered_dns_queries.push_back(DnsQuery {
+                        transport: dns::Transport::Tcp,
+                        ..query.into_owned()
+                    });
+                }
+                Some(dns::ResolveStrategy::RecurseViaGateway { resource, query }) => {
+                    let query = DnsQuery {
+                        transport: dns::Transport::Tcp,
+                        ..query
+                    };
+
+                    if let Some(response) = self.recurse_via_gateway(resource, query, now) {
+                        self.tcp_dns_server.send_response(response);
+                    }
+                }
+                None => self.tcp_dns_server.discard_query(&packet.as_immutable()),
+            }
+        }
+    }
+
+    /// Sends a DNS query that we received over TCP through the tunnel to an upstream resolver that is a CIDR resource.
+    ///
+    /// Like the queries we receive over UDP, it is sent to the resolver as a UDP packet.
+    /// Returns a SERVFAIL response to send back if we cannot send the query to the gateway of the resource yet.
+    fn forward_tcp_dns_query_to_resource(
+        &mut self,
+        packet: MutableIpPacket<'static>,
+        upstream: IpAddr,
+        now: Instant,
+    ) -> Option<IpPacket<'static>> {
+        let query = packet.as_immutable().to_owned();
+        let servfail = || {
+            ip_packet::make::dns_err_response(
+                query.clone(),
+                hickory_proto::op::ResponseCode::ServFail,
+            )
+            .into_immutable()
+        };
+
+        let Some(resource) = self.get_resource_by_destination(upstream) else {
+            return Some(servfail());
+        };
+        let Some(peer) = peer_by_resource_mut(&self.resources_gateways, &mut self.peers, resource)
+        else {
+            self.on_not_connected_resource(resource, &upstream, now);
+
+            tracing::debug!(%upstream, "Not yet connected to gateway of upstream DNS server, failing DNS query");
+            return Some(servfail());
+        };
+        if peer.allowed_ips.longest_match(upstream).is_none() {
+            tracing::debug!(%upstream, "Upstream DNS server is not allowed by gateway, failing DNS query");
+            return Some(servfail());
+        }
+        let gid = peer.id();
+
+        let packet = maybe_mangle_dns_query_to_cidr_resource(
+            packet,
+            &self.dns_mapping,
+            &mut self.mangled_dns_queries,
+            Some(query.clone()),
+            now,
+        );
+
+        let Some(transmit) = self
+            .node
+            .encapsulate(gid, packet.as_immutable(), now)
+            .inspect_err(|e| tracing::debug!(%gid, "Failed to encapsulate: {e}"))
+            .ok()
+            .flatten()
+        else {
+            return Some(servfail());
+        };
+
+        self.buffered_transmits.push_back(transmit.into_owned());
+
+        None
+    }
+
+    /// Sends a DNS query for a record of a DNS resource through the tunnel to the gateway of the resource.
+    ///
+    /// Returns a SERVFAIL response to send back if we are not yet connected to the gateway.
+    fn recurse_via_gateway(
+        &mut self,
+        resource: ResourceId,
+        query: DnsQuery<'_>,
+        now: Instant,
+    ) -> Option<IpPacket<'static>> {
+        let Some(peer) = peer_by_resource_mut(&self.resources_gateways, &mut self.peers, resource)
+        else {
+            // The gateway authorizes the connection to a DNS resource for a specific name and needs our proxy IPs for it.
+            if let Some(proxy_ip) = self
+                .stub_resolver
+                .get_or_assign_ips(query.name.clone(), now)
+                .and_then(|ips| ips.first().copied())
+            {
+                self.on_not_connected_resource(resource, &proxy_ip, now);
+            }
+
+            tracing::debug!(name = %query.name, "Not yet connected to gateway of resource, failing DNS query");
+
+            let response = ip_packet::make::dns_err_response(
+                query.query.to_owned(),
+                hickory_proto::op::ResponseCode::ServFail,
+            )
+            .into_immutable();
+            self.audit_dns_response(&response, DnsResolution::Local);
+
+            return Some(response);
+        };
+        let gid = peer.id();
+        let key = (
+            SocketAddr::new(
+                query.query.source(),
+                query.query.unwrap_as_udp().get_source(),
+            ),
+            query.query.unwrap_as_dns().id(),
+        );
+
+        let query = query.into_owned();
+
+        let transmit = self
+            .node
+            .encapsulate(gid, query.query.clone(), now)
+            .inspect_err(|e| tracing::debug!(%gid, "Failed to encapsulate: {e}"))
+            .ok()??
+            .into_owned();
+
+        self.buffered_transmits.push_back(transmit);
+        self.dns_queries_via_gateway.insert(
+            key,
+            DnsQueryViaGateway {
+                gateway: gid,
+                query,
+                expires_at: now + IDS_EXPIRE,
+            },
+        );
+
+        None
+    }
+
+    /// Forwards the queries we sent to a gateway to our upstream resolvers instead, once we learn that it doesn't resolve DNS.
+    ///
+    /// Otherwise, the gateway would drop them and they'd never be answered.
+    fn forward_dns_queries_via_gateway_upstream(&mut self, gid: GatewayId) {
+        self.dns_queries_via_gateway.retain(|_, q| {
+            if q.gateway != gid {
+                return true;
+            }
+
+            tracing::debug!(name = %q.query.name, %gid, "Gateway doesn't resolve DNS, forwarding query upstream");
+            self.buffered_dns_queries.push_back(q.query.clone());
+
+            false
+        });
+    }
+
+    /// Handles the response to a query we sent via [`ClientState::recurse_via_gateway`].
+    ///
+    /// Returns `true` if the packet was such a response.
+    fn handle_dns_response_via_gateway(
+        &mut self,
+        gid: GatewayId,
+        packet: IpPacket<'_>,
+        now: Instant,
+    ) -> bool {
+        if self.dns_mapping.get_by_left(&packet.source()).is_none() {
+            return false;
+        }
+
+        let Some(key) = packet
+            .as_udp()
+            .filter(|udp| udp.get_source() == DNS_PORT)
+            .and_then(|udp| {
+                let message = domain::base::Message::from_slice(udp.payload()).ok()?;
+
+                message.header().qr().then(|| {
+                    (
+                        SocketAddr::new(packet.destination(), udp.get_destination()),
+                        message.header().id(),
+                    )
+                })
+            })
+        else {
+            return false;
+        };
+
+        let Entry::Occupied(entry) = self.dns_queries_via_gateway.entry(key) else {
+            return false;
+        };
+        if entry.get().gateway != gid {
+            return false;
+        }
+        let query = entry.remove();
+
+        let response = self.stub_resolver.map_gateway_response(packet, now);
+        self.forget_reclaimed_proxy_ips();
+
+        let Some(response) = response else {
+            tracing::debug!(%gid, "Failed to parse DNS response from gateway");
+            return true;
+        };
+        self.audit_dns_response(&response, DnsResolution::Gateway);
+
+        match query.query.transport {
+            dns::Transport::Udp => self.buffered_packets.push_back(response),
+            dns::Transport::Tcp => self.tcp_dns_server.send_response(response),
+        }
+
+        true
+    }
+
+    /// Answers a query that we would otherwise forward to an upstream resolver from the [`ResponseCache`].
+    fn cached_dns_response(
+        &mut self,
+        query: &DnsQuery<'_>,
+        now: Instant,
+    ) -> Option<IpPacket<'static>> {
+        let response = self.dns_cache.get(
+            query.query.destination(),
+            &query.name,
+            query.record_type,
+            now,
+        )?;
+
+        tracing::trace!(name = %query.name, "Answering DNS query from cache");
+
+        Some(dns::build_response_or_servfail(
+            query.query.to_owned(),
+            Ok(Ok(response)),
+        ))
+    }
+
+    /// Removes proxy IPs that the [`StubResolver`] reassigned to a different name from our peers.
+    ///
+    /// If we are already connected to the gateway of the new name's resource, we send it the new name and its proxy IPs right away, which replaces its translations of the reclaimed IPs.
+    /// Otherwise, the next packet to such an IP does that.
+    fn forget_reclaimed_proxy_ips(&mut self) {
+        let reclaimed = self.stub_resolver.poll_reclaimed_proxy_ips();
+        if reclaimed.is_empty() {
+            return;
+        }
+
+        self.peers
+            .remove_ips(&reclaimed.iter().copied().map_into().collect_vec());
+
+        let reassigned = reclaimed
+            .into_iter()
+            .filter_map(|ip| {
+                let (fqdn, _) = self.stub_resolver.get_fqdn(&ip)?;
+                let resource = self.stub_resolver.get_description(&ip)?.id;
+                let gateway = *self.resources_gateways.get(&resource)?;
+                self.peers.get(&gateway)?;
+
+                Some((fqdn.clone(), ip, resource, gateway))
+            })
+            .unique_by(|(fqdn, ..)| fqdn.clone())
+            .collect_vec();
+
+        for (_, ip, resource, gateway) in reassigned {
+            self.send_proxy_ips(&ip, resource, gateway);
+        }
+    }
+
+    pub(crate) fn set_dns_query_audit(&mut self, enabled: bool) {
+        self.audit_dns_queries = enabled;
+    }
+
+    pub(crate) fn restore_proxy_ips(
+        &mut self,
+        proxy_ips: HashMap<String, Vec<IpAddr>>,
+        now: Instant,
+    ) {
+        self.stub_resolver.restore_proxy_ips(proxy_ips, now);
+    }
+
+    fn audit_dns_response(&mut self, response: &IpPacket<'_>, resolution: DnsResolution) {
+        if !self.audit_dns_queries {
+            return;
+        }
+
+        let Some((name, record_type, response_code)) = dns::parse_response(response) else {
+            return;
+        };
+
+        self.buffered_events
+            .push_back(ClientEvent::DnsQueryAnswered {
+                query: callbacks::DnsQueryEvent {
+                    resource: self.stub_resolver.resource_id_by_name(&name),
+                    name: name.to_string(),
+                    record_type: record_type.to_string(),
+                    resolution,
+                    response_code: response_code.to_string(),
+                },
+            });
+    }
+
+    /// Returns the IP of the upstream resolver of this query if it is a CIDR resource.
+    ///
+    /// Encrypted resolvers are always queried by `Io`: The TLS session needs to be terminated on our side, we cannot forward the query as-is.
+    fn upstream_dns_resource(&self, query: &DnsQuery<'_>) -> Option<IpAddr> {
+        let DnsServer::IpPort(srv) = self.dns_mapping.get_by_left(&query.query.destination())?
+        else {
+            return None;
+        };
+        let ip = srv.address.ip();
+
+        self.cidr_resources
+            .longest_match(ip)
+            .is_some()
+            .then_some(ip)
+    }
+
     #[tracing::instrument(level = "debug", skip_all, fields(name = %query.name, server = %query.query.destination()))] // On debug level, we can log potentially sensitive information such as domain names.
     pub(crate) fn on_dns_result(
         &mut self,
@@ -626,35 +1226,26 @@ impl ClientState {
             >,
             DnsQueryError,
         >,
+        now: Instant,
     ) {
-        let query = query.query;
-        let make_error_reply = {
-            let query = query.clone();
-
-            |e: &dyn fmt::Display| {
-                // To avoid sensitive data getting into the logs, only log the error if debug logging is enabled.
-                // We always want to see a warning.
-                if tracing::enabled!(Level::DEBUG) {
-                    tracing::warn!("DNS query failed: {e}");
-                } else {
-                    tracing::warn!("DNS query failed");
-                };
-
-                ip_packet::make::dns_err_response(query, hickory_proto::op::ResponseCode::ServFail)
-                    .into_immutable()
-            }
-        };
+        if let Ok(Ok(response)) = &response {
+            self.dns_cache.insert(
+                query.query.destination(),
+                query.name.clone(),
+                query.record_type,
+                response,
+                now,
+            );
+        }
 
-        let dns_reply = match response {
-            Ok(Ok(response)) => match dns::build_response_from_resolve_result(query, response) {
-                Ok(dns_reply) => dns_reply,
-                Err(e) => make_error_reply(&e),
-            },
-            Ok(Err(timeout)) => make_error_reply(&timeout),
-            Err(e) => make_error_reply(&e),
-        };
+        let transport = query.transport;
+        let dns_reply = dns::build_response_or_servfail(query.query, response);
+        self.audit_dns_response(&dns_reply, DnsResolution::Forwarded);
 
-        self.buffered_packets.push_back(dns_reply);
+        match transport {
+            dns::Transport::Udp => self.buffered_packets.push_back(dns_reply),
+            dns::Transport::Tcp => self.tcp_dns_server.send_response(dns_reply),
+        }
     }
 
     pub fn on_connection_failed(&mut self, resource: ResourceId) {
@@ -728,6 +1319,8 @@ impl ClientState {
     fn set_dns_mapping(&mut self, new_mapping: BiMap<IpAddr, DnsServer>) {
         self.dns_mapping = new_mapping;
         self.mangled_dns_queries.clear();
+        self.dns_queries_via_gateway.clear();
+        self.dns_cache.clear();
     }
 
     pub fn dns_mapping(&self) -> BiMap<IpAddr, DnsServer> {
@@ -773,13 +1366,18 @@ impl ClientState {
 
     #[must_use]
     pub(crate) fn update_interface_config(&mut self, config: InterfaceConfig) -> bool {
+        self.stub_resolver.set_resource_ttl(config.dns_resource_ttl);
+        self.stub_resolver
+            .set_search_domains(&config.search_domains);
         self.interface_config = Some(config);
 
         self.update_dns_mapping()
     }
 
     pub fn poll_packets(&mut self) -> Option<IpPacket<'static>> {
-        self.buffered_packets.pop_front()
+        self.buffered_packets
+            .pop_front()
+            .or_else(|| self.tcp_dns_server.poll_segment())
     }
 
     pub fn poll_dns_queries(&mut self) -> Option<DnsQuery<'static>> {
@@ -787,17 +1385,37 @@ impl ClientState {
     }
 
     pub fn poll_timeout(&mut self) -> Option<Instant> {
-        // The number of mangled DNS queries is expected to be fairly small because we only track them whilst connecting to a CIDR resource that is a DNS server.
+        // The number of tracked DNS queries is expected to be fairly small because we only track them whilst connecting to a CIDR resource that is a DNS server or whilst a gateway resolves them.
         // Thus, sorting these values on-demand even within `poll_timeout` is expected to be performant enough.
-        let next_dns_query_expiry = self.mangled_dns_queries.values().min().copied();
+        let next_dns_query_expiry = self
+            .mangled_dns_queries
+            .values()
+            .map(|q| q.expires_at)
+            .chain(self.dns_queries_via_gateway.values().map(|q| q.expires_at))
+            .min();
         let next_node_timeout = self.node.poll_timeout();
+        let next_tcp_dns_timeout = self.tcp_dns_server.poll_timeout();
 
-        earliest(next_dns_query_expiry, next_node_timeout)
+        earliest(
+            earliest(next_dns_query_expiry, next_node_timeout),
+            next_tcp_dns_timeout,
+        )
     }
 
     pub fn handle_timeout(&mut self, now: Instant) {
         self.node.handle_timeout(now);
-        self.mangled_dns_queries.retain(|_, exp| now < *exp);
+        self.mangled_dns_queries.retain(|_, q| {
+            let expired = now >= q.expires_at;
+
+            if let Some(query) = q.tcp_query.as_ref().filter(|_| expired) {
+                self.tcp_dns_server.discard_query(query);
+            }
+
+            !expired
+        });
+        self.dns_queries_via_gateway
+            .retain(|_, q| now < q.expires_at);
+        self.tcp_dns_server.handle_timeout(now);
 
         self.drain_node_events();
     }
@@ -806,11 +1424,14 @@ impl ClientState {
         let mut resources_changed = false; // Track this separately to batch together `ResourcesChanged` events.
         let mut added_ice_candidates = HashMap::<GatewayId, HashSet<String>>::default();
         let mut removed_ice_candidates = HashMap::<GatewayId, HashSet<String>>::default();
+        let mut end_of_ice_candidates = HashSet::<GatewayId>::default();
+        let mut new_ice_credentials = HashMap::<GatewayId, IceCredentials>::default();
 
         while let Some(event) = self.node.poll_event() {
             match event {
                 snownet::Event::ConnectionFailed(id) | snownet::Event::ConnectionClosed(id) => {
                     self.cleanup_connected_gateway(&id);
+                    self.tun_mtu.on_connection_removed(&id);
                     resources_changed = true;
                 }
                 snownet::Event::NewIceCandidate {
@@ -831,6 +1452,43 @@ impl ClientState {
                         .or_default()
                         .insert(candidate);
                 }
+                snownet::Event::EndOfIceCandidates { connection } => {
+                    added_ice_candidates.entry(connection).or_default();
+                    end_of_ice_candidates.insert(connection);
+                }
+                snownet::Event::NewIceCredentials {
+                    connection,
+                    credentials,
+                } => {
+                    new_ice_credentials.insert(
+                        connection,
+                        IceCredentials {
+                            username: credentials.username,
+                            password: credentials.password,
+                        },
+                    );
+                }
+                snownet::Event::NewPresharedKey { connection, key } => {
+                    self.buffered_events
+                        .push_back(ClientEvent::NewPresharedKey {
+                            conn_id: connection,
+                            key,
+                        });
+                }
+                snownet::Event::PathMtuChanged { connection, mtu } => {
+                    tracing::info!(gateway = %connection, %mtu, "Path MTU changed");
+                    self.tun_mtu.on_path_mtu_changed(connection, mtu);
+                }
+                snownet::Event::NatStatusChanged(status) => {
+                    tracing::info!(ipv4 = ?status.ipv4, ipv6 = ?status.ipv6, "NAT status changed");
+                }
+                snownet::Event::ConnectionPathChanged {
+                    connection,
+                    from,
+                    to,
+                } => {
+                    tracing::info!(gateway = %connection, ?from, ?to, "Connection changed its network path");
+                }
                 snownet::Event::ConnectionEstablished(id) => {
                     self.update_site_status_by_gateway(&id, Status::Online);
                     resources_changed = true;
@@ -845,11 +1503,21 @@ impl ClientState {
                 });
         }
 
+        // New credentials must be signalled before the candidates that belong to them.
+        for (conn_id, credentials) in new_ice_credentials.drain() {
+            self.buffered_events
+                .push_back(ClientEvent::NewIceCredentials {
+                    conn_id,
+                    credentials,
+                })
+        }
+
         for (conn_id, candidates) in added_ice_candidates.drain() {
             self.buffered_events
                 .push_back(ClientEvent::AddedIceCandidates {
                     conn_id,
                     candidates,
+                    end_of_candidates: end_of_ice_candidates.contains(&conn_id),
                 })
         }
 
@@ -874,18 +1542,45 @@ impl ClientState {
     }
 
     pub(crate) fn poll_event(&mut self) -> Option<ClientEvent> {
+        if self.stub_resolver.poll_proxy_ips_changed() {
+            self.buffered_events
+                .push_back(ClientEvent::ProxyIpsChanged {
+                    proxy_ips: self.stub_resolver.proxy_ips(),
+                });
+        }
+
         self.buffered_events.pop_front()
     }
 
-    pub(crate) fn reset(&mut self) {
+    pub(crate) fn set_connection_config(&mut self, config: ConnectionConfig) {
+        self.connection_config = config;
+        self.node.set_default_connection_config(config);
+    }
+
+    /// Limits how many datagrams we coalesce for UDP GSO to what our sockets support.
+    pub(crate) fn set_max_gso_segments(&mut self, segments: usize) {
+        self.node.set_max_gso_segments(segments);
+    }
+
+    /// Resets the network state after a change in network connectivity.
+    ///
+    /// Instead of closing all connections, we restart ICE which allows us to keep the wireguard sessions alive.
+    pub(crate) fn reset(&mut self, now: Instant) {
         tracing::info!("Resetting network state");
 
-        self.node.reset();
+        self.node.ice_restart(now);
         self.drain_node_events();
     }
 
+    /// Returns the new MTU of the TUN device if it changed.
+    pub(crate) fn poll_tun_mtu(&mut self) -> Option<u16> {
+        self.tun_mtu.poll_change()
+    }
+
     pub(crate) fn poll_transmit(&mut self) -> Option<snownet::Transmit<'static>> {
-        self.node.poll_transmit()
+        self.buffered_transmits
+            .pop_front()
+            .or_else(|| self.node.poll_transmit())
     }
 
     /// Sets a new set of resources.
@@ -947,10 +1642,14 @@ impl ClientState {
             self.resource_ids
                 .insert(resource_description.id(), resource_description.clone());
         }
+
+        self.dns_cache.clear();
     }
 
     #[tracing::instrument(level = "debug", skip_all, fields(?ids))]
     pub(crate) fn remove_resources(&mut self, ids: &[ResourceId]) {
+        self.dns_cache.clear();
+
         for id in ids {
             self.awaiting_connection_details.remove(id);
             self.stub_resolver.remove_resource(*id);
@@ -1041,6 +1740,14 @@ impl ClientState {
     ) {
         self.node.update_relays(to_remove, &to_add, now);
     }
+
+    /// Discovers the prefix of the NAT64 on our network, see [`snownet::Node::discover_nat64_prefix`].
+    pub fn discover_nat64_prefix(
+        &mut self,
+        lookup: impl FnOnce(&str) -> std::io::Result<Vec<Ipv6Addr>>,
+    ) {
+        self.node.discover_nat64_prefix(lookup);
+    }
 }
 
 fn peer_by_resource_mut<'p>(
@@ -1152,15 +1859,18 @@ fn is_definitely_not_a_resource(ip: IpAddr) -> bool {
 }
 
 /// In case the given packet is a DNS query, change its source IP to that of the actual DNS server.
+///
+/// `tcp_query` is the query as the [`TcpDnsServer`] handed it to us, if we received it over TCP.
 fn maybe_mangle_dns_query_to_cidr_resource<'p>(
     mut packet: MutableIpPacket<'p>,
     dns_mapping: &BiMap<IpAddr, DnsServer>,
-    mangeled_dns_queries: &mut HashMap<u16, Instant>,
+    mangeled_dns_queries: &mut HashMap<u16, MangledDnsQuery>,
+    tcp_query: Option<IpPacket<'static>>,
     now: Instant,
 ) -> MutableIpPacket<'p> {
     let dst = packet.destination();
 
-    let Some(srv) = dns_mapping.get_by_left(&dst) else {
+    let Some(DnsServer::IpPort(srv)) = dns_mapping.get_by_left(&dst) else {
         return packet;
     };
 
@@ -1172,43 +1882,50 @@ fn maybe_mangle_dns_query_to_cidr_resource<'p>(
         return packet;
     };
 
-    tracing::trace!(old_dst = %dst, new_dst = %srv.ip(), "Mangling DNS query to CIDR resource");
+    tracing::trace!(old_dst = %dst, new_dst = %srv.address.ip(), "Mangling DNS query to CIDR resource");
 
-    mangeled_dns_queries.insert(message.header().id(), now + IDS_EXPIRE);
-    packet.set_dst(srv.ip());
+    mangeled_dns_queries.insert(
+        message.header().id(),
+        MangledDnsQuery {
+            expires_at: now + IDS_EXPIRE,
+            tcp_query,
+        },
+    );
+    packet.set_dst(srv.address.ip());
     packet.update_checksum();
 
     packet
 }
 
+/// In case the given packet is a response to a query we mangled, change its source IP back to that of the sentinel.
+///
+/// Returns the mangled query the packet answers, if any.
 fn maybe_mangle_dns_response_from_cidr_resource<'p>(
     mut packet: MutableIpPacket<'p>,
     dns_mapping: &BiMap<IpAddr, DnsServer>,
-    mangeled_dns_queries: &mut HashMap<u16, Instant>,
+    mangeled_dns_queries: &mut HashMap<u16, MangledDnsQuery>,
     now: Instant,
-) -> MutableIpPacket<'p> {
+) -> (MutableIpPacket<'p>, Option<MangledDnsQuery>) {
     let src_ip = packet.source();
 
     let Some(udp) = packet.as_udp() else {
-        return packet;
+        return (packet, None);
     };
 
     let src_port = udp.get_source();
 
     let Some(sentinel) = dns_mapping.get_by_right(&DnsServer::from((src_ip, src_port))) else {
-        return packet;
+        return (packet, None);
     };
 
     let Ok(message) = domain::base::Message::from_slice(udp.payload()) else {
-        return packet;
+        return (packet, None);
     };
 
-    let Some(query_sent_at) = mangeled_dns_queries
-        .remove(&message.header().id())
-        .map(|expires_at| expires_at - IDS_EXPIRE)
-    else {
-        return packet;
+    let Some(query) = mangeled_dns_queries.remove(&message.header().id()) else {
+        return (packet, None);
     };
+    let query_sent_at = query.expires_at - IDS_EXPIRE;
 
     let rtt = now.duration_since(query_sent_at);
 
@@ -1222,12 +1939,23 @@ fn maybe_mangle_dns_response_from_cidr_resource<'p>(
     packet.set_src(*sentinel);
     packet.update_checksum();
 
-    packet
+    (packet, Some(query))
 }
 
 pub struct IpProvider {
     ipv4: Box<dyn Iterator<Item = Ipv4Addr> + Send + Sync>,
     ipv6: Box<dyn Iterator<Item = Ipv6Addr> + Send + Sync>,
+    /// Addresses that were handed out before and have since been released, see [`IpProvider::release`].
+    released_ipv4: VecDeque<Ipv4Addr>,
+    released_ipv6: VecDeque<Ipv6Addr>,
+    /// Addresses that are in use without us having handed them out, see [`IpProvider::reserve`].
+    ///
+    /// Entries are removed once our iterators skip over them.
+    reserved: HashSet<IpAddr>,
+
+    ipv4_network: Ipv4Network,
+    ipv6_network: Ipv6Network,
+    exclusions: Vec<IpNetwork>,
 }
 
 impl IpProvider {
@@ -1250,7 +1978,7 @@ impl IpProvider {
         )
     }
 
-    fn new(ipv4: Ipv4Network, ipv6: Ipv6Network, exclusions: Vec<IpNetwork>) -> Self {
+    pub(crate) fn new(ipv4: Ipv4Network, ipv6: Ipv6Network, exclusions: Vec<IpNetwork>) -> Self {
         Self {
             ipv4: Box::new({
                 let exclusions = exclusions.clone();
@@ -1258,10 +1986,17 @@ impl IpProvider {
                     .filter(move |ip| !exclusions.iter().any(|e| e.contains(*ip)))
             }),
             ipv6: Box::new({
+                let exclusions = exclusions.clone();
                 ipv6.subnets_with_prefix(128)
                     .map(|ip| ip.network_address())
                     .filter(move |ip| !exclusions.iter().any(|e| e.contains(*ip)))
             }),
+            released_ipv4: VecDeque::new(),
+            released_ipv6: VecDeque::new(),
+            reserved: HashSet::new(),
+            ipv4_network: ipv4,
+            ipv6_network: ipv6,
+            exclusions,
         }
     }
 
@@ -1280,12 +2015,63 @@ impl IpProvider {
         proxy_ip
     }
 
+    /// Returns up to `n` IPv4 addresses, preferring released ones over ones that were never handed out.
     pub fn get_n_ipv4(&mut self, n: usize) -> Vec<IpAddr> {
-        self.ipv4.by_ref().take(n).map_into().collect_vec()
+        let num_released = self.released_ipv4.len().min(n);
+        let reserved = &mut self.reserved;
+
+        self.released_ipv4
+            .drain(..num_released)
+            .chain(
+                self.ipv4
+                    .by_ref()
+                    .filter(|ip| !reserved.remove(&IpAddr::V4(*ip))),
+            )
+            .take(n)
+            .map_into()
+            .collect_vec()
     }
 
+    /// Returns up to `n` IPv6 addresses, preferring released ones over ones that were never handed out.
     pub fn get_n_ipv6(&mut self, n: usize) -> Vec<IpAddr> {
-        self.ipv6.by_ref().take(n).map_into().collect_vec()
+        let num_released = self.released_ipv6.len().min(n);
+        let reserved = &mut self.reserved;
+
+        self.released_ipv6
+            .drain(..num_released)
+            .chain(
+                self.ipv6
+                    .by_ref()
+                    .filter(|ip| !reserved.remove(&IpAddr::V6(*ip))),
+            )
+            .take(n)
+            .map_into()
+            .collect_vec()
+    }
+
+    /// Marks an address as in use, such that we never hand it out, e.g. because we restored it from a previous session.
+    ///
+    /// Returns `false` if the address is not one we hand out or is already reserved.
+    pub fn reserve(&mut self, ip: IpAddr) -> bool {
+        let is_in_range = match ip {
+            IpAddr::V4(ip) => self.ipv4_network.contains(ip),
+            IpAddr::V6(ip) => self.ipv6_network.contains(ip),
+        };
+        if !is_in_range || self.exclusions.iter().any(|e| e.contains(ip)) {
+            return false;
+        }
+
+        self.reserved.insert(ip)
+    }
+
+    /// Makes addresses that are no longer in use available again.
+    pub fn release(&mut self, ips: impl IntoIterator<Item = IpAddr>) {
+        for ip in ips {
+            match ip {
+                IpAddr::V4(ip) => self.released_ipv4.push_back(ip),
+                IpAddr::V6(ip) => self.released_ipv6.push_back(ip),
+            }
+        }
     }
 }
 
@@ -1463,6 +2249,46 @@ mod tests {
         )
     }
 
+    #[test]
+    fn audits_dns_queries_only_when_enabled() {
+        let mut client_state = ClientState::new(
+            StaticSecret::random_from_rng(OsRng),
+            HashMap::from([("api.firezone.dev".to_owned(), vec![ip("93.184.216.34")])]),
+        );
+        let _ = client_state.update_interface_config(interface_config_with_dns());
+        let sentinel = *client_state
+            .dns_mapping()
+            .get_by_right(&dns("1.1.1.1:53"))
+            .unwrap();
+
+        let _ = client_state.encapsulate(known_host_query(sentinel), Instant::now());
+        assert!(!std::iter::from_fn(|| client_state.poll_event())
+            .any(|e| matches!(e, ClientEvent::DnsQueryAnswered { .. })));
+
+        client_state.set_dns_query_audit(true);
+        let _ = client_state.encapsulate(known_host_query(sentinel), Instant::now());
+
+        let query = std::iter::from_fn(|| client_state.poll_event())
+            .find_map(|e| {
+                if let ClientEvent::DnsQueryAnswered { query } = e {
+                    Some(query)
+                } else {
+                    None
+                }
+            })
+            .unwrap();
+        assert_eq!(
+            query,
+            callbacks::DnsQueryEvent {
+                name: "api.firezone.dev".to_owned(),
+                record_type: "A".to_owned(),
+                resource: None,
+                resolution: DnsResolution::Local,
+                response_code: "NOERROR".to_owned(),
+            }
+        );
+    }
+
     impl ClientState {
         pub fn for_test() -> ClientState {
             ClientState::new(StaticSecret::random_from_rng(OsRng), HashMap::new())
@@ -1481,6 +2307,8 @@ mod tests {
             ipv4: "10.0.0.1".parse().unwrap(),
             ipv6: "fe80::".parse().unwrap(),
             upstream_dns: Vec::new(),
+            dns_resource_ttl: None,
+            search_domains: vec![],
         }
     }
 
@@ -1489,6 +2317,8 @@ mod tests {
             ipv4: "10.0.0.1".parse().unwrap(),
             ipv6: "fe80::".parse().unwrap(),
             upstream_dns: dns_list(),
+            dns_resource_ttl: None,
+            search_domains: vec![],
         }
     }
 
@@ -1513,6 +2343,16 @@ mod tests {
         })
     }
 
+    fn known_host_query(sentinel: IpAddr) -> MutableIpPacket<'static> {
+        ip_packet::make::dns_query(
+            "api.firezone.dev".parse().unwrap(),
+            hickory_proto::rr::RecordType::A,
+            SocketAddr::new(ip("10.0.0.1"), 5353),
+            SocketAddr::new(sentinel, 53),
+            0,
+        )
+    }
+
     fn ip(addr: &str) -> IpAddr {
         addr.parse().unwrap()
     }
@@ -30,6 +30,8 @@ pub use tun::Tun;
 
 pub struct Device {
     tun: Option<Tun>,
+    /// The MTU to apply to the TUN device, see [`Device::set_mtu`].
+    mtu: Option<u16>,
     waker: Option<Waker>,
 }
 
@@ -37,6 +39,7 @@ impl Device {
     pub(crate) fn new() -> Self {
         Self {
             tun: None,
+            mtu: None,
             waker: None,
         }
     }
@@ -44,6 +47,12 @@ impl Device {
     pub(crate) fn set_tun(&mut self, tun: Tun) {
         tracing::info!(name = %tun.name(), "Initializing TUN device");
 
+        if let Some(mtu) = self.mtu {
+            if let Err(e) = set_mtu(&tun, mtu) {
+                tracing::warn!(%mtu, "Failed to set MTU of TUN device: {e}");
+            }
+        }
+
         self.tun = Some(tun);
 
         if let Some(waker) = self.waker.take() {
@@ -128,11 +137,38 @@ impl Device {
         }
     }
 
+    /// Sets the MTU of the TUN device, following the path MTUs of our connections.
+    pub(crate) fn set_mtu(&mut self, mtu: u16) -> io::Result<()> {
+        self.mtu = Some(mtu);
+
+        let Some(tun) = self.tun.as_ref() else {
+            return Ok(()); // We apply it once we have a TUN device.
+        };
+
+        tracing::info!(%mtu, "Setting MTU of TUN device");
+
+        set_mtu(tun, mtu)
+    }
+
     fn tun(&self) -> io::Result<&Tun> {
         self.tun.as_ref().ok_or_else(io_error_not_initialized)
     }
 }
 
+#[cfg(any(target_os = "linux", target_os = "windows"))]
+fn set_mtu(tun: &Tun, mtu: u16) -> io::Result<()> {
+    tun.set_mtu(mtu)
+}
+
+/// On Android and Apple platforms, the VPN framework of the OS owns the interface and we cannot change its MTU.
+///
+/// It stays at [`DEFAULT_MTU`](connlib_shared::DEFAULT_MTU) there.
+/// That is safe because it is the minimum MTU of IPv6 and thus never larger than any path MTU we discover.
+#[cfg(any(target_os = "android", target_os = "macos", target_os = "ios"))]
+fn set_mtu(_: &Tun, _: u16) -> io::Result<()> {
+    Ok(())
+}
+
 fn io_error_not_initialized() -> io::Error {
     io::Error::new(io::ErrorKind::NotConnected, "device is not initialized yet")
 }
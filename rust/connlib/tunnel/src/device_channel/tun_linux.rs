@@ -10,7 +10,7 @@ use std::{
     ffi::CStr,
     fs, io,
     os::{
-        fd::{AsRawFd, RawFd},
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
         unix::fs::PermissionsExt,
     },
 };
@@ -85,6 +85,24 @@ impl Tun {
     pub fn name(&self) -> &str {
         IFACE_NAME
     }
+
+    /// Sets the MTU of the interface.
+    pub fn set_mtu(&self, mtu: u16) -> io::Result<()> {
+        // The MTU of an interface can only be set through a socket, not the TUN device itself.
+        let socket = match unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) } {
+            -1 => return Err(get_last_error()),
+            fd => unsafe { OwnedFd::from_raw_fd(fd) }, // Safety: We just opened the socket.
+        };
+
+        // Safety: The socket is open until the end of this function.
+        unsafe {
+            ioctl::exec(
+                socket.as_raw_fd(),
+                libc::SIOCSIFMTU,
+                &mut ioctl::Request::<SetMtuPayload>::new(mtu),
+            )
+        }
+    }
 }
 
 fn get_last_error() -> io::Error {
@@ -146,14 +164,8 @@ fn write(fd: RawFd, buf: &[u8]) -> io::Result<usize> {
 
 impl ioctl::Request<SetTunFlagsPayload> {
     fn new() -> Self {
-        let name_as_bytes = IFACE_NAME.as_bytes();
-        debug_assert!(name_as_bytes.len() < libc::IF_NAMESIZE);
-
-        let mut name = [0u8; libc::IF_NAMESIZE];
-        name[..name_as_bytes.len()].copy_from_slice(name_as_bytes);
-
         Self {
-            name,
+            name: iface_name(),
             payload: SetTunFlagsPayload {
                 flags: (IFF_TUN | IFF_NO_PI) as _,
             },
@@ -161,7 +173,33 @@ impl ioctl::Request<SetTunFlagsPayload> {
     }
 }
 
+impl ioctl::Request<SetMtuPayload> {
+    fn new(mtu: u16) -> Self {
+        Self {
+            name: iface_name(),
+            payload: SetMtuPayload {
+                mtu: std::ffi::c_int::from(mtu),
+            },
+        }
+    }
+}
+
+fn iface_name() -> [u8; libc::IF_NAMESIZE] {
+    let name_as_bytes = IFACE_NAME.as_bytes();
+    debug_assert!(name_as_bytes.len() < libc::IF_NAMESIZE);
+
+    let mut name = [0u8; libc::IF_NAMESIZE];
+    name[..name_as_bytes.len()].copy_from_slice(name_as_bytes);
+
+    name
+}
+
 #[repr(C)]
 struct SetTunFlagsPayload {
     flags: std::ffi::c_short,
 }
+
+#[repr(C)]
+struct SetMtuPayload {
+    mtu: std::ffi::c_int,
+}
@@ -1,7 +1,6 @@
-use crate::MTU;
 use connlib_shared::{
     windows::{CREATE_NO_WINDOW, TUNNEL_NAME},
-    Result,
+    Result, DEFAULT_MTU,
 };
 use std::{
     io,
@@ -39,6 +38,7 @@ pub struct Tun {
     /// The index of our network adapter, we can use this when asking Windows to add / remove routes / DNS rules
     /// It's stable across app restarts and I'm assuming across system reboots too.
     iface_idx: u32,
+    luid: NET_LUID_LH,
     packet_rx: mpsc::Receiver<wintun::Packet>,
     recv_thread: Option<std::thread::JoinHandle<()>>,
     session: Arc<wintun::Session>,
@@ -93,7 +93,14 @@ impl Tun {
             .stdout(Stdio::null())
             .status()?;
 
-        set_iface_config(adapter.get_luid(), MTU as u32)?;
+        // SAFETY: Both NET_LUID_LH unions should be the same. We're just copying out
+        // the u64 value and re-wrapping it, since wintun doesn't refer to the windows
+        // crate's version of NET_LUID_LH.
+        let luid = NET_LUID_LH {
+            Value: unsafe { adapter.get_luid().Value },
+        };
+
+        set_iface_config(luid, DEFAULT_MTU)?;
 
         let session = Arc::new(adapter.start_session(RING_BUFFER_SIZE)?);
         // 4 is a nice power of two. Wintun already queues packets for us, so we don't
@@ -103,6 +110,7 @@ impl Tun {
 
         Ok(Self {
             iface_idx,
+            luid,
             recv_thread: Some(recv_thread),
             packet_rx,
             session: Arc::clone(&session),
@@ -113,6 +121,11 @@ impl Tun {
         self.iface_idx
     }
 
+    /// Sets the IPv4 and IPv6 MTU of the interface.
+    pub fn set_mtu(&self, mtu: u16) -> io::Result<()> {
+        set_iface_config(self.luid, u32::from(mtu)).map_err(io::Error::other)
+    }
+
     // Moves packets from the user towards the Internet
     pub fn poll_read(&mut self, buf: &mut [u8], cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
         let pkt = ready!(self.packet_rx.poll_recv(cx));
@@ -211,14 +224,7 @@ fn start_recv_thread(
 
 /// Sets MTU on the interface
 /// TODO: Set IP and other things in here too, so the code is more organized
-fn set_iface_config(luid: wintun::NET_LUID_LH, mtu: u32) -> Result<()> {
-    // SAFETY: Both NET_LUID_LH unions should be the same. We're just copying out
-    // the u64 value and re-wrapping it, since wintun doesn't refer to the windows
-    // crate's version of NET_LUID_LH.
-    let luid = NET_LUID_LH {
-        Value: unsafe { luid.Value },
-    };
-
+fn set_iface_config(luid: NET_LUID_LH, mtu: u32) -> Result<()> {
     // Set MTU for IPv4
     {
         let mut row = MIB_IPINTERFACE_ROW {
@@ -1,11 +1,13 @@
 use crate::client::IpProvider;
+use crate::io::DnsQueryError;
 use connlib_shared::messages::client::ResourceDescriptionDns;
 use connlib_shared::messages::{DnsServer, ResourceId};
 use connlib_shared::DomainName;
+use domain::base::message_builder::RecordSectionBuilder;
 use domain::base::RelativeName;
 use domain::base::{
     iana::{Class, Rcode, Rtype},
-    Message, MessageBuilder, ToName,
+    Message, MessageBuilder, ParsedName, RecordSection, ToName,
 };
 use domain::rdata::AllRecordData;
 use hickory_resolver::lookup::Lookup;
@@ -16,16 +18,42 @@ use ip_packet::udp::UdpPacket;
 use ip_packet::Packet as _;
 use ip_packet::{udp::MutableUdpPacket, IpPacket, MutableIpPacket, MutablePacket, PacketSize};
 use itertools::Itertools;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-
-const DNS_TTL: u32 = 1;
+use std::time::{Duration, Instant};
+use tracing::Level;
+
+mod cache;
+mod tcp;
+mod tls;
+
+pub(crate) use cache::ResponseCache;
+pub(crate) use tcp::TcpDnsServer;
+pub(crate) use tls::{https_client_config, tls_client_config, Error as TlsError};
+
+/// The TTL of the records we synthesise for DNS resources, unless the portal configures a different one.
+///
+/// Our proxy IPs don't change while they are in use, so there is no need for clients to re-query them constantly.
+const DEFAULT_RESOURCE_TTL: u32 = 60;
+/// The TTL of the records of known hosts, these never change while connlib is running.
+const KNOWN_HOSTS_TTL: u32 = 300;
+/// The number of IPv4 and IPv6 proxy IPs we assign to each name of a DNS resource.
+const PROXY_IPS_PER_FAMILY: usize = 4;
+/// How long the proxy IPs of a name must not have been queried or used before we may reassign them to another name.
+///
+/// Applications and OS caches may hold on to resolved addresses for longer than the TTL we hand out, so we are generous here.
+const PROXY_IPS_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
 const UDP_HEADER_SIZE: usize = 8;
 const REVERSE_DNS_ADDRESS_END: &str = "arpa";
 const REVERSE_DNS_ADDRESS_V4: &str = "in-addr";
 const REVERSE_DNS_ADDRESS_V6: &str = "ip6";
 const DNS_PORT: u16 = 53;
 
+/// Record types we cannot synthesize for DNS resources and instead resolve on the gateway that serves the resource.
+pub(crate) const GATEWAY_RECORD_TYPES: [Rtype; 4] =
+    [Rtype::SRV, Rtype::TXT, Rtype::CNAME, Rtype::MX];
+
 /// Tells the Client how to reply to a single DNS query
 #[derive(Debug)]
 pub(crate) enum ResolveStrategy<'a> {
@@ -33,6 +61,11 @@ pub(crate) enum ResolveStrategy<'a> {
     LocalResponse(IpPacket<'static>),
     /// The query is for a non-Resource, forward it to an upstream or system resolver
     ForwardQuery(DnsQuery<'a>),
+    /// The query is for a record of a Resource we cannot synthesize, send it to the gateway of the Resource.
+    RecurseViaGateway {
+        resource: ResourceId,
+        query: DnsQuery<'a>,
+    },
 }
 
 #[derive(Debug)]
@@ -42,6 +75,15 @@ pub struct DnsQuery<'a> {
     // We could be much more efficient with this field,
     // we only need the header to create the response.
     pub query: ip_packet::IpPacket<'a>,
+    /// How the query reached us, we forward it upstream via the same transport.
+    pub transport: Transport,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    /// The query was sent over TCP and `query` is a UDP packet synthesized by [`TcpDnsServer`].
+    Tcp,
 }
 
 impl<'a> DnsQuery<'a> {
@@ -50,6 +92,7 @@ impl<'a> DnsQuery<'a> {
             name,
             record_type,
             query,
+            transport,
         } = self;
         let buf = query.packet().to_vec();
         let query = ip_packet::IpPacket::owned(buf)
@@ -59,6 +102,7 @@ impl<'a> DnsQuery<'a> {
             name,
             record_type,
             query,
+            transport,
         }
     }
 }
@@ -69,6 +113,7 @@ impl Clone for DnsQuery<'static> {
             name: self.name.clone(),
             record_type: self.record_type,
             query: self.query.clone(),
+            transport: self.transport,
         }
     }
 }
@@ -119,11 +164,25 @@ impl KnownHosts {
 pub struct StubResolver {
     fqdn_to_ips: HashMap<DomainName, Vec<IpAddr>>,
     ips_to_fqdn: HashMap<IpAddr, DomainName>,
+    /// When a name with proxy IPs was last queried or sent traffic to.
+    ///
+    /// Once we run out of proxy IPs, we reclaim the ones of the least recently used name.
+    proxy_ips_last_used: HashMap<DomainName, Instant>,
+    /// The names in [`StubResolver::proxy_ips_last_used`], ordered by when they were last used.
+    proxy_ips_by_last_use: BTreeSet<(Instant, DomainName)>,
+    /// Proxy IPs we reassigned to a different name since the last call to [`StubResolver::poll_reclaimed_proxy_ips`].
+    reclaimed_proxy_ips: Vec<IpAddr>,
+    /// Whether we assigned or reclaimed proxy IPs since the last call to [`StubResolver::poll_proxy_ips_changed`].
+    proxy_ips_changed: bool,
     ip_provider: IpProvider,
     /// All DNS resources we know about, indexed by their domain (could be wildcard domain like `*.mycompany.com`).
     dns_resources: HashMap<String, ResourceDescriptionDns>,
     /// Fixed dns name that will be resolved to fixed ip addrs, similar to /etc/hosts
     known_hosts: KnownHosts,
+    /// The TTL of the records we synthesise for DNS resources and their proxy IPs.
+    resource_ttl: u32,
+    /// Domains that single-label names are resolved relative to, in order of preference.
+    search_domains: Vec<DomainName>,
 }
 
 fn fqdn_to_ips_for_known_hosts(
@@ -154,12 +213,36 @@ impl StubResolver {
         StubResolver {
             fqdn_to_ips: Default::default(),
             ips_to_fqdn: Default::default(),
+            proxy_ips_last_used: Default::default(),
+            proxy_ips_by_last_use: Default::default(),
+            reclaimed_proxy_ips: Default::default(),
+            proxy_ips_changed: false,
             ip_provider: IpProvider::for_resources(),
             dns_resources: Default::default(),
             known_hosts: KnownHosts::new(known_hosts),
+            resource_ttl: DEFAULT_RESOURCE_TTL,
+            search_domains: Default::default(),
         }
     }
 
+    pub(crate) fn set_search_domains(&mut self, search_domains: &[String]) {
+        self.search_domains = search_domains
+            .iter()
+            .filter_map(|domain| match DomainName::vec_from_str(domain) {
+                Ok(domain) => Some(domain),
+                Err(e) => {
+                    tracing::warn!(%domain, "Ignoring invalid search domain: {e}");
+                    None
+                }
+            })
+            .collect();
+    }
+
+    /// Sets the TTL of the records we synthesise for DNS resources, falling back to [`DEFAULT_RESOURCE_TTL`].
+    pub(crate) fn set_resource_ttl(&mut self, ttl: Option<u32>) {
+        self.resource_ttl = ttl.unwrap_or(DEFAULT_RESOURCE_TTL);
+    }
+
     pub(crate) fn get_description(&self, ip: &IpAddr) -> Option<ResourceDescriptionDns> {
         let name = self.ips_to_fqdn.get(ip)?;
         get_description(name, &self.dns_resources)
@@ -194,40 +277,201 @@ impl StubResolver {
     fn get_or_assign_a_records(
         &mut self,
         fqdn: DomainName,
-    ) -> Vec<AllRecordData<Vec<u8>, DomainName>> {
-        to_a_records(self.get_or_assign_ips(fqdn).into_iter())
+        now: Instant,
+    ) -> Option<Vec<AllRecordData<Vec<u8>, DomainName>>> {
+        Some(to_a_records(self.get_or_assign_ips(fqdn, now)?.into_iter()))
     }
 
     fn get_or_assign_aaaa_records(
         &mut self,
         fqdn: DomainName,
-    ) -> Vec<AllRecordData<Vec<u8>, DomainName>> {
-        to_aaaa_records(self.get_or_assign_ips(fqdn).into_iter())
-    }
-
-    fn get_or_assign_ips(&mut self, fqdn: DomainName) -> Vec<IpAddr> {
-        let ips = self
-            .fqdn_to_ips
-            .entry(fqdn.clone())
-            .or_insert_with(|| {
-                // TODO: the side effeccts are executed even if this is not inserted
-                // make it so that's not the case
-                let mut ips = self.ip_provider.get_n_ipv4(4);
-                ips.extend_from_slice(&self.ip_provider.get_n_ipv6(4));
-                ips
-            })
-            .clone();
+        now: Instant,
+    ) -> Option<Vec<AllRecordData<Vec<u8>, DomainName>>> {
+        Some(to_aaaa_records(
+            self.get_or_assign_ips(fqdn, now)?.into_iter(),
+        ))
+    }
+
+    /// Returns the proxy IPs of `fqdn`, assigning new ones if it doesn't have any yet.
+    ///
+    /// Returns `None` if all proxy IPs are in use and none of them can be reclaimed.
+    pub(crate) fn get_or_assign_ips(
+        &mut self,
+        fqdn: DomainName,
+        now: Instant,
+    ) -> Option<Vec<IpAddr>> {
+        if let Some(ips) = self.fqdn_to_ips.get(&fqdn).cloned() {
+            self.mark_proxy_ips_used(fqdn, now);
+
+            return Some(ips);
+        }
+
+        let ips = self.allocate_proxy_ips(now)?;
         for ip in &ips {
             self.ips_to_fqdn.insert(*ip, fqdn.clone());
         }
+        self.fqdn_to_ips.insert(fqdn.clone(), ips.clone());
+        self.mark_proxy_ips_used(fqdn, now);
+        self.proxy_ips_changed = true;
 
-        ips
+        Some(ips)
+    }
+
+    fn mark_proxy_ips_used(&mut self, fqdn: DomainName, now: Instant) {
+        if let Some(last_used) = self.proxy_ips_last_used.insert(fqdn.clone(), now) {
+            self.proxy_ips_by_last_use
+                .remove(&(last_used, fqdn.clone()));
+        }
+        self.proxy_ips_by_last_use.insert((now, fqdn));
+    }
+
+    /// Restores the proxy IPs we assigned in a previous session, see [`StubResolver::proxy_ips`].
+    ///
+    /// Names and IPs that are invalid or conflict with existing assignments are skipped.
+    pub(crate) fn restore_proxy_ips(
+        &mut self,
+        proxy_ips: HashMap<String, Vec<IpAddr>>,
+        now: Instant,
+    ) {
+        for (name, ips) in proxy_ips {
+            let fqdn = match DomainName::vec_from_str(&name) {
+                Ok(fqdn) => fqdn,
+                Err(e) => {
+                    tracing::debug!(%name, "Not restoring proxy IPs of invalid name: {e}");
+                    continue;
+                }
+            };
+            if self.fqdn_to_ips.contains_key(&fqdn) {
+                continue;
+            }
+
+            let ips = ips
+                .into_iter()
+                .filter(|ip| !self.ips_to_fqdn.contains_key(ip) && self.ip_provider.reserve(*ip))
+                .collect_vec();
+            if ips.is_empty() {
+                continue;
+            }
+
+            for ip in &ips {
+                self.ips_to_fqdn.insert(*ip, fqdn.clone());
+            }
+            self.fqdn_to_ips.insert(fqdn.clone(), ips);
+            self.mark_proxy_ips_used(fqdn, now);
+        }
+    }
+
+    /// The proxy IPs of all names that belong to one of our DNS resources.
+    pub(crate) fn proxy_ips(&self) -> HashMap<String, Vec<IpAddr>> {
+        self.fqdn_to_ips
+            .iter()
+            .filter(|(fqdn, _)| self.is_fqdn_resource(fqdn))
+            .map(|(fqdn, ips)| (fqdn.to_string(), ips.clone()))
+            .collect()
+    }
+
+    /// Returns whether we assigned or reclaimed proxy IPs since the last call.
+    pub(crate) fn poll_proxy_ips_changed(&mut self) -> bool {
+        std::mem::take(&mut self.proxy_ips_changed)
+    }
+
+    /// Records that a packet was sent to one of our proxy IPs, which keeps them from being reclaimed.
+    pub(crate) fn on_proxy_ip_traffic(&mut self, ip: &IpAddr, now: Instant) {
+        let Some(fqdn) = self.ips_to_fqdn.get(ip).cloned() else {
+            return;
+        };
+
+        self.mark_proxy_ips_used(fqdn, now);
+    }
+
+    /// Returns the proxy IPs that were reassigned to a different name.
+    ///
+    /// Gateways may still translate these to the addresses of their previous name, so they must be sent again before they are used.
+    pub(crate) fn poll_reclaimed_proxy_ips(&mut self) -> Vec<IpAddr> {
+        std::mem::take(&mut self.reclaimed_proxy_ips)
+    }
+
+    fn allocate_proxy_ips(&mut self, now: Instant) -> Option<Vec<IpAddr>> {
+        loop {
+            let ipv4 = self.ip_provider.get_n_ipv4(PROXY_IPS_PER_FAMILY);
+            let ipv6 = self.ip_provider.get_n_ipv6(PROXY_IPS_PER_FAMILY);
+            let ips = ipv4.into_iter().chain(ipv6).collect_vec();
+
+            if ips.len() == 2 * PROXY_IPS_PER_FAMILY {
+                return Some(ips);
+            }
+
+            self.ip_provider.release(ips);
+
+            if !self.reclaim_least_recently_used_proxy_ips(now) {
+                tracing::error!("IP exhaustion: All proxy IPs are in use");
+
+                return None;
+            }
+        }
+    }
+
+    /// Releases the proxy IPs of the least recently used name, provided it has been idle for long enough.
+    fn reclaim_least_recently_used_proxy_ips(&mut self, now: Instant) -> bool {
+        let idle_timeout =
+            PROXY_IPS_IDLE_TIMEOUT.max(Duration::from_secs(self.resource_ttl.into()));
+
+        let Some((last_used, _)) = self.proxy_ips_by_last_use.first() else {
+            return false;
+        };
+        if now.duration_since(*last_used) < idle_timeout {
+            return false;
+        }
+
+        let (_, fqdn) = self
+            .proxy_ips_by_last_use
+            .pop_first()
+            .expect("checked above");
+        self.proxy_ips_last_used.remove(&fqdn);
+        let ips = self.fqdn_to_ips.remove(&fqdn).unwrap_or_default();
+        for ip in &ips {
+            self.ips_to_fqdn.remove(ip);
+        }
+
+        tracing::debug!(%fqdn, "Reclaiming proxy IPs of idle name");
+
+        self.reclaimed_proxy_ips.extend_from_slice(&ips);
+        self.ip_provider.release(ips);
+        self.proxy_ips_changed = true;
+
+        true
     }
 
     fn is_fqdn_resource(&self, domain_name: &DomainName) -> bool {
         get_description(domain_name, &self.dns_resources).is_some()
     }
 
+    /// The DNS resource a queried name belongs to, taking our search domains into account.
+    pub(crate) fn resource_id_by_name(&self, name: &DomainName) -> Option<ResourceId> {
+        let fqdn = self
+            .resource_name_by_search_domains(name)
+            .unwrap_or_else(|| name.clone());
+
+        get_description(&fqdn, &self.dns_resources).map(|r| r.id)
+    }
+
+    /// Expands a single-label name like `wiki` with our search domains, returning the first name that is a DNS resource.
+    ///
+    /// We only do this for resources: Queries for other names are forwarded as-is because the OS applies its own search domains before sending them to us.
+    fn resource_name_by_search_domains(&self, domain: &DomainName) -> Option<DomainName> {
+        // The root label counts as well.
+        if domain.label_count() != 2 {
+            return None;
+        }
+
+        self.search_domains
+            .iter()
+            .filter_map(|search_domain| {
+                DomainName::vec_from_str(&format!("{}.{search_domain}", domain.first())).ok()
+            })
+            .find(|fqdn| self.is_fqdn_resource(fqdn))
+    }
+
     fn resource_address_name_by_reservse_dns(
         &self,
         reverse_dns_name: &DomainName,
@@ -252,6 +496,7 @@ impl StubResolver {
         &mut self,
         dns_mapping: &bimap::BiMap<IpAddr, DnsServer>,
         packet: IpPacket<'a>,
+        now: Instant,
     ) -> Option<ResolveStrategy<'a>> {
         dns_mapping.get_by_left(&packet.destination())?;
         let datagram = packet.as_udp()?;
@@ -267,39 +512,222 @@ impl StubResolver {
         tracing::trace!("Parsed packet as DNS query: '{qtype} {domain}'");
 
         if let Some(records) = self.known_hosts.get_records(qtype, &domain) {
-            let response = build_dns_with_answer(message, domain, records)?;
+            let response = build_dns_with_answer(message, domain, records, KNOWN_HOSTS_TTL)?;
             return Some(ResolveStrategy::LocalResponse(build_response(
                 packet, response,
             )));
         }
 
-        let resource_records = match qtype {
-            Rtype::A if self.is_fqdn_resource(&domain) => {
-                self.get_or_assign_a_records(domain.clone())
+        if GATEWAY_RECORD_TYPES.contains(&qtype) {
+            if let Some(resource) = get_description(&domain, &self.dns_resources) {
+                return Some(ResolveStrategy::RecurseViaGateway {
+                    resource: resource.id,
+                    query: DnsQuery {
+                        name: domain,
+                        record_type: u16::from(qtype).into(),
+                        query: packet,
+                        transport: Transport::Udp,
+                    },
+                });
             }
-            Rtype::AAAA if self.is_fqdn_resource(&domain) => {
-                self.get_or_assign_aaaa_records(domain.clone())
+        }
+
+        // We answer with records for the name that was queried but assign the proxy IPs to the full name of the resource.
+        let fqdn = self
+            .resource_name_by_search_domains(&domain)
+            .unwrap_or_else(|| domain.clone());
+
+        let resource_records = match qtype {
+            Rtype::A if self.is_fqdn_resource(&fqdn) => self.get_or_assign_a_records(fqdn, now),
+            Rtype::AAAA if self.is_fqdn_resource(&fqdn) => {
+                self.get_or_assign_aaaa_records(fqdn, now)
             }
             Rtype::PTR => {
                 let fqdn = self.resource_address_name_by_reservse_dns(&domain)?;
 
-                vec![AllRecordData::Ptr(domain::rdata::Ptr::new(fqdn))]
+                Some(vec![AllRecordData::Ptr(domain::rdata::Ptr::new(fqdn))])
             }
             _ => {
                 return Some(ResolveStrategy::ForwardQuery(DnsQuery {
                     name: domain,
                     record_type: u16::from(qtype).into(),
                     query: packet,
+                    transport: Transport::Udp,
                 }))
             }
         };
+        let Some(resource_records) = resource_records else {
+            return Some(ResolveStrategy::LocalResponse(
+                ip_packet::make::dns_err_response(
+                    packet.to_owned(),
+                    hickory_proto::op::ResponseCode::ServFail,
+                )
+                .into_immutable(),
+            ));
+        };
 
-        let response = build_dns_with_answer(message, domain, resource_records)?;
+        let response = build_dns_with_answer(message, domain, resource_records, self.resource_ttl)?;
 
         Some(ResolveStrategy::LocalResponse(build_response(
             packet, response,
         )))
     }
+
+    /// Rewrites a response the gateway sent us for a [`ResolveStrategy::RecurseViaGateway`] query.
+    ///
+    /// The gateway resolves names to the real IPs of resources.
+    /// We replace those with our proxy IPs and add proxy IPs for SRV and MX targets that are resources, such that clients don't need a second round-trip to resolve them.
+    /// If we can't do that, e.g. because we ran out of proxy IPs, we answer with SERVFAIL instead.
+    pub(crate) fn map_gateway_response(
+        &mut self,
+        packet: IpPacket<'_>,
+        now: Instant,
+    ) -> Option<IpPacket<'static>> {
+        let datagram = packet.as_udp()?;
+        let message = Message::from_slice(datagram.payload()).ok()?;
+
+        let payload = match self.map_gateway_message(message, now) {
+            Some(payload) => payload,
+            None => build_servfail(message)?,
+        };
+
+        Some(
+            ip_packet::make::udp_packet(
+                packet.source(),
+                packet.destination(),
+                datagram.get_source(),
+                datagram.get_destination(),
+                payload,
+            )
+            .into_immutable(),
+        )
+    }
+
+    fn map_gateway_message(&mut self, message: &Message<[u8]>, now: Instant) -> Option<Vec<u8>> {
+        let mut builder =
+            MessageBuilder::from_target(Vec::with_capacity(message.as_slice().len() * 2)).ok()?;
+        *builder.header_mut() = message.header();
+
+        let mut builder = builder.question();
+        for question in message.question() {
+            builder.push(question.ok()?).ok()?;
+        }
+
+        let mut targets = BTreeSet::new();
+
+        let mut builder = builder.answer();
+        self.push_mapped_records(
+            &mut builder,
+            message.answer().ok()?,
+            &mut targets,
+            false,
+            now,
+        )?;
+
+        let mut builder = builder.authority();
+        self.push_mapped_records(
+            &mut builder,
+            message.authority().ok()?,
+            &mut targets,
+            false,
+            now,
+        )?;
+
+        let mut builder = builder.additional();
+        self.push_mapped_records(
+            &mut builder,
+            message.additional().ok()?,
+            &mut targets,
+            true,
+            now,
+        )?;
+        for target in targets {
+            // The additional records are only a hint, so we omit them if we are out of proxy IPs.
+            let Some(ips) = self.get_or_assign_ips(target.clone(), now) else {
+                continue;
+            };
+            let records = to_a_records(ips.iter().copied())
+                .into_iter()
+                .chain(to_aaaa_records(ips.iter().copied()));
+
+            for record in records {
+                builder
+                    .push((&target, Class::IN, self.resource_ttl, record))
+                    .ok()?;
+            }
+        }
+
+        Some(builder.finish())
+    }
+
+    /// Copies the records of `section` to `builder`, replacing the addresses of resources with our proxy IPs.
+    ///
+    /// Resources that are the target of an SRV or MX record are collected into `targets`.
+    /// In the additional section, we drop the records of resources and collect them into `targets` too, to append all proxy IPs at the end.
+    fn push_mapped_records<B>(
+        &mut self,
+        builder: &mut B,
+        section: RecordSection<'_, [u8]>,
+        targets: &mut BTreeSet<DomainName>,
+        is_additional: bool,
+        now: Instant,
+    ) -> Option<()>
+    where
+        B: RecordSectionBuilder<Vec<u8>>,
+    {
+        for record in section {
+            let Some(record) = record
+                .ok()?
+                .into_record::<AllRecordData<&[u8], ParsedName<&[u8]>>>()
+                .ok()?
+            else {
+                continue;
+            };
+            let owner = record.owner().to_vec();
+            let rtype = record.rtype();
+
+            if (rtype == Rtype::A || rtype == Rtype::AAAA) && self.is_fqdn_resource(&owner) {
+                if is_additional {
+                    targets.insert(owner);
+                    continue;
+                }
+
+                // Without proxy IPs, we can't answer and the caller fails the query.
+                let proxy_records = if rtype == Rtype::A {
+                    self.get_or_assign_a_records(owner.clone(), now)?
+                } else {
+                    self.get_or_assign_aaaa_records(owner.clone(), now)?
+                };
+                for proxy_record in proxy_records {
+                    builder
+                        .push((&owner, Class::IN, self.resource_ttl, proxy_record))
+                        .ok()?;
+                }
+
+                continue;
+            }
+
+            if let Some(target) = target_name(record.data()) {
+                if self.is_fqdn_resource(&target) {
+                    targets.insert(target);
+                }
+            }
+
+            builder.push(record).ok()?;
+        }
+
+        Some(())
+    }
+}
+
+/// The name an SRV or MX record points to.
+fn target_name(data: &AllRecordData<&[u8], ParsedName<&[u8]>>) -> Option<DomainName> {
+    #[allow(clippy::wildcard_enum_match_arm)]
+    match data {
+        AllRecordData::Srv(srv) => Some(srv.target().to_vec()),
+        AllRecordData::Mx(mx) => Some(mx.exchange().to_vec()),
+        _ => None,
+    }
 }
 
 fn to_a_records(ips: impl Iterator<Item = IpAddr>) -> Vec<AllRecordData<Vec<u8>, DomainName>> {
@@ -351,6 +779,86 @@ pub(crate) fn build_response_from_resolve_result(
     Ok(packet)
 }
 
+/// Builds the reply to a query we forwarded to a resolver, answering with SERVFAIL if we failed to resolve it.
+pub(crate) fn build_response_or_servfail(
+    query: IpPacket<'static>,
+    response: Result<
+        Result<hickory_resolver::error::ResolveResult<Lookup>, futures_bounded::Timeout>,
+        DnsQueryError,
+    >,
+) -> IpPacket<'static> {
+    let make_error_reply = {
+        let query = query.clone();
+
+        |e: &dyn fmt::Display| {
+            // To avoid sensitive data getting into the logs, only log the error if debug logging is enabled.
+            // We always want to see a warning.
+            if tracing::enabled!(Level::DEBUG) {
+                tracing::warn!("DNS query failed: {e}");
+            } else {
+                tracing::warn!("DNS query failed");
+            };
+
+            ip_packet::make::dns_err_response(query, hickory_proto::op::ResponseCode::ServFail)
+                .into_immutable()
+        }
+    };
+
+    match response {
+        Ok(Ok(response)) => match build_response_from_resolve_result(query, response) {
+            Ok(dns_reply) => dns_reply,
+            Err(e) => make_error_reply(&e),
+        },
+        Ok(Err(timeout)) => make_error_reply(&timeout),
+        Err(e) => make_error_reply(&e),
+    }
+}
+
+/// Parses the question and the response code of a DNS response we send back to the client.
+pub(crate) fn parse_response(packet: &IpPacket<'_>) -> Option<(DomainName, Rtype, Rcode)> {
+    let datagram = packet.as_udp()?;
+    let message = Message::from_slice(datagram.payload()).ok()?;
+    if !message.header().qr() {
+        return None;
+    }
+
+    let question = message.first_question()?;
+
+    Some((
+        question.qname().to_vec(),
+        question.qtype(),
+        message.header().rcode(),
+    ))
+}
+
+/// Parses a DNS query that a client sent through the tunnel to the gateway, see [`ResolveStrategy::RecurseViaGateway`].
+///
+/// Only queries for one of the [`GATEWAY_RECORD_TYPES`] are accepted.
+pub(crate) fn parse_gateway_query(packet: IpPacket<'_>) -> Option<DnsQuery<'_>> {
+    let (name, qtype) = {
+        let datagram = packet.as_udp()?;
+        let message = as_dns(&datagram)?;
+        if message.header().qr() {
+            return None;
+        }
+
+        let question = message.first_question()?;
+
+        (question.qname().to_vec(), question.qtype())
+    };
+
+    if !GATEWAY_RECORD_TYPES.contains(&qtype) {
+        return None;
+    }
+
+    Some(DnsQuery {
+        name,
+        record_type: u16::from(qtype).into(),
+        query: packet,
+        transport: Transport::Udp,
+    })
+}
+
 /// Constructs an IP packet responding to an IP packet containing a DNS query
 fn build_response(original_pkt: IpPacket<'_>, mut dns_answer: Vec<u8>) -> IpPacket<'static> {
     let response_len = dns_answer.len();
@@ -393,6 +901,7 @@ fn build_dns_with_answer(
     message: &Message<[u8]>,
     qname: DomainName,
     records: Vec<AllRecordData<Vec<u8>, DomainName>>,
+    ttl: u32,
 ) -> Option<Vec<u8>> {
     let msg_buf = Vec::with_capacity(message.as_slice().len() * 2);
     let msg_builder = MessageBuilder::from_target(msg_buf).expect(
@@ -403,14 +912,27 @@ fn build_dns_with_answer(
     answer_builder.header_mut().set_ra(true);
 
     for record in records {
-        answer_builder
-            .push((&qname, Class::IN, DNS_TTL, record))
-            .ok()?;
+        answer_builder.push((&qname, Class::IN, ttl, record)).ok()?;
     }
 
     Some(answer_builder.finish())
 }
 
+/// Builds a SERVFAIL response with the header and question of `message`.
+fn build_servfail(message: &Message<[u8]>) -> Option<Vec<u8>> {
+    let mut builder =
+        MessageBuilder::from_target(Vec::with_capacity(message.as_slice().len())).ok()?;
+    *builder.header_mut() = message.header();
+    builder.header_mut().set_rcode(Rcode::SERVFAIL);
+
+    let mut builder = builder.question();
+    for question in message.question() {
+        builder.push(question.ok()?).ok()?;
+    }
+
+    Some(builder.finish())
+}
+
 pub fn as_dns<'a>(pkt: &'a UdpPacket<'a>) -> Option<&'a Message<[u8]>> {
     (pkt.get_destination() == DNS_PORT)
         .then(|| Message::from_slice(pkt.payload()).ok())
@@ -439,41 +961,59 @@ pub fn is_subdomain(name: &DomainName, resource: &str) -> bool {
     name == &resource
 }
 
+/// Finds the most specific DNS resource for `name`, skipping resources that exclude it.
+///
+/// An excluded name may still match a less specific resource.
 fn get_description(
     name: &DomainName,
     dns_resources: &HashMap<String, ResourceDescriptionDns>,
 ) -> Option<ResourceDescriptionDns> {
-    if let Some(resource) = dns_resources.get(&name.to_string()) {
-        return Some(resource.clone());
+    let get = |address: String| {
+        dns_resources
+            .get(&address)
+            .filter(|r| !is_excluded(name, &r.exclusions))
+            .cloned()
+    };
+
+    if let Some(resource) = get(name.to_string()) {
+        return Some(resource);
     }
 
-    if let Some(resource) = dns_resources.get(
-        &RelativeName::<Vec<_>>::from_octets(b"\x01?".as_ref().into())
+    if let Some(resource) = get(
+        RelativeName::<Vec<_>>::from_octets(b"\x01?".as_ref().into())
             .ok()?
             .chain(name)
             .ok()?
             .to_string(),
     ) {
-        return Some(resource.clone());
+        return Some(resource);
     }
 
     if let Some(parent) = name.parent() {
-        if let Some(resource) = dns_resources.get(
-            &RelativeName::<Vec<_>>::from_octets(b"\x01?".as_ref().into())
+        if let Some(resource) = get(
+            RelativeName::<Vec<_>>::from_octets(b"\x01?".as_ref().into())
                 .ok()?
                 .chain(parent)
                 .ok()?
                 .to_string(),
         ) {
-            return Some(resource.clone());
+            return Some(resource);
         }
     }
 
-    name.iter_suffixes().find_map(|n| {
-        dns_resources
-            .get(&RelativeName::wildcard_vec().chain(n).ok()?.to_string())
-            .cloned()
-    })
+    name.iter_suffixes()
+        .find_map(|n| get(RelativeName::wildcard_vec().chain(n).ok()?.to_string()))
+}
+
+/// Whether `name` belongs to a DNS resource with the given address and exclusions.
+pub(crate) fn is_resource_name(name: &DomainName, address: &str, exclusions: &[String]) -> bool {
+    is_subdomain(name, address) && !is_excluded(name, exclusions)
+}
+
+pub(crate) fn is_excluded(name: &DomainName, exclusions: &[String]) -> bool {
+    exclusions
+        .iter()
+        .any(|exclusion| is_subdomain(name, exclusion))
 }
 
 fn reverse_dns_addr(name: &str) -> Option<IpAddr> {
@@ -529,8 +1069,24 @@ mod test {
 
     use crate::dns::is_subdomain;
 
-    use super::{get_description, reverse_dns_addr};
-    use std::{collections::HashMap, net::Ipv4Addr};
+    use super::{
+        get_description, reverse_dns_addr, ResolveStrategy, StubResolver, DEFAULT_RESOURCE_TTL,
+        KNOWN_HOSTS_TTL, PROXY_IPS_IDLE_TIMEOUT,
+    };
+    use crate::client::IpProvider;
+    use bimap::BiMap;
+    use connlib_shared::messages::DnsServer;
+    use domain::base::{
+        iana::{Class, Rcode, Rtype},
+        Message, MessageBuilder,
+    };
+    use domain::rdata::{Aaaa, Srv, A};
+    use ip_packet::{IpPacket, MutableIpPacket, Packet as _};
+    use std::{
+        collections::HashMap,
+        net::{IpAddr, Ipv4Addr},
+        time::{Duration, Instant},
+    };
 
     fn foo() -> ResourceDescriptionDns {
         serde_json::from_str(
@@ -571,6 +1127,33 @@ mod test {
         .unwrap()
     }
 
+    fn corp() -> ResourceDescriptionDns {
+        serde_json::from_str(
+            r#"{
+                "id": "c4bb3d79-afa7-4660-8918-06c38fda3a4d",
+                "address": "*.corp.example.com",
+                "name": "corp.example.com wildcard",
+                "address_description": "corp",
+                "gateway_groups": [{"id": "bf56f32d-7b2c-4f5d-a784-788977d014a4", "name": "test"}],
+                "exclusions": ["public.corp.example.com", "*.static.corp.example.com", "?.cdn.corp.example.com"]
+            }"#,
+        )
+        .unwrap()
+    }
+
+    fn example() -> ResourceDescriptionDns {
+        serde_json::from_str(
+            r#"{
+                "id": "c4bb3d79-afa7-4660-8918-06c38fda3a4e",
+                "address": "*.example.com",
+                "name": "example.com wildcard",
+                "address_description": "example",
+                "gateway_groups": [{"id": "bf56f32d-7b2c-4f5d-a784-788977d014a4", "name": "test"}]
+            }"#,
+        )
+        .unwrap()
+    }
+
     fn dns_resource_fixture() -> HashMap<String, ResourceDescriptionDns> {
         let mut dns_resources_fixture = HashMap::new();
 
@@ -729,6 +1312,149 @@ mod test {
         .is_none(),);
     }
 
+    #[test]
+    fn exact_exclusion_matching() {
+        let dns_resources_fixture = HashMap::from([("*.corp.example.com".to_string(), corp())]);
+
+        assert!(get_description(
+            &DomainName::vec_from_str("public.corp.example.com").unwrap(),
+            &dns_resources_fixture,
+        )
+        .is_none());
+
+        assert_eq!(
+            get_description(
+                &DomainName::vec_from_str("a.public.corp.example.com").unwrap(),
+                &dns_resources_fixture,
+            )
+            .unwrap(),
+            corp(),
+        );
+
+        assert_eq!(
+            get_description(
+                &DomainName::vec_from_str("corp.example.com").unwrap(),
+                &dns_resources_fixture,
+            )
+            .unwrap(),
+            corp(),
+        );
+
+        assert_eq!(
+            get_description(
+                &DomainName::vec_from_str("a.corp.example.com").unwrap(),
+                &dns_resources_fixture,
+            )
+            .unwrap(),
+            corp(),
+        );
+    }
+
+    #[test]
+    fn wildcard_exclusion_matching() {
+        let dns_resources_fixture = HashMap::from([("*.corp.example.com".to_string(), corp())]);
+
+        assert!(get_description(
+            &DomainName::vec_from_str("static.corp.example.com").unwrap(),
+            &dns_resources_fixture,
+        )
+        .is_none());
+
+        assert!(get_description(
+            &DomainName::vec_from_str("a.static.corp.example.com").unwrap(),
+            &dns_resources_fixture,
+        )
+        .is_none());
+
+        assert!(get_description(
+            &DomainName::vec_from_str("a.b.static.corp.example.com").unwrap(),
+            &dns_resources_fixture,
+        )
+        .is_none());
+
+        assert_eq!(
+            get_description(
+                &DomainName::vec_from_str("astatic.corp.example.com").unwrap(),
+                &dns_resources_fixture,
+            )
+            .unwrap(),
+            corp(),
+        );
+    }
+
+    #[test]
+    fn question_mark_exclusion_matching() {
+        let dns_resources_fixture = HashMap::from([("*.corp.example.com".to_string(), corp())]);
+
+        assert!(get_description(
+            &DomainName::vec_from_str("cdn.corp.example.com").unwrap(),
+            &dns_resources_fixture,
+        )
+        .is_none());
+
+        assert!(get_description(
+            &DomainName::vec_from_str("a.cdn.corp.example.com").unwrap(),
+            &dns_resources_fixture,
+        )
+        .is_none());
+
+        assert_eq!(
+            get_description(
+                &DomainName::vec_from_str("a.b.cdn.corp.example.com").unwrap(),
+                &dns_resources_fixture,
+            )
+            .unwrap(),
+            corp(),
+        );
+    }
+
+    #[test]
+    fn excluded_name_matches_less_specific_resource() {
+        let dns_resources_fixture = HashMap::from([
+            ("*.corp.example.com".to_string(), corp()),
+            ("*.example.com".to_string(), example()),
+        ]);
+
+        assert_eq!(
+            get_description(
+                &DomainName::vec_from_str("public.corp.example.com").unwrap(),
+                &dns_resources_fixture,
+            )
+            .unwrap(),
+            example(),
+        );
+
+        assert_eq!(
+            get_description(
+                &DomainName::vec_from_str("a.corp.example.com").unwrap(),
+                &dns_resources_fixture,
+            )
+            .unwrap(),
+            corp(),
+        );
+    }
+
+    #[test]
+    fn query_for_excluded_name_is_forwarded() {
+        let mut resolver = StubResolver::new(HashMap::new());
+        resolver.add_resource(&corp());
+        let dns_mapping = BiMap::from_iter([(SENTINEL, DnsServer::from((UPSTREAM, 53)))]);
+
+        let query = dns_query("public.corp.example.com", Rtype::A);
+        let strategy = resolver
+            .handle(&dns_mapping, query.as_immutable(), Instant::now())
+            .unwrap();
+
+        assert!(matches!(strategy, ResolveStrategy::ForwardQuery(_)));
+
+        let query = dns_query("a.corp.example.com", Rtype::A);
+        let strategy = resolver
+            .handle(&dns_mapping, query.as_immutable(), Instant::now())
+            .unwrap();
+
+        assert!(matches!(strategy, ResolveStrategy::LocalResponse(_)));
+    }
+
     #[test]
     fn exact_subdomain_match() {
         assert!(is_subdomain(
@@ -832,4 +1558,516 @@ mod test {
             "?.foo.com"
         ));
     }
+
+    #[test]
+    fn srv_query_for_resource_is_resolved_by_gateway() {
+        let mut resolver = StubResolver::new(HashMap::new());
+        resolver.add_resource(&foo());
+        let dns_mapping = BiMap::from_iter([(SENTINEL, DnsServer::from((UPSTREAM, 53)))]);
+
+        let query = dns_query("_ldap._tcp.a.foo.com", Rtype::SRV);
+        let strategy = resolver
+            .handle(&dns_mapping, query.as_immutable(), Instant::now())
+            .unwrap();
+
+        assert!(
+            matches!(strategy, ResolveStrategy::RecurseViaGateway { resource, .. } if resource == foo().id)
+        );
+    }
+
+    #[test]
+    fn srv_query_for_non_resource_is_forwarded() {
+        let mut resolver = StubResolver::new(HashMap::new());
+        resolver.add_resource(&foo());
+        let dns_mapping = BiMap::from_iter([(SENTINEL, DnsServer::from((UPSTREAM, 53)))]);
+
+        let query = dns_query("_ldap._tcp.example.com", Rtype::SRV);
+        let strategy = resolver
+            .handle(&dns_mapping, query.as_immutable(), Instant::now())
+            .unwrap();
+
+        assert!(matches!(strategy, ResolveStrategy::ForwardQuery(_)));
+    }
+
+    #[test]
+    fn gateway_response_maps_resources_to_proxy_ips() {
+        let mut resolver = StubResolver::new(HashMap::new());
+        resolver.add_resource(&foo());
+
+        let mut builder = MessageBuilder::new_vec();
+        builder.header_mut().set_qr(true);
+        let mut builder = builder.question();
+        builder
+            .push((name("_ldap._tcp.foo.com"), Rtype::SRV))
+            .unwrap();
+        let mut builder = builder.answer();
+        for target in ["ldap.foo.com", "ldap.example.com"] {
+            builder
+                .push((
+                    name("_ldap._tcp.foo.com"),
+                    Class::IN,
+                    UPSTREAM_TTL,
+                    Srv::new(0, 0, 389, name(target)),
+                ))
+                .unwrap();
+        }
+        let mut builder = builder.additional();
+        builder
+            .push((
+                name("ldap.example.com"),
+                Class::IN,
+                UPSTREAM_TTL,
+                A::new(EXTERNAL_IP),
+            ))
+            .unwrap();
+        builder
+            .push((
+                name("ldap.foo.com"),
+                Class::IN,
+                UPSTREAM_TTL,
+                A::new(INTERNAL_IP),
+            ))
+            .unwrap();
+        let response = ip_packet::make::udp_packet(
+            IpAddr::from(SENTINEL_V4),
+            IpAddr::from(CLIENT_V4),
+            53,
+            5353,
+            builder.finish(),
+        );
+
+        let response = resolver
+            .map_gateway_response(response.as_immutable(), Instant::now())
+            .unwrap();
+        let message = Message::from_octets(response.as_udp().unwrap().payload().to_vec()).unwrap();
+
+        assert_eq!(message.answer().unwrap().count(), 2);
+        let a_records = message
+            .additional()
+            .unwrap()
+            .limit_to::<A>()
+            .map(|r| {
+                let r = r.unwrap();
+                (r.owner().to_vec(), r.data().addr())
+            })
+            .collect::<Vec<_>>();
+        let aaaa_records = message.additional().unwrap().limit_to::<Aaaa>().count();
+
+        assert_eq!(a_records.len(), 5);
+        assert!(a_records.contains(&(name("ldap.example.com"), EXTERNAL_IP)));
+        assert!(!a_records.iter().any(|(_, ip)| *ip == INTERNAL_IP));
+        assert!(a_records
+            .iter()
+            .filter(|(owner, _)| *owner == name("ldap.foo.com"))
+            .all(|(_, ip)| resolver.get_fqdn(&IpAddr::from(*ip)).is_some()));
+        assert_eq!(aaaa_records, 4);
+    }
+
+    #[test]
+    fn single_label_query_is_resolved_with_search_domains() {
+        let mut resolver = StubResolver::new(HashMap::new());
+        resolver.add_resource(&foo());
+        resolver.set_search_domains(&["example.com".to_owned(), "foo.com".to_owned()]);
+        let dns_mapping = BiMap::from_iter([(SENTINEL, DnsServer::from((UPSTREAM, 53)))]);
+
+        let response = local_response(&mut resolver, &dns_mapping, "wiki", Rtype::A);
+        let message = Message::from_octets(response.as_udp().unwrap().payload().to_vec()).unwrap();
+        let records = message
+            .answer()
+            .unwrap()
+            .limit_to::<A>()
+            .map(|r| r.unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(records.len(), 4);
+        assert!(records.iter().all(|r| r.owner().to_vec() == name("wiki")));
+        assert!(records.iter().all(|r| {
+            resolver
+                .get_fqdn(&IpAddr::from(r.data().addr()))
+                .is_some_and(|(fqdn, _)| *fqdn == name("wiki.foo.com"))
+        }));
+    }
+
+    #[test]
+    fn only_single_label_resource_names_are_expanded() {
+        let mut resolver = StubResolver::new(HashMap::new());
+        resolver.add_resource(&foo());
+        resolver.set_search_domains(&["foo.com".to_owned()]);
+        let dns_mapping = BiMap::from_iter([(SENTINEL, DnsServer::from((UPSTREAM, 53)))]);
+
+        for domain in ["wiki.internal", "printer.local"] {
+            let query = dns_query(domain, Rtype::A);
+            let strategy = resolver
+                .handle(&dns_mapping, query.as_immutable(), Instant::now())
+                .unwrap();
+
+            assert!(matches!(strategy, ResolveStrategy::ForwardQuery(_)));
+        }
+
+        resolver.set_search_domains(&[]);
+
+        let query = dns_query("wiki", Rtype::A);
+        let strategy = resolver
+            .handle(&dns_mapping, query.as_immutable(), Instant::now())
+            .unwrap();
+
+        assert!(matches!(strategy, ResolveStrategy::ForwardQuery(_)));
+    }
+
+    #[test]
+    fn resource_answers_use_configured_ttl() {
+        let mut resolver = StubResolver::new(HashMap::new());
+        resolver.add_resource(&foo());
+        let dns_mapping = BiMap::from_iter([(SENTINEL, DnsServer::from((UPSTREAM, 53)))]);
+
+        let response = local_response(&mut resolver, &dns_mapping, "a.foo.com", Rtype::A);
+        assert_eq!(answer_ttls(&response), vec![DEFAULT_RESOURCE_TTL; 4]);
+
+        resolver.set_resource_ttl(Some(3600));
+
+        let response = local_response(&mut resolver, &dns_mapping, "a.foo.com", Rtype::AAAA);
+        assert_eq!(answer_ttls(&response), vec![3600; 4]);
+    }
+
+    #[test]
+    fn ptr_answers_for_proxy_ips_use_resource_ttl() {
+        let mut resolver = StubResolver::new(HashMap::new());
+        resolver.add_resource(&foo());
+        resolver.set_resource_ttl(Some(3600));
+        let dns_mapping = BiMap::from_iter([(SENTINEL, DnsServer::from((UPSTREAM, 53)))]);
+
+        let proxy_ip = resolver
+            .get_or_assign_ips(name("a.foo.com"), Instant::now())
+            .unwrap()[0];
+        let IpAddr::V4(proxy_ip) = proxy_ip else {
+            panic!("Expected IPv4 proxy IPs to be assigned first");
+        };
+        let [a, b, c, d] = proxy_ip.octets();
+
+        let response = local_response(
+            &mut resolver,
+            &dns_mapping,
+            &format!("{d}.{c}.{b}.{a}.in-addr.arpa"),
+            Rtype::PTR,
+        );
+        assert_eq!(answer_ttls(&response), vec![3600]);
+    }
+
+    #[test]
+    fn known_host_answers_use_known_hosts_ttl() {
+        let mut resolver = StubResolver::new(HashMap::from([(
+            "api.firezone.dev".to_owned(),
+            vec![IpAddr::from(EXTERNAL_IP)],
+        )]));
+        resolver.set_resource_ttl(Some(3600));
+        let dns_mapping = BiMap::from_iter([(SENTINEL, DnsServer::from((UPSTREAM, 53)))]);
+
+        let response = local_response(&mut resolver, &dns_mapping, "api.firezone.dev", Rtype::A);
+        assert_eq!(answer_ttls(&response), vec![KNOWN_HOSTS_TTL]);
+    }
+
+    #[test]
+    fn reclaims_proxy_ips_of_least_recently_used_name() {
+        let mut resolver = resolver_with_room_for_three_names();
+        let now = Instant::now();
+
+        let a = resolver.get_or_assign_ips(name("a.foo.com"), now).unwrap();
+        let b = resolver
+            .get_or_assign_ips(name("b.foo.com"), now + Duration::from_secs(1))
+            .unwrap();
+        let _ = resolver.get_or_assign_ips(name("c.foo.com"), now + Duration::from_secs(2));
+
+        let d = resolver
+            .get_or_assign_ips(
+                name("d.foo.com"),
+                now + PROXY_IPS_IDLE_TIMEOUT + Duration::from_secs(2),
+            )
+            .unwrap();
+
+        assert_eq!(sorted(d), sorted(a.clone()));
+        assert_eq!(
+            sorted(resolver.poll_reclaimed_proxy_ips()),
+            sorted(a.clone())
+        );
+        assert!(resolver.poll_reclaimed_proxy_ips().is_empty());
+        assert_eq!(resolver.get_fqdn(&a[0]).unwrap().0, &name("d.foo.com"));
+        assert_eq!(resolver.get_fqdn(&b[0]).unwrap().0, &name("b.foo.com"));
+    }
+
+    #[test]
+    fn queries_keep_proxy_ips_from_being_reclaimed() {
+        let mut resolver = resolver_with_room_for_three_names();
+        let now = Instant::now();
+
+        let a = resolver.get_or_assign_ips(name("a.foo.com"), now).unwrap();
+        let b = resolver
+            .get_or_assign_ips(name("b.foo.com"), now + Duration::from_secs(1))
+            .unwrap();
+        let _ = resolver.get_or_assign_ips(name("c.foo.com"), now + Duration::from_secs(2));
+        let _ = resolver.get_or_assign_ips(name("a.foo.com"), now + Duration::from_secs(3));
+
+        let d = resolver
+            .get_or_assign_ips(
+                name("d.foo.com"),
+                now + PROXY_IPS_IDLE_TIMEOUT + Duration::from_secs(2),
+            )
+            .unwrap();
+
+        assert_eq!(sorted(d), sorted(b));
+        assert_eq!(resolver.get_fqdn(&a[0]).unwrap().0, &name("a.foo.com"));
+    }
+
+    #[test]
+    fn traffic_keeps_proxy_ips_from_being_reclaimed() {
+        let mut resolver = resolver_with_room_for_three_names();
+        let now = Instant::now();
+
+        let a = resolver.get_or_assign_ips(name("a.foo.com"), now).unwrap();
+        let b = resolver
+            .get_or_assign_ips(name("b.foo.com"), now + Duration::from_secs(1))
+            .unwrap();
+        let _ = resolver.get_or_assign_ips(name("c.foo.com"), now + Duration::from_secs(2));
+        resolver.on_proxy_ip_traffic(&a[0], now + Duration::from_secs(3));
+
+        let d = resolver
+            .get_or_assign_ips(
+                name("d.foo.com"),
+                now + PROXY_IPS_IDLE_TIMEOUT + Duration::from_secs(2),
+            )
+            .unwrap();
+
+        assert_eq!(sorted(d), sorted(b));
+        assert_eq!(resolver.get_fqdn(&a[0]).unwrap().0, &name("a.foo.com"));
+    }
+
+    #[test]
+    fn does_not_reclaim_proxy_ips_of_recently_used_names() {
+        let mut resolver = resolver_with_room_for_three_names();
+        let now = Instant::now();
+
+        let a = resolver.get_or_assign_ips(name("a.foo.com"), now).unwrap();
+        let _ = resolver.get_or_assign_ips(name("b.foo.com"), now);
+        let _ = resolver.get_or_assign_ips(name("c.foo.com"), now);
+
+        let d = resolver.get_or_assign_ips(name("d.foo.com"), now + Duration::from_secs(60));
+
+        assert!(d.is_none());
+        assert!(resolver.poll_reclaimed_proxy_ips().is_empty());
+        assert_eq!(resolver.get_fqdn(&a[0]).unwrap().0, &name("a.foo.com"));
+    }
+
+    #[test]
+    fn queries_fail_if_no_proxy_ips_can_be_reclaimed() {
+        let mut resolver = resolver_with_room_for_three_names();
+        let dns_mapping = BiMap::from_iter([(SENTINEL, DnsServer::from((UPSTREAM, 53)))]);
+        let now = Instant::now();
+
+        let _ = resolver.get_or_assign_ips(name("a.foo.com"), now);
+        let _ = resolver.get_or_assign_ips(name("b.foo.com"), now);
+        let _ = resolver.get_or_assign_ips(name("c.foo.com"), now);
+
+        let response = local_response(&mut resolver, &dns_mapping, "d.foo.com", Rtype::A);
+        let message = Message::from_octets(response.as_udp().unwrap().payload().to_vec()).unwrap();
+
+        assert_eq!(message.header().rcode(), Rcode::SERVFAIL);
+        assert!(resolver.get_fqdn(&ip("100.96.0.13")).is_none());
+        assert!(!resolver
+            .proxy_ips_last_used
+            .contains_key(&name("d.foo.com")));
+    }
+
+    #[test]
+    fn proxy_ips_are_not_reclaimed_before_the_resource_ttl_expires() {
+        let mut resolver = resolver_with_room_for_three_names();
+        resolver.set_resource_ttl(Some(2 * PROXY_IPS_IDLE_TIMEOUT.as_secs() as u32));
+        let now = Instant::now();
+
+        let _ = resolver.get_or_assign_ips(name("a.foo.com"), now);
+        let _ = resolver.get_or_assign_ips(name("b.foo.com"), now);
+        let _ = resolver.get_or_assign_ips(name("c.foo.com"), now);
+
+        let d = resolver.get_or_assign_ips(
+            name("d.foo.com"),
+            now + PROXY_IPS_IDLE_TIMEOUT + Duration::from_secs(1),
+        );
+
+        assert!(d.is_none());
+        assert!(resolver.poll_reclaimed_proxy_ips().is_empty());
+    }
+
+    #[test]
+    fn restored_proxy_ips_are_assigned_to_their_name() {
+        let mut resolver = resolver_with_room_for_three_names();
+        let restored = vec![ip("100.96.0.9"), ip("fd00:2021:1111:8000::9")];
+
+        resolver.restore_proxy_ips(
+            HashMap::from([("a.foo.com".to_owned(), restored.clone())]),
+            Instant::now(),
+        );
+
+        assert_eq!(
+            resolver.get_or_assign_ips(name("a.foo.com"), Instant::now()),
+            Some(restored)
+        );
+        assert!(!resolver.poll_proxy_ips_changed());
+    }
+
+    #[test]
+    fn restored_proxy_ips_are_not_assigned_to_other_names() {
+        let mut resolver = resolver_with_room_for_three_names();
+        resolver.restore_proxy_ips(
+            HashMap::from([(
+                "a.foo.com".to_owned(),
+                vec![
+                    ip("100.96.0.1"),
+                    ip("100.96.0.3"),
+                    ip("fd00:2021:1111:8000::2"),
+                ],
+            )]),
+            Instant::now(),
+        );
+
+        let b = resolver
+            .get_or_assign_ips(name("b.foo.com"), Instant::now())
+            .unwrap();
+
+        assert_eq!(
+            b,
+            vec![
+                ip("100.96.0.2"),
+                ip("100.96.0.4"),
+                ip("100.96.0.5"),
+                ip("100.96.0.6"),
+                ip("fd00:2021:1111:8000::"),
+                ip("fd00:2021:1111:8000::1"),
+                ip("fd00:2021:1111:8000::3"),
+                ip("fd00:2021:1111:8000::4"),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_restored_proxy_ips_are_skipped() {
+        let mut resolver = resolver_with_room_for_three_names();
+
+        resolver.restore_proxy_ips(
+            HashMap::from([
+                (
+                    "a.foo.com".to_owned(),
+                    vec![ip("1.1.1.1"), ip("100.96.0.1")],
+                ),
+                ("b.foo.com".to_owned(), vec![ip("100.96.0.1")]),
+                ("c.foo.com".to_owned(), vec![ip("100.100.111.1")]),
+            ]),
+            Instant::now(),
+        );
+
+        let a = resolver
+            .get_or_assign_ips(name("a.foo.com"), Instant::now())
+            .unwrap();
+        let b = resolver
+            .get_or_assign_ips(name("b.foo.com"), Instant::now())
+            .unwrap();
+
+        assert!(a == vec![ip("100.96.0.1")] || b == vec![ip("100.96.0.1")]);
+        assert!(!a.contains(&ip("1.1.1.1")));
+        assert_eq!(resolver.get_fqdn(&ip("100.96.0.1")).unwrap().1.len(), 1);
+    }
+
+    #[test]
+    fn only_proxy_ips_of_resources_are_persisted() {
+        let mut resolver = resolver_with_room_for_three_names();
+        resolver.restore_proxy_ips(
+            HashMap::from([("removed.bar.com".to_owned(), vec![ip("100.96.0.9")])]),
+            Instant::now(),
+        );
+
+        let a = resolver
+            .get_or_assign_ips(name("a.foo.com"), Instant::now())
+            .unwrap();
+
+        assert!(resolver.poll_proxy_ips_changed());
+        assert!(!resolver.poll_proxy_ips_changed());
+        assert_eq!(
+            resolver.proxy_ips(),
+            HashMap::from([(name("a.foo.com").to_string(), a)])
+        );
+    }
+
+    const SENTINEL_V4: Ipv4Addr = Ipv4Addr::new(100, 100, 111, 1);
+    const SENTINEL: IpAddr = IpAddr::V4(SENTINEL_V4);
+    const UPSTREAM: IpAddr = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
+    const CLIENT_V4: Ipv4Addr = Ipv4Addr::new(100, 64, 0, 1);
+    const INTERNAL_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(93, 184, 216, 34);
+    const UPSTREAM_TTL: u32 = 300;
+
+    fn name(name: &str) -> DomainName {
+        DomainName::vec_from_str(name).unwrap()
+    }
+
+    fn dns_query(domain: &str, qtype: Rtype) -> MutableIpPacket<'static> {
+        let mut builder = MessageBuilder::new_vec().question();
+        builder.push((name(domain), qtype)).unwrap();
+
+        ip_packet::make::udp_packet(
+            IpAddr::from(CLIENT_V4),
+            SENTINEL,
+            5353,
+            53,
+            builder.finish(),
+        )
+    }
+
+    fn local_response(
+        resolver: &mut StubResolver,
+        dns_mapping: &BiMap<IpAddr, DnsServer>,
+        domain: &str,
+        qtype: Rtype,
+    ) -> IpPacket<'static> {
+        let query = dns_query(domain, qtype);
+
+        let Some(ResolveStrategy::LocalResponse(response)) =
+            resolver.handle(dns_mapping, query.as_immutable(), Instant::now())
+        else {
+            panic!("Expected a local response for '{qtype} {domain}'");
+        };
+
+        response.to_owned()
+    }
+
+    /// A resolver whose IPv4 pool only has room for the proxy IPs of three names.
+    fn resolver_with_room_for_three_names() -> StubResolver {
+        let mut resolver = StubResolver {
+            ip_provider: IpProvider::new(
+                "100.96.0.0/28".parse().unwrap(),
+                "fd00:2021:1111:8000::/120".parse().unwrap(),
+                vec![],
+            ),
+            ..StubResolver::new(HashMap::new())
+        };
+        resolver.add_resource(&foo());
+
+        resolver
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn sorted(mut ips: Vec<IpAddr>) -> Vec<IpAddr> {
+        ips.sort();
+
+        ips
+    }
+
+    fn answer_ttls(response: &IpPacket<'_>) -> Vec<u32> {
+        let message = Message::from_octets(response.as_udp().unwrap().payload().to_vec()).unwrap();
+
+        message
+            .answer()
+            .unwrap()
+            .map(|record| record.unwrap().ttl().as_secs())
+            .collect()
+    }
 }
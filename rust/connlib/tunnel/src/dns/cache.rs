@@ -0,0 +1,396 @@
+//! A cache for the responses of DNS queries we forward to upstream resolvers.
+//!
+//! Positive responses are cached for the lowest TTL of their records.
+//! Negative responses (NXDOMAIN and NODATA) are cached for the TTL derived from their SOA record, see [RFC2308](https://www.rfc-editor.org/rfc/rfc2308#section-5).
+//! All other failures are never cached.
+
+use connlib_shared::DomainName;
+use hickory_resolver::{
+    error::{ResolveError, ResolveErrorKind, ResolveResult},
+    lookup::Lookup,
+    proto::{error::ProtoErrorKind, rr::RecordType},
+};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// The maximum number of responses we keep in the cache.
+const MAX_ENTRIES: usize = 1024;
+/// Upper bound for how long we cache a positive response, regardless of its TTL.
+const MAX_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+/// Upper bound for how long we cache a negative response, see <https://www.rfc-editor.org/rfc/rfc2308#section-5>.
+const MAX_NEGATIVE_TTL: Duration = Duration::from_secs(60 * 60 * 3);
+
+pub(crate) struct ResponseCache {
+    /// Responses indexed by the sentinel IP of the upstream resolver, the queried name and the record type.
+    entries: HashMap<(IpAddr, DomainName, RecordType), Entry>,
+}
+
+struct Entry {
+    response: ResolveResult<Lookup>,
+    inserted_at: Instant,
+    expires_at: Instant,
+}
+
+impl ResponseCache {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Returns the cached response for this query, with the TTLs of its records reduced by the time it spent in the cache.
+    pub(crate) fn get(
+        &mut self,
+        server: IpAddr,
+        name: &DomainName,
+        record_type: RecordType,
+        now: Instant,
+    ) -> Option<ResolveResult<Lookup>> {
+        let key = (server, name.clone(), record_type);
+        let entry = self.entries.get(&key)?;
+
+        if now >= entry.expires_at {
+            self.entries.remove(&key);
+            return None;
+        }
+
+        let elapsed =
+            u32::try_from(now.duration_since(entry.inserted_at).as_secs()).unwrap_or(u32::MAX);
+
+        let lookup = match &entry.response {
+            Ok(lookup) => lookup,
+            Err(e) => return Some(Err(e.clone())),
+        };
+
+        let records = lookup
+            .records()
+            .iter()
+            .cloned()
+            .map(|mut record| {
+                record.set_ttl(record.ttl().saturating_sub(elapsed));
+                record
+            })
+            .collect::<Vec<_>>();
+
+        Some(Ok(Lookup::new_with_deadline(
+            lookup.query().clone(),
+            records.into(),
+            lookup.valid_until(),
+        )))
+    }
+
+    /// Caches the response of an upstream resolver, if it is cacheable.
+    pub(crate) fn insert(
+        &mut self,
+        server: IpAddr,
+        name: DomainName,
+        record_type: RecordType,
+        response: &ResolveResult<Lookup>,
+        now: Instant,
+    ) {
+        let Some(ttl) = cache_ttl(response) else {
+            return;
+        };
+
+        if self.entries.len() >= MAX_ENTRIES {
+            self.entries.retain(|_, entry| now < entry.expires_at);
+        }
+
+        if self.entries.len() >= MAX_ENTRIES {
+            if let Some(key) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires_at)
+                .map(|(key, _)| key.clone())
+            {
+                self.entries.remove(&key);
+            }
+        }
+
+        self.entries.insert(
+            (server, name, record_type),
+            Entry {
+                response: response.clone(),
+                inserted_at: now,
+                expires_at: now + ttl,
+            },
+        );
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+fn cache_ttl(response: &ResolveResult<Lookup>) -> Option<Duration> {
+    let ttl = match response {
+        Ok(lookup) => {
+            let ttl = lookup.records().iter().map(|r| r.ttl()).min()?;
+
+            Duration::from_secs(ttl.into()).min(MAX_TTL)
+        }
+        Err(e) => {
+            let negative_ttl = negative_ttl(e)?;
+
+            Duration::from_secs(negative_ttl.into()).min(MAX_NEGATIVE_TTL)
+        }
+    };
+
+    (!ttl.is_zero()).then_some(ttl)
+}
+
+/// The TTL of a negative response.
+///
+/// Negative responses without an SOA record must not be cached, see <https://www.rfc-editor.org/rfc/rfc2308#section-5>.
+fn negative_ttl(e: &ResolveError) -> Option<u32> {
+    let ResolveErrorKind::Proto(e) = e.kind() else {
+        return None;
+    };
+    let ProtoErrorKind::NoRecordsFound {
+        negative_ttl, soa, ..
+    } = e.kind()
+    else {
+        return None;
+    };
+    soa.as_ref()?;
+
+    *negative_ttl
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_resolver::proto::{
+        error::ProtoError,
+        op::{Query, ResponseCode},
+        rr::{
+            rdata::{A, SOA},
+            Name, RData, Record,
+        },
+    };
+    use std::str::FromStr as _;
+
+    const SERVER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(100, 100, 111, 1));
+
+    #[test]
+    fn reduces_ttl_of_cached_records() {
+        let mut cache = ResponseCache::new();
+        let now = Instant::now();
+
+        cache.insert(
+            SERVER,
+            name("example.com"),
+            RecordType::A,
+            &Ok(a_lookup(300)),
+            now,
+        );
+
+        let lookup = cache
+            .get(
+                SERVER,
+                &name("example.com"),
+                RecordType::A,
+                now + Duration::from_secs(100),
+            )
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(lookup.records()[0].ttl(), 200);
+    }
+
+    #[test]
+    fn expires_after_ttl() {
+        let mut cache = ResponseCache::new();
+        let now = Instant::now();
+
+        cache.insert(
+            SERVER,
+            name("example.com"),
+            RecordType::A,
+            &Ok(a_lookup(300)),
+            now,
+        );
+
+        assert!(cache
+            .get(
+                SERVER,
+                &name("example.com"),
+                RecordType::A,
+                now + Duration::from_secs(300)
+            )
+            .is_none());
+    }
+
+    #[test]
+    fn keys_by_server_and_record_type() {
+        let mut cache = ResponseCache::new();
+        let now = Instant::now();
+
+        cache.insert(
+            SERVER,
+            name("example.com"),
+            RecordType::A,
+            &Ok(a_lookup(300)),
+            now,
+        );
+
+        assert!(cache
+            .get(SERVER, &name("example.com"), RecordType::AAAA, now)
+            .is_none());
+        assert!(cache
+            .get(
+                IpAddr::V4(std::net::Ipv4Addr::new(100, 100, 111, 2)),
+                &name("example.com"),
+                RecordType::A,
+                now
+            )
+            .is_none());
+    }
+
+    #[test]
+    fn caches_negative_responses_for_negative_ttl() {
+        let mut cache = ResponseCache::new();
+        let now = Instant::now();
+
+        cache.insert(
+            SERVER,
+            name("does.not.exist"),
+            RecordType::A,
+            &Err(nx_domain(Some(60))),
+            now,
+        );
+
+        assert!(cache
+            .get(
+                SERVER,
+                &name("does.not.exist"),
+                RecordType::A,
+                now + Duration::from_secs(59)
+            )
+            .unwrap()
+            .is_err());
+        assert!(cache
+            .get(
+                SERVER,
+                &name("does.not.exist"),
+                RecordType::A,
+                now + Duration::from_secs(60)
+            )
+            .is_none());
+    }
+
+    #[test]
+    fn does_not_cache_negative_responses_without_soa() {
+        let mut cache = ResponseCache::new();
+        let now = Instant::now();
+
+        cache.insert(
+            SERVER,
+            name("does.not.exist"),
+            RecordType::A,
+            &Err(nx_domain(None)),
+            now,
+        );
+
+        assert!(cache
+            .get(SERVER, &name("does.not.exist"), RecordType::A, now)
+            .is_none());
+    }
+
+    #[test]
+    fn does_not_cache_other_errors() {
+        let mut cache = ResponseCache::new();
+        let now = Instant::now();
+
+        cache.insert(
+            SERVER,
+            name("example.com"),
+            RecordType::A,
+            &Err(ResolveError::from("connection refused")),
+            now,
+        );
+
+        assert!(cache
+            .get(SERVER, &name("example.com"), RecordType::A, now)
+            .is_none());
+    }
+
+    #[test]
+    fn evicts_earliest_expiring_entry_when_full() {
+        let mut cache = ResponseCache::new();
+        let now = Instant::now();
+
+        for i in 0..MAX_ENTRIES {
+            cache.insert(
+                SERVER,
+                name(&format!("{i}.example.com")),
+                RecordType::A,
+                &Ok(a_lookup(300 + i as u32)),
+                now,
+            );
+        }
+        cache.insert(
+            SERVER,
+            name("new.example.com"),
+            RecordType::A,
+            &Ok(a_lookup(300)),
+            now,
+        );
+
+        assert_eq!(cache.entries.len(), MAX_ENTRIES);
+        assert!(cache
+            .get(SERVER, &name("0.example.com"), RecordType::A, now)
+            .is_none());
+        assert!(cache
+            .get(SERVER, &name("new.example.com"), RecordType::A, now)
+            .is_some());
+    }
+
+    fn name(name: &str) -> DomainName {
+        DomainName::vec_from_str(name).unwrap()
+    }
+
+    fn a_lookup(ttl: u32) -> Lookup {
+        let name = Name::from_str("example.com.").unwrap();
+
+        Lookup::new_with_max_ttl(
+            Query::query(name.clone(), RecordType::A),
+            vec![Record::from_rdata(
+                name,
+                ttl,
+                RData::A(A::new(93, 184, 215, 14)),
+            )]
+            .into(),
+        )
+    }
+
+    fn nx_domain(negative_ttl: Option<u32>) -> ResolveError {
+        let name = Name::from_str("does.not.exist.").unwrap();
+        let soa = negative_ttl.map(|ttl| {
+            Box::new(Record::from_rdata(
+                Name::from_str("exist.").unwrap(),
+                ttl,
+                SOA::new(
+                    Name::from_str("ns.exist.").unwrap(),
+                    Name::from_str("admin.exist.").unwrap(),
+                    1,
+                    3600,
+                    600,
+                    86400,
+                    ttl,
+                ),
+            ))
+        });
+
+        ResolveError::from(ProtoError::from(ProtoErrorKind::NoRecordsFound {
+            query: Box::new(Query::query(name, RecordType::A)),
+            soa,
+            negative_ttl,
+            response_code: ResponseCode::NXDomain,
+            trusted: true,
+        }))
+    }
+}
@@ -0,0 +1,1035 @@
+//! A minimal TCP server for DNS queries sent to our sentinel DNS servers, see [RFC7766](https://www.rfc-editor.org/rfc/rfc7766).
+//!
+//! Resolvers use TCP for responses that don't fit into a UDP datagram and some of them use it by default.
+//! We only implement as much of TCP as is needed to exchange DNS messages with a local resolver over the TUN device:
+//! no options other than the client's MSS, no congestion control and no reassembly of out-of-order segments.
+//!
+//! To reuse the UDP code paths for resolving queries, every query is handed out as a UDP packet from the client's socket to the sentinel.
+//! Responses to those are passed back in as UDP packets and written to the corresponding TCP stream.
+
+use bimap::BiMap;
+use connlib_shared::messages::DnsServer;
+use ip_packet::{
+    tcp::{TcpFlags, TcpOptionNumbers, TcpPacket},
+    IpPacket, MutableIpPacket, Packet as _,
+};
+use rand_core::{OsRng, RngCore as _};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+const DNS_PORT: u16 = 53;
+
+/// The MSS we assume if the client's SYN doesn't carry one, see <https://www.rfc-editor.org/rfc/rfc9293#section-3.7.1>.
+const DEFAULT_MSS_V4: usize = 536;
+const DEFAULT_MSS_V6: usize = 1220;
+/// The largest segments we send, regardless of the client's MSS: Our packets need to fit into the MTU of the TUN device.
+const MAX_MSS: usize = crate::MTU - 60;
+/// The receive window we advertise.
+const WINDOW: u16 = u16::MAX;
+
+/// How many connections we accept at the same time, further SYNs are reset.
+const MAX_CONNECTIONS: usize = 100;
+
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_RETRANSMITS: u32 = 5;
+/// How long we keep a connection open without any activity, see <https://www.rfc-editor.org/rfc/rfc7766#section-6.2.3>.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) struct TcpDnsServer {
+    /// The connections we accepted, indexed by the client's and our socket.
+    connections: HashMap<(SocketAddr, SocketAddr), Connection>,
+
+    buffered_queries: VecDeque<MutableIpPacket<'static>>,
+    buffered_segments: VecDeque<IpPacket<'static>>,
+
+    last_now: Instant,
+}
+
+struct Connection {
+    state: State,
+
+    /// The sequence number of our SYN.
+    iss: u32,
+    /// The oldest sequence number we sent that hasn't been acknowledged.
+    snd_una: u32,
+    /// The sequence number of the next byte we send.
+    snd_nxt: u32,
+    /// The next sequence number we expect from the client.
+    rcv_nxt: u32,
+    /// How many bytes beyond [`Connection::snd_una`] the client is willing to receive.
+    snd_wnd: u32,
+    /// The largest payload of a segment the client is willing to receive.
+    mss: usize,
+
+    /// Data that hasn't been acknowledged yet, starting at [`Connection::snd_una`].
+    ///
+    /// Everything up to [`Connection::snd_nxt`] has been sent, the rest waits for the client to open its window.
+    send_buffer: VecDeque<u8>,
+    /// Data of the client that doesn't form a complete DNS message yet.
+    received: Vec<u8>,
+
+    /// How many queries we handed out that we haven't sent a response for.
+    pending_queries: usize,
+    fin_received: bool,
+    fin_sent: bool,
+
+    retransmit_at: Option<Instant>,
+    num_retransmits: u32,
+    last_activity: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// We received the SYN and answered with a SYN-ACK.
+    SynReceived,
+    Established,
+}
+
+impl TcpDnsServer {
+    pub(crate) fn new(now: Instant) -> Self {
+        Self {
+            connections: Default::default(),
+            buffered_queries: Default::default(),
+            buffered_segments: Default::default(),
+            last_now: now,
+        }
+    }
+
+    /// Handles a TCP segment sent to port 53 of one of our sentinel DNS servers.
+    ///
+    /// Returns `false` if the packet is not such a segment.
+    pub(crate) fn handle(
+        &mut self,
+        dns_mapping: &BiMap<IpAddr, DnsServer>,
+        packet: &IpPacket<'_>,
+        now: Instant,
+    ) -> bool {
+        if !dns_mapping.contains_left(&packet.destination()) {
+            return false;
+        }
+        let Some(segment) = packet.as_tcp() else {
+            return false;
+        };
+        if segment.get_destination() != DNS_PORT {
+            return false;
+        }
+
+        self.last_now = now;
+
+        let client = SocketAddr::new(packet.source(), segment.get_source());
+        let server = SocketAddr::new(packet.destination(), segment.get_destination());
+        let flags = segment.get_flags();
+        let seq = segment.get_sequence();
+        let payload = segment.payload();
+
+        if flags & TcpFlags::RST != 0 {
+            self.connections.remove(&(client, server));
+            return true;
+        }
+
+        let Some(connection) = self.connections.get_mut(&(client, server)) else {
+            if flags & TcpFlags::SYN != 0 && flags & TcpFlags::ACK == 0 {
+                if self.connections.len() >= MAX_CONNECTIONS {
+                    tracing::debug!(%client, "Too many DNS over TCP connections, refusing new one");
+
+                    self.buffered_segments.push_back(segment_to(
+                        client,
+                        server,
+                        0,
+                        seq.wrapping_add(1),
+                        TcpFlags::RST | TcpFlags::ACK,
+                        Vec::new(),
+                    ));
+
+                    return true;
+                }
+
+                let mss = mss_option(&segment)
+                    .map(usize::from)
+                    .unwrap_or(match server {
+                        SocketAddr::V4(_) => DEFAULT_MSS_V4,
+                        SocketAddr::V6(_) => DEFAULT_MSS_V6,
+                    })
+                    .min(MAX_MSS);
+                let connection = Connection::new(seq, segment.get_window(), mss, now);
+                self.buffered_segments
+                    .push_back(connection.syn_ack(client, server));
+                self.connections.insert((client, server), connection);
+
+                return true;
+            }
+
+            if flags & TcpFlags::ACK != 0 {
+                self.buffered_segments.push_back(segment_to(
+                    client,
+                    server,
+                    segment.get_acknowledgement(),
+                    0,
+                    TcpFlags::RST,
+                    Vec::new(),
+                ));
+            }
+
+            return true;
+        };
+        connection.last_activity = now;
+
+        if flags & TcpFlags::SYN != 0 {
+            // The client didn't receive our SYN-ACK and retransmitted its SYN.
+            if connection.state == State::SynReceived {
+                self.buffered_segments
+                    .push_back(connection.syn_ack(client, server));
+            }
+
+            return true;
+        }
+
+        if flags & TcpFlags::ACK != 0 {
+            connection.on_ack(segment.get_acknowledgement(), segment.get_window(), now);
+
+            // The client may have opened its window.
+            self.buffered_segments
+                .extend(connection.send_buffered(client, server, now));
+        }
+
+        let mut needs_ack = false;
+
+        if !payload.is_empty() {
+            needs_ack = true;
+
+            // Segments from the TUN device are very unlikely to be reordered, thus we drop out-of-order ones and let the client retransmit them.
+            if seq == connection.rcv_nxt {
+                connection.received.extend_from_slice(payload);
+                connection.rcv_nxt = connection.rcv_nxt.wrapping_add(payload.len() as u32);
+
+                while let Some(message) = connection.take_message() {
+                    connection.pending_queries += 1;
+                    self.buffered_queries.push_back(ip_packet::make::udp_packet(
+                        client.ip(),
+                        server.ip(),
+                        client.port(),
+                        server.port(),
+                        message,
+                    ));
+                }
+            }
+        }
+
+        if flags & TcpFlags::FIN != 0
+            && seq.wrapping_add(payload.len() as u32) == connection.rcv_nxt
+            && !connection.fin_received
+        {
+            needs_ack = true;
+
+            connection.rcv_nxt = connection.rcv_nxt.wrapping_add(1);
+            connection.fin_received = true;
+        }
+
+        if needs_ack {
+            self.buffered_segments.push_back(connection.segment(
+                client,
+                server,
+                connection.snd_nxt,
+                TcpFlags::ACK,
+                Vec::new(),
+            ));
+        }
+
+        self.maybe_close(client, server);
+
+        true
+    }
+
+    /// Writes the DNS response contained in the given UDP packet to the TCP stream of the query it answers.
+    pub(crate) fn send_response(&mut self, response: IpPacket<'_>) {
+        let Some(datagram) = response.as_udp() else {
+            return;
+        };
+
+        let client = SocketAddr::new(response.destination(), datagram.get_destination());
+        let server = SocketAddr::new(response.source(), datagram.get_source());
+
+        let Some(connection) = self.connections.get_mut(&(client, server)) else {
+            tracing::debug!(%client, "Dropping DNS response because TCP connection is closed");
+            return;
+        };
+
+        let message = datagram.payload();
+        let Ok(len) = u16::try_from(message.len()) else {
+            tracing::debug!(%client, "Dropping DNS response because it is too large");
+            return;
+        };
+        connection.pending_queries = connection.pending_queries.saturating_sub(1);
+
+        let mut data = Vec::with_capacity(2 + message.len());
+        data.extend_from_slice(&len.to_be_bytes());
+        data.extend_from_slice(message);
+
+        let now = self.last_now;
+        self.buffered_segments
+            .extend(connection.send_data(client, server, data, now));
+
+        self.maybe_close(client, server);
+    }
+
+    /// Forgets a query that we won't send a response for.
+    pub(crate) fn discard_query(&mut self, query: &IpPacket<'_>) {
+        let Some(datagram) = query.as_udp() else {
+            return;
+        };
+
+        let client = SocketAddr::new(query.source(), datagram.get_source());
+        let server = SocketAddr::new(query.destination(), datagram.get_destination());
+
+        if let Some(connection) = self.connections.get_mut(&(client, server)) {
+            connection.pending_queries = connection.pending_queries.saturating_sub(1);
+        }
+
+        self.maybe_close(client, server);
+    }
+
+    /// Returns the next DNS query we received, wrapped in a UDP packet from the client to the sentinel.
+    pub(crate) fn poll_query(&mut self) -> Option<MutableIpPacket<'static>> {
+        self.buffered_queries.pop_front()
+    }
+
+    /// Returns the next TCP segment to be written to the TUN device.
+    pub(crate) fn poll_segment(&mut self) -> Option<IpPacket<'static>> {
+        self.buffered_segments.pop_front()
+    }
+
+    pub(crate) fn poll_timeout(&self) -> Option<Instant> {
+        self.connections
+            .values()
+            .flat_map(|c| {
+                let idle_timeout = c.is_idle().then_some(c.last_activity + IDLE_TIMEOUT);
+
+                [c.retransmit_at, idle_timeout]
+            })
+            .flatten()
+            .min()
+    }
+
+    pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        self.last_now = now;
+
+        let mut closed = Vec::new();
+
+        for ((client, server), connection) in self.connections.iter_mut() {
+            if connection.is_idle() && now >= connection.last_activity + IDLE_TIMEOUT {
+                tracing::debug!(%client, "Closing idle DNS over TCP connection");
+
+                self.buffered_segments.push_back(connection.segment(
+                    *client,
+                    *server,
+                    connection.snd_nxt,
+                    TcpFlags::RST,
+                    Vec::new(),
+                ));
+                closed.push((*client, *server));
+                continue;
+            }
+
+            if connection.retransmit_at.is_some_and(|at| now >= at) {
+                if connection.num_retransmits >= MAX_RETRANSMITS {
+                    tracing::debug!(%client, "DNS over TCP connection timed out");
+
+                    self.buffered_segments.push_back(connection.segment(
+                        *client,
+                        *server,
+                        connection.snd_nxt,
+                        TcpFlags::RST,
+                        Vec::new(),
+                    ));
+                    closed.push((*client, *server));
+                    continue;
+                }
+
+                self.buffered_segments
+                    .extend(connection.retransmit(*client, *server, now));
+            }
+        }
+
+        for key in closed {
+            self.connections.remove(&key);
+        }
+    }
+
+    /// Sends our FIN once the client sent its FIN and we sent the responses to all its queries, and forgets the connection once that FIN is acknowledged.
+    fn maybe_close(&mut self, client: SocketAddr, server: SocketAddr) {
+        let Some(connection) = self.connections.get_mut(&(client, server)) else {
+            return;
+        };
+
+        if connection.fin_received
+            && connection.pending_queries == 0
+            && !connection.has_unsent_data()
+            && !connection.fin_sent
+        {
+            let now = self.last_now;
+            self.buffered_segments
+                .push_back(connection.send_fin(client, server, now));
+        }
+
+        if connection.fin_sent && connection.snd_una == connection.snd_nxt {
+            self.connections.remove(&(client, server));
+        }
+    }
+}
+
+impl Connection {
+    fn new(client_seq: u32, window: u16, mss: usize, now: Instant) -> Self {
+        let iss = OsRng.next_u32();
+
+        Self {
+            state: State::SynReceived,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1),
+            rcv_nxt: client_seq.wrapping_add(1),
+            snd_wnd: u32::from(window),
+            mss,
+            send_buffer: VecDeque::default(),
+            received: Vec::default(),
+            pending_queries: 0,
+            fin_received: false,
+            fin_sent: false,
+            retransmit_at: Some(now + RETRANSMIT_TIMEOUT),
+            num_retransmits: 0,
+            last_activity: now,
+        }
+    }
+
+    fn on_ack(&mut self, ack: u32, window: u16, now: Instant) {
+        let num_acked = ack.wrapping_sub(self.snd_una);
+        let num_outstanding = self.snd_nxt.wrapping_sub(self.snd_una);
+
+        if num_acked > num_outstanding {
+            return;
+        }
+
+        // We don't negotiate window scaling, thus the window is always in bytes.
+        self.snd_wnd = u32::from(window);
+
+        if num_acked == 0 {
+            return;
+        }
+
+        let mut num_acked_data = num_acked as usize;
+
+        if self.state == State::SynReceived {
+            self.state = State::Established;
+            num_acked_data -= 1;
+        }
+
+        let num_drained = num_acked_data.min(self.send_buffer.len());
+        self.send_buffer.drain(..num_drained);
+
+        self.snd_una = ack;
+        self.num_retransmits = 0;
+        self.retransmit_at = (self.snd_una != self.snd_nxt || self.has_unsent_data())
+            .then_some(now + RETRANSMIT_TIMEOUT);
+    }
+
+    /// The number of bytes in [`Connection::send_buffer`] that we have sent.
+    fn num_sent(&self) -> usize {
+        (self.snd_nxt.wrapping_sub(self.snd_una) as usize).min(self.send_buffer.len())
+    }
+
+    fn has_unsent_data(&self) -> bool {
+        self.num_sent() < self.send_buffer.len()
+    }
+
+    /// Whether we are neither resolving queries nor waiting for the client to acknowledge our data.
+    fn is_idle(&self) -> bool {
+        self.pending_queries == 0 && self.snd_una == self.snd_nxt && !self.has_unsent_data()
+    }
+
+    /// Takes the next complete, length-prefixed DNS message out of the received data.
+    fn take_message(&mut self) -> Option<Vec<u8>> {
+        let len = u16::from_be_bytes([*self.received.first()?, *self.received.get(1)?]) as usize;
+
+        if self.received.len() < 2 + len {
+            return None;
+        }
+
+        let message = self.received[2..2 + len].to_vec();
+        self.received.drain(..2 + len);
+
+        Some(message)
+    }
+
+    fn send_data(
+        &mut self,
+        client: SocketAddr,
+        server: SocketAddr,
+        data: Vec<u8>,
+        now: Instant,
+    ) -> Vec<IpPacket<'static>> {
+        self.send_buffer.extend(data);
+        self.retransmit_at.get_or_insert(now + RETRANSMIT_TIMEOUT);
+
+        self.send_buffered(client, server, now)
+    }
+
+    /// Sends as much of the unsent data as the client's window allows, in segments of at most the client's MSS.
+    fn send_buffered(
+        &mut self,
+        client: SocketAddr,
+        server: SocketAddr,
+        now: Instant,
+    ) -> Vec<IpPacket<'static>> {
+        let mut segments = Vec::new();
+
+        if self.state == State::SynReceived {
+            return segments;
+        }
+
+        loop {
+            let num_sent = self.num_sent();
+            let window = (self.snd_wnd as usize).saturating_sub(num_sent);
+            let len = (self.send_buffer.len() - num_sent)
+                .min(window)
+                .min(self.mss);
+
+            if len == 0 {
+                break;
+            }
+
+            let payload = self
+                .send_buffer
+                .range(num_sent..num_sent + len)
+                .copied()
+                .collect();
+
+            segments.push(self.segment(
+                client,
+                server,
+                self.snd_nxt,
+                TcpFlags::PSH | TcpFlags::ACK,
+                payload,
+            ));
+            self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+        }
+
+        if !segments.is_empty() {
+            self.retransmit_at.get_or_insert(now + RETRANSMIT_TIMEOUT);
+        }
+
+        segments
+    }
+
+    fn send_fin(
+        &mut self,
+        client: SocketAddr,
+        server: SocketAddr,
+        now: Instant,
+    ) -> IpPacket<'static> {
+        let fin = self.segment(
+            client,
+            server,
+            self.snd_nxt,
+            TcpFlags::FIN | TcpFlags::ACK,
+            Vec::new(),
+        );
+
+        self.snd_nxt = self.snd_nxt.wrapping_add(1);
+        self.fin_sent = true;
+        self.retransmit_at.get_or_insert(now + RETRANSMIT_TIMEOUT);
+
+        fin
+    }
+
+    /// Resends everything that hasn't been acknowledged yet, backing off exponentially.
+    ///
+    /// If the client's window is closed, this sends a single byte to probe whether it opened again.
+    fn retransmit(
+        &mut self,
+        client: SocketAddr,
+        server: SocketAddr,
+        now: Instant,
+    ) -> Vec<IpPacket<'static>> {
+        self.num_retransmits += 1;
+        self.retransmit_at = Some(now + RETRANSMIT_TIMEOUT * 2u32.pow(self.num_retransmits));
+
+        if self.state == State::SynReceived {
+            return vec![self.syn_ack(client, server)];
+        }
+
+        if self.num_sent() == 0 && self.has_unsent_data() {
+            let probe = self.send_buffer.front().copied().into_iter().collect();
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+
+            return vec![self.segment(
+                client,
+                server,
+                self.snd_una,
+                TcpFlags::PSH | TcpFlags::ACK,
+                probe,
+            )];
+        }
+
+        let unacked = self
+            .send_buffer
+            .range(..self.num_sent())
+            .copied()
+            .collect::<Vec<_>>();
+        let mut segments = Vec::new();
+        let mut seq = self.snd_una;
+
+        for chunk in unacked.chunks(self.mss) {
+            segments.push(self.segment(
+                client,
+                server,
+                seq,
+                TcpFlags::PSH | TcpFlags::ACK,
+                chunk.to_vec(),
+            ));
+            seq = seq.wrapping_add(chunk.len() as u32);
+        }
+
+        if self.fin_sent {
+            segments.push(self.segment(
+                client,
+                server,
+                seq,
+                TcpFlags::FIN | TcpFlags::ACK,
+                Vec::new(),
+            ));
+        }
+
+        segments
+    }
+
+    fn syn_ack(&self, client: SocketAddr, server: SocketAddr) -> IpPacket<'static> {
+        self.segment(
+            client,
+            server,
+            self.iss,
+            TcpFlags::SYN | TcpFlags::ACK,
+            Vec::new(),
+        )
+    }
+
+    fn segment(
+        &self,
+        client: SocketAddr,
+        server: SocketAddr,
+        seq: u32,
+        flags: u8,
+        payload: Vec<u8>,
+    ) -> IpPacket<'static> {
+        segment_to(client, server, seq, self.rcv_nxt, flags, payload)
+    }
+}
+
+/// Returns the value of the MSS option of the given segment.
+fn mss_option(segment: &TcpPacket<'_>) -> Option<u16> {
+    let option = segment
+        .get_options_iter()
+        .find(|o| o.get_number() == TcpOptionNumbers::MSS)?;
+    let value = option.payload();
+
+    Some(u16::from_be_bytes([*value.first()?, *value.get(1)?]))
+}
+
+fn segment_to(
+    client: SocketAddr,
+    server: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: Vec<u8>,
+) -> IpPacket<'static> {
+    ip_packet::make::tcp_segment(
+        server.ip(),
+        client.ip(),
+        server.port(),
+        client.port(),
+        seq,
+        ack,
+        flags,
+        WINDOW,
+        payload,
+    )
+    .into_immutable()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const CLIENT: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(100, 64, 0, 1)), 40000);
+    const SENTINEL: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(100, 100, 111, 1)), 53);
+    const CLIENT_ISS: u32 = 1000;
+
+    #[test]
+    fn answers_query_over_established_connection() {
+        let now = Instant::now();
+        let mut server = TcpDnsServer::new(now);
+
+        let server_iss = handshake(&mut server, now);
+
+        let query = b"\x00\x03abc";
+        send(
+            &mut server,
+            CLIENT_ISS + 1,
+            server_iss.wrapping_add(1),
+            TcpFlags::PSH | TcpFlags::ACK,
+            query,
+            now,
+        );
+
+        let ack = server.poll_segment().unwrap();
+        let ack = ack.as_tcp().unwrap();
+        assert_eq!(ack.get_flags(), TcpFlags::ACK);
+        assert_eq!(
+            ack.get_acknowledgement(),
+            CLIENT_ISS + 1 + query.len() as u32
+        );
+
+        let mut query = server.poll_query().unwrap();
+        assert_eq!(query.source(), CLIENT.ip());
+        assert_eq!(query.destination(), SENTINEL.ip());
+        assert_eq!(query.unwrap_as_udp().payload(), b"abc");
+
+        server.send_response(
+            ip_packet::make::udp_packet(
+                SENTINEL.ip(),
+                CLIENT.ip(),
+                53,
+                CLIENT.port(),
+                b"defg".to_vec(),
+            )
+            .into_immutable(),
+        );
+
+        let response = server.poll_segment().unwrap();
+        assert_eq!(response.destination(), CLIENT.ip());
+        let response = response.as_tcp().unwrap();
+        assert_eq!(response.get_sequence(), server_iss.wrapping_add(1));
+        assert_eq!(response.payload(), b"\x00\x04defg");
+    }
+
+    #[test]
+    fn reassembles_query_split_across_segments() {
+        let now = Instant::now();
+        let mut server = TcpDnsServer::new(now);
+
+        let server_iss = handshake(&mut server, now);
+
+        send(
+            &mut server,
+            CLIENT_ISS + 1,
+            server_iss.wrapping_add(1),
+            TcpFlags::ACK,
+            b"\x00\x03a",
+            now,
+        );
+        assert!(server.poll_query().is_none());
+
+        send(
+            &mut server,
+            CLIENT_ISS + 4,
+            server_iss.wrapping_add(1),
+            TcpFlags::ACK,
+            b"bc\x00\x01d",
+            now,
+        );
+
+        assert_eq!(
+            server.poll_query().unwrap().unwrap_as_udp().payload(),
+            b"abc"
+        );
+        assert_eq!(server.poll_query().unwrap().unwrap_as_udp().payload(), b"d");
+    }
+
+    #[test]
+    fn closes_connection_after_answering_all_queries() {
+        let now = Instant::now();
+        let mut server = TcpDnsServer::new(now);
+
+        let server_iss = handshake(&mut server, now);
+
+        send(
+            &mut server,
+            CLIENT_ISS + 1,
+            server_iss.wrapping_add(1),
+            TcpFlags::FIN | TcpFlags::ACK,
+            b"\x00\x01a",
+            now,
+        );
+        let query = server.poll_query().unwrap();
+        drain_segments(&mut server);
+
+        server.discard_query(&query.as_immutable());
+
+        let fin = server.poll_segment().unwrap();
+        let fin = fin.as_tcp().unwrap();
+        assert_eq!(fin.get_flags(), TcpFlags::FIN | TcpFlags::ACK);
+
+        send(
+            &mut server,
+            CLIENT_ISS + 5,
+            server_iss.wrapping_add(2),
+            TcpFlags::ACK,
+            b"",
+            now,
+        );
+
+        assert!(server.connections.is_empty());
+    }
+
+    #[test]
+    fn resets_segments_of_unknown_connections() {
+        let now = Instant::now();
+        let mut server = TcpDnsServer::new(now);
+
+        send(&mut server, CLIENT_ISS, 42, TcpFlags::ACK, b"", now);
+
+        let rst = server.poll_segment().unwrap();
+        let rst = rst.as_tcp().unwrap();
+        assert_eq!(rst.get_flags(), TcpFlags::RST);
+        assert_eq!(rst.get_sequence(), 42);
+    }
+
+    #[test]
+    fn retransmits_unacknowledged_response() {
+        let now = Instant::now();
+        let mut server = TcpDnsServer::new(now);
+
+        let server_iss = handshake(&mut server, now);
+        send(
+            &mut server,
+            CLIENT_ISS + 1,
+            server_iss.wrapping_add(1),
+            TcpFlags::ACK,
+            b"\x00\x01a",
+            now,
+        );
+        server.poll_query().unwrap();
+        server.send_response(
+            ip_packet::make::udp_packet(
+                SENTINEL.ip(),
+                CLIENT.ip(),
+                53,
+                CLIENT.port(),
+                b"b".to_vec(),
+            )
+            .into_immutable(),
+        );
+        drain_segments(&mut server);
+
+        let timeout = server.poll_timeout().unwrap();
+        assert_eq!(timeout, now + RETRANSMIT_TIMEOUT);
+        server.handle_timeout(timeout);
+
+        let retransmit = server.poll_segment().unwrap();
+        let retransmit = retransmit.as_tcp().unwrap();
+        assert_eq!(retransmit.get_sequence(), server_iss.wrapping_add(1));
+        assert_eq!(retransmit.payload(), b"\x00\x01b");
+    }
+
+    #[test]
+    fn sends_no_more_than_window_of_client() {
+        let now = Instant::now();
+        let mut server = TcpDnsServer::new(now);
+
+        let server_iss = handshake(&mut server, now);
+        send_with_window(
+            &mut server,
+            CLIENT_ISS + 1,
+            server_iss.wrapping_add(1),
+            TcpFlags::ACK,
+            4,
+            b"\x00\x01a",
+            now,
+        );
+        server.poll_query().unwrap();
+        drain_segments(&mut server);
+
+        server.send_response(
+            ip_packet::make::udp_packet(
+                SENTINEL.ip(),
+                CLIENT.ip(),
+                53,
+                CLIENT.port(),
+                b"bcdef".to_vec(),
+            )
+            .into_immutable(),
+        );
+
+        let segment = server.poll_segment().unwrap();
+        assert_eq!(segment.as_tcp().unwrap().payload(), b"\x00\x05bc");
+        assert!(server.poll_segment().is_none());
+
+        send_with_window(
+            &mut server,
+            CLIENT_ISS + 4,
+            server_iss.wrapping_add(5),
+            TcpFlags::ACK,
+            4,
+            b"",
+            now,
+        );
+
+        let segment = server.poll_segment().unwrap();
+        let segment = segment.as_tcp().unwrap();
+        assert_eq!(segment.get_sequence(), server_iss.wrapping_add(5));
+        assert_eq!(segment.payload(), b"def");
+    }
+
+    #[test]
+    fn parses_mss_option() {
+        let mut header = [0u8; 24];
+        header[12] = 6 << 4; // Data offset of 6 words.
+        header[20..].copy_from_slice(&[2, 4, 0x05, 0xb4]);
+
+        let segment = TcpPacket::new(&header).unwrap();
+
+        assert_eq!(mss_option(&segment), Some(1460));
+    }
+
+    #[test]
+    fn resets_connections_beyond_limit() {
+        let now = Instant::now();
+        let mut server = TcpDnsServer::new(now);
+
+        for port in 0..=MAX_CONNECTIONS as u16 {
+            let syn = ip_packet::make::tcp_segment(
+                CLIENT.ip(),
+                SENTINEL.ip(),
+                1000 + port,
+                SENTINEL.port(),
+                CLIENT_ISS,
+                0,
+                TcpFlags::SYN,
+                WINDOW,
+                Vec::new(),
+            )
+            .into_immutable();
+
+            assert!(server.handle(&dns_mapping(), &syn, now));
+        }
+
+        let last = server.buffered_segments.pop_back().unwrap();
+        let last = last.as_tcp().unwrap();
+        assert_eq!(last.get_flags(), TcpFlags::RST | TcpFlags::ACK);
+        assert_eq!(last.get_acknowledgement(), CLIENT_ISS + 1);
+        assert_eq!(server.connections.len(), MAX_CONNECTIONS);
+    }
+
+    #[test]
+    fn resets_connection_when_giving_up_on_retransmits() {
+        let now = Instant::now();
+        let mut server = TcpDnsServer::new(now);
+
+        let server_iss = handshake(&mut server, now);
+        send(
+            &mut server,
+            CLIENT_ISS + 1,
+            server_iss.wrapping_add(1),
+            TcpFlags::ACK,
+            b"\x00\x01a",
+            now,
+        );
+        server.poll_query().unwrap();
+        server.send_response(
+            ip_packet::make::udp_packet(
+                SENTINEL.ip(),
+                CLIENT.ip(),
+                53,
+                CLIENT.port(),
+                b"b".to_vec(),
+            )
+            .into_immutable(),
+        );
+
+        let mut last_segment = None;
+
+        while let Some(timeout) = server.poll_timeout() {
+            server.handle_timeout(timeout);
+
+            while let Some(segment) = server.poll_segment() {
+                last_segment = Some(segment);
+            }
+        }
+
+        let rst = last_segment.unwrap();
+        assert_eq!(rst.as_tcp().unwrap().get_flags(), TcpFlags::RST);
+        assert!(server.connections.is_empty());
+    }
+
+    fn handshake(server: &mut TcpDnsServer, now: Instant) -> u32 {
+        send(server, CLIENT_ISS, 0, TcpFlags::SYN, b"", now);
+
+        let syn_ack = server.poll_segment().unwrap();
+        let syn_ack = syn_ack.as_tcp().unwrap();
+        assert_eq!(syn_ack.get_flags(), TcpFlags::SYN | TcpFlags::ACK);
+        assert_eq!(syn_ack.get_acknowledgement(), CLIENT_ISS + 1);
+        let server_iss = syn_ack.get_sequence();
+
+        send(
+            server,
+            CLIENT_ISS + 1,
+            server_iss.wrapping_add(1),
+            TcpFlags::ACK,
+            b"",
+            now,
+        );
+        assert!(server.poll_segment().is_none());
+
+        server_iss
+    }
+
+    fn send(
+        server: &mut TcpDnsServer,
+        seq: u32,
+        ack: u32,
+        flags: u8,
+        payload: &[u8],
+        now: Instant,
+    ) {
+        send_with_window(server, seq, ack, flags, WINDOW, payload, now)
+    }
+
+    fn send_with_window(
+        server: &mut TcpDnsServer,
+        seq: u32,
+        ack: u32,
+        flags: u8,
+        window: u16,
+        payload: &[u8],
+        now: Instant,
+    ) {
+        let segment = ip_packet::make::tcp_segment(
+            CLIENT.ip(),
+            SENTINEL.ip(),
+            CLIENT.port(),
+            SENTINEL.port(),
+            seq,
+            ack,
+            flags,
+            window,
+            payload.to_vec(),
+        )
+        .into_immutable();
+
+        assert!(server.handle(&dns_mapping(), &segment, now));
+    }
+
+    fn drain_segments(server: &mut TcpDnsServer) {
+        while server.poll_segment().is_some() {}
+    }
+
+    fn dns_mapping() -> BiMap<IpAddr, DnsServer> {
+        BiMap::from_iter([(
+            SENTINEL.ip(),
+            DnsServer::from(("1.1.1.1".parse::<IpAddr>().unwrap(), 53)),
+        )])
+    }
+}
@@ -0,0 +1,143 @@
+//! TLS configuration for DNS over HTTPS and DNS over TLS upstream resolvers.
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use ring::digest::{digest, SHA256, SHA256_OUTPUT_LEN};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate, CertificateError, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName,
+};
+use std::{sync::Arc, time::SystemTime};
+
+/// The ALPN protocol DNS over HTTPS servers must support, see <https://www.rfc-editor.org/rfc/rfc8484#section-5.2>.
+const ALPN_H2: &[u8] = b"h2";
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum Error {
+    #[error("SPKI pin is not a base64-encoded SHA-256 digest")]
+    InvalidPin,
+    #[error("DNS over HTTPS is only supported on the `/dns-query` path")]
+    UnsupportedHttpsPath,
+}
+
+/// Builds the [`ClientConfig`] for a DNS over HTTPS server.
+pub(crate) fn https_client_config(spki_pin: Option<&str>) -> Result<ClientConfig, Error> {
+    let mut config = client_config(spki_pin)?;
+    config.alpn_protocols = vec![ALPN_H2.to_vec()];
+
+    Ok(config)
+}
+
+/// Builds the [`ClientConfig`] for a DNS over TLS server.
+pub(crate) fn tls_client_config(spki_pin: Option<&str>) -> Result<ClientConfig, Error> {
+    client_config(spki_pin)
+}
+
+fn client_config(spki_pin: Option<&str>) -> Result<ClientConfig, Error> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    let webpki = WebPkiVerifier::new(roots, None);
+
+    let verifier: Arc<dyn ServerCertVerifier> = match spki_pin {
+        Some(pin) => Arc::new(SpkiPinVerifier {
+            inner: webpki,
+            pin: parse_pin(pin)?,
+        }),
+        None => Arc::new(webpki),
+    };
+
+    Ok(ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth())
+}
+
+fn parse_pin(pin: &str) -> Result<[u8; SHA256_OUTPUT_LEN], Error> {
+    STANDARD
+        .decode(pin)
+        .ok()
+        .and_then(|pin| pin.try_into().ok())
+        .ok_or(Error::InvalidPin)
+}
+
+/// Verifies the certificate chain as usual and additionally requires the public key of the server's certificate to match the pin.
+///
+/// Only the end-entity certificate is checked against the pin: Intermediates are sent by the server and a matching one may not be part of the verified chain.
+struct SpkiPinVerifier {
+    inner: WebPkiVerifier,
+    pin: [u8; SHA256_OUTPUT_LEN],
+}
+
+impl ServerCertVerifier for SpkiPinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+
+        let (_, certificate) = x509_parser::parse_x509_certificate(&end_entity.0)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        let spki = certificate.public_key().raw;
+
+        if digest(&SHA256, spki).as_ref() != self.pin {
+            tracing::warn!(actual_pin = %STANDARD.encode(digest(&SHA256, spki)), "Certificate of DNS server doesn't match SPKI pin");
+
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+
+        Ok(verified)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A self-signed P-256 certificate for `dns.example.com`.
+    const CERTIFICATE: &str = "MIIBiTCCAS+gAwIBAgIUMaDlS5szES4VZxZDwTXc7yVeABQwCgYIKoZIzj0EAwIwGjEYMBYGA1UEAwwPZG5zLmV4YW1wbGUuY29tMB4XDTI2MTAxODE3MzcxOVoXDTM2MTAxNTE3MzcxOVowGjEYMBYGA1UEAwwPZG5zLmV4YW1wbGUuY29tMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEzMOQQ8tI/aqOQbReAqhKf5R23av4FL0/a0SrD5i//aSMLiIf8oXdJtrUnwt6gJSM2pk11Ak8UZizE5tbMTHcAKNTMFEwHQYDVR0OBBYEFG66V5LGFam1H3ORfNVDpycafaKxMB8GA1UdIwQYMBaAFG66V5LGFam1H3ORfNVDpycafaKxMA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZIzj0EAwIDSAAwRQIgRAb9vDIQm+vFJWnJJFt9inrysgQRmJUOxDgS1i6F/toCIQClsHiiGFGi2JeGYk9cxd/VNPNFuMVRmimwvMwUhUN1tA==";
+
+    /// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64`
+    const CERTIFICATE_PIN: &str = "TvmnPNksEs9MSWTqlqSxsIBB/oVE1b2ShZoJrWtfa40=";
+
+    #[test]
+    fn pin_matches_digest_of_spki() {
+        let certificate = STANDARD.decode(CERTIFICATE).unwrap();
+        let spki = spki(&certificate);
+
+        assert_eq!(
+            digest(&SHA256, &spki).as_ref(),
+            parse_pin(CERTIFICATE_PIN).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_pins() {
+        assert!(parse_pin("not base64!").is_err());
+        assert!(parse_pin("AAAA").is_err()); // Valid base64 but not 32 bytes.
+    }
+
+    fn spki(certificate: &[u8]) -> Vec<u8> {
+        let (_, certificate) = x509_parser::parse_x509_certificate(certificate).unwrap();
+
+        certificate.public_key().raw.to_vec()
+    }
+}
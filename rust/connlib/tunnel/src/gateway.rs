@@ -1,17 +1,23 @@
+use crate::client::{DNS_SENTINELS_V4, DNS_SENTINELS_V6};
+use crate::dns::{self, DnsQuery};
+use crate::io::DnsQueryError;
+use crate::mtu::TunMtu;
 use crate::peer::ClientOnGateway;
 use crate::peer_store::PeerStore;
-use crate::utils::earliest;
-use crate::{GatewayEvent, GatewayTunnel, Tun};
+use crate::sockets::Received;
+use crate::utils::{self, earliest};
+use crate::{GatewayEvent, GatewayTunnel, Tun, DEFAULT_MAX_RELAYS};
 use boringtun::x25519::PublicKey;
 use chrono::{DateTime, Utc};
 use connlib_shared::messages::{
-    gateway::ResolvedResourceDescriptionDns, gateway::ResourceDescription, Answer, ClientId, Key,
-    Offer, RelayId, ResourceId,
+    gateway::ResolvedResourceDescriptionDns, gateway::ResourceDescription, Answer, ClientId,
+    IceCredentials, Key, Offer, RelayId, ResourceId,
 };
 use connlib_shared::{Callbacks, DomainName, Error, Result, StaticSecret};
+use ip_network::IpNetwork;
 use ip_packet::{IpPacket, MutableIpPacket};
 use secrecy::{ExposeSecret as _, Secret};
-use snownet::{RelaySocket, ServerNode};
+use snownet::{ConnectionConfig, RelaySocket, ServerNode, TrafficClassPropagation};
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::time::{Duration, Instant};
@@ -113,6 +119,50 @@ where
     pub fn remove_ice_candidate(&mut self, conn_id: ClientId, ice_candidate: String) {
         self.role_state.remove_ice_candidate(conn_id, ice_candidate);
     }
+
+    pub fn end_of_ice_candidates(&mut self, conn_id: ClientId) {
+        self.role_state
+            .end_of_ice_candidates(conn_id, Instant::now());
+    }
+
+    /// A snapshot of the state of our connections and relays, to be included in support bundles.
+    pub fn snapshot(&self) -> snownet::NodeSnapshot<ClientId, RelayId> {
+        self.role_state.snapshot(Instant::now())
+    }
+
+    pub fn set_remote_ice_credentials(&mut self, conn_id: ClientId, credentials: IceCredentials) {
+        self.role_state
+            .set_remote_ice_credentials(conn_id, credentials, Instant::now());
+    }
+
+    pub fn set_preshared_key(&mut self, conn_id: ClientId, key: Secret<Key>) {
+        self.role_state
+            .set_preshared_key(conn_id, key, Instant::now());
+    }
+
+    /// Sets which parts of the traffic class of tunneled packets we copy onto the outer datagram and back.
+    ///
+    /// Both DSCP and ECN are copied unless configured otherwise.
+    pub fn set_traffic_class_propagation(&mut self, propagation: TrafficClassPropagation) {
+        self.role_state
+            .node
+            .set_traffic_class_propagation(propagation);
+    }
+
+    /// Limits relay candidates to the `max_relays` relays with the lowest RTT, see [`DEFAULT_MAX_RELAYS`].
+    pub fn set_max_relays(&mut self, max_relays: usize) {
+        self.role_state.node.set_max_relays(max_relays);
+    }
+
+    /// Sets how many threads we use to encrypt and decrypt the traffic of different clients in parallel.
+    pub fn set_crypto_workers(&mut self, workers: usize) {
+        self.role_state.node.set_crypto_workers(workers);
+    }
+
+    /// Sets the idle timeout, keep-alive and consent check interval of connections to clients created from now on.
+    pub fn set_connection_config(&mut self, config: ConnectionConfig) {
+        self.role_state.node.set_default_connection_config(config);
+    }
 }
 
 /// A SANS-IO implementation of a gateway's functionality.
@@ -130,28 +180,94 @@ pub struct GatewayState {
     next_expiry_resources_check: Option<Instant>,
 
     buffered_events: VecDeque<GatewayEvent>,
+    /// DNS queries of clients for records of their DNS resources that we need to resolve.
+    buffered_dns_queries: VecDeque<DnsQuery<'static>>,
+    /// Responses to DNS queries of clients, already encrypted for the respective client.
+    buffered_transmits: VecDeque<snownet::Transmit<'static>>,
+    /// The MTU of the TUN device, following the path MTUs of our connections to clients.
+    tun_mtu: TunMtu<ClientId>,
+    /// The IP ranges of the sentinel resolvers via which clients send us DNS queries for records of their DNS resources.
+    dns_sentinels: [IpNetwork; 2],
 }
 
 impl GatewayState {
     pub(crate) fn new(private_key: impl Into<StaticSecret>) -> Self {
+        let mut node = ServerNode::new(private_key.into());
+        node.set_traffic_class_propagation(TrafficClassPropagation {
+            dscp: true,
+            ecn: true,
+        });
+        node.set_max_relays(DEFAULT_MAX_RELAYS);
+
         Self {
             peers: Default::default(),
-            node: ServerNode::new(private_key.into()),
+            node,
             next_expiry_resources_check: Default::default(),
             buffered_events: VecDeque::default(),
+            buffered_dns_queries: VecDeque::default(),
+            buffered_transmits: VecDeque::default(),
+            tun_mtu: Default::default(),
+            dns_sentinels: [
+                DNS_SENTINELS_V4.parse().expect("valid IPv4 network"),
+                DNS_SENTINELS_V6.parse().expect("valid IPv6 network"),
+            ],
         }
     }
 
+    /// Limits how many datagrams we coalesce for UDP GSO to what our sockets support.
+    pub(crate) fn set_max_gso_segments(&mut self, segments: usize) {
+        self.node.set_max_gso_segments(segments);
+    }
+
     #[cfg(all(feature = "proptest", test))]
     pub(crate) fn public_key(&self) -> PublicKey {
         self.node.public_key()
     }
 
+    #[cfg(all(feature = "proptest", test))]
     pub(crate) fn encapsulate<'s>(
         &'s mut self,
         packet: MutableIpPacket<'_>,
         now: Instant,
     ) -> Option<snownet::Transmit<'s>> {
+        let (cid, packet) = self.route(packet, now)?;
+
+        let transmit = self
+            .node
+            .encapsulate(cid, packet.as_immutable(), now)
+            .inspect_err(|e| tracing::debug!(%cid, "Failed to encapsulate: {e}"))
+            .ok()??;
+
+        Some(transmit)
+    }
+
+    /// Encapsulates a batch of packets read from the TUN device.
+    ///
+    /// The packets of different clients are encrypted in parallel and those for the same client are coalesced for UDP GSO, see [`snownet::Node::encapsulate_many`].
+    pub(crate) fn encapsulate_batch<'p>(
+        &mut self,
+        packets: impl IntoIterator<Item = MutableIpPacket<'p>>,
+        now: Instant,
+    ) -> Vec<snownet::Transmit<'static>> {
+        let packets = packets
+            .into_iter()
+            .filter_map(|packet| self.route(packet, now))
+            .collect::<Vec<_>>();
+
+        self.node.encapsulate_many(
+            packets
+                .iter()
+                .map(|(cid, packet)| (*cid, packet.as_immutable())),
+            now,
+        )
+    }
+
+    /// Figures out which client a packet read from the TUN device needs to be sent to and translates it back if it was NATed.
+    fn route<'p>(
+        &mut self,
+        packet: MutableIpPacket<'p>,
+        now: Instant,
+    ) -> Option<(ClientId, MutableIpPacket<'p>)> {
         let dst = packet.destination();
 
         let Some(peer) = self.peers.peer_by_ip_mut(dst) else {
@@ -166,47 +282,111 @@ impl GatewayState {
             .inspect_err(|e| tracing::debug!(%cid, "Failed to encapsulate: {e}"))
             .ok()??;
 
-        let transmit = self
-            .node
-            .encapsulate(peer.id(), packet.as_immutable(), now)
-            .inspect_err(|e| tracing::debug!(%cid, "Failed to encapsulate: {e}"))
-            .ok()??;
-
-        Some(transmit)
+        Some((cid, packet))
     }
 
-    pub(crate) fn decapsulate<'b>(
+    pub(crate) fn decapsulate_batch<'b>(
         &mut self,
-        local: SocketAddr,
-        from: SocketAddr,
-        packet: &[u8],
+        received: Received<'_>,
         now: Instant,
         buffer: &'b mut [u8],
-    ) -> Option<IpPacket<'b>> {
-        let (cid, packet) = self.node.decapsulate(
+    ) -> Vec<IpPacket<'b>> {
+        let Received {
             local,
             from,
-            packet,
-            now,
-            buffer,
-        )
-        .inspect_err(|e| tracing::debug!(%from, num_bytes = %packet.len(), "Failed to decapsulate incoming packet: {e}"))
-        .ok()??;
+            datagrams,
+            stride,
+            ecn,
+        } = received;
+
+        self.node
+            .decapsulate_batch(local, from, datagrams, stride, now, buffer)
+            .into_iter()
+            .filter_map(|(cid, packet)| self.on_decapsulated(from, cid, packet, ecn, now))
+            .collect()
+    }
 
+    fn on_decapsulated<'b>(
+        &mut self,
+        from: SocketAddr,
+        cid: ClientId,
+        packet: MutableIpPacket<'b>,
+        ecn: u8,
+        now: Instant,
+    ) -> Option<IpPacket<'b>> {
         let Some(peer) = self.peers.get_mut(&cid) else {
             tracing::warn!(%cid, "Couldn't find connection by ID");
 
             return None;
         };
 
-        let packet = peer
+        let destination = packet.destination();
+        if self.dns_sentinels.iter().any(|s| s.contains(destination)) {
+            if let Some(query) = dns::parse_gateway_query(packet.as_immutable()) {
+                match peer.ensure_allowed_dns_query(&query) {
+                    Ok(()) => self.buffered_dns_queries.push_back(query.into_owned()),
+                    Err(e) => tracing::debug!(%cid, "Refusing DNS query: {e}"),
+                }
+
+                return None;
+            }
+        }
+
+        let mut packet = peer
             .decapsulate(packet, now)
             .inspect_err(|e| tracing::debug!(%cid, "Invalid packet: {e}"))
             .ok()?;
 
+        if self.node.traffic_class_propagation().ecn && !utils::propagate_ecn(&mut packet, ecn) {
+            tracing::debug!(%from, "Dropping packet without ECN support that experienced congestion");
+            return None;
+        }
+
         Some(packet.into_immutable())
     }
 
+    pub(crate) fn poll_dns_queries(&mut self) -> Option<DnsQuery<'static>> {
+        self.buffered_dns_queries.pop_front()
+    }
+
+    /// Sends the result of a client's DNS query back to the client.
+    #[tracing::instrument(level = "debug", skip_all, fields(name = %query.name, client = %query.query.source()))] // On debug level, we can log potentially sensitive information such as domain names.
+    pub(crate) fn on_dns_result(
+        &mut self,
+        query: DnsQuery<'static>,
+        response: std::result::Result<
+            std::result::Result<
+                std::result::Result<
+                    hickory_resolver::lookup::Lookup,
+                    hickory_resolver::error::ResolveError,
+                >,
+                futures_bounded::Timeout,
+            >,
+            DnsQueryError,
+        >,
+        now: Instant,
+    ) {
+        let dns_reply = dns::build_response_or_servfail(query.query, response);
+
+        let Some(peer) = self.peers.peer_by_ip(dns_reply.destination()) else {
+            tracing::debug!("Client disconnected before we resolved its DNS query");
+            return;
+        };
+        let cid = peer.id();
+
+        let Some(transmit) = self
+            .node
+            .encapsulate(cid, dns_reply, now)
+            .inspect_err(|e| tracing::debug!(%cid, "Failed to encapsulate: {e}"))
+            .ok()
+            .flatten()
+        else {
+            return;
+        };
+
+        self.buffered_transmits.push_back(transmit.into_owned());
+    }
+
     pub fn add_ice_candidate(&mut self, conn_id: ClientId, ice_candidate: String, now: Instant) {
         self.node.add_remote_candidate(conn_id, ice_candidate, now);
     }
@@ -215,6 +395,38 @@ impl GatewayState {
         self.node.remove_remote_candidate(conn_id, ice_candidate);
     }
 
+    pub fn end_of_ice_candidates(&mut self, conn_id: ClientId, now: Instant) {
+        self.node.end_of_remote_candidates(conn_id, now);
+    }
+
+    pub fn snapshot(&self, now: Instant) -> snownet::NodeSnapshot<ClientId, RelayId> {
+        self.node.snapshot(now)
+    }
+
+    pub fn set_remote_ice_credentials(
+        &mut self,
+        conn_id: ClientId,
+        credentials: IceCredentials,
+        now: Instant,
+    ) {
+        self.node.set_remote_credentials(
+            conn_id,
+            snownet::Credentials {
+                username: credentials.username,
+                password: credentials.password,
+            },
+            now,
+        );
+    }
+
+    pub fn set_preshared_key(&mut self, conn_id: ClientId, key: Secret<Key>, now: Instant) {
+        self.node.set_preshared_key(
+            conn_id,
+            snownet::PresharedKey::new(key.expose_secret().0),
+            now,
+        );
+    }
+
     /// Accept a connection request from a client.
     #[allow(clippy::too_many_arguments)]
     pub fn accept(
@@ -231,7 +443,7 @@ impl GatewayState {
     ) -> Result<Answer> {
         match (&domain, &resource) {
             (Some((domain, _)), ResourceDescription::Dns(r)) => {
-                if !crate::dns::is_subdomain(domain, &r.domain) {
+                if !crate::dns::is_resource_name(domain, &r.domain, &r.exclusions) {
                     return Err(Error::InvalidResource);
                 }
             }
@@ -286,7 +498,7 @@ impl GatewayState {
     ) -> Result<()> {
         match (&domain, &resource) {
             (Some((domain, _)), ResourceDescription::Dns(r)) => {
-                if !crate::dns::is_subdomain(domain, &r.domain) {
+                if !crate::dns::is_resource_name(domain, &r.domain, &r.exclusions) {
                     return Err(Error::InvalidResource);
                 }
             }
@@ -336,11 +548,14 @@ impl GatewayState {
 
         let mut added_ice_candidates = HashMap::<ClientId, HashSet<String>>::default();
         let mut removed_ice_candidates = HashMap::<ClientId, HashSet<String>>::default();
+        let mut end_of_ice_candidates = HashSet::<ClientId>::default();
+        let mut new_ice_credentials = HashMap::<ClientId, IceCredentials>::default();
 
         while let Some(event) = self.node.poll_event() {
             match event {
                 snownet::Event::ConnectionFailed(id) | snownet::Event::ConnectionClosed(id) => {
                     self.peers.remove(&id);
+                    self.tun_mtu.on_connection_removed(&id);
                 }
                 snownet::Event::NewIceCandidate {
                     connection,
@@ -360,15 +575,58 @@ impl GatewayState {
                         .or_default()
                         .insert(candidate);
                 }
+                snownet::Event::EndOfIceCandidates { connection } => {
+                    added_ice_candidates.entry(connection).or_default();
+                    end_of_ice_candidates.insert(connection);
+                }
+                snownet::Event::NewIceCredentials {
+                    connection,
+                    credentials,
+                } => {
+                    new_ice_credentials.insert(
+                        connection,
+                        IceCredentials {
+                            username: credentials.username,
+                            password: credentials.password,
+                        },
+                    );
+                }
+                snownet::Event::NewPresharedKey { connection, .. } => {
+                    tracing::warn!(client = %connection, "Gateways don't rotate preshared keys");
+                }
+                snownet::Event::PathMtuChanged { connection, mtu } => {
+                    tracing::info!(client = %connection, %mtu, "Path MTU changed");
+                    self.tun_mtu.on_path_mtu_changed(connection, mtu);
+                }
+                snownet::Event::NatStatusChanged(status) => {
+                    tracing::info!(ipv4 = ?status.ipv4, ipv6 = ?status.ipv6, "NAT status changed");
+                }
+                snownet::Event::ConnectionPathChanged {
+                    connection,
+                    from,
+                    to,
+                } => {
+                    tracing::info!(client = %connection, ?from, ?to, "Connection changed its network path");
+                }
                 snownet::Event::ConnectionEstablished(_) => {}
             }
         }
 
+        // New credentials must be signalled before the candidates that belong to them.
+        for (conn_id, credentials) in new_ice_credentials.drain() {
+            self.buffered_events
+                .push_back(GatewayEvent::NewIceCredentials {
+                    conn_id,
+                    credentials,
+                })
+        }
+
         for (conn_id, candidates) in added_ice_candidates.drain() {
             self.buffered_events
                 .push_back(GatewayEvent::AddedIceCandidates {
                     conn_id,
                     candidates,
+                    end_of_candidates: end_of_ice_candidates.contains(&conn_id),
                 })
         }
 
@@ -381,8 +639,15 @@ impl GatewayState {
         }
     }
 
+    /// Returns the new MTU of the TUN device if it changed.
+    pub(crate) fn poll_tun_mtu(&mut self) -> Option<u16> {
+        self.tun_mtu.poll_change()
+    }
+
     pub(crate) fn poll_transmit(&mut self) -> Option<snownet::Transmit<'static>> {
-        self.node.poll_transmit()
+        self.buffered_transmits
+            .pop_front()
+            .or_else(|| self.node.poll_transmit())
     }
 
     pub(crate) fn poll_event(&mut self) -> Option<GatewayEvent> {
@@ -407,4 +672,12 @@ impl GatewayState {
     ) {
         self.node.update_relays(to_remove, &to_add, now);
     }
+
+    /// Discovers the prefix of the NAT64 on our network, see [`snownet::Node::discover_nat64_prefix`].
+    pub fn discover_nat64_prefix(
+        &mut self,
+        lookup: impl FnOnce(&str) -> std::io::Result<Vec<Ipv6Addr>>,
+    ) {
+        self.node.discover_nat64_prefix(lookup);
+    }
 }
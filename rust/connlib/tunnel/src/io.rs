@@ -1,29 +1,38 @@
 use crate::{
     device_channel::Device,
-    dns::DnsQuery,
+    dns::{self, DnsQuery},
     sockets::{Received, Sockets},
 };
 use bytes::Bytes;
 use connlib_shared::messages::DnsServer;
 use futures_bounded::FuturesTupleSet;
-use futures_util::FutureExt as _;
+use futures_util::{future::BoxFuture, FutureExt as _};
 use hickory_resolver::{
-    config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
+    config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts, TlsClientConfig},
     TokioAsyncResolver,
 };
 use ip_packet::{IpPacket, MutableIpPacket};
-use quinn_udp::Transmit;
+use quinn_udp::{EcnCodepoint, Transmit};
 use std::{
     collections::HashMap,
     io,
-    net::IpAddr,
+    net::{IpAddr, Ipv6Addr},
     pin::Pin,
+    sync::Arc,
     task::{ready, Context, Poll},
     time::{Duration, Instant},
 };
 
 const DNS_QUERIES_QUEUE_SIZE: usize = 100;
 
+/// The path our resolver sends DNS over HTTPS queries to.
+const DNS_OVER_HTTPS_PATH: &str = "/dns-query";
+
+/// The buffer size for a single packet read from the TUN device.
+///
+/// We have 20 extra bytes to be able to convert between ipv4 and ipv6.
+pub(crate) const DEVICE_PACKET_BUFFER_SIZE: usize = crate::MTU + 20;
+
 /// Bundles together all side-effects that connlib needs to have access to.
 pub struct Io {
     /// The TUN device offered to the user.
@@ -35,15 +44,22 @@ pub struct Io {
     timeout: Option<Pin<Box<tokio::time::Sleep>>>,
 
     upstream_dns_servers: HashMap<IpAddr, TokioAsyncResolver>,
+    /// Resolvers that only use TCP, for forwarding queries that we received over TCP.
+    upstream_tcp_dns_servers: HashMap<IpAddr, TokioAsyncResolver>,
+    /// Resolves queries that aren't sent to one of the upstream servers, only used by gateways.
+    system_resolver: Option<TokioAsyncResolver>,
     forwarded_dns_queries: FuturesTupleSet<
         Result<hickory_resolver::lookup::Lookup, hickory_resolver::error::ResolveError>,
         DnsQuery<'static>,
     >,
+    /// The ongoing lookup of [`snownet::IPV4ONLY_ARPA`], see [`Io::discover_nat64_prefix`].
+    nat64_discovery: Option<BoxFuture<'static, io::Result<Vec<Ipv6Addr>>>>,
 }
 
 pub enum Input<'a, I> {
     Timeout(Instant),
-    Device(MutableIpPacket<'a>),
+    /// A batch of packets read from the TUN device.
+    Device(Vec<MutableIpPacket<'a>>),
     Network(I),
     DnsResponse(
         DnsQuery<'static>,
@@ -52,6 +68,8 @@ pub enum Input<'a, I> {
             futures_bounded::Timeout,
         >,
     ),
+    /// The AAAA records of [`snownet::IPV4ONLY_ARPA`].
+    Nat64Discovery(io::Result<Vec<Ipv6Addr>>),
 }
 
 impl Io {
@@ -66,13 +84,19 @@ impl Io {
             timeout: None,
             sockets,
             upstream_dns_servers: HashMap::default(),
+            upstream_tcp_dns_servers: HashMap::default(),
+            system_resolver: None,
             forwarded_dns_queries: FuturesTupleSet::new(
                 Duration::from_secs(60),
                 DNS_QUERIES_QUEUE_SIZE,
             ),
+            nat64_discovery: None,
         })
     }
 
+    /// Polls for new input.
+    ///
+    /// `device_buffer` is split into chunks of [`DEVICE_PACKET_BUFFER_SIZE`] so we can read several packets from the TUN device at once.
     pub fn poll<'b>(
         &mut self,
         cx: &mut Context<'_>,
@@ -84,6 +108,11 @@ impl Io {
             return Poll::Ready(Ok(Input::DnsResponse(query, response)));
         }
 
+        if let Some(Poll::Ready(addrs)) = self.nat64_discovery.as_mut().map(|f| f.poll_unpin(cx)) {
+            self.nat64_discovery = None;
+            return Poll::Ready(Ok(Input::Nat64Discovery(addrs)));
+        }
+
         if let Some(timeout) = self.timeout.as_mut() {
             if timeout.poll_unpin(cx).is_ready() {
                 return Poll::Ready(Ok(Input::Timeout(timeout.deadline().into())));
@@ -96,8 +125,16 @@ impl Io {
 
         ready!(self.sockets.poll_flush(cx))?;
 
-        if let Poll::Ready(packet) = self.device.poll_read(device_buffer, cx)? {
-            return Poll::Ready(Ok(Input::Device(packet)));
+        let mut packets = Vec::new();
+        for buffer in device_buffer.chunks_mut(DEVICE_PACKET_BUFFER_SIZE) {
+            match self.device.poll_read(buffer, cx)? {
+                Poll::Ready(packet) => packets.push(packet),
+                Poll::Pending => break,
+            }
+        }
+
+        if !packets.is_empty() {
+            return Poll::Ready(Ok(Input::Device(packets)));
         }
 
         Poll::Pending
@@ -117,18 +154,54 @@ impl Io {
     ) {
         tracing::info!("Setting new DNS resolvers");
 
+        let dns_servers = dns_servers.into_iter().collect::<Vec<_>>();
+
         self.forwarded_dns_queries =
             FuturesTupleSet::new(Duration::from_secs(60), DNS_QUERIES_QUEUE_SIZE);
-        self.upstream_dns_servers = create_resolvers(dns_servers);
+        self.upstream_dns_servers =
+            create_resolvers(dns_servers.clone(), &[Protocol::Udp, Protocol::Tcp]);
+        self.upstream_tcp_dns_servers = create_resolvers(dns_servers, &[Protocol::Tcp]);
+    }
+
+    /// Resolves queries to unknown upstream servers with the system's DNS configuration.
+    pub fn use_system_resolver(&mut self) {
+        match TokioAsyncResolver::tokio_from_system_conf() {
+            Ok(resolver) => self.system_resolver = Some(resolver),
+            Err(e) => tracing::warn!("Failed to read system DNS configuration: {e}"),
+        }
+    }
+
+    /// Resolves [`snownet::IPV4ONLY_ARPA`] with the system's DNS configuration to discover the prefix of the NAT64 on our network.
+    ///
+    /// The lookup runs in the background and its result is returned from [`Io::poll`].
+    /// A lookup that is still ongoing is discarded, e.g. because we moved to a different network.
+    pub fn discover_nat64_prefix(&mut self) {
+        self.nat64_discovery = Some(
+            async {
+                let resolver =
+                    TokioAsyncResolver::tokio_from_system_conf().map_err(io::Error::other)?;
+                let lookup = resolver
+                    .ipv6_lookup(snownet::IPV4ONLY_ARPA)
+                    .await
+                    .map_err(io::Error::other)?;
+
+                Ok(lookup.iter().map(|aaaa| aaaa.0).collect())
+            }
+            .boxed(),
+        );
     }
 
     pub fn perform_dns_query(&mut self, query: DnsQuery<'static>) -> Result<(), DnsQueryError> {
         let upstream = query.query.destination();
-        let resolver = self
-            .upstream_dns_servers
+        let resolvers = match query.transport {
+            dns::Transport::Udp => &self.upstream_dns_servers,
+            dns::Transport::Tcp => &self.upstream_tcp_dns_servers,
+        };
+        let resolver = resolvers
             .get(&upstream)
+            .or(self.system_resolver.as_ref())
             .cloned()
-            .expect("Only DNS queries to known upstream servers should be forwarded to `Io`");
+            .ok_or(DnsQueryError::NoResolver)?;
 
         if self
             .forwarded_dns_queries
@@ -162,13 +235,16 @@ impl Io {
     }
 
     pub fn send_network(&mut self, transmit: snownet::Transmit) -> io::Result<()> {
-        self.sockets.try_send(Transmit {
-            destination: transmit.dst,
-            ecn: None,
-            contents: Bytes::copy_from_slice(&transmit.payload),
-            segment_size: None,
-            src_ip: transmit.src.map(|s| s.ip()),
-        })?;
+        self.sockets.try_send(
+            Transmit {
+                destination: transmit.dst,
+                ecn: EcnCodepoint::from_bits(transmit.tos),
+                contents: Bytes::copy_from_slice(&transmit.payload),
+                segment_size: transmit.segment_size,
+                src_ip: transmit.src.map(|s| s.ip()),
+            },
+            transmit.tos,
+        )?;
 
         Ok(())
     }
@@ -184,25 +260,89 @@ impl Io {
 pub enum DnsQueryError {
     #[error("Too many ongoing DNS queries")]
     TooManyQueries,
+    /// We have no resolver for this query, e.g. because the TLS configuration of its upstream server is invalid.
+    #[error("No resolver for upstream DNS server")]
+    NoResolver,
 }
 
 fn create_resolvers(
     dns_servers: impl IntoIterator<Item = (IpAddr, DnsServer)>,
+    protocols: &[Protocol],
 ) -> HashMap<IpAddr, TokioAsyncResolver> {
     dns_servers
         .into_iter()
-        .map(|(sentinel, srv)| {
+        .filter_map(|(sentinel, srv)| {
             let mut resolver_config = ResolverConfig::new();
-            resolver_config.add_name_server(NameServerConfig::new(srv.address(), Protocol::Udp));
-            resolver_config.add_name_server(NameServerConfig::new(srv.address(), Protocol::Tcp));
+            match name_server_configs(&srv, protocols) {
+                Ok(name_servers) => {
+                    for name_server in name_servers {
+                        resolver_config.add_name_server(name_server);
+                    }
+                }
+                Err(e) => {
+                    // Fail closed: Queries to this sentinel will be answered with SERVFAIL instead of leaking to an unverified server.
+                    tracing::warn!(server = %srv.address(), "Failed to configure encrypted DNS server: {e}");
+                    return None;
+                }
+            }
 
             let mut resolver_opts = ResolverOpts::default();
             resolver_opts.edns0 = true;
 
-            (
+            Some((
                 sentinel,
                 TokioAsyncResolver::tokio(resolver_config, resolver_opts),
-            )
+            ))
         })
         .collect()
 }
+
+/// Encrypted DNS servers always use their own protocol, regardless of `protocols`.
+///
+/// DNS over HTTPS servers are rejected unless their URL uses [`DNS_OVER_HTTPS_PATH`].
+fn name_server_configs(
+    srv: &DnsServer,
+    protocols: &[Protocol],
+) -> Result<Vec<NameServerConfig>, dns::TlsError> {
+    let config = match srv {
+        DnsServer::IpPort(srv) => {
+            return Ok(protocols
+                .iter()
+                .map(|protocol| NameServerConfig::new(srv.address, *protocol))
+                .collect())
+        }
+        DnsServer::Https(srv) => {
+            // Our resolver always sends queries to this path, we must not send them anywhere else than the configured endpoint.
+            if https_path(&srv.url) != Some(DNS_OVER_HTTPS_PATH) {
+                return Err(dns::TlsError::UnsupportedHttpsPath);
+            }
+
+            let mut config = NameServerConfig::new(srv.address, Protocol::Https);
+            config.tls_dns_name = Some(srv.hostname.clone());
+            config.tls_config = Some(TlsClientConfig(Arc::new(dns::https_client_config(
+                srv.spki_pin.as_deref(),
+            )?)));
+
+            config
+        }
+        DnsServer::Tls(srv) => {
+            let mut config = NameServerConfig::new(srv.address, Protocol::Tls);
+            config.tls_dns_name = Some(srv.hostname.clone());
+            config.tls_config = Some(TlsClientConfig(Arc::new(dns::tls_client_config(
+                srv.spki_pin.as_deref(),
+            )?)));
+
+            config
+        }
+    };
+
+    Ok(vec![config])
+}
+
+/// Returns the path of a DNS over HTTPS URL, without the `{?dns}` variable of an RFC8484 URI template.
+fn https_path(url: &str) -> Option<&str> {
+    let authority_and_path = url.strip_prefix("https://")?;
+    let path = &authority_and_path[authority_and_path.find('/')?..];
+
+    Some(path.strip_suffix("{?dns}").unwrap_or(path))
+}
@@ -7,10 +7,12 @@ use boringtun::x25519::StaticSecret;
 use chrono::Utc;
 use connlib_shared::{
     callbacks,
-    messages::{ClientId, GatewayId, Relay, RelayId, ResourceId, ReuseConnection},
+    messages::{ClientId, GatewayId, IceCredentials, Relay, RelayId, ResourceId, ReuseConnection},
     Callbacks, DomainName, Result,
 };
-use io::Io;
+use io::{Io, DEVICE_PACKET_BUFFER_SIZE};
+pub use snownet::ConnectionConfig;
+use snownet::PresharedKey;
 use std::{
     collections::{HashMap, HashSet},
     net::{IpAddr, SocketAddr},
@@ -29,6 +31,7 @@ mod device_channel;
 mod dns;
 mod gateway;
 mod io;
+mod mtu;
 mod peer;
 mod peer_store;
 mod sockets;
@@ -40,10 +43,19 @@ pub use device_channel::Tun;
 mod tests;
 
 const MAX_UDP_SIZE: usize = (1 << 16) - 1;
-const MTU: usize = 1280;
+/// The largest IP packet we read from or write to the TUN device.
+const MTU: usize = snownet::MAX_MTU as usize;
+
+/// How many packets we read from the TUN device before encapsulating them together.
+///
+/// This matches the maximum number of segments we coalesce for UDP GSO.
+const DEVICE_BATCH_SIZE: usize = snownet::MAX_GSO_SEGMENTS;
 
 const REALM: &str = "firezone";
 
+/// How many of the nearest relays we use for relay candidates, unless configured otherwise.
+pub const DEFAULT_MAX_RELAYS: usize = 2;
+
 pub type GatewayTunnel<CB> = Tunnel<CB, GatewayState>;
 pub type ClientTunnel<CB> = Tunnel<CB, ClientState>;
 
@@ -65,10 +77,10 @@ pub struct Tunnel<CB: Callbacks, TRoleState> {
     ip4_read_buf: Box<[u8; MAX_UDP_SIZE]>,
     ip6_read_buf: Box<[u8; MAX_UDP_SIZE]>,
 
-    // We need an extra 16 bytes on top of the MTU for write_buf since boringtun copies the extra AEAD tag before decrypting it
-    write_buf: Box<[u8; MTU + 16 + 20]>,
-    // We have 20 extra bytes to be able to convert between ipv4 and ipv6
-    device_read_buf: Box<[u8; MTU + 20]>,
+    /// Each datagram of a GRO batch is decrypted into its own region, so this needs to be as large as the read buffers.
+    write_buf: Box<[u8; MAX_UDP_SIZE]>,
+    /// Holds up to [`DEVICE_BATCH_SIZE`] packets read from the TUN device.
+    device_read_buf: Box<[u8; DEVICE_PACKET_BUFFER_SIZE * DEVICE_BATCH_SIZE]>,
 }
 
 impl<CB> ClientTunnel<CB>
@@ -81,20 +93,29 @@ where
         callbacks: CB,
         known_hosts: HashMap<String, Vec<IpAddr>>,
     ) -> std::io::Result<Self> {
+        let mut role_state = ClientState::new(private_key, known_hosts);
+
+        let mut io = Io::new(sockets)?;
+        io.discover_nat64_prefix();
+        role_state.set_max_gso_segments(io.sockets_mut().max_gso_segments());
+
         Ok(Self {
-            io: Io::new(sockets)?,
+            io,
             callbacks,
-            role_state: ClientState::new(private_key, known_hosts),
-            write_buf: Box::new([0u8; MTU + 16 + 20]),
+            role_state,
+            write_buf: Box::new([0u8; MAX_UDP_SIZE]),
             ip4_read_buf: Box::new([0u8; MAX_UDP_SIZE]),
             ip6_read_buf: Box::new([0u8; MAX_UDP_SIZE]),
-            device_read_buf: Box::new([0u8; MTU + 20]),
+            device_read_buf: Box::new([0u8; DEVICE_PACKET_BUFFER_SIZE * DEVICE_BATCH_SIZE]),
         })
     }
 
     pub fn reset(&mut self) -> std::io::Result<()> {
-        self.role_state.reset();
+        self.io.discover_nat64_prefix(); // We may have moved to or from a network with NAT64.
+        self.role_state.reset(Instant::now());
         self.io.sockets_mut().rebind()?;
+        self.role_state
+            .set_max_gso_segments(self.io.sockets_mut().max_gso_segments());
 
         Ok(())
     }
@@ -105,6 +126,13 @@ where
                 return Poll::Ready(Ok(e));
             }
 
+            if let Some(mtu) = self.role_state.poll_tun_mtu() {
+                if let Err(e) = self.io.device_mut().set_mtu(mtu) {
+                    tracing::warn!(%mtu, "Failed to set MTU of TUN device: {e}");
+                }
+                continue;
+            }
+
             if let Some(packet) = self.role_state.poll_packets() {
                 self.io.send_device(packet)?;
                 continue;
@@ -117,7 +145,8 @@ where
 
             if let Some(dns_query) = self.role_state.poll_dns_queries() {
                 if let Err(e) = self.io.perform_dns_query(dns_query.clone()) {
-                    self.role_state.on_dns_result(dns_query, Err(e))
+                    self.role_state
+                        .on_dns_result(dns_query, Err(e), Instant::now())
                 }
                 continue;
             }
@@ -136,34 +165,33 @@ where
                     self.role_state.handle_timeout(timeout);
                     continue;
                 }
-                Poll::Ready(io::Input::Device(packet)) => {
-                    let Some(transmit) = self.role_state.encapsulate(packet, Instant::now()) else {
-                        continue;
-                    };
-
-                    self.io.send_network(transmit)?;
+                Poll::Ready(io::Input::Device(packets)) => {
+                    for transmit in self.role_state.encapsulate_batch(packets, Instant::now()) {
+                        self.io.send_network(transmit)?;
+                    }
 
                     continue;
                 }
                 Poll::Ready(io::Input::Network(packets)) => {
                     for received in packets {
-                        let Some(packet) = self.role_state.decapsulate(
-                            received.local,
-                            received.from,
-                            received.packet,
+                        for packet in self.role_state.decapsulate_batch(
+                            received,
                             std::time::Instant::now(),
                             self.write_buf.as_mut(),
-                        ) else {
-                            continue;
-                        };
-
-                        self.io.device_mut().write(packet)?;
+                        ) {
+                            self.io.device_mut().write(packet)?;
+                        }
                     }
 
                     continue;
                 }
                 Poll::Ready(io::Input::DnsResponse(query, response)) => {
-                    self.role_state.on_dns_result(query, Ok(response));
+                    self.role_state
+                        .on_dns_result(query, Ok(response), Instant::now());
+                    continue;
+                }
+                Poll::Ready(io::Input::Nat64Discovery(addrs)) => {
+                    self.role_state.discover_nat64_prefix(|_| addrs);
                     continue;
                 }
                 Poll::Pending => {}
@@ -183,14 +211,21 @@ where
         sockets: Sockets,
         callbacks: CB,
     ) -> std::io::Result<Self> {
+        let mut role_state = GatewayState::new(private_key);
+
+        let mut io = Io::new(sockets)?;
+        io.discover_nat64_prefix();
+        io.use_system_resolver(); // Clients have us resolve records of DNS resources.
+        role_state.set_max_gso_segments(io.sockets_mut().max_gso_segments());
+
         Ok(Self {
-            io: Io::new(sockets)?,
+            io,
             callbacks,
-            role_state: GatewayState::new(private_key),
-            write_buf: Box::new([0u8; MTU + 20 + 16]),
+            role_state,
+            write_buf: Box::new([0u8; MAX_UDP_SIZE]),
             ip4_read_buf: Box::new([0u8; MAX_UDP_SIZE]),
             ip6_read_buf: Box::new([0u8; MAX_UDP_SIZE]),
-            device_read_buf: Box::new([0u8; MTU + 20]),
+            device_read_buf: Box::new([0u8; DEVICE_PACKET_BUFFER_SIZE * DEVICE_BATCH_SIZE]),
         })
     }
 
@@ -205,11 +240,26 @@ where
                 return Poll::Ready(Ok(other));
             }
 
+            if let Some(mtu) = self.role_state.poll_tun_mtu() {
+                if let Err(e) = self.io.device_mut().set_mtu(mtu) {
+                    tracing::warn!(%mtu, "Failed to set MTU of TUN device: {e}");
+                }
+                continue;
+            }
+
             if let Some(transmit) = self.role_state.poll_transmit() {
                 self.io.send_network(transmit)?;
                 continue;
             }
 
+            if let Some(dns_query) = self.role_state.poll_dns_queries() {
+                if let Err(e) = self.io.perform_dns_query(dns_query.clone()) {
+                    self.role_state
+                        .on_dns_result(dns_query, Err(e), Instant::now())
+                }
+                continue;
+            }
+
             if let Some(timeout) = self.role_state.poll_timeout() {
                 self.io.reset_timeout(timeout);
             }
@@ -224,37 +274,34 @@ where
                     self.role_state.handle_timeout(timeout, Utc::now());
                     continue;
                 }
-                Poll::Ready(io::Input::Device(packet)) => {
-                    let Some(transmit) = self
-                        .role_state
-                        .encapsulate(packet, std::time::Instant::now())
-                    else {
-                        continue;
-                    };
-
-                    self.io.send_network(transmit)?;
+                Poll::Ready(io::Input::Device(packets)) => {
+                    for transmit in self.role_state.encapsulate_batch(packets, Instant::now()) {
+                        self.io.send_network(transmit)?;
+                    }
 
                     continue;
                 }
                 Poll::Ready(io::Input::Network(packets)) => {
                     for received in packets {
-                        let Some(packet) = self.role_state.decapsulate(
-                            received.local,
-                            received.from,
-                            received.packet,
+                        for packet in self.role_state.decapsulate_batch(
+                            received,
                             std::time::Instant::now(),
                             self.write_buf.as_mut(),
-                        ) else {
-                            continue;
-                        };
-
-                        self.io.device_mut().write(packet)?;
+                        ) {
+                            self.io.device_mut().write(packet)?;
+                        }
                     }
 
                     continue;
                 }
-                Poll::Ready(io::Input::DnsResponse(_, _)) => {
-                    unreachable!("Gateway does not (yet) resolve DNS queries via `Io`")
+                Poll::Ready(io::Input::DnsResponse(query, response)) => {
+                    self.role_state
+                        .on_dns_result(query, Ok(response), Instant::now());
+                    continue;
+                }
+                Poll::Ready(io::Input::Nat64Discovery(addrs)) => {
+                    self.role_state.discover_nat64_prefix(|_| addrs);
+                    continue;
                 }
                 Poll::Pending => {}
             }
@@ -269,11 +316,25 @@ pub enum ClientEvent {
     AddedIceCandidates {
         conn_id: GatewayId,
         candidates: HashSet<String>,
+        /// Whether we finished gathering candidates for this connection, i.e. these are the last ones until ICE restarts.
+        end_of_candidates: bool,
     },
     RemovedIceCandidates {
         conn_id: GatewayId,
         candidates: HashSet<String>,
     },
+    /// We restarted ICE for this connection and the new credentials need to be signalled to the gateway.
+    ///
+    /// Must be sent before any [`ClientEvent::AddedIceCandidates`] for the same connection.
+    NewIceCredentials {
+        conn_id: GatewayId,
+        credentials: IceCredentials,
+    },
+    /// We rotated the preshared key for this connection and the new key needs to be signalled to the gateway.
+    NewPresharedKey {
+        conn_id: GatewayId,
+        key: PresharedKey,
+    },
     ConnectionIntent {
         resource: ResourceId,
         connected_gateway_ids: HashSet<GatewayId>,
@@ -285,6 +346,17 @@ pub enum ClientEvent {
     ResourcesChanged {
         resources: Vec<callbacks::ResourceDescription>,
     },
+    /// We answered a DNS query, only emitted if enabled with [`ClientTunnel::set_dns_query_audit`].
+    DnsQueryAnswered {
+        query: callbacks::DnsQueryEvent,
+    },
+    /// We assigned proxy IPs to a new name of a DNS resource or reclaimed some.
+    ///
+    /// Clients should persist these and restore them with [`ClientTunnel::restore_proxy_ips`] in the next session.
+    ProxyIpsChanged {
+        /// The proxy IPs of all names that belong to a DNS resource.
+        proxy_ips: HashMap<String, Vec<IpAddr>>,
+    },
     DnsServersChanged {
         /// The map of DNS servers that connlib will use.
         ///
@@ -301,11 +373,20 @@ pub enum GatewayEvent {
     AddedIceCandidates {
         conn_id: ClientId,
         candidates: HashSet<String>,
+        /// Whether we finished gathering candidates for this connection, i.e. these are the last ones until ICE restarts.
+        end_of_candidates: bool,
     },
     RemovedIceCandidates {
         conn_id: ClientId,
         candidates: HashSet<String>,
     },
+    /// We restarted ICE for this connection and the new credentials need to be signalled to the client.
+    ///
+    /// Must be sent before any [`GatewayEvent::AddedIceCandidates`] for the same connection.
+    NewIceCredentials {
+        conn_id: ClientId,
+        credentials: IceCredentials,
+    },
     RefreshDns {
         name: DomainName,
         conn_id: ClientId,
@@ -0,0 +1,74 @@
+use std::{collections::HashMap, hash::Hash};
+
+/// Derives the MTU of the TUN device from the path MTUs of our connections.
+///
+/// All connections share the TUN device, so it can only use the smallest path MTU among them.
+/// Connections only count once path MTU discovery reported a result for them, otherwise every new connection would shrink the TUN device.
+pub(crate) struct TunMtu<TId> {
+    path_mtus: HashMap<TId, u16>,
+    /// The MTU we last reported via [`TunMtu::poll_change`].
+    reported: u16,
+}
+
+impl<TId> Default for TunMtu<TId> {
+    fn default() -> Self {
+        Self {
+            path_mtus: HashMap::default(),
+            reported: snownet::MIN_MTU,
+        }
+    }
+}
+
+impl<TId> TunMtu<TId>
+where
+    TId: Eq + Hash,
+{
+    pub(crate) fn on_path_mtu_changed(&mut self, id: TId, mtu: u16) {
+        self.path_mtus.insert(id, mtu);
+    }
+
+    pub(crate) fn on_connection_removed(&mut self, id: &TId) {
+        self.path_mtus.remove(id);
+    }
+
+    /// Returns the new MTU of the TUN device if it changed since the last call.
+    pub(crate) fn poll_change(&mut self) -> Option<u16> {
+        let mtu = self.mtu();
+
+        if mtu == self.reported {
+            return None;
+        }
+
+        self.reported = mtu;
+
+        Some(mtu)
+    }
+
+    fn mtu(&self) -> u16 {
+        self.path_mtus
+            .values()
+            .min()
+            .copied()
+            .unwrap_or(snownet::MIN_MTU)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_smallest_path_mtu() {
+        let mut mtu = TunMtu::default();
+
+        mtu.on_path_mtu_changed(1, 1440);
+        assert_eq!(mtu.poll_change(), Some(1440));
+
+        mtu.on_path_mtu_changed(2, 1416);
+        assert_eq!(mtu.poll_change(), Some(1416));
+
+        mtu.on_connection_removed(&2);
+        assert_eq!(mtu.poll_change(), Some(1440));
+        assert_eq!(mtu.poll_change(), None);
+    }
+}
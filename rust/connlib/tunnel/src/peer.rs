@@ -15,6 +15,7 @@ use ip_packet::{IpPacket, MutableIpPacket};
 use itertools::Itertools;
 use rangemap::RangeInclusiveSet;
 
+use crate::dns::{self, DnsQuery};
 use crate::utils::network_contains_network;
 use crate::GatewayEvent;
 
@@ -108,6 +109,8 @@ impl AllowRules {
 pub(crate) struct GatewayOnClient {
     id: GatewayId,
     pub allowed_ips: IpNetworkTable<HashSet<ResourceId>>,
+    /// Whether the gateway resolves queries for records of DNS resources, see [`ResolveStrategy::RecurseViaGateway`](crate::dns::ResolveStrategy::RecurseViaGateway).
+    pub resolves_dns: bool,
 }
 
 impl GatewayOnClient {
@@ -131,7 +134,11 @@ impl GatewayOnClient {
             allowed_ips.insert(*ip, resource.clone());
         }
 
-        GatewayOnClient { id, allowed_ips }
+        GatewayOnClient {
+            id,
+            allowed_ips,
+            resolves_dns: false,
+        }
     }
 }
 
@@ -142,6 +149,7 @@ impl ClientOnGateway {
             ipv4,
             ipv6,
             resources: HashMap::new(),
+            dns_resource_addresses: HashMap::new(),
             filters: IpNetworkTable::new(),
             permanent_translations: Default::default(),
             nat_table: Default::default(),
@@ -220,6 +228,12 @@ impl ClientOnGateway {
 
         let ip_maps = ipv4_maps.chain(ipv6_maps);
 
+        // The client reclaims the proxy IPs of names it no longer uses and sends us the new set of a name's proxy IPs.
+        // Drop the ones it took away from this name, such that `refresh_translation` doesn't translate them to it anymore.
+        self.permanent_translations.retain(|proxy_ip, state| {
+            state.name != name || state.resource_id != resource_id || proxy_ips.contains(proxy_ip)
+        });
+
         for (proxy_ip, real_ip) in ip_maps {
             tracing::debug!(%name, %proxy_ip, %real_ip);
 
@@ -238,6 +252,9 @@ impl ClientOnGateway {
     ) -> connlib_shared::Result<()> {
         match (resource, domain_ips) {
             (ResourceDescription::Dns(r), Some((name, resource_ips))) => {
+                self.dns_resource_addresses
+                    .insert(r.id, (r.domain.clone(), r.exclusions.clone()));
+
                 if resource_ips.is_empty() {
                     tracing::debug!("Client hasn't sent us any proxy IPs, skipping IP translation");
                     return Ok(());
@@ -253,6 +270,29 @@ impl ClientOnGateway {
         Ok(())
     }
 
+    /// Checks whether we should resolve this query for the client, see [`dns::parse_gateway_query`].
+    ///
+    /// The client may only query records of names that belong to one of its DNS resources.
+    pub(crate) fn ensure_allowed_dns_query(
+        &self,
+        query: &DnsQuery<'_>,
+    ) -> Result<(), connlib_shared::Error> {
+        let src = query.query.source();
+        if !self.allowed_ips().contains(&src) {
+            return Err(connlib_shared::Error::SrcNotAllowed { src });
+        }
+
+        if !self
+            .dns_resource_addresses
+            .values()
+            .any(|(address, exclusions)| dns::is_resource_name(&query.name, address, exclusions))
+        {
+            return Err(connlib_shared::Error::UnknownResource);
+        }
+
+        Ok(())
+    }
+
     pub(crate) fn is_emptied(&self) -> bool {
         self.resources.is_empty()
     }
@@ -263,6 +303,8 @@ impl ClientOnGateway {
         }
 
         self.resources.retain(|_, r| !r.is_empty());
+        self.dns_resource_addresses
+            .retain(|id, _| self.resources.contains_key(id));
         self.recalculate_filters();
     }
 
@@ -309,6 +351,7 @@ impl ClientOnGateway {
 
     pub(crate) fn remove_resource(&mut self, resource: &ResourceId) {
         self.resources.remove(resource);
+        self.dns_resource_addresses.remove(resource);
         self.recalculate_filters();
     }
 
@@ -333,16 +376,34 @@ impl ClientOnGateway {
         self.recalculate_filters();
     }
 
-    // Note: we only allow updating filters and names
+    // Note: we only allow updating filters, names and exclusions
     // but names updates have no effect on the gateway
     pub(crate) fn update_resource(&mut self, resource: &ResourceDescription) {
         let Some(old_resource) = self.resources.get_mut(&resource.id()) else {
             return;
         };
-        for r in old_resource {
+        for r in old_resource.iter_mut() {
             r.filters = resource.filters();
         }
 
+        // Names that a DNS resource excludes now lose access.
+        if let ResourceDescription::Dns(r) = resource {
+            old_resource.retain(|o| {
+                !o.domain
+                    .as_ref()
+                    .is_some_and(|name| dns::is_excluded(name, &r.exclusions))
+            });
+            self.permanent_translations.retain(|_, state| {
+                state.resource_id != r.id || !dns::is_excluded(&state.name, &r.exclusions)
+            });
+            if let Some((_, exclusions)) = self.dns_resource_addresses.get_mut(&r.id) {
+                exclusions.clone_from(&r.exclusions);
+            }
+        }
+
+        self.resources.retain(|_, r| !r.is_empty());
+        self.dns_resource_addresses
+            .retain(|id, _| self.resources.contains_key(id));
         self.recalculate_filters();
     }
 
@@ -427,10 +488,10 @@ impl ClientOnGateway {
             return Ok(None);
         };
 
-        self.permanent_translations
-            .get_mut(&ip)
-            .expect("inconsistent state")
-            .on_incoming_traffic(now);
+        // The client may have reassigned the proxy IP since we created the NAT session.
+        if let Some(state) = self.permanent_translations.get_mut(&ip) {
+            state.on_incoming_traffic(now);
+        }
 
         packet.set_destination_protocol(proto.value());
         packet.update_checksum();
@@ -460,7 +521,7 @@ impl ClientOnGateway {
         if !self
             .filters
             .longest_match(dst)
-            .is_some_and(|(_, filter)| filter.is_allowed(&packet.to_immutable()))
+            .is_some_and(|(_, filter)| filter.is_allowed(&packet.as_immutable()))
         {
             return Err(connlib_shared::Error::DstNotAllowed { dst });
         };
@@ -596,6 +657,8 @@ pub struct ClientOnGateway {
     ipv4: Ipv4Addr,
     ipv6: Ipv6Addr,
     resources: HashMap<ResourceId, Vec<ResourceOnGateway>>,
+    /// The addresses of the DNS resources the client has access to, e.g. `*.example.com`, and the names they exclude.
+    dns_resource_addresses: HashMap<ResourceId, (String, Vec<String>)>,
     filters: IpNetworkTable<FilterEngine>,
     permanent_translations: HashMap<IpAddr, TranslationState>,
     nat_table: NatTable,
@@ -611,12 +674,19 @@ mod tests {
 
     use chrono::Utc;
     use connlib_shared::messages::{
-        gateway::{Filter, PortRange},
+        gateway::{
+            Filter, PortRange, ResolvedResourceDescriptionDns, ResourceDescription,
+            ResourceDescriptionDns,
+        },
         ClientId, ResourceId,
     };
+    use connlib_shared::DomainName;
+    use domain::base::{iana::Rtype, MessageBuilder};
     use ip_network::Ipv4Network;
+    use ip_packet::MutableIpPacket;
 
     use super::{ClientOnGateway, TranslationState};
+    use crate::dns;
 
     #[test]
     fn gateway_filters_expire_individually() {
@@ -687,6 +757,186 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn gateway_only_resolves_names_of_dns_resources() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        let name = DomainName::vec_from_str("ldap.example.com").unwrap();
+        peer.assign_proxies(
+            &ResourceDescription::Dns(ResolvedResourceDescriptionDns {
+                id: resource_id(),
+                domain: "*.example.com".to_owned(),
+                name: "example.com".to_owned(),
+                addresses: vec![],
+                exclusions: vec![],
+                filters: vec![],
+            }),
+            Some((name.clone(), vec![])),
+            Instant::now(),
+        )
+        .unwrap();
+        peer.add_resource(vec![], resource_id(), vec![], None, Some(name));
+
+        let resource_query = srv_query(source_v4_addr().into(), "_ldap._tcp.example.com");
+        let other_query = srv_query(source_v4_addr().into(), "_ldap._tcp.example.org");
+        let spoofed_query = srv_query("100.64.0.2".parse().unwrap(), "_ldap._tcp.example.com");
+
+        assert!(peer
+            .ensure_allowed_dns_query(
+                &dns::parse_gateway_query(resource_query.as_immutable()).unwrap()
+            )
+            .is_ok());
+        assert!(matches!(
+            peer.ensure_allowed_dns_query(
+                &dns::parse_gateway_query(other_query.as_immutable()).unwrap()
+            ),
+            Err(connlib_shared::Error::UnknownResource)
+        ));
+        assert!(matches!(
+            peer.ensure_allowed_dns_query(
+                &dns::parse_gateway_query(spoofed_query.as_immutable()).unwrap()
+            ),
+            Err(connlib_shared::Error::SrcNotAllowed { .. })
+        ));
+
+        peer.remove_resource(&resource_id());
+
+        assert!(peer.dns_resource_addresses.is_empty());
+        assert!(matches!(
+            peer.ensure_allowed_dns_query(
+                &dns::parse_gateway_query(resource_query.as_immutable()).unwrap()
+            ),
+            Err(connlib_shared::Error::UnknownResource)
+        ));
+    }
+
+    #[test]
+    fn gateway_does_not_resolve_excluded_names() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        let name = DomainName::vec_from_str("ldap.example.com").unwrap();
+        peer.assign_proxies(
+            &ResourceDescription::Dns(ResolvedResourceDescriptionDns {
+                id: resource_id(),
+                domain: "*.example.com".to_owned(),
+                name: "example.com".to_owned(),
+                addresses: vec![],
+                exclusions: vec!["_ldap._tcp.public.example.com".to_owned()],
+                filters: vec![],
+            }),
+            Some((name.clone(), vec![])),
+            Instant::now(),
+        )
+        .unwrap();
+        peer.add_resource(vec![], resource_id(), vec![], None, Some(name));
+
+        let resource_query = srv_query(source_v4_addr().into(), "_ldap._tcp.example.com");
+        let excluded_query = srv_query(source_v4_addr().into(), "_ldap._tcp.public.example.com");
+
+        assert!(peer
+            .ensure_allowed_dns_query(
+                &dns::parse_gateway_query(resource_query.as_immutable()).unwrap()
+            )
+            .is_ok());
+        assert!(matches!(
+            peer.ensure_allowed_dns_query(
+                &dns::parse_gateway_query(excluded_query.as_immutable()).unwrap()
+            ),
+            Err(connlib_shared::Error::UnknownResource)
+        ));
+    }
+
+    #[test]
+    fn updated_exclusions_revoke_access_to_excluded_names() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        let public = DomainName::vec_from_str("public.example.com").unwrap();
+        let private = DomainName::vec_from_str("private.example.com").unwrap();
+        let now = Instant::now();
+        for (name, proxy_ip) in [(&public, "100.96.0.1"), (&private, "100.96.0.2")] {
+            peer.assign_proxies(
+                &ResourceDescription::Dns(ResolvedResourceDescriptionDns {
+                    id: resource_id(),
+                    domain: "*.example.com".to_owned(),
+                    name: "example.com".to_owned(),
+                    addresses: vec!["10.0.0.1".parse().unwrap()],
+                    exclusions: vec![],
+                    filters: vec![],
+                }),
+                Some((name.clone(), vec![proxy_ip.parse().unwrap()])),
+                now,
+            )
+            .unwrap();
+            peer.add_resource(
+                vec!["10.0.0.1/32".parse().unwrap()],
+                resource_id(),
+                vec![],
+                None,
+                Some(name.clone()),
+            );
+        }
+
+        peer.update_resource(&ResourceDescription::Dns(ResourceDescriptionDns {
+            id: resource_id(),
+            address: "*.example.com".to_owned(),
+            name: "example.com".to_owned(),
+            exclusions: vec!["public.example.com".to_owned()],
+            filters: vec![],
+        }));
+
+        assert_eq!(peer.resources[&resource_id()].len(), 1);
+        assert_eq!(peer.resources[&resource_id()][0].domain, Some(private));
+        assert_eq!(
+            peer.dns_resource_addresses[&resource_id()].1,
+            vec!["public.example.com".to_owned()]
+        );
+        assert!(!peer
+            .permanent_translations
+            .contains_key(&"100.96.0.1".parse::<IpAddr>().unwrap()));
+        assert!(peer
+            .permanent_translations
+            .contains_key(&"100.96.0.2".parse::<IpAddr>().unwrap()));
+    }
+
+    #[test]
+    fn gateway_replaces_translations_of_reassigned_proxy_ips() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        let resource = ResourceDescription::Dns(ResolvedResourceDescriptionDns {
+            id: resource_id(),
+            domain: "*.example.com".to_owned(),
+            name: "example.com".to_owned(),
+            addresses: vec!["10.0.0.1".parse().unwrap()],
+            exclusions: vec![],
+            filters: vec![],
+        });
+        let a = DomainName::vec_from_str("a.example.com").unwrap();
+        let b = DomainName::vec_from_str("b.example.com").unwrap();
+        let proxy_ip1 = "100.96.0.1".parse().unwrap();
+        let proxy_ip2 = "100.96.0.2".parse().unwrap();
+        let now = Instant::now();
+
+        peer.assign_proxies(
+            &resource,
+            Some((a.clone(), vec![proxy_ip1, proxy_ip2])),
+            now,
+        )
+        .unwrap();
+        peer.assign_proxies(&resource, Some((b.clone(), vec![proxy_ip1])), now)
+            .unwrap();
+        peer.assign_proxies(&resource, Some((a.clone(), vec![proxy_ip2])), now)
+            .unwrap();
+
+        assert_eq!(peer.permanent_translations[&proxy_ip1].name, b);
+        assert_eq!(peer.permanent_translations[&proxy_ip2].name, a);
+
+        peer.assign_proxies(
+            &resource,
+            Some((a.clone(), vec!["100.96.0.3".parse().unwrap()])),
+            now,
+        )
+        .unwrap();
+
+        assert!(!peer.permanent_translations.contains_key(&proxy_ip2));
+        assert_eq!(peer.permanent_translations[&proxy_ip1].name, b);
+    }
+
     #[test]
     fn initial_translation_state_is_not_expired() {
         let now = Instant::now();
@@ -977,6 +1227,21 @@ mod tests {
     fn client_id() -> ClientId {
         "9d4b79f6-1db7-4cb3-a077-712102204d73".parse().unwrap()
     }
+
+    fn srv_query(src: IpAddr, name: &str) -> MutableIpPacket<'static> {
+        let mut builder = MessageBuilder::new_vec().question();
+        builder
+            .push((DomainName::vec_from_str(name).unwrap(), Rtype::SRV))
+            .unwrap();
+
+        ip_packet::make::udp_packet(
+            src,
+            "100.100.111.1".parse().unwrap(),
+            5353,
+            53,
+            builder.finish(),
+        )
+    }
 }
 
 #[cfg(all(test, feature = "proptest"))]
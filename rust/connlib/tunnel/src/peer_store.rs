@@ -35,6 +35,17 @@ impl PeerStore<GatewayId, GatewayOnClient> {
             peer.insert_id(ip, resource);
         }
     }
+
+    /// Removes IPs from all peers, regardless of which resources they were added for.
+    pub(crate) fn remove_ips(&mut self, ips: &[IpNetwork]) {
+        for ip in ips {
+            self.id_by_ip.remove(*ip);
+
+            for peer in self.peer_by_id.values_mut() {
+                peer.allowed_ips.remove(*ip);
+            }
+        }
+    }
 }
 
 impl<TId, P> PeerStore<TId, P>
@@ -121,6 +132,7 @@ impl Peer for GatewayOnClient {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashSet;
 
     struct DummyPeer {
         id: u64,
@@ -173,6 +185,34 @@ mod tests {
             .is_none())
     }
 
+    #[test]
+    fn removing_ips_removes_them_from_gateways() {
+        let mut peer_storage = PeerStore::<GatewayId, GatewayOnClient>::default();
+        let gateway_id = "0f4a1c3e-5b6d-4e7f-8a9b-0c1d2e3f4a5b".parse().unwrap();
+        let proxy_ip: IpNetwork = "100.96.0.1/32".parse().unwrap();
+        let other_ip: IpNetwork = "100.96.0.2/32".parse().unwrap();
+        peer_storage.insert(
+            GatewayOnClient::new(
+                gateway_id,
+                &[proxy_ip, other_ip],
+                HashSet::from([ResourceId::random()]),
+            ),
+            &[proxy_ip, other_ip],
+        );
+
+        peer_storage.remove_ips(&[proxy_ip]);
+
+        assert!(peer_storage
+            .peer_by_ip("100.96.0.1".parse().unwrap())
+            .is_none());
+        assert!(peer_storage
+            .peer_by_ip("100.96.0.2".parse().unwrap())
+            .is_some());
+        let gateway = peer_storage.get(&gateway_id).unwrap();
+        assert!(gateway.allowed_ips.exact_match(proxy_ip).is_none());
+        assert!(gateway.allowed_ips.exact_match(other_ip).is_some());
+    }
+
     #[test]
     fn inserting_peer_removes_previous_instances_of_same_id() {
         let mut peer_storage = PeerStore::<u64, DummyPeer>::default();
@@ -1,8 +1,10 @@
+use bytes::Bytes;
 use core::slice;
 use quinn_udp::{RecvMeta, UdpSockRef, UdpSocketState};
 use socket2::{SockAddr, Type};
 use std::{
     io::{self, IoSliceMut},
+    iter,
     net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     task::{ready, Context, Poll},
 };
@@ -46,6 +48,16 @@ impl Sockets {
         }
     }
 
+    /// How many segments we can send at most in a single GSO send, `1` if the sockets don't support GSO.
+    pub fn max_gso_segments(&self) -> usize {
+        self.socket_v4
+            .iter()
+            .chain(self.socket_v6.iter())
+            .map(|s| s.max_gso_segments())
+            .min()
+            .unwrap_or(1)
+    }
+
     pub fn can_handle(&self, addr: &SocketAddr) -> bool {
         match addr {
             SocketAddr::V4(_) => self.socket_v4.is_some(),
@@ -110,21 +122,24 @@ impl Sockets {
         Poll::Ready(Ok(()))
     }
 
-    pub fn try_send(&mut self, transmit: quinn_udp::Transmit) -> io::Result<()> {
+    /// Queues a datagram for sending.
+    ///
+    /// `tos` is the full TOS / traffic class byte, i.e. the DSCP together with the ECN bits of `transmit`.
+    pub fn try_send(&mut self, transmit: quinn_udp::Transmit, tos: u8) -> io::Result<()> {
         match transmit.destination {
             SocketAddr::V4(dst) => {
                 let socket = self.socket_v4.as_mut().ok_or(io::Error::new(
                     io::ErrorKind::NotConnected,
                     format!("failed send packet to {dst}: no IPv4 socket"),
                 ))?;
-                socket.send(transmit);
+                socket.send(transmit, tos);
             }
             SocketAddr::V6(dst) => {
                 let socket = self.socket_v6.as_mut().ok_or(io::Error::new(
                     io::ErrorKind::NotConnected,
                     format!("failed send packet to {dst}: no IPv6 socket"),
                 ))?;
-                socket.send(transmit);
+                socket.send(transmit, tos);
             }
         }
 
@@ -201,10 +216,15 @@ where
     }
 }
 
+/// A batch of datagrams from the same remote, as returned by a single UDP GRO read.
 pub struct Received<'a> {
     pub local: SocketAddr,
     pub from: SocketAddr,
-    pub packet: &'a [u8],
+    /// All datagrams are `stride` bytes long, except for the last one which may be shorter.
+    pub datagrams: &'a [u8],
+    pub stride: usize,
+    /// The ECN bits of the IP header the packet arrived with.
+    pub ecn: u8,
 }
 
 struct Socket {
@@ -213,6 +233,12 @@ struct Socket {
     socket: UdpSocket,
 
     buffered_transmits: Vec<quinn_udp::Transmit>,
+    /// The TOS of each datagram in `buffered_transmits`, at the same index.
+    buffered_tos: Vec<u8>,
+    /// Whether a GSO send of ours failed, i.e. the network adapter doesn't support it.
+    ///
+    /// `quinn-udp` tracks this for the datagrams it sends, this covers the ones we send in [`send_with_tos`].
+    gso_unsupported: bool,
 }
 
 impl Socket {
@@ -225,6 +251,8 @@ impl Socket {
             port,
             socket: tokio::net::UdpSocket::from_std(socket)?,
             buffered_transmits: Vec::new(),
+            buffered_tos: Vec::new(),
+            gso_unsupported: false,
         })
     }
 
@@ -237,6 +265,8 @@ impl Socket {
             port,
             socket: tokio::net::UdpSocket::from_std(socket)?,
             buffered_transmits: Vec::new(),
+            buffered_tos: Vec::new(),
+            gso_unsupported: false,
         })
     }
 
@@ -275,34 +305,63 @@ impl Socket {
 
                 let local = SocketAddr::new(local_ip, *port);
 
-                let iter = buffer[..meta.len]
-                    .chunks(meta.stride)
-                    .map(move |packet| Received {
-                        local,
-                        from: meta.addr,
-                        packet,
-                    })
-                    .inspect(|r| {
-                        tracing::trace!(target: "wire::net::recv", src = %r.from, dst = %r.local, num_bytes = %r.packet.len());
-                    });
-
-                return Poll::Ready(Ok(iter));
+                tracing::trace!(target: "wire::net::recv", src = %meta.addr, dst = %local, num_bytes = %meta.len, stride = %meta.stride);
+
+                return Poll::Ready(Ok(iter::once(Received {
+                    local,
+                    from: meta.addr,
+                    datagrams: &buffer[..meta.len],
+                    stride: meta.stride,
+                    ecn: meta.ecn.map_or(0, |ecn| ecn as u8),
+                })));
             }
         }
     }
 
     fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        loop {
-            match self.socket.try_io(Interest::WRITABLE, || {
-                self.state
-                    .send((&self.socket).into(), &self.buffered_transmits)
-            }) {
+        while let Some(&tos) = self.buffered_tos.first() {
+            // `quinn-udp` only sets the ECN bits, datagrams with a DSCP are sent by us.
+            // Either way, we send consecutive datagrams together, as long as they don't need a different DSCP.
+            let num_batched = self
+                .buffered_tos
+                .iter()
+                .take_while(|other| {
+                    if has_dscp(tos) {
+                        **other == tos
+                    } else {
+                        !has_dscp(**other)
+                    }
+                })
+                .count();
+            let batch = &self.buffered_transmits[..num_batched];
+
+            let result = self.socket.try_io(Interest::WRITABLE, || {
+                if has_dscp(tos) {
+                    send_with_tos(&self.state, &self.socket, batch, tos)
+                } else {
+                    self.state.send((&self.socket).into(), batch)
+                }
+            });
+
+            match result {
                 Ok(0) => break,
                 Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
-                Err(e) => return Poll::Ready(Err(e)),
+                Err(e) => {
+                    // Drop the datagram that failed, otherwise we'd try to send it forever.
+                    let transmit = self.buffered_transmits.remove(0);
+                    self.buffered_tos.remove(0);
+
+                    if transmit.segment_size.is_some() && is_gso_unsupported(&e) {
+                        tracing::info!("GSO is not supported by the network adapter, sending datagrams individually");
+                        self.gso_unsupported = true;
+                    }
+
+                    return Poll::Ready(Err(e));
+                }
 
                 Ok(num_sent) => {
                     self.buffered_transmits.drain(..num_sent);
+                    self.buffered_tos.drain(..num_sent);
 
                     // I am not sure if we'd ever send less than what is in `buffered_transmits`.
                     // loop once more to be sure we `break` on either an empty buffer or on `WouldBlock`.
@@ -321,10 +380,51 @@ impl Socket {
         Poll::Ready(Ok(()))
     }
 
-    fn send(&mut self, transmit: quinn_udp::Transmit) {
-        tracing::trace!(target: "wire::net::send", src = ?transmit.src_ip, dst = %transmit.destination, num_bytes = %transmit.contents.len());
+    fn max_gso_segments(&self) -> usize {
+        if self.gso_unsupported {
+            return 1;
+        }
+
+        self.state.max_gso_segments()
+    }
+
+    fn send(&mut self, transmit: quinn_udp::Transmit, tos: u8) {
+        tracing::trace!(target: "wire::net::send", src = ?transmit.src_ip, dst = %transmit.destination, num_bytes = %transmit.contents.len(), segment_size = ?transmit.segment_size);
+
+        let max_gso_segments = self.max_gso_segments();
+
+        match transmit.segment_size {
+            // Without GSO support, we need to send each segment individually.
+            Some(segment_size) if max_gso_segments <= 1 => {
+                self.buffered_transmits
+                    .extend(chunks(&transmit.contents, segment_size).map(|contents| {
+                        quinn_udp::Transmit {
+                            destination: transmit.destination,
+                            ecn: transmit.ecn,
+                            contents,
+                            segment_size: None,
+                            src_ip: transmit.src_ip,
+                        }
+                    }));
+            }
+            // Split batches that have more segments than the socket can send at once.
+            Some(segment_size) if transmit.contents.len() > segment_size * max_gso_segments => {
+                self.buffered_transmits.extend(
+                    chunks(&transmit.contents, segment_size * max_gso_segments).map(|contents| {
+                        quinn_udp::Transmit {
+                            destination: transmit.destination,
+                            ecn: transmit.ecn,
+                            contents,
+                            segment_size: Some(segment_size),
+                            src_ip: transmit.src_ip,
+                        }
+                    }),
+                );
+            }
+            Some(_) | None => self.buffered_transmits.push(transmit),
+        }
 
-        self.buffered_transmits.push(transmit);
+        self.buffered_tos.resize(self.buffered_transmits.len(), tos);
 
         debug_assert!(
             self.buffered_transmits.len() < 10_000,
@@ -333,6 +433,175 @@ impl Socket {
     }
 }
 
+/// Whether the TOS / traffic class byte carries a DSCP that we can set, i.e. anything on top of the ECN bits.
+fn has_dscp(tos: u8) -> bool {
+    cfg!(any(target_os = "linux", target_os = "android")) && tos >> 2 != 0
+}
+
+/// Whether sending a GSO batch failed because the network adapter doesn't support it.
+fn is_gso_unsupported(e: &io::Error) -> bool {
+    cfg!(any(target_os = "linux", target_os = "android")) && e.raw_os_error() == Some(libc::EIO)
+}
+
+/// Sends datagrams with the given TOS / traffic class byte and returns how many we sent.
+///
+/// `quinn-udp` always sets the TOS via a control message that only contains the ECN bits, overriding any socket option.
+/// Thus, we encode the control messages ourselves for datagrams that carry a DSCP.
+/// An error is only returned if we couldn't send the first datagram, it resurfaces on the next call otherwise.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn send_with_tos(
+    _: &UdpSocketState,
+    socket: &UdpSocket,
+    transmits: &[quinn_udp::Transmit],
+    tos: u8,
+) -> io::Result<usize> {
+    for (num_sent, transmit) in transmits.iter().enumerate() {
+        match send_one_with_tos(socket, transmit, tos) {
+            Ok(()) => {}
+            Err(_) if num_sent > 0 => return Ok(num_sent),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(transmits.len())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn send_one_with_tos(
+    socket: &UdpSocket,
+    transmit: &quinn_udp::Transmit,
+    tos: u8,
+) -> io::Result<()> {
+    use std::{mem, net::IpAddr, os::fd::AsRawFd as _};
+
+    const UDP_SEGMENT: libc::c_int = 103;
+
+    /// Appends a control message after `cmsg` and returns where the next one goes.
+    ///
+    /// # Safety
+    ///
+    /// `cmsg` must point to a control message header within the buffer of `hdr` that has enough space for `value`.
+    unsafe fn push<T>(
+        hdr: &libc::msghdr,
+        cmsg: *mut libc::cmsghdr,
+        level: libc::c_int,
+        ty: libc::c_int,
+        value: T,
+    ) -> *mut libc::cmsghdr {
+        (*cmsg).cmsg_level = level;
+        (*cmsg).cmsg_type = ty;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<T>() as _) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<T>(), value);
+
+        libc::CMSG_NXTHDR(hdr, cmsg)
+    }
+
+    let destination = SockAddr::from(transmit.destination);
+    let mut iov = libc::iovec {
+        iov_base: transmit.contents.as_ptr() as *mut _,
+        iov_len: transmit.contents.len(),
+    };
+    let mut control = [0u64; 16]; // `u64` for the alignment of `cmsghdr`.
+
+    // SAFETY: All-zero is a valid `msghdr`.
+    let mut hdr = unsafe { mem::zeroed::<libc::msghdr>() };
+    hdr.msg_name = destination.as_ptr() as *mut _;
+    hdr.msg_namelen = destination.len();
+    hdr.msg_iov = &mut iov;
+    hdr.msg_iovlen = 1;
+    hdr.msg_control = control.as_mut_ptr().cast();
+    hdr.msg_controllen = mem::size_of_val(&control) as _;
+
+    let tos = libc::c_int::from(tos);
+
+    // SAFETY: `control` has room for all control messages we push, see `CMSG_SPACE` below.
+    let controllen = unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&hdr);
+        let mut controllen = 0;
+
+        match transmit.destination {
+            SocketAddr::V4(_) => {
+                cmsg = push(&hdr, cmsg, libc::IPPROTO_IP, libc::IP_TOS, tos);
+            }
+            SocketAddr::V6(_) => {
+                cmsg = push(&hdr, cmsg, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos);
+            }
+        }
+        controllen += libc::CMSG_SPACE(mem::size_of::<libc::c_int>() as _);
+
+        if let Some(segment_size) = transmit.segment_size {
+            cmsg = push(
+                &hdr,
+                cmsg,
+                libc::IPPROTO_UDP,
+                UDP_SEGMENT,
+                segment_size as u16,
+            );
+            controllen += libc::CMSG_SPACE(mem::size_of::<u16>() as _);
+        }
+
+        match transmit.src_ip {
+            Some(IpAddr::V4(src)) => {
+                let pktinfo = libc::in_pktinfo {
+                    ipi_ifindex: 0,
+                    ipi_spec_dst: libc::in_addr {
+                        s_addr: u32::from_ne_bytes(src.octets()),
+                    },
+                    ipi_addr: libc::in_addr { s_addr: 0 },
+                };
+                push(&hdr, cmsg, libc::IPPROTO_IP, libc::IP_PKTINFO, pktinfo);
+                controllen += libc::CMSG_SPACE(mem::size_of::<libc::in_pktinfo>() as _);
+            }
+            Some(IpAddr::V6(src)) => {
+                let pktinfo = libc::in6_pktinfo {
+                    ipi6_ifindex: 0,
+                    ipi6_addr: libc::in6_addr {
+                        s6_addr: src.octets(),
+                    },
+                };
+                push(&hdr, cmsg, libc::IPPROTO_IPV6, libc::IPV6_PKTINFO, pktinfo);
+                controllen += libc::CMSG_SPACE(mem::size_of::<libc::in6_pktinfo>() as _);
+            }
+            None => {}
+        }
+
+        controllen
+    };
+    hdr.msg_controllen = controllen as _;
+
+    loop {
+        // SAFETY: `hdr` only points to memory that outlives this call.
+        if unsafe { libc::sendmsg(socket.as_raw_fd(), &hdr, 0) } != -1 {
+            return Ok(());
+        }
+
+        let e = io::Error::last_os_error();
+
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+/// Sends datagrams, setting only the ECN bits of their TOS.
+///
+/// We don't know how to set the DSCP per datagram on this platform, see [`has_dscp`].
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn send_with_tos(
+    state: &UdpSocketState,
+    socket: &UdpSocket,
+    transmits: &[quinn_udp::Transmit],
+    _: u8,
+) -> io::Result<usize> {
+    state.send(socket.into(), transmits)
+}
+
+fn chunks(contents: &Bytes, size: usize) -> impl Iterator<Item = Bytes> + '_ {
+    (0..contents.len())
+        .step_by(size.max(1))
+        .map(move |start| contents.slice(start..(start + size).min(contents.len())))
+}
+
 fn make_socket(addr: impl Into<SocketAddr>) -> Result<std::net::UdpSocket> {
     let addr: SockAddr = addr.into().into();
     let socket = socket2::Socket::new(addr.domain(), Type::DGRAM, None)?;
@@ -355,13 +355,7 @@ impl ReferenceStateMachine for ReferenceState {
                     .network
                     .add_host(state.client.inner().id, &state.client));
 
-                // When roaming, we are not connected to any resource and wait for the next packet to re-establish a connection.
-                state
-                    .client
-                    .exec_mut(|client| client.connected_cidr_resources.clear());
-                state
-                    .client
-                    .exec_mut(|client| client.connected_dns_resources.clear());
+                // When roaming, we restart ICE and thus stay connected to all resources.
             }
         };
 
@@ -5,7 +5,7 @@ use super::{
     sut::domain_to_hickory_name,
     IcmpIdentifier, IcmpSeq, QueryId,
 };
-use crate::{tests::sut::hickory_name_to_domain, ClientState};
+use crate::{sockets::Received, tests::sut::hickory_name_to_domain, ClientState};
 use bimap::BiMap;
 use connlib_shared::{
     messages::{
@@ -21,7 +21,7 @@ use hickory_proto::{
     serialize::binary::BinDecodable as _,
 };
 use ip_network_table::IpNetworkTable;
-use ip_packet::{IpPacket, MutableIpPacket, Packet as _};
+use ip_packet::{IpPacket, MutableIpPacket, Packet as _, ECN_NOT_ECT};
 use itertools::Itertools as _;
 use prop::collection;
 use proptest::prelude::*;
@@ -150,15 +150,26 @@ impl SimClient {
         dst: SocketAddr,
         now: Instant,
     ) {
-        let Some(packet) = self
+        let packets = self
             .sut
-            .decapsulate(dst, src, payload, now, &mut self.buffer)
-        else {
-            return;
-        };
-        let packet = packet.to_owned();
+            .decapsulate_batch(
+                Received {
+                    local: dst,
+                    from: src,
+                    datagrams: payload,
+                    stride: payload.len(),
+                    ecn: ECN_NOT_ECT,
+                },
+                now,
+                &mut self.buffer,
+            )
+            .into_iter()
+            .map(|packet| packet.to_owned())
+            .collect::<Vec<_>>();
 
-        self.on_received_packet(packet);
+        for packet in packets {
+            self.on_received_packet(packet);
+        }
     }
 
     /// Process an IP packet received on the client.
@@ -264,6 +275,8 @@ impl RefClient {
             ipv4: self.tunnel_ip4,
             ipv6: self.tunnel_ip6,
             upstream_dns: self.upstream_dns_resolvers,
+            dns_resource_ttl: None,
+            search_domains: vec![],
         });
         let _ = client_state.update_system_resolvers(self.system_dns_resolvers);
 
@@ -406,7 +419,12 @@ impl RefClient {
     fn dns_resource_by_domain(&self, domain: &DomainName) -> Option<ResourceId> {
         self.dns_resources
             .values()
-            .filter(|r| is_subdomain(&domain.to_string(), &r.address))
+            .filter(|r| {
+                let domain = domain.to_string();
+
+                is_subdomain(&domain, &r.address)
+                    && !r.exclusions.iter().any(|e| is_subdomain(&domain, e))
+            })
             .sorted_by_key(|r| r.address.len())
             .rev()
             .map(|r| r.id)
@@ -2,9 +2,9 @@ use super::{
     reference::{private_key, PrivateKey},
     sim_net::{any_ip_stack, any_port, host, Host},
 };
-use crate::{tests::sut::hickory_name_to_domain, GatewayState};
+use crate::{sockets::Received, tests::sut::hickory_name_to_domain, GatewayState};
 use connlib_shared::DomainName;
-use ip_packet::IpPacket;
+use ip_packet::{IpPacket, ECN_NOT_ECT};
 use proptest::prelude::*;
 use snownet::Transmit;
 use std::{
@@ -41,7 +41,19 @@ impl SimGateway {
     ) -> Option<Transmit<'static>> {
         let packet = self
             .sut
-            .decapsulate(dst, src, payload, now, &mut self.buffer)?
+            .decapsulate_batch(
+                Received {
+                    local: dst,
+                    from: src,
+                    datagrams: payload,
+                    stride: payload.len(),
+                    ecn: ECN_NOT_ECT,
+                },
+                now,
+                &mut self.buffer,
+            )
+            .into_iter()
+            .next()?
             .to_owned();
 
         self.on_received_packet(global_dns_records, packet, now)
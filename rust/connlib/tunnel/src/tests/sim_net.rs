@@ -1,3 +1,4 @@
+use bimap::BiMap;
 use connlib_shared::messages::{ClientId, GatewayId, RelayId};
 use firezone_relay::{AddressFamily, IpStack};
 use ip_network::{IpNetwork, Ipv4Network, Ipv6Network};
@@ -5,10 +6,11 @@ use ip_network_table::IpNetworkTable;
 use itertools::Itertools as _;
 use prop::sample;
 use proptest::prelude::*;
+use snownet::Nat64Prefix;
 use std::{
     collections::HashSet,
-    fmt,
-    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     num::NonZeroU16,
 };
 use tracing::Span;
@@ -203,6 +205,98 @@ impl RoutingTable {
     }
 }
 
+/// A NAT64 through which IPv6-only hosts reach IPv4 hosts via the well-known prefix.
+///
+/// Mapping and filtering are endpoint-independent: once an IPv6 socket has a mapping, any IPv4 host can reach it.
+#[derive(Debug, Clone)]
+pub(crate) struct SimNat64 {
+    /// The public IPv4 address of the NAT64.
+    ///
+    /// This uses the `TEST-NET-2` (`198.51.100.0/24`) address space to not collide with [`host_ip4s`].
+    ip4: Ipv4Addr,
+    mappings: BiMap<SocketAddrV6, u16>,
+    next_port: u16,
+}
+
+impl Default for SimNat64 {
+    fn default() -> Self {
+        Self {
+            ip4: Ipv4Addr::new(198, 51, 100, 64),
+            mappings: BiMap::default(),
+            next_port: 1024,
+        }
+    }
+}
+
+impl SimNat64 {
+    /// Translates a datagram sent to a synthesized IPv6 address into IPv4, creating a mapping for `src` if necessary.
+    pub(crate) fn outbound(
+        &mut self,
+        src: SocketAddr,
+        dst: SocketAddr,
+    ) -> Option<(SocketAddr, SocketAddr)> {
+        let (SocketAddr::V6(src), SocketAddr::V6(dst)) = (src, dst) else {
+            return None;
+        };
+        let dst_ip = Nat64Prefix::WELL_KNOWN.extract(*dst.ip())?;
+
+        let port = match self.mappings.get_by_left(&src) {
+            Some(port) => *port,
+            None => {
+                let port = self.next_port;
+                self.next_port += 1;
+                self.mappings.insert(src, port);
+
+                port
+            }
+        };
+
+        Some((
+            SocketAddrV4::new(self.ip4, port).into(),
+            SocketAddrV4::new(dst_ip, dst.port()).into(),
+        ))
+    }
+
+    /// Translates a datagram sent to our public IPv4 address back to the IPv6 socket that owns the mapping.
+    pub(crate) fn inbound(
+        &self,
+        src: SocketAddr,
+        dst: SocketAddr,
+    ) -> Option<(SocketAddr, SocketAddr)> {
+        let (SocketAddr::V4(src), SocketAddr::V4(dst)) = (src, dst) else {
+            return None;
+        };
+        if *dst.ip() != self.ip4 {
+            return None;
+        }
+
+        let internal = self.mappings.get_by_right(&dst.port())?;
+
+        Some((
+            Nat64Prefix::WELL_KNOWN.synthesize_socket(src).into(),
+            (*internal).into(),
+        ))
+    }
+}
+
+/// The DNS64 answer for [`snownet::IPV4ONLY_ARPA`] that hosts on an IPv6-only network see.
+///
+/// Dual-stack hosts don't use the NAT64 and thus see no AAAA records.
+pub(crate) fn dns64_lookup<T>(host: &Host<T>) -> impl FnOnce(&str) -> io::Result<Vec<Ipv6Addr>> {
+    let ipv6_only = host.ip4.is_none();
+
+    move |_| {
+        if !ipv6_only {
+            return Ok(vec![]);
+        }
+
+        Ok(vec![
+            Nat64Prefix::WELL_KNOWN.synthesize(Ipv4Addr::new(192, 0, 0, 170)),
+            Nat64Prefix::WELL_KNOWN.synthesize(Ipv4Addr::new(192, 0, 0, 171)),
+        ])
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Ord, Eq, Hash)]
 pub(crate) enum HostId {
     Client(ClientId),
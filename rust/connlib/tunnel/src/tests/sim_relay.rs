@@ -1,4 +1,4 @@
-use super::sim_net::{dual_ip_stack, host, Host};
+use super::sim_net::{dual_ip_stack, host, host_ip4s, Host};
 use connlib_shared::messages::RelayId;
 use firezone_relay::{AddressFamily, AllocationPort, ClientSocket, IpStack, PeerSocket};
 use proptest::prelude::*;
@@ -140,6 +140,8 @@ impl SimRelay {
             src: Some(src),
             dst,
             payload: Cow::Owned(payload.to_vec()),
+            segment_size: None,
+            tos: 0,
         })
     }
 
@@ -167,6 +169,8 @@ impl SimRelay {
             src: Some(sending_socket),
             dst: receiving_socket,
             payload: Cow::Owned(self.buffer[..full_length].to_vec()),
+            segment_size: None,
+            tos: 0,
         })
     }
 
@@ -186,7 +190,8 @@ impl SimRelay {
 
 pub(crate) fn relay_prototype() -> impl Strategy<Value = Host<u64>> {
     host(
-        dual_ip_stack(), // For this test, our relays always run in dual-stack mode to ensure connectivity!
+        // IPv6-only hosts reach IPv4-only relays through the NAT64 of our network.
+        prop_oneof![dual_ip_stack(), host_ip4s().prop_map(IpStack::Ip4)],
         Just(3478),
         any::<u64>(),
     )
@@ -1,7 +1,7 @@
 use super::reference::ReferenceState;
 use super::sim_client::SimClient;
 use super::sim_gateway::SimGateway;
-use super::sim_net::{Host, HostId, RoutingTable};
+use super::sim_net::{dns64_lookup, Host, HostId, RoutingTable, SimNat64};
 use super::sim_portal::SimPortal;
 use super::sim_relay::SimRelay;
 use crate::tests::assertions::*;
@@ -9,7 +9,7 @@ use crate::tests::sim_relay::map_explode;
 use crate::tests::transition::Transition;
 use crate::{dns::DnsQuery, ClientEvent, GatewayEvent, Request};
 use chrono::{DateTime, Utc};
-use connlib_shared::messages::{Interface, RelayId};
+use connlib_shared::messages::{Interface, Key, RelayId};
 use connlib_shared::{
     messages::{
         client::{ResourceDescription, ResourceDescriptionCidr, ResourceDescriptionDns},
@@ -26,7 +26,7 @@ use hickory_resolver::lookup::Lookup;
 use ip_network_table::IpNetworkTable;
 use proptest_state_machine::{ReferenceStateMachine, StateMachineTest};
 use rand::SeedableRng as _;
-use secrecy::ExposeSecret as _;
+use secrecy::{ExposeSecret as _, Secret};
 use snownet::Transmit;
 use std::collections::BTreeMap;
 use std::{
@@ -53,6 +53,7 @@ pub(crate) struct TunnelTest {
     portal: SimPortal,
 
     network: RoutingTable,
+    nat64: SimNat64,
 
     #[allow(dead_code)]
     logger: DefaultGuard,
@@ -111,7 +112,9 @@ impl StateMachineTest for TunnelTest {
             .collect::<HashMap<_, _>>();
 
         // Configure client and gateway with the relays.
+        let lookup = dns64_lookup(&client);
         client.exec_mut(|c| {
+            c.sut.discover_nat64_prefix(lookup);
             c.sut.update_relays(
                 HashSet::default(),
                 HashSet::from_iter(map_explode(relays.iter(), "client")),
@@ -119,7 +122,9 @@ impl StateMachineTest for TunnelTest {
             )
         });
         for (id, gateway) in &mut gateways {
+            let lookup = dns64_lookup(gateway);
             gateway.exec_mut(|g| {
+                g.sut.discover_nat64_prefix(lookup);
                 g.sut.update_relays(
                     HashSet::default(),
                     HashSet::from_iter(map_explode(relays.iter(), &format!("gateway_{id}"))),
@@ -132,6 +137,7 @@ impl StateMachineTest for TunnelTest {
             now: ref_state.now,
             utc_now: ref_state.utc_now,
             network: ref_state.network.clone(),
+            nat64: SimNat64::default(),
             client,
             gateways,
             portal: SimPortal::new(),
@@ -254,6 +260,8 @@ impl StateMachineTest for TunnelTest {
                         ipv4: c.sut.tunnel_ip4().unwrap(),
                         ipv6: c.sut.tunnel_ip6().unwrap(),
                         upstream_dns: servers,
+                        dns_resource_ttl: None,
+                        search_domains: vec![],
                     })
                 });
             }
@@ -264,8 +272,10 @@ impl StateMachineTest for TunnelTest {
                     .network
                     .add_host(state.client.inner().id, &state.client));
 
+                let lookup = dns64_lookup(&state.client);
                 state.client.exec_mut(|c| {
-                    c.sut.reset();
+                    c.sut.discover_nat64_prefix(lookup);
+                    c.sut.reset(state.now);
 
                     // In prod, we reconnect to the portal and receive a new `init` message.
                     c.sut.update_relays(
@@ -384,6 +394,8 @@ impl TunnelTest {
                                 src: Some(src),
                                 dst,
                                 payload: payload.into(),
+                                segment_size: None,
+                                tos: 0,
                             },
                             relay,
                         );
@@ -456,7 +468,7 @@ impl TunnelTest {
     /// This function is basically the "network layer" of our tests.
     /// It takes a [`Transmit`] and checks, which host accepts it, i.e. has configured the correct IP address.
     ///
-    /// Currently, the network topology of our tests are a single subnet without NAT.
+    /// Currently, the network topology of our tests are a single subnet without NAT, except for a NAT64 that IPv6-only hosts use to reach IPv4 hosts.
     fn dispatch_transmit(
         &mut self,
         transmit: Transmit,
@@ -467,6 +479,24 @@ impl TunnelTest {
             .src
             .expect("`src` should always be set in these tests");
         let dst = transmit.dst;
+
+        if let Some((src, dst)) = self
+            .nat64
+            .outbound(src, dst)
+            .or_else(|| self.nat64.inbound(src, dst))
+        {
+            self.dispatch_transmit(
+                Transmit {
+                    src: Some(src),
+                    dst,
+                    ..transmit
+                },
+                buffered_transmits,
+                global_dns_records,
+            );
+            return;
+        }
+
         let payload = &transmit.payload;
 
         let Some(host) = self.network.host_by_ip(dst.ip()) else {
@@ -518,6 +548,7 @@ impl TunnelTest {
             ClientEvent::AddedIceCandidates {
                 candidates,
                 conn_id,
+                end_of_candidates,
             } => {
                 let gateway = self.gateways.get_mut(&conn_id).expect("unknown gateway");
 
@@ -525,6 +556,10 @@ impl TunnelTest {
                     for candidate in candidates {
                         g.sut.add_ice_candidate(src, candidate, self.now)
                     }
+
+                    if end_of_candidates {
+                        g.sut.end_of_ice_candidates(src, self.now)
+                    }
                 })
             }
             ClientEvent::RemovedIceCandidates {
@@ -539,6 +574,22 @@ impl TunnelTest {
                     }
                 })
             }
+            ClientEvent::NewIceCredentials {
+                conn_id,
+                credentials,
+            } => {
+                let gateway = self.gateways.get_mut(&conn_id).expect("unknown gateway");
+
+                gateway.exec_mut(|g| g.sut.set_remote_ice_credentials(src, credentials, self.now))
+            }
+            ClientEvent::NewPresharedKey { conn_id, key } => {
+                let gateway = self.gateways.get_mut(&conn_id).expect("unknown gateway");
+
+                gateway.exec_mut(|g| {
+                    g.sut
+                        .set_preshared_key(src, Secret::new(Key(*key.expose_secret())), self.now)
+                })
+            }
             ClientEvent::ConnectionIntent {
                 resource,
                 connected_gateway_ids,
@@ -627,6 +678,8 @@ impl TunnelTest {
                                     },
                                     resource_id,
                                     gateway.inner().sut.public_key(),
+                                    true,
+                                    true,
                                     self.now,
                                 )
                             })
@@ -689,7 +742,9 @@ impl TunnelTest {
                         .unwrap();
                 }
             }
-            ClientEvent::ResourcesChanged { .. } => {
+            ClientEvent::ResourcesChanged { .. }
+            | ClientEvent::DnsQueryAnswered { .. }
+            | ClientEvent::ProxyIpsChanged { .. } => {
                 tracing::warn!("Unimplemented");
             }
             ClientEvent::DnsServersChanged { dns_by_sentinel } => {
@@ -730,6 +785,7 @@ impl TunnelTest {
                     Query::query(name, requested_type),
                     record_data,
                 )))),
+                self.now,
             )
         })
     }
@@ -742,16 +798,27 @@ fn on_gateway_event(
     now: Instant,
 ) {
     match event {
-        GatewayEvent::AddedIceCandidates { candidates, .. } => client.exec_mut(|c| {
+        GatewayEvent::AddedIceCandidates {
+            candidates,
+            end_of_candidates,
+            ..
+        } => client.exec_mut(|c| {
             for candidate in candidates {
                 c.sut.add_ice_candidate(src, candidate, now)
             }
+
+            if end_of_candidates {
+                c.sut.end_of_ice_candidates(src, now)
+            }
         }),
         GatewayEvent::RemovedIceCandidates { candidates, .. } => client.exec_mut(|c| {
             for candidate in candidates {
                 c.sut.remove_ice_candidate(src, candidate)
             }
         }),
+        GatewayEvent::NewIceCredentials { credentials, .. } => {
+            client.exec_mut(|c| c.sut.set_remote_ice_credentials(src, credentials, now))
+        }
         GatewayEvent::RefreshDns { .. } => todo!(),
     }
 }
@@ -779,6 +846,7 @@ fn map_client_resource_to_gateway_resource(
             filters: Vec::new(),
             domain: r.address.clone(),
             addresses: resolved_ips.clone(),
+            exclusions: r.exclusions.clone(),
         })
     });
 
@@ -201,18 +201,23 @@ pub(crate) fn star_wildcard_dns_resource(
         let wildcard_address = format!("*.{}", r.address);
 
         let records = subdomain_records(r.address, domain_name(1..3));
-        let resource = Just(ResourceDescriptionDns {
+        let resource = ResourceDescriptionDns {
             address: wildcard_address,
             ..r
-        });
+        };
 
-        (resource, records, Just(gateway)).prop_map(|(resource, records, gateway)| {
-            Transition::AddDnsResource {
+        records
+            .prop_flat_map(move |records| {
+                (
+                    with_excluded_records(resource.clone(), &records),
+                    Just(records),
+                )
+            })
+            .prop_map(move |(resource, records)| Transition::AddDnsResource {
                 records,
                 resource,
                 gateway,
-            }
-        })
+            })
     })
 }
 
@@ -223,18 +228,42 @@ pub(crate) fn question_mark_wildcard_dns_resource(
         let wildcard_address = format!("?.{}", r.address);
 
         let records = subdomain_records(r.address, domain_label());
-        let resource = Just(ResourceDescriptionDns {
+        let resource = ResourceDescriptionDns {
             address: wildcard_address,
             ..r
-        });
+        };
 
-        (resource, records, Just(gateway)).prop_map(|(resource, records, gateway)| {
-            Transition::AddDnsResource {
+        records
+            .prop_flat_map(move |records| {
+                (
+                    with_excluded_records(resource.clone(), &records),
+                    Just(records),
+                )
+            })
+            .prop_map(move |(resource, records)| Transition::AddDnsResource {
                 records,
                 resource,
                 gateway,
-            }
-        })
+            })
+    })
+}
+
+/// Excludes a random subset of the names in `records` from the wildcard `resource`.
+///
+/// The excluded names remain regular DNS records that resolve via the upstream resolvers.
+fn with_excluded_records(
+    resource: ResourceDescriptionDns,
+    records: &HashMap<DomainName, HashSet<IpAddr>>,
+) -> impl Strategy<Value = ResourceDescriptionDns> {
+    let names = records
+        .keys()
+        .map(|name| name.to_string())
+        .collect::<Vec<_>>();
+    let num_names = names.len();
+
+    sample::subsequence(names, 0..=num_names).prop_map(move |exclusions| ResourceDescriptionDns {
+        exclusions,
+        ..resource.clone()
     })
 }
 
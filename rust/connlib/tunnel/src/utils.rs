@@ -1,6 +1,7 @@
 use crate::REALM;
 use connlib_shared::messages::{Relay, RelayId};
 use ip_network::{IpNetwork, Ipv4Network, Ipv6Network};
+use ip_packet::{MutableIpPacket, ECN_CE, ECN_NOT_ECT};
 use itertools::Itertools;
 use snownet::RelaySocket;
 use std::{collections::HashSet, net::SocketAddr, time::Instant};
@@ -66,6 +67,24 @@ pub fn earliest(left: Option<Instant>, right: Option<Instant>) -> Option<Instant
     }
 }
 
+/// Propagates a "Congestion Experienced" mark of the outer datagram onto the packet it carried, as per RFC 6040.
+///
+/// Returns `false` if the packet must be dropped because it experienced congestion but its sender doesn't support ECN, see <https://www.rfc-editor.org/rfc/rfc6040#section-4.2>.
+#[must_use]
+pub(crate) fn propagate_ecn(packet: &mut MutableIpPacket<'_>, outer_ecn: u8) -> bool {
+    if outer_ecn != ECN_CE {
+        return true;
+    }
+
+    if packet.as_immutable().ecn() == ECN_NOT_ECT {
+        return false;
+    }
+
+    packet.set_ecn(ECN_CE);
+
+    true
+}
+
 pub(crate) fn network_contains_network(ip_a: IpNetwork, ip_b: IpNetwork) -> bool {
     ip_a.contains(ip_b.network_address()) && ip_a.netmask() <= ip_b.netmask()
 }
@@ -85,3 +104,46 @@ pub(crate) fn ipv6(ip: IpNetwork) -> Option<Ipv6Network> {
         IpNetwork::V6(v6) => Some(v6),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const ECT0: u8 = 0b10;
+
+    #[test]
+    fn congestion_mark_is_copied_onto_ecn_capable_packet() {
+        let mut packet = udp_packet();
+        packet.set_ecn(ECT0);
+
+        assert!(propagate_ecn(&mut packet, ECN_CE));
+        assert_eq!(packet.as_immutable().ecn(), ECN_CE);
+    }
+
+    #[test]
+    fn congested_packet_without_ecn_support_is_dropped() {
+        let mut packet = udp_packet();
+
+        assert!(!propagate_ecn(&mut packet, ECN_CE));
+    }
+
+    #[test]
+    fn packet_without_congestion_is_left_alone() {
+        let mut packet = udp_packet();
+        packet.set_ecn(ECT0);
+
+        assert!(propagate_ecn(&mut packet, ECT0));
+        assert_eq!(packet.as_immutable().ecn(), ECT0);
+    }
+
+    fn udp_packet() -> MutableIpPacket<'static> {
+        ip_packet::make::udp_packet(
+            Ipv4Addr::new(100, 64, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 1),
+            1234,
+            5678,
+            vec![0; 8],
+        )
+    }
+}
@@ -1,6 +1,7 @@
 use crate::messages::{
-    AllowAccess, ClientIceCandidates, ClientsIceCandidates, ConnectionReady, EgressMessages,
-    IngressMessages, RejectAccess, RequestConnection,
+    AllowAccess, ClientIceCandidates, ClientIceCredentials, ClientPresharedKey,
+    ClientsIceCandidates, ClientsIceCredentials, ConnectionReady, EgressMessages, IngressMessages,
+    RejectAccess, RequestConnection,
 };
 use crate::CallbackHandler;
 use anyhow::Result;
@@ -110,12 +111,14 @@ impl Eventloop {
             firezone_tunnel::GatewayEvent::AddedIceCandidates {
                 conn_id: client,
                 candidates,
+                end_of_candidates,
             } => {
                 self.portal.send(
                     PHOENIX_TOPIC,
                     EgressMessages::BroadcastIceCandidates(ClientsIceCandidates {
                         client_ids: vec![client],
                         candidates,
+                        end_of_candidates,
                     }),
                 );
             }
@@ -128,6 +131,19 @@ impl Eventloop {
                     EgressMessages::BroadcastInvalidatedIceCandidates(ClientsIceCandidates {
                         client_ids: vec![client],
                         candidates,
+                        end_of_candidates: false,
+                    }),
+                );
+            }
+            firezone_tunnel::GatewayEvent::NewIceCredentials {
+                conn_id: client,
+                credentials,
+            } => {
+                self.portal.send(
+                    PHOENIX_TOPIC,
+                    EgressMessages::BroadcastIceCredentials(ClientsIceCredentials {
+                        client_ids: vec![client],
+                        credentials,
                     }),
                 );
             }
@@ -187,18 +203,24 @@ impl Eventloop {
                     IngressMessages::IceCandidates(ClientIceCandidates {
                         client_id,
                         candidates,
+                        end_of_candidates,
                     }),
                 ..
             } => {
                 for candidate in candidates {
                     self.tunnel.add_ice_candidate(client_id, candidate);
                 }
+
+                if end_of_candidates {
+                    self.tunnel.end_of_ice_candidates(client_id);
+                }
             }
             phoenix_channel::Event::InboundMessage {
                 msg:
                     IngressMessages::InvalidateIceCandidates(ClientIceCandidates {
                         client_id,
                         candidates,
+                        ..
                     }),
                 ..
             } => {
@@ -206,6 +228,27 @@ impl Eventloop {
                     self.tunnel.remove_ice_candidate(client_id, candidate);
                 }
             }
+            phoenix_channel::Event::InboundMessage {
+                msg:
+                    IngressMessages::IceCredentials(ClientIceCredentials {
+                        client_id,
+                        credentials,
+                    }),
+                ..
+            } => {
+                self.tunnel
+                    .set_remote_ice_credentials(client_id, credentials);
+            }
+            phoenix_channel::Event::InboundMessage {
+                msg:
+                    IngressMessages::PresharedKey(ClientPresharedKey {
+                        client_id,
+                        preshared_key,
+                    }),
+                ..
+            } => {
+                self.tunnel.set_preshared_key(client_id, preshared_key);
+            }
             phoenix_channel::Event::InboundMessage {
                 msg:
                     IngressMessages::RejectAccess(RejectAccess {
@@ -290,6 +333,8 @@ impl Eventloop {
                                     address: addresses,
                                 }
                             }),
+                            supports_psk_rotation: true,
+                            supports_dns_resolution: true,
                         }),
                     }),
                 );
@@ -6,15 +6,17 @@ use connlib_shared::{
     get_user_agent, keypair, messages::Interface, Callbacks, LoginUrl, StaticSecret,
 };
 use firezone_bin_shared::{setup_global_subscriber, CommonArgs, TunDeviceManager};
-use firezone_tunnel::{GatewayTunnel, Sockets, Tun};
+use firezone_tunnel::{ConnectionConfig, GatewayTunnel, Sockets, Tun, DEFAULT_MAX_RELAYS};
 use futures::channel::mpsc;
 use futures::{future, StreamExt, TryFutureExt};
 use ip_network::{Ipv4Network, Ipv6Network};
 use phoenix_channel::PhoenixChannel;
 use secrecy::{Secret, SecretString};
 use std::convert::Infallible;
+use std::num::NonZeroUsize;
 use std::path::Path;
 use std::pin::pin;
+use std::time::Duration;
 use tokio::io::AsyncWriteExt;
 use tokio::signal::ctrl_c;
 use tracing_subscriber::layer;
@@ -56,7 +58,16 @@ async fn try_main() -> Result<()> {
         public_key.to_bytes(),
     )?;
 
-    let task = tokio::spawn(run(login, private_key)).err_into();
+    let connection_config = cli.connection_config();
+    let crypto_workers = cli.crypto_workers();
+    let task = tokio::spawn(run(
+        login,
+        private_key,
+        cli.max_relays,
+        connection_config,
+        crypto_workers,
+    ))
+    .err_into();
 
     let ctrl_c = pin!(ctrl_c().map_err(anyhow::Error::new));
 
@@ -99,8 +110,17 @@ async fn get_firezone_id(env_id: Option<String>) -> Result<String> {
     Ok(id)
 }
 
-async fn run(login: LoginUrl, private_key: StaticSecret) -> Result<Infallible> {
+async fn run(
+    login: LoginUrl,
+    private_key: StaticSecret,
+    max_relays: usize,
+    connection_config: ConnectionConfig,
+    crypto_workers: usize,
+) -> Result<Infallible> {
     let mut tunnel = GatewayTunnel::new(private_key, Sockets::new(), CallbackHandler)?;
+    tunnel.set_max_relays(max_relays);
+    tunnel.set_connection_config(connection_config);
+    tunnel.set_crypto_workers(crypto_workers);
     let portal = PhoenixChannel::connect(
         Secret::new(login),
         get_user_agent(None, env!("CARGO_PKG_VERSION")),
@@ -168,4 +188,44 @@ struct Cli {
     /// Identifier generated by the portal to identify and display the device.
     #[arg(short = 'i', long, env = "FIREZONE_ID")]
     pub firezone_id: Option<String>,
+
+    /// How many of the relays with the lowest latency to use for relayed connections.
+    #[arg(long, env = "FIREZONE_MAX_RELAYS", default_value_t = DEFAULT_MAX_RELAYS)]
+    max_relays: usize,
+
+    /// After how many seconds without traffic to close a connection to a client.
+    #[arg(long, env = "FIREZONE_IDLE_TIMEOUT", default_value_t = 5 * 60)]
+    idle_timeout: u64,
+
+    /// After how many seconds without traffic to send a WireGuard keep-alive to a client, 0 disables keep-alives.
+    #[arg(long, env = "FIREZONE_KEEPALIVE", default_value_t = 10)]
+    keepalive: u64,
+
+    /// At most how often (in seconds) to send ICE consent checks to a connected client, 0 lets ICE check at its own pace.
+    #[arg(long, env = "FIREZONE_CONSENT_CHECK_INTERVAL", default_value_t = 0)]
+    consent_check_interval: u64,
+
+    /// How many threads to use for encrypting and decrypting the traffic of different clients, defaults to the number of CPUs.
+    #[arg(long, env = "FIREZONE_CRYPTO_WORKERS")]
+    crypto_workers: Option<usize>,
+}
+
+impl Cli {
+    fn connection_config(&self) -> ConnectionConfig {
+        ConnectionConfig {
+            idle_timeout: Duration::from_secs(self.idle_timeout),
+            keepalive: (self.keepalive > 0).then(|| Duration::from_secs(self.keepalive)),
+            consent_check_interval: (self.consent_check_interval > 0)
+                .then(|| Duration::from_secs(self.consent_check_interval)),
+            ..ConnectionConfig::default()
+        }
+    }
+
+    fn crypto_workers(&self) -> usize {
+        self.crypto_workers.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(NonZeroUsize::get)
+                .unwrap_or(1)
+        })
+    }
 }
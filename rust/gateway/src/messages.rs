@@ -3,11 +3,12 @@ use std::{collections::HashSet, net::IpAddr};
 use chrono::{serde::ts_seconds_option, DateTime, Utc};
 use connlib_shared::{
     messages::{
-        gateway::ResourceDescription, ClientId, GatewayResponse, Interface, Offer, Peer, Relay,
-        RelaysPresence, ResourceId,
+        gateway::ResourceDescription, ClientId, GatewayResponse, IceCredentials, Interface, Offer,
+        Peer, Relay, RelaysPresence, ResourceId, SecretKey,
     },
     DomainName,
 };
+use secrecy::ExposeSecret as _;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Clone, PartialEq)]
@@ -110,6 +111,8 @@ pub enum IngressMessages {
     RejectAccess(RejectAccess),
     IceCandidates(ClientIceCandidates),
     InvalidateIceCandidates(ClientIceCandidates),
+    IceCredentials(ClientIceCredentials),
+    PresharedKey(ClientPresharedKey),
     Init(InitGateway),
     RelaysPresence(RelaysPresence),
     ResourceUpdated(ResourceDescription),
@@ -122,6 +125,9 @@ pub struct ClientsIceCandidates {
     pub client_ids: Vec<ClientId>,
     /// Actual RTC ice candidates
     pub candidates: HashSet<String>,
+    /// Whether we finished gathering candidates, i.e. no more will follow until ICE restarts.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub end_of_candidates: bool,
 }
 
 /// A client's ice candidate message.
@@ -131,6 +137,43 @@ pub struct ClientIceCandidates {
     pub client_id: ClientId,
     /// Actual RTC ice candidates
     pub candidates: Vec<String>,
+    /// Whether the client finished gathering candidates, i.e. no more will follow until ICE restarts.
+    #[serde(default)]
+    pub end_of_candidates: bool,
+}
+
+/// A client's ice credentials message.
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct ClientsIceCredentials {
+    /// Client's id the ice credentials are meant for
+    pub client_ids: Vec<ClientId>,
+    /// The new ICE credentials after restarting ICE.
+    pub credentials: IceCredentials,
+}
+
+/// A client's ice credentials message.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct ClientIceCredentials {
+    /// Client's id the ice credentials came from
+    pub client_id: ClientId,
+    /// The new ICE credentials after restarting ICE.
+    pub credentials: IceCredentials,
+}
+
+/// A client's preshared key message.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ClientPresharedKey {
+    /// Client's id the preshared key came from
+    pub client_id: ClientId,
+    /// The new preshared key after the client rotated it.
+    pub preshared_key: SecretKey,
+}
+
+impl PartialEq for ClientPresharedKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.client_id == other.client_id
+            && self.preshared_key.expose_secret() == other.preshared_key.expose_secret()
+    }
 }
 
 // These messages can be sent from a gateway
@@ -141,6 +184,7 @@ pub enum EgressMessages {
     ConnectionReady(ConnectionReady),
     BroadcastIceCandidates(ClientsIceCandidates),
     BroadcastInvalidatedIceCandidates(ClientsIceCandidates),
+    BroadcastIceCredentials(ClientsIceCredentials),
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -158,6 +202,7 @@ mod test {
     use connlib_shared::messages::gateway::ResourceDescriptionDns;
     use connlib_shared::messages::Turn;
     use phoenix_channel::PhoenixMessage;
+    use secrecy::Secret;
 
     #[test]
     fn request_connection_message() {
@@ -394,6 +439,54 @@ mod test {
                 "candidate:7854631899965427361 1 udp 1694498559 172.28.0.100 47717 typ srflx"
                     .to_owned(),
             ],
+            end_of_candidates: false,
+        });
+
+        let actual = serde_json::from_str::<IngressMessages>(msg).unwrap();
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn ice_candidates_message_with_end_of_candidates() {
+        let msg = r#"{"event":"ice_candidates","ref":null,"topic":"gateway","payload":{"candidates":[],"client_id":"2b1524e6-239e-4570-bc73-70a188e12101","end_of_candidates":true}}"#;
+        let expected = IngressMessages::IceCandidates(ClientIceCandidates {
+            client_id: "2b1524e6-239e-4570-bc73-70a188e12101".parse().unwrap(),
+            candidates: vec![],
+            end_of_candidates: true,
+        });
+
+        let actual = serde_json::from_str::<IngressMessages>(msg).unwrap();
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn preshared_key_message() {
+        let msg = r#"{"event":"preshared_key","ref":null,"topic":"gateway","payload":{"preshared_key":"NZEyHD409lCdOPqmnLffloVEqgeGOe43u+pz10awMFc=","client_id":"2b1524e6-239e-4570-bc73-70a188e12101"}}"#;
+        let expected = IngressMessages::PresharedKey(ClientPresharedKey {
+            client_id: "2b1524e6-239e-4570-bc73-70a188e12101".parse().unwrap(),
+            preshared_key: Secret::new(
+                "NZEyHD409lCdOPqmnLffloVEqgeGOe43u+pz10awMFc="
+                    .parse()
+                    .unwrap(),
+            ),
+        });
+
+        let actual = serde_json::from_str::<IngressMessages>(msg).unwrap();
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn ice_credentials_message() {
+        let msg = r#"{"event":"ice_credentials","ref":null,"topic":"gateway","payload":{"credentials":{"username":"fvJ4","password":"ZpG2MxbBQ8JBzErm9RzRsRlS"},"client_id":"2b1524e6-239e-4570-bc73-70a188e12101"}}"#;
+        let expected = IngressMessages::IceCredentials(ClientIceCredentials {
+            client_id: "2b1524e6-239e-4570-bc73-70a188e12101".parse().unwrap(),
+            credentials: IceCredentials {
+                username: "fvJ4".to_owned(),
+                password: "ZpG2MxbBQ8JBzErm9RzRsRlS".to_owned(),
+            },
         });
 
         let actual = serde_json::from_str::<IngressMessages>(msg).unwrap();
@@ -410,6 +503,8 @@ mod test {
                     ipv4: "100.115.164.78".parse().unwrap(),
                     ipv6: "fd00:2021:1111::2c:f6ab".parse().unwrap(),
                     upstream_dns: vec![],
+                    dns_resource_ttl: None,
+                    search_domains: vec![],
                 },
                 config: Config {
                     ipv4_masquerade_enabled: true,
@@ -435,6 +530,8 @@ mod test {
                     ipv4: "100.115.164.78".parse().unwrap(),
                     ipv6: "fd00:2021:1111::2c:f6ab".parse().unwrap(),
                     upstream_dns: vec![],
+                    dns_resource_ttl: None,
+                    search_domains: vec![],
                 },
                 config: Config {
                     ipv4_masquerade_enabled: true,
@@ -460,6 +557,8 @@ mod test {
                     ipv4: "100.115.164.78".parse().unwrap(),
                     ipv6: "fd00:2021:1111::2c:f6ab".parse().unwrap(),
                     upstream_dns: vec![],
+                    dns_resource_ttl: None,
+                    search_domains: vec![],
                 },
                 config: Config {
                     ipv4_masquerade_enabled: true,
@@ -485,6 +584,8 @@ mod test {
                     ipv4: "100.115.164.78".parse().unwrap(),
                     ipv6: "fd00:2021:1111::2c:f6ab".parse().unwrap(),
                     upstream_dns: vec![],
+                    dns_resource_ttl: None,
+                    search_domains: vec![],
                 },
                 config: Config {
                     ipv4_masquerade_enabled: true,
@@ -510,6 +611,8 @@ mod test {
                     ipv4: "100.115.164.78".parse().unwrap(),
                     ipv6: "fd00:2021:1111::2c:f6ab".parse().unwrap(),
                     upstream_dns: vec![],
+                    dns_resource_ttl: None,
+                    search_domains: vec![],
                 },
                 config: Config {
                     ipv4_masquerade_enabled: true,
@@ -535,6 +638,8 @@ mod test {
                     ipv4: "100.115.164.78".parse().unwrap(),
                     ipv6: "fd00:2021:1111::2c:f6ab".parse().unwrap(),
                     upstream_dns: vec![],
+                    dns_resource_ttl: None,
+                    search_domains: vec![],
                 },
                 config: Config {
                     ipv4_masquerade_enabled: true,
@@ -560,6 +665,8 @@ mod test {
                     ipv4: "100.115.164.78".parse().unwrap(),
                     ipv6: "fd00:2021:1111::2c:f6ab".parse().unwrap(),
                     upstream_dns: vec![],
+                    dns_resource_ttl: None,
+                    search_domains: vec![],
                 },
                 config: Config {
                     ipv4_masquerade_enabled: true,
@@ -584,6 +691,7 @@ mod test {
                 id: "57f9ebbb-21d5-4f9f-bf86-b25122fc7a43".parse().unwrap(),
                 address: "?.httpbin".to_string(),
                 name: "?.httpbin".to_string(),
+                exclusions: vec![],
                 filters: vec![
                     Filter::Icmp,
                     Filter::Tcp(PortRange {
@@ -50,20 +50,33 @@ impl Drop for DnsController {
 }
 
 impl DnsController {
-    /// Set the computer's system-wide DNS servers
+    /// Set the computer's system-wide DNS servers and search domains
     ///
     /// The `mut` in `&mut self` is not needed by Rust's rules, but
     /// it would be bad if this was called from 2 threads at once.
-    pub(crate) async fn set_dns(&mut self, dns_config: &[IpAddr]) -> Result<()> {
+    pub(crate) async fn set_dns(
+        &mut self,
+        dns_config: &[IpAddr],
+        search_domains: &[String],
+    ) -> Result<()> {
         match self.dns_control_method {
             None => Ok(()),
-            Some(DnsControlMethod::EtcResolvConf) => etc_resolv_conf::configure(dns_config).await,
+            Some(DnsControlMethod::EtcResolvConf) => {
+                etc_resolv_conf::configure(dns_config, search_domains).await
+            }
             Some(DnsControlMethod::NetworkManager) => configure_network_manager(dns_config),
-            Some(DnsControlMethod::Systemd) => configure_systemd_resolved(dns_config).await,
+            Some(DnsControlMethod::Systemd) => {
+                configure_systemd_resolved(dns_config, search_domains).await
+            }
         }
         .context("Failed to control DNS")
     }
 
+    /// Needed to match the Windows interface, DNS control stays in place until we revert it on drop
+    pub(crate) fn deactivate(&mut self) -> Result<()> {
+        deactivate()
+    }
+
     /// Flush systemd-resolved's system-wide DNS cache
     ///
     /// Does nothing if we're using other DNS control methods or none at all
@@ -92,7 +105,10 @@ fn configure_network_manager(_dns_config: &[IpAddr]) -> Result<()> {
     anyhow::bail!("DNS control with NetworkManager is not implemented yet",)
 }
 
-async fn configure_systemd_resolved(dns_config: &[IpAddr]) -> Result<()> {
+async fn configure_systemd_resolved(
+    dns_config: &[IpAddr],
+    search_domains: &[String],
+) -> Result<()> {
     let status = tokio::process::Command::new("resolvectl")
         .arg("dns")
         .arg(TunDeviceManager::IFACE_NAME)
@@ -104,10 +120,12 @@ async fn configure_systemd_resolved(dns_config: &[IpAddr]) -> Result<()> {
         bail!("`resolvectl dns` returned non-zero");
     }
 
+    // `~.` routes all queries to our sentinels, the others are search domains for single-label names.
     let status = tokio::process::Command::new("resolvectl")
         .arg("domain")
         .arg(TunDeviceManager::IFACE_NAME)
         .arg("~.")
+        .args(search_domains)
         .status()
         .await
         .context("`resolvectl domain` didn't run")?;
@@ -115,7 +133,11 @@ async fn configure_systemd_resolved(dns_config: &[IpAddr]) -> Result<()> {
         bail!("`resolvectl domain` returned non-zero");
     }
 
-    tracing::info!(?dns_config, "Configured DNS sentinels with `resolvectl`");
+    tracing::info!(
+        ?dns_config,
+        ?search_domains,
+        "Configured DNS sentinels with `resolvectl`"
+    );
 
     Ok(())
 }
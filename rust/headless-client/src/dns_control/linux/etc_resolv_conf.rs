@@ -30,8 +30,8 @@ impl Default for ResolvPaths {
 /// This is async because it's called in a Tokio context and it's nice to use their
 /// `fs` module
 #[cfg_attr(test, mutants::skip)] // Would modify system-wide `/etc/resolv.conf`
-pub(crate) async fn configure(dns_config: &[IpAddr]) -> Result<()> {
-    configure_at_paths(dns_config, &ResolvPaths::default()).await
+pub(crate) async fn configure(dns_config: &[IpAddr], search_domains: &[String]) -> Result<()> {
+    configure_at_paths(dns_config, search_domains, &ResolvPaths::default()).await
 }
 
 /// Revert changes Firezone made to `/etc/resolv.conf`
@@ -42,7 +42,11 @@ pub(crate) fn revert() -> Result<()> {
     revert_at_paths(&ResolvPaths::default())
 }
 
-async fn configure_at_paths(dns_config: &[IpAddr], paths: &ResolvPaths) -> Result<()> {
+async fn configure_at_paths(
+    dns_config: &[IpAddr],
+    search_domains: &[String],
+    paths: &ResolvPaths,
+) -> Result<()> {
     if dns_config.is_empty() {
         tracing::warn!("`dns_config` is empty, leaving `/etc/resolv.conf` unchanged");
         return Ok(());
@@ -94,6 +98,22 @@ async fn configure_at_paths(dns_config: &[IpAddr], paths: &ResolvPaths) -> Resul
 
     new_resolv_conf.nameservers = dns_config.iter().map(|addr| (*addr).into()).collect();
 
+    // Our search domains take precedence, the system's ones still apply to names that aren't found with ours.
+    if !search_domains.is_empty() {
+        let existing = parsed.get_search().cloned().unwrap_or_default();
+        let search = search_domains
+            .iter()
+            .chain(existing.iter().filter(|domain| *domain != "."))
+            .fold(Vec::<String>::new(), |mut search, domain| {
+                if !search.contains(domain) {
+                    search.push(domain.clone());
+                }
+                search
+            });
+
+        new_resolv_conf.set_search(search);
+    }
+
     // Over-writing `/etc/resolv.conf` actually violates Docker's plan for handling DNS
     // https://docs.docker.com/network/#dns-services
     // But this is just a hack to get a smoke test working in CI for now.
@@ -291,7 +311,7 @@ nameserver 100.100.111.2
 
         write_resolv_conf(&paths.resolv, &[GOOGLE_DNS.into()])?;
 
-        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &paths).await?;
+        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &[], &paths).await?;
 
         check_resolv_conf(&paths.resolv, &[IpAddr::from([100, 100, 111, 1])])?;
         check_resolv_conf(&paths.backup, &[GOOGLE_DNS.into()])?;
@@ -306,6 +326,46 @@ nameserver 100.100.111.2
         Ok(())
     }
 
+    /// Search domains from the portal should come before the system's own ones
+    #[tokio::test]
+    async fn search_domains() -> Result<()> {
+        let (_temp_dir, paths) = create_temp_paths();
+
+        std::fs::write(
+            &paths.resolv,
+            "nameserver 8.8.8.8\nsearch home.arpa corp.example.com\n",
+        )?;
+
+        configure_at_paths(
+            &[IpAddr::from([100, 100, 111, 1])],
+            &["corp.example.com".to_owned(), "example.com".to_owned()],
+            &paths,
+        )
+        .await?;
+
+        let parsed = resolv_conf::Config::parse(std::fs::read_to_string(&paths.resolv)?)?;
+        ensure!(
+            parsed.get_search()
+                == Some(&vec![
+                    "corp.example.com".to_owned(),
+                    "example.com".to_owned(),
+                    "home.arpa".to_owned()
+                ]),
+            "Unexpected search domains {:?}",
+            parsed.get_search()
+        );
+
+        revert_at_paths(&paths)?;
+
+        let parsed = resolv_conf::Config::parse(std::fs::read_to_string(&paths.resolv)?)?;
+        ensure!(
+            parsed.get_search()
+                == Some(&vec!["home.arpa".to_owned(), "corp.example.com".to_owned()])
+        );
+
+        Ok(())
+    }
+
     /// If there are no sentinels for some reason, don't change resolv.conf
     #[tokio::test]
     async fn no_sentinels() -> Result<()> {
@@ -313,7 +373,7 @@ nameserver 100.100.111.2
 
         write_resolv_conf(&paths.resolv, &[GOOGLE_DNS.into()])?;
 
-        configure_at_paths(&[], &paths).await?;
+        configure_at_paths(&[], &[], &paths).await?;
 
         check_resolv_conf(&paths.resolv, &[GOOGLE_DNS.into()])?;
         // No backup since we didn't touch the original file
@@ -328,11 +388,11 @@ nameserver 100.100.111.2
         let (_temp_dir, paths) = create_temp_paths();
 
         write_resolv_conf(&paths.resolv, &[GOOGLE_DNS.into()])?;
-        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &paths).await?;
+        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &[], &paths).await?;
         revert_at_paths(&paths)?;
 
         write_resolv_conf(&paths.resolv, &[CLOUDFLARE_DNS.into()])?;
-        configure_at_paths(&[IpAddr::from([100, 100, 111, 2])], &paths).await?;
+        configure_at_paths(&[IpAddr::from([100, 100, 111, 2])], &[], &paths).await?;
         check_resolv_conf(&paths.resolv, &[IpAddr::from([100, 100, 111, 2])])?;
         check_resolv_conf(&paths.backup, &[CLOUDFLARE_DNS.into()])?;
         revert_at_paths(&paths)?;
@@ -355,7 +415,7 @@ nameserver 100.100.111.2
         write_resolv_conf(&paths.resolv, &[GOOGLE_DNS.into()])?;
 
         // First run
-        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &paths).await?;
+        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &[], &paths).await?;
         check_resolv_conf(&paths.resolv, &[IpAddr::from([100, 100, 111, 1])])
             .context("First run, resolv.conf should have sentinel")?;
         check_resolv_conf(&paths.backup, &[GOOGLE_DNS.into()])
@@ -364,7 +424,7 @@ nameserver 100.100.111.2
         // Crash happens
 
         // Second run
-        configure_at_paths(&[IpAddr::from([100, 100, 111, 2])], &paths).await?;
+        configure_at_paths(&[IpAddr::from([100, 100, 111, 2])], &[], &paths).await?;
         check_resolv_conf(&paths.resolv, &[IpAddr::from([100, 100, 111, 2])])
             .context("Second run, resolv.conf should have new sentinel")?;
         check_resolv_conf(&paths.backup, &[GOOGLE_DNS.into()])
@@ -388,7 +448,7 @@ nameserver 100.100.111.2
         write_resolv_conf(&paths.resolv, &[GOOGLE_DNS.into()])?;
 
         // First run
-        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &paths).await?;
+        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &[], &paths).await?;
         check_resolv_conf(&paths.resolv, &[IpAddr::from([100, 100, 111, 1])])
             .context("First run, resolv.conf should have sentinel")?;
         check_resolv_conf(&paths.backup, &[GOOGLE_DNS.into()])
@@ -399,7 +459,7 @@ nameserver 100.100.111.2
         write_resolv_conf(&paths.resolv, &[CLOUDFLARE_DNS.into()])?;
 
         // Second run
-        configure_at_paths(&[IpAddr::from([100, 100, 111, 2])], &paths).await?;
+        configure_at_paths(&[IpAddr::from([100, 100, 111, 2])], &[], &paths).await?;
         check_resolv_conf(&paths.resolv, &[IpAddr::from([100, 100, 111, 2])])
             .context("Second run, resolv.conf should have new sentinel")?;
         check_resolv_conf(&paths.backup, &[CLOUDFLARE_DNS.into()])
@@ -424,11 +484,11 @@ nameserver 100.100.111.2
         write_resolv_conf(&paths.resolv, &[GOOGLE_DNS.into()])?;
 
         // Configure twice
-        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &paths).await?;
+        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &[], &paths).await?;
         check_resolv_conf(&paths.resolv, &[IpAddr::from([100, 100, 111, 1])])?;
         check_resolv_conf(&paths.backup, &[GOOGLE_DNS.into()])?;
 
-        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &paths).await?;
+        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &[], &paths).await?;
         check_resolv_conf(&paths.resolv, &[IpAddr::from([100, 100, 111, 1])])?;
         check_resolv_conf(&paths.backup, &[GOOGLE_DNS.into()])?;
 
@@ -11,6 +11,9 @@
 //!
 //! The system default resolvers don't need to be reverted because they're never deleted.
 //!
+//! Search domains go into the global suffix search list because Windows has no per-interface list.
+//! We restore the previous list when we disconnect or exit, but a crash leaves our search domains in it.
+//!
 //! <https://superuser.com/a/1752670>
 
 use anyhow::{Context as _, Result};
@@ -22,7 +25,10 @@ pub fn system_resolvers_for_gui() -> Result<Vec<IpAddr>> {
 }
 
 #[derive(Default)]
-pub(crate) struct DnsController {}
+pub(crate) struct DnsController {
+    /// The global suffix search list from before we added our search domains to it, if we did.
+    original_suffix_search_list: Option<Vec<String>>,
+}
 
 // Unique magic number that we can use to delete our well-known NRPT rule.
 // Copied from the deep link schema
@@ -30,14 +36,14 @@ const FZ_MAGIC: &str = "firezone-fd0020211111";
 
 impl Drop for DnsController {
     fn drop(&mut self) {
-        if let Err(error) = deactivate() {
+        if let Err(error) = self.deactivate() {
             tracing::error!(?error, "Failed to deactivate DNS control");
         }
     }
 }
 
 impl DnsController {
-    /// Set the computer's system-wide DNS servers
+    /// Set the computer's system-wide DNS servers and search domains
     ///
     /// There's a gap in this because on Windows we deactivate and re-activate control.
     ///
@@ -46,9 +52,68 @@ impl DnsController {
     ///
     /// Must be async to match the Linux signature
     #[allow(clippy::unused_async)]
-    pub(crate) async fn set_dns(&mut self, dns_config: &[IpAddr]) -> Result<()> {
+    pub(crate) async fn set_dns(
+        &mut self,
+        dns_config: &[IpAddr],
+        search_domains: &[String],
+    ) -> Result<()> {
         deactivate().context("Failed to deactivate DNS control")?;
         activate(dns_config).context("Failed to activate DNS control")?;
+        self.set_search_domains(search_domains)
+            .context("Failed to set search domains")?;
+        Ok(())
+    }
+
+    /// Stops sending DNS queries to our sentinels and restores the previous search domains
+    pub(crate) fn deactivate(&mut self) -> Result<()> {
+        deactivate()?;
+
+        if let Some(original) = self.original_suffix_search_list.take() {
+            set_suffix_search_list(&original)?;
+            tracing::info!("Restored DNS suffix search list");
+        }
+
+        Ok(())
+    }
+
+    /// Puts our search domains in front of the global suffix search list
+    fn set_search_domains(&mut self, search_domains: &[String]) -> Result<()> {
+        let search_domains = search_domains
+            .iter()
+            .filter(|domain| {
+                let valid = is_valid_search_domain(domain);
+                if !valid {
+                    tracing::warn!(%domain, "Ignoring invalid search domain");
+                }
+
+                valid
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if search_domains.is_empty() {
+            if let Some(original) = self.original_suffix_search_list.take() {
+                set_suffix_search_list(&original)?;
+            }
+
+            return Ok(());
+        }
+
+        let original = match self.original_suffix_search_list.as_ref() {
+            Some(original) => original,
+            None => self
+                .original_suffix_search_list
+                .insert(get_suffix_search_list()?),
+        };
+        let suffix_search_list = search_domains
+            .iter()
+            .chain(original.iter().filter(|d| !search_domains.contains(d)))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        set_suffix_search_list(&suffix_search_list)?;
+        tracing::info!(?search_domains, "Set DNS suffix search list");
+
         Ok(())
     }
 
@@ -123,6 +188,60 @@ pub(crate) fn activate(dns_config: &[IpAddr]) -> Result<()> {
     Ok(())
 }
 
+fn get_suffix_search_list() -> Result<Vec<String>> {
+    let output = Command::new("powershell")
+        .creation_flags(CREATE_NO_WINDOW)
+        .args([
+            "-Command",
+            "(Get-DnsClientGlobalSetting).SuffixSearchList -join ','",
+        ])
+        .output()?;
+    anyhow::ensure!(
+        output.status.success(),
+        "`Get-DnsClientGlobalSetting` returned non-zero"
+    );
+
+    Ok(String::from_utf8(output.stdout)?
+        .trim()
+        .split(',')
+        .filter(|domain| !domain.is_empty())
+        .map(ToOwned::to_owned)
+        .collect())
+}
+
+/// Replaces the global suffix search list
+///
+/// The domains must have passed [`is_valid_search_domain`], we pass them to Powershell as-is.
+fn set_suffix_search_list(domains: &[String]) -> Result<()> {
+    let domains = domains
+        .iter()
+        .map(|domain| format!("'{domain}'"))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let status = Command::new("powershell")
+        .creation_flags(CREATE_NO_WINDOW)
+        .arg("-Command")
+        .arg(format!(
+            "Set-DnsClientGlobalSetting -SuffixSearchList @({domains})"
+        ))
+        .status()?;
+    anyhow::ensure!(
+        status.success(),
+        "`Set-DnsClientGlobalSetting` returned non-zero"
+    );
+
+    Ok(())
+}
+
+/// Search domains come from the portal, make sure they can't inject Powershell commands
+fn is_valid_search_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && domain
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+}
+
 // Must be `sync` so we can call it from `Drop`
 pub(crate) fn deactivate() -> Result<()> {
     Command::new("powershell")
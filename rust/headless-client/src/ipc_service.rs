@@ -1,12 +1,16 @@
 use crate::{
     device_id,
     dns_control::{self, DnsController},
-    known_dirs, signals, CallbackHandler, CliCommon, InternalServerMsg, IpcServerMsg,
-    TOKEN_ENV_KEY,
+    known_dirs,
+    proxy_ips::DiskProxyIpStore,
+    signals, CallbackHandler, CliCommon, InternalServerMsg, IpcServerMsg, TOKEN_ENV_KEY,
 };
 use anyhow::{Context as _, Result};
 use clap::Parser;
-use connlib_client_shared::{file_logger, keypair, ConnectArgs, LoginUrl, Session, Sockets};
+use connlib_client_shared::{
+    file_logger, keypair, ConnectArgs, ConnectionConfig, LoginUrl, Session, Sockets,
+    DEFAULT_MAX_RELAYS,
+};
 use futures::{future, SinkExt as _, StreamExt as _};
 use std::{net::IpAddr, path::PathBuf, pin::pin, time::Duration};
 use tokio::{sync::mpsc, time::Instant};
@@ -181,7 +185,10 @@ impl Handler {
         let tun_device = TunDeviceManager::new()?;
 
         Ok(Self {
-            callback_handler: CallbackHandler { cb_tx },
+            callback_handler: CallbackHandler {
+                cb_tx,
+                dns_query_tx: None,
+            },
             cb_rx,
             connlib: None,
             dns_controller: Default::default(),
@@ -250,9 +257,14 @@ impl Handler {
                     .await
                     .context("Error while sending IPC message")?
             }
-            InternalServerMsg::OnSetInterfaceConfig { ipv4, ipv6, dns } => {
+            InternalServerMsg::OnSetInterfaceConfig {
+                ipv4,
+                ipv6,
+                dns,
+                search_domains,
+            } => {
                 self.tun_device.set_ips(ipv4, ipv6).await?;
-                self.dns_controller.set_dns(&dns).await?;
+                self.dns_controller.set_dns(&dns, &search_domains).await?;
             }
             InternalServerMsg::OnUpdateRoutes { ipv4, ipv6 } => {
                 self.tun_device.set_routes(ipv4, ipv6).await?
@@ -290,6 +302,9 @@ impl Handler {
                     app_version: env!("CARGO_PKG_VERSION").to_string(),
                     callbacks: self.callback_handler.clone(),
                     max_partition_time: Some(Duration::from_secs(60 * 60 * 24 * 30)),
+                    max_relays: DEFAULT_MAX_RELAYS,
+                    connection_config: ConnectionConfig::default(),
+                    proxy_ip_store: Some(Box::new(DiskProxyIpStore::new()?)),
                 };
                 let new_session = Session::connect(args, tokio::runtime::Handle::try_current()?);
                 new_session.set_tun(self.tun_device.make_tun()?);
@@ -299,7 +314,7 @@ impl Handler {
             ClientMsg::Disconnect => {
                 if let Some(connlib) = self.connlib.take() {
                     connlib.disconnect();
-                    dns_control::deactivate()?;
+                    self.dns_controller.deactivate()?;
                 } else {
                     tracing::error!("Error - Got Disconnect when we're already not connected");
                 }
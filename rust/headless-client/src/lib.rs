@@ -27,6 +27,7 @@ pub(crate) mod device_id;
 pub mod dns_control;
 mod ipc_service;
 pub mod known_dirs;
+mod proxy_ips;
 mod signals;
 mod standalone;
 pub mod uptime;
@@ -83,6 +84,7 @@ enum InternalServerMsg {
         ipv4: Ipv4Addr,
         ipv6: Ipv6Addr,
         dns: Vec<IpAddr>,
+        search_domains: Vec<String>,
     },
     OnUpdateRoutes {
         ipv4: Vec<Ipv4Network>,
@@ -103,6 +105,8 @@ pub enum IpcServerMsg {
 #[derive(Clone)]
 struct CallbackHandler {
     cb_tx: mpsc::Sender<InternalServerMsg>,
+    /// DNS query audit events get their own channel, such that a burst of queries can't fill up `cb_tx`.
+    dns_query_tx: Option<mpsc::Sender<callbacks::DnsQueryEvent>>,
 }
 
 impl Callbacks for CallbackHandler {
@@ -121,10 +125,21 @@ impl Callbacks for CallbackHandler {
             .expect("should be able to send OnDisconnect");
     }
 
-    fn on_set_interface_config(&self, ipv4: Ipv4Addr, ipv6: Ipv6Addr, dns: Vec<IpAddr>) {
+    fn on_set_interface_config(
+        &self,
+        ipv4: Ipv4Addr,
+        ipv6: Ipv6Addr,
+        dns: Vec<IpAddr>,
+        search_domains: Vec<String>,
+    ) {
         tracing::info!("TunnelReady (on_set_interface_config)");
         self.cb_tx
-            .try_send(InternalServerMsg::OnSetInterfaceConfig { ipv4, ipv6, dns })
+            .try_send(InternalServerMsg::OnSetInterfaceConfig {
+                ipv4,
+                ipv6,
+                dns,
+                search_domains,
+            })
             .expect("Should be able to send TunnelReady");
     }
 
@@ -142,6 +157,17 @@ impl Callbacks for CallbackHandler {
             .try_send(InternalServerMsg::OnUpdateRoutes { ipv4, ipv6 })
             .expect("Should be able to send messages");
     }
+
+    fn on_dns_query(&self, query: callbacks::DnsQueryEvent) {
+        let Some(dns_query_tx) = self.dns_query_tx.as_ref() else {
+            return;
+        };
+
+        // Dropping audit events is better than blocking connlib if we can't keep up writing them.
+        if dns_query_tx.try_send(query).is_err() {
+            tracing::warn!("Dropped DNS query audit event");
+        }
+    }
 }
 
 /// Sets up logging for stdout only, with INFO level by default
@@ -0,0 +1,99 @@
+//! Persists the proxy IPs of DNS resources across connlib sessions
+//!
+//! Otherwise, every restart would reassign them and apps or OS DNS caches still
+//! holding the old addresses would break until they resolve the names again.
+
+use anyhow::{Context as _, Result};
+use atomicwrites::{AtomicFile, OverwriteBehavior};
+use connlib_client_shared::ProxyIpStore;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write as _},
+    net::IpAddr,
+    path::PathBuf,
+};
+
+/// Stores the proxy IPs as JSON next to the device ID
+///
+/// e.g. `C:\ProgramData\dev.firezone.client/proxy-ips.json` or
+/// `/var/lib/dev.firezone.client/config/proxy-ips.json`.
+pub(crate) struct DiskProxyIpStore {
+    path: PathBuf,
+}
+
+impl DiskProxyIpStore {
+    pub(crate) fn new() -> Result<Self> {
+        let path = crate::known_dirs::ipc_service_config()
+            .context("Failed to compute path for proxy IPs file")?
+            .join("proxy-ips.json");
+
+        Ok(Self { path })
+    }
+}
+
+impl ProxyIpStore for DiskProxyIpStore {
+    fn load(&self) -> io::Result<HashMap<String, Vec<IpAddr>>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e),
+        };
+        let proxy_ips = serde_json::from_str(&content)?;
+
+        tracing::debug!(path = %self.path.display(), "Loaded proxy IPs from disk");
+
+        Ok(proxy_ips)
+    }
+
+    fn save(&self, proxy_ips: &HashMap<String, Vec<IpAddr>>) -> io::Result<()> {
+        let content = serde_json::to_vec(proxy_ips)?;
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        // `atomicwrites` makes sure we never leave a half-written file behind for the next session.
+        AtomicFile::new(&self.path, OverwriteBehavior::AllowOverwrite)
+            .write(|f| f.write_all(&content))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DiskProxyIpStore {
+            path: dir.path().join("config").join("proxy-ips.json"),
+        };
+
+        assert!(store.load().unwrap().is_empty());
+
+        let proxy_ips = HashMap::from([(
+            "app.example.com".to_owned(),
+            vec![
+                "100.96.0.1".parse().unwrap(),
+                "fd00:2021:1111:8000::".parse().unwrap(),
+            ],
+        )]);
+        store.save(&proxy_ips).unwrap();
+
+        assert_eq!(store.load().unwrap(), proxy_ips);
+    }
+
+    #[test]
+    fn corrupt_file_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DiskProxyIpStore {
+            path: dir.path().join("proxy-ips.json"),
+        };
+        fs::write(&store.path, "not json").unwrap();
+
+        assert!(store.load().is_err());
+    }
+}
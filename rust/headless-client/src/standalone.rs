@@ -1,22 +1,32 @@
 //! AKA "Headless"
 
 use crate::{
-    default_token_path, device_id, dns_control, platform, signals, CallbackHandler, CliCommon,
-    DnsController, InternalServerMsg, IpcServerMsg, TOKEN_ENV_KEY,
+    default_token_path, device_id, dns_control, platform, proxy_ips::DiskProxyIpStore, signals,
+    CallbackHandler, CliCommon, DnsController, InternalServerMsg, IpcServerMsg, TOKEN_ENV_KEY,
 };
 use anyhow::{anyhow, Context as _, Result};
 use clap::Parser;
-use connlib_client_shared::{file_logger, keypair, ConnectArgs, LoginUrl, Session, Sockets};
+use connlib_client_shared::{
+    file_logger, keypair, ConnectArgs, ConnectionConfig, LoginUrl, Session, Sockets,
+    DEFAULT_MAX_RELAYS,
+};
+use connlib_shared::callbacks;
 use firezone_bin_shared::{setup_global_subscriber, TunDeviceManager};
 use futures::{FutureExt as _, StreamExt as _};
 use secrecy::SecretString;
 use std::{
+    fs::File,
+    io::Write as _,
     path::{Path, PathBuf},
     pin::pin,
+    time::SystemTime,
 };
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
 
+/// How many DNS query audit events we buffer before we drop new ones.
+const DNS_AUDIT_QUEUE_SIZE: usize = 1000;
+
 /// Command-line args for the headless Client
 #[derive(clap::Parser)]
 #[command(author, version, about, long_about = None)]
@@ -70,6 +80,14 @@ struct Cli {
     // on disk somewhere anyway.)
     #[arg(default_value = default_token_path().display().to_string(), env = "FIREZONE_TOKEN_PATH", long)]
     token_path: PathBuf,
+
+    /// Appends an audit record for every DNS query answered by Firezone to this file, as JSON lines.
+    #[arg(long, env = "FIREZONE_DNS_AUDIT_LOG")]
+    dns_audit_log: Option<PathBuf>,
+
+    /// How many of the relays with the lowest latency to use for relayed connections.
+    #[arg(long, env = "FIREZONE_MAX_RELAYS", default_value_t = DEFAULT_MAX_RELAYS)]
+    max_relays: usize,
 }
 
 #[derive(clap::Subcommand, Clone, Copy)]
@@ -145,13 +163,30 @@ pub fn run_only_headless_client() -> Result<()> {
         public_key.to_bytes(),
     )?;
 
+    let dns_audit_log = cli
+        .dns_audit_log
+        .as_deref()
+        .map(open_dns_audit_log)
+        .transpose()?;
+
     if cli.check {
         tracing::info!("Check passed");
         return Ok(());
     }
 
     let (cb_tx, cb_rx) = mpsc::channel(10);
-    let callbacks = CallbackHandler { cb_tx };
+    let (dns_query_tx, dns_query_rx) = mpsc::channel(DNS_AUDIT_QUEUE_SIZE);
+    let dns_query_audit = dns_audit_log.is_some();
+    if let Some(file) = dns_audit_log {
+        std::thread::Builder::new()
+            .name("dns-audit-log".to_owned())
+            .spawn(move || write_dns_audit_log(file, dns_query_rx))
+            .context("Couldn't spawn DNS audit log thread")?;
+    }
+    let callbacks = CallbackHandler {
+        cb_tx,
+        dns_query_tx: dns_query_audit.then_some(dns_query_tx),
+    };
 
     platform::setup_before_connlib()?;
     let args = ConnectArgs {
@@ -162,8 +197,12 @@ pub fn run_only_headless_client() -> Result<()> {
         app_version: env!("CARGO_PKG_VERSION").to_string(),
         callbacks,
         max_partition_time,
+        max_relays: cli.max_relays,
+        connection_config: ConnectionConfig::default(),
+        proxy_ip_store: Some(Box::new(DiskProxyIpStore::new()?)),
     };
     let session = Session::connect(args, rt.handle().clone());
+    session.set_dns_query_audit(dns_query_audit);
     platform::notify_service_controller()?;
 
     let result = rt.block_on(async {
@@ -203,9 +242,14 @@ pub fn run_only_headless_client() -> Result<()> {
                     // On every resources update, flush DNS to mitigate <https://github.com/firezone/firezone/issues/5052>
                     dns_controller.flush()?;
                 }
-                InternalServerMsg::OnSetInterfaceConfig { ipv4, ipv6, dns } => {
+                InternalServerMsg::OnSetInterfaceConfig {
+                    ipv4,
+                    ipv6,
+                    dns,
+                    search_domains,
+                } => {
                     tun_device.set_ips(ipv4, ipv6).await?;
-                    dns_controller.set_dns(&dns).await?;
+                    dns_controller.set_dns(&dns, &search_domains).await?;
                 }
                 InternalServerMsg::OnUpdateRoutes { ipv4, ipv6 } => {
                     tun_device.set_routes(ipv4, ipv6).await?
@@ -219,6 +263,48 @@ pub fn run_only_headless_client() -> Result<()> {
     result
 }
 
+/// One line of the DNS audit log
+#[derive(serde::Serialize)]
+struct DnsAuditRecord {
+    /// RFC 3339 timestamp of when we answered the query
+    timestamp: String,
+    #[serde(flatten)]
+    query: callbacks::DnsQueryEvent,
+}
+
+fn open_dns_audit_log(path: &Path) -> Result<File> {
+    File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Couldn't open DNS audit log `{}`", path.display()))
+}
+
+/// Writes DNS query audit events to the log until connlib shuts down
+///
+/// Runs on its own thread because we do blocking file I/O
+fn write_dns_audit_log(mut file: File, mut queries: mpsc::Receiver<callbacks::DnsQueryEvent>) {
+    while let Some(query) = queries.blocking_recv() {
+        if let Err(error) = write_dns_audit_record(&mut file, query) {
+            tracing::error!(?error, "Failed to write DNS audit record");
+        }
+    }
+}
+
+/// Sync because we do blocking file I/O
+fn write_dns_audit_record(file: &mut File, query: callbacks::DnsQueryEvent) -> Result<()> {
+    let record = DnsAuditRecord {
+        timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        query,
+    };
+    let mut line = serde_json::to_string(&record)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())
+        .context("Couldn't write to DNS audit log")?;
+
+    Ok(())
+}
+
 /// Read the token from disk if it was not in the environment
 ///
 /// # Returns
@@ -273,7 +359,7 @@ fn read_token_file(path: &Path) -> Result<Option<SecretString>> {
 
 #[cfg(test)]
 mod tests {
-    use super::Cli;
+    use super::{callbacks, Cli, DnsAuditRecord};
     use clap::Parser;
     use std::path::PathBuf;
     use url::Url;
@@ -295,4 +381,23 @@ mod tests {
         assert!(actual.check);
         assert_eq!(actual.common.log_dir, Some(PathBuf::from("bogus_log_dir")));
     }
+
+    #[test]
+    fn dns_audit_record_is_flat() {
+        let record = DnsAuditRecord {
+            timestamp: "2024-06-01T12:00:00.000Z".to_string(),
+            query: callbacks::DnsQueryEvent {
+                name: "app.example.com".to_string(),
+                record_type: "A".to_string(),
+                resource: None,
+                resolution: callbacks::DnsResolution::Local,
+                response_code: "NOERROR".to_string(),
+            },
+        };
+
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"timestamp":"2024-06-01T12:00:00.000Z","name":"app.example.com","record_type":"A","resource":null,"resolution":"local","response_code":"NOERROR"}"#
+        );
+    }
 }
@@ -44,6 +44,13 @@ macro_rules! swap_src_dst {
     };
 }
 
+/// The ECN codepoint signalling "Congestion Experienced", see RFC 3168.
+pub const ECN_CE: u8 = 0b11;
+/// The ECN codepoint of packets that don't support ECN.
+pub const ECN_NOT_ECT: u8 = 0b00;
+
+const ECN_MASK: u8 = 0b11;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Protocol {
     /// Contains either the source or destination port.
@@ -243,6 +250,10 @@ impl<'a> ConvertibleIpv4Packet<'a> {
         self.as_ipv4().set_header_length(header_length);
     }
 
+    fn set_ecn(&mut self, ecn: u8) {
+        self.as_ipv4().set_ecn(ecn);
+    }
+
     fn consume_to_immutable(self) -> Ipv4Packet<'a> {
         match self.buf {
             MaybeOwned::RefMut(buf) => {
@@ -569,6 +580,13 @@ impl<'a> ConvertibleIpv6Packet<'a> {
         self.as_ipv6().set_payload_length(payload_length);
     }
 
+    fn set_ecn(&mut self, ecn: u8) {
+        let traffic_class = self.to_immutable().get_traffic_class();
+
+        self.as_ipv6()
+            .set_traffic_class((traffic_class & !ECN_MASK) | (ecn & ECN_MASK));
+    }
+
     fn consume_to_immutable(self) -> Ipv6Packet<'a> {
         match self.buf {
             MaybeOwned::RefMut(buf) => {
@@ -1023,6 +1041,13 @@ impl<'a> MutableIpPacket<'a> {
         for_both!(self, |i| i.get_destination().into())
     }
 
+    /// Sets the ECN bits of this packet, updating the IPv4 header checksum if necessary.
+    pub fn set_ecn(&mut self, ecn: u8) {
+        for_both!(self, |i| i.set_ecn(ecn));
+
+        self.set_ipv4_checksum();
+    }
+
     pub fn set_source_protocol(&mut self, v: u16) {
         if let Some(mut p) = self.as_tcp() {
             p.set_source(v);
@@ -1352,6 +1377,22 @@ impl<'a> IpPacket<'a> {
         for_both!(self, |i| i.get_destination().into())
     }
 
+    /// The Differentiated Services Code Point of this packet.
+    pub fn dscp(&self) -> u8 {
+        match self {
+            Self::Ipv4(p) => p.get_dscp(),
+            Self::Ipv6(p) => p.get_traffic_class() >> 2,
+        }
+    }
+
+    /// The Explicit Congestion Notification bits of this packet.
+    pub fn ecn(&self) -> u8 {
+        match self {
+            Self::Ipv4(p) => p.get_ecn(),
+            Self::Ipv6(p) => p.get_traffic_class() & ECN_MASK,
+        }
+    }
+
     pub fn udp_payload(&self) -> &[u8] {
         debug_assert_eq!(
             match self {
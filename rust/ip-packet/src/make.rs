@@ -167,11 +167,38 @@ pub fn tcp_packet<IP>(
     dport: u16,
     payload: Vec<u8>,
 ) -> MutableIpPacket<'static>
+where
+    IP: Into<IpAddr>,
+{
+    tcp_segment(saddr, daddr, sport, dport, 0, 0, 0, 128, payload)
+}
+
+/// Makes a TCP segment with the given sequence and acknowledgement number, flags and receive window.
+#[allow(clippy::too_many_arguments)]
+pub fn tcp_segment<IP>(
+    saddr: IP,
+    daddr: IP,
+    sport: u16,
+    dport: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    payload: Vec<u8>,
+) -> MutableIpPacket<'static>
 where
     IP: Into<IpAddr>,
 {
     let saddr = saddr.into();
     let daddr = daddr.into();
+    let header = TcpHeader {
+        sport,
+        dport,
+        seq,
+        ack,
+        flags,
+        window,
+    };
 
     match (saddr, daddr) {
         (IpAddr::V4(src), IpAddr::V4(dst)) => {
@@ -183,7 +210,7 @@ where
 
             ipv4_header(src, dst, IpNextHeaderProtocols::Tcp, 5, &mut buf[20..]);
 
-            tcp_header(saddr, daddr, sport, dport, &payload, &mut buf[40..]);
+            tcp_header(saddr, daddr, header, &payload, &mut buf[40..]);
             MutableIpPacket::owned(buf).unwrap()
         }
         (IpAddr::V6(src), IpAddr::V6(dst)) => {
@@ -193,7 +220,7 @@ where
 
             ipv6_header(src, dst, IpNextHeaderProtocols::Tcp, &mut buf[20..]);
 
-            tcp_header(saddr, daddr, sport, dport, &payload, &mut buf[60..]);
+            tcp_header(saddr, daddr, header, &payload, &mut buf[60..]);
             MutableIpPacket::owned(buf).unwrap()
         }
         (IpAddr::V6(_), IpAddr::V4(_)) | (IpAddr::V4(_), IpAddr::V6(_)) => {
@@ -372,22 +399,24 @@ fn ipv6_header(src: Ipv6Addr, dst: Ipv6Addr, proto: IpNextHeaderProtocol, buf: &
     ipv6_packet.set_destination(dst);
 }
 
-fn tcp_header(
-    saddr: IpAddr,
-    daddr: IpAddr,
+struct TcpHeader {
     sport: u16,
     dport: u16,
-    payload: &[u8],
-    buf: &mut [u8],
-) {
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+}
+
+fn tcp_header(saddr: IpAddr, daddr: IpAddr, header: TcpHeader, payload: &[u8], buf: &mut [u8]) {
     let mut tcp_packet = MutableTcpPacket::new(buf).unwrap();
-    tcp_packet.set_source(sport);
-    tcp_packet.set_destination(dport);
-    tcp_packet.set_sequence(0);
-    tcp_packet.set_acknowledgement(0);
+    tcp_packet.set_source(header.sport);
+    tcp_packet.set_destination(header.dport);
+    tcp_packet.set_sequence(header.seq);
+    tcp_packet.set_acknowledgement(header.ack);
     tcp_packet.set_data_offset(5);
-    tcp_packet.set_flags(0);
-    tcp_packet.set_window(128);
+    tcp_packet.set_flags(header.flags);
+    tcp_packet.set_window(header.window);
     tcp_packet.set_payload(payload);
     match (saddr, daddr) {
         (IpAddr::V4(src), IpAddr::V4(dst)) => {
@@ -404,6 +404,12 @@ impl<T> Eventloop<T> {
             }
             Some(
                 snownet::Event::InvalidateIceCandidate { .. }
+                | snownet::Event::EndOfIceCandidates { .. }
+                | snownet::Event::NewIceCredentials { .. }
+                | snownet::Event::NewPresharedKey { .. }
+                | snownet::Event::ConnectionPathChanged { .. }
+                | snownet::Event::PathMtuChanged { .. }
+                | snownet::Event::NatStatusChanged(_)
                 | snownet::Event::ConnectionClosed { .. },
             )
             | None => {}
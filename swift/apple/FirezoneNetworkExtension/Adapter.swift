@@ -318,7 +318,8 @@ extension Adapter {
 
 extension Adapter: CallbackHandlerDelegate {
   public func onSetInterfaceConfig(
-    tunnelAddressIPv4: String, tunnelAddressIPv6: String, dnsAddresses: [String]
+    tunnelAddressIPv4: String, tunnelAddressIPv6: String, dnsAddresses: [String],
+    searchDomains: [String]
   ) {
     // This is a queued callback to ensure ordering
     workQueue.async { [weak self] in
@@ -330,7 +331,7 @@ extension Adapter: CallbackHandlerDelegate {
       }
 
       Log.tunnel.log(
-        "\(#function): \(tunnelAddressIPv4) \(tunnelAddressIPv6) \(dnsAddresses)")
+        "\(#function): \(tunnelAddressIPv4) \(tunnelAddressIPv6) \(dnsAddresses) \(searchDomains)")
 
       switch state {
       case .tunnelStarted(session: _):
@@ -338,6 +339,7 @@ extension Adapter: CallbackHandlerDelegate {
         networkSettings.tunnelAddressIPv4 = tunnelAddressIPv4
         networkSettings.tunnelAddressIPv6 = tunnelAddressIPv6
         networkSettings.dnsAddresses = dnsAddresses
+        networkSettings.searchDomains = searchDomains
         networkSettings.apply()
       case .tunnelStopped:
         Log.tunnel.error(
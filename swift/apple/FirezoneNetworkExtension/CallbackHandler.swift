@@ -20,7 +20,8 @@ public protocol CallbackHandlerDelegate: AnyObject {
   func onSetInterfaceConfig(
     tunnelAddressIPv4: String,
     tunnelAddressIPv6: String,
-    dnsAddresses: [String]
+    dnsAddresses: [String],
+    searchDomains: [String]
   )
   func onUpdateRoutes(routeList4: String, routeList6: String)
   func onUpdateResources(resourceList: String)
@@ -33,7 +34,8 @@ public class CallbackHandler {
   func onSetInterfaceConfig(
     tunnelAddressIPv4: RustString,
     tunnelAddressIPv6: RustString,
-    dnsAddresses: RustString
+    dnsAddresses: RustString,
+    searchDomains: RustString
   ) {
     Log.tunnel.log(
       """
@@ -41,15 +43,19 @@ public class CallbackHandler {
           IPv4: \(tunnelAddressIPv4.toString())
           IPv6: \(tunnelAddressIPv6.toString())
           DNS: \(dnsAddresses.toString())
+          Search domains: \(searchDomains.toString())
       """)
 
     let dnsData = dnsAddresses.toString().data(using: .utf8)!
     let dnsArray = try! JSONDecoder().decode([String].self, from: dnsData)
+    let searchDomainsData = searchDomains.toString().data(using: .utf8)!
+    let searchDomainsArray = try! JSONDecoder().decode([String].self, from: searchDomainsData)
 
     delegate?.onSetInterfaceConfig(
       tunnelAddressIPv4: tunnelAddressIPv4.toString(),
       tunnelAddressIPv6: tunnelAddressIPv6.toString(),
-      dnsAddresses: dnsArray
+      dnsAddresses: dnsArray,
+      searchDomains: searchDomainsArray
     )
   }
 